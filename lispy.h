@@ -52,6 +52,9 @@ extern void set_return_value(output_t output, value_t value);
 extern void set_exception_value(output_t output, exception_t value);
 
 extern fun_shape_t new_function_shape(char** args, size_t len, size_t* str_lens);
+extern void function_shape_add_optional(fun_shape_t shape, char* name, size_t len);
+extern void function_shape_add_keyword(fun_shape_t shape, char* name, size_t len);
+extern void function_shape_set_rest(fun_shape_t shape, char* name, size_t len);
 extern void value_call_function(value_t fun, context_t ctx, value_t* args, size_t arg_len, kwargs_t kwargs, output_t output);
 
 extern kwargs_t kwargs_new(void);
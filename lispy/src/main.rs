@@ -8,6 +8,31 @@ extern crate lispy_core;
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
 
-    lispy_core::run_from_file(&args[1], ".")?;
+    match args.get(1).map(|arg| arg.as_str()) {
+	Some(flag @ ("--expand" | "--vm" | "--trace" | "--disassemble" | "--lint")) => {
+	    let Some(file) = args.get(2) else {
+		usage();
+	    };
+	    match flag {
+		"--expand" => lispy_core::expand_file(file, ".")?,
+		"--vm" => lispy_core::run_from_file_with_vm(file, ".")?,
+		"--trace" => lispy_core::trace_file(file, ".")?,
+		"--disassemble" => lispy_core::disassemble_file(file, ".")?,
+		_ => lispy_core::lint_file(file, ".")?,
+	    }
+	},
+	Some(file) if !file.starts_with("--") => lispy_core::run_from_file(file, ".")?,
+	_ => usage(),
+    }
     Ok(())
 }
+
+fn usage() -> ! {
+    eprintln!("usage: lispy [--expand | --vm | --trace | --disassemble | --lint] <file>");
+    eprintln!("  --expand       print the file after macro expansion");
+    eprintln!("  --vm           run the file on the virtual machine");
+    eprintln!("  --trace        run the file on the virtual machine, logging each instruction");
+    eprintln!("  --disassemble  print the bytecode of each top level form");
+    eprintln!("  --lint         check the file without running it, matches over enums from imported modules are not checked");
+    std::process::exit(2);
+}
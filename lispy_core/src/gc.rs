@@ -152,8 +152,9 @@ impl<T> Clone for Gc<T> {
 		    let ptr_ref = ptr.lock().unwrap();
 		    ptr_ref.as_ref().count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
+		    // The clone gets a mutex of its own, copying this one bitwise would copy it while it is locked
 		    Gc { raw: UnsafeCell::new(RawGc::Protected {
-			ptr: std::ptr::read(ptr),
+			ptr: Mutex::new(*ptr_ref),
 			marked: *marked,
 		    }) }
 		},
@@ -176,9 +177,9 @@ impl<T: ?Sized> Drop for Gc<T> {
 		RawGc::Protected { ptr, .. } => {
 		    let ptr_ref = ptr.lock().unwrap();
 		    ptr_ref.as_ref().count.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+		    // The guard is reused, locking the mutex a second time here would never return
 		    if ptr_ref.as_ref().count.load(std::sync::atomic::Ordering::Relaxed) == 0 {
-			let ptr = ptr.lock().unwrap();
-			drop(Box::from_raw(ptr.as_ptr()));
+			drop(Box::from_raw(ptr_ref.as_ptr()));
 		    }
 		},
 	    }
@@ -250,3 +251,52 @@ pub fn garbage_collect(table: &mut GcTable, receiver: std::sync::mpsc::Receiver<
 	time = std::time::Instant::now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts how many times it is dropped, a value dropped twice was freed twice
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+	fn drop(&mut self) {
+	    self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+	}
+    }
+
+    fn drops(count: &Arc<AtomicUsize>) -> usize {
+	count.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_protected_clone_outlives_original() {
+	let count = Arc::new(AtomicUsize::new(0));
+	let gc = Gc::new((Counted(count.clone()), 5));
+	gc.protect();
+	let clone = gc.clone();
+	drop(gc);
+	// The value is still there for the clone to read
+	assert_eq!(drops(&count), 0);
+	assert_eq!(clone.get().1, 5);
+	let second = clone.clone();
+	drop(clone);
+	assert_eq!(second.get().1, 5);
+	drop(second);
+	assert_eq!(drops(&count), 1);
+    }
+
+    #[test]
+    fn test_protected_value_freed_once() {
+	let count = Arc::new(AtomicUsize::new(0));
+	let gc = Gc::new(Counted(count.clone()));
+	let normal = gc.clone();
+	gc.protect();
+	let clones = (0..3).map(|_| gc.clone()).collect::<Vec<_>>();
+	drop(clones);
+	drop(gc);
+	assert_eq!(drops(&count), 0);
+	drop(normal);
+	assert_eq!(drops(&count), 1);
+    }
+}
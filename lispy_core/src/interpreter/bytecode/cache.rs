@@ -0,0 +1,776 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::interpreter::context::Context;
+use crate::interpreter::value::function::{FunctionShape, Parameter};
+use crate::parser::{Atom, Sexpr, Span};
+use crate::parser::r#macro::Macro;
+
+use super::{verifier, Bytecode, CatchBinding, Capture, Prototype, RawBytecode};
+
+const MAGIC: &[u8; 4] = b"LPYC";
+/// Changes whenever the layout of the file or the meaning of an instruction does
+const VERSION: u32 = 2;
+const NO_FILE: u32 = u32::MAX;
+
+/// Where the compiled form of a source file is kept, next to it unless `LISPY_CACHE_DIR` names a directory
+pub fn cache_path(source: &Path) -> PathBuf {
+    match std::env::var("LISPY_CACHE_DIR") {
+	Ok(directory) => {
+	    let stem = source.file_stem().and_then(|stem| stem.to_str()).unwrap_or("module");
+	    let name = format!("{}-{:016x}.lpyc", stem, hash(source.to_string_lossy().as_bytes()));
+	    Path::new(&directory).join(name)
+	},
+	Err(_) => source.with_extension("lpyc"),
+    }
+}
+
+/// FNV-1a, which unlike the standard library's hasher is the same from one build to the next
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Hashes the definitions of the macros a file is expanded with, in no particular order
+pub fn macros_hash(macros: &HashSet<Macro>) -> u64 {
+    let mut definitions = macros.iter().map(Macro::definition).collect::<Vec<String>>();
+    definitions.sort();
+    hash(definitions.join("\n").as_bytes())
+}
+
+/// The compiled top level forms of a source file, if it has a cache that was written from its current contents
+/// expanded with the same macros and the code in it passes the verifier
+pub fn load(source: &Path, text: &str, macros_hash: u64, context: &Context) -> Option<Vec<Vec<Bytecode>>> {
+    let bytes = std::fs::read(cache_path(source)).ok()?;
+    let forms = decode(&bytes, hash(text.as_bytes()), macros_hash)?;
+    forms.iter().all(|code| verifier::verify(code, 0, context).is_ok()).then_some(forms)
+}
+
+/// Saves the compiled top level forms of a source file, a cache that can't be written is only a slower next import
+pub fn store(source: &Path, text: &str, macros_hash: u64, forms: &[Vec<Bytecode>]) {
+    let path = cache_path(source);
+    if let Some(directory) = path.parent() {
+	let _ = std::fs::create_dir_all(directory);
+    }
+    let _ = std::fs::write(path, encode(forms, hash(text.as_bytes()), macros_hash));
+}
+
+/// Writes the magic bytes, the format and lispy versions, the source hash, the hash of the macros it was expanded with,
+/// the table of strings the code uses, the table of procedures it makes and then each form's code. Strings and procedures
+/// are referred to by their index in their table, a procedure comes in the table before those that make it.
+pub fn encode(forms: &[Vec<Bytecode>], source_hash: u64, macros_hash: u64) -> Vec<u8> {
+    let mut writer = Writer::default();
+    let mut body = Vec::new();
+    write_usize(&mut body, forms.len());
+    for code in forms {
+	writer.code(&mut body, code);
+    }
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_bytes(&mut bytes, env!("CARGO_PKG_VERSION").as_bytes());
+    bytes.extend_from_slice(&source_hash.to_le_bytes());
+    bytes.extend_from_slice(&macros_hash.to_le_bytes());
+    write_usize(&mut bytes, writer.constants.len());
+    for constant in writer.constants.iter() {
+	write_bytes(&mut bytes, constant.as_bytes());
+    }
+    write_usize(&mut bytes, writer.function_count);
+    bytes.extend_from_slice(&writer.functions);
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+/// Reads back what `encode` wrote, nothing if it was written by another version, for other source or macros or is damaged
+pub fn decode(bytes: &[u8], source_hash: u64, macros_hash: u64) -> Option<Vec<Vec<Bytecode>>> {
+    let mut reader = Reader {
+	bytes,
+	position: 0,
+	constants: Vec::new(),
+	files: HashMap::new(),
+	functions: Vec::new(),
+    };
+    if reader.take(4)? != MAGIC || reader.u32()? != VERSION {
+	return None;
+    }
+    if reader.bytes()? != env!("CARGO_PKG_VERSION").as_bytes() || reader.u64()? != source_hash || reader.u64()? != macros_hash {
+	return None;
+    }
+    for _ in 0..reader.usize()? {
+	let constant = String::from_utf8(reader.bytes()?.to_vec()).ok()?;
+	reader.constants.push(constant);
+    }
+    for _ in 0..reader.usize()? {
+	let prototype = reader.prototype()?;
+	reader.functions.push(prototype);
+    }
+    let mut forms = Vec::new();
+    for _ in 0..reader.usize()? {
+	forms.push(reader.code()?);
+    }
+    if reader.position != bytes.len() {
+	return None;
+    }
+    Some(forms)
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_usize(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_usize(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+#[derive(Default)]
+struct Writer {
+    constants: Vec<String>,
+    indices: HashMap<String, u32>,
+    functions: Vec<u8>,
+    function_count: usize,
+}
+
+impl Writer {
+    fn constant(&mut self, out: &mut Vec<u8>, constant: &str) {
+	let index = match self.indices.get(constant) {
+	    Some(index) => *index,
+	    None => {
+		let index = self.constants.len() as u32;
+		self.constants.push(constant.to_string());
+		self.indices.insert(constant.to_string(), index);
+		index
+	    },
+	};
+	write_u32(out, index);
+    }
+
+    fn symbol(&mut self, out: &mut Vec<u8>, symbol: &[String]) {
+	write_usize(out, symbol.len());
+	for part in symbol {
+	    self.constant(out, part);
+	}
+    }
+
+    fn optional(&mut self, out: &mut Vec<u8>, constant: Option<&String>) {
+	match constant {
+	    Some(constant) => {
+		out.push(1);
+		self.constant(out, constant);
+	    },
+	    None => out.push(0),
+	}
+    }
+
+    fn code(&mut self, out: &mut Vec<u8>, code: &[Bytecode]) {
+	write_usize(out, code.len());
+	for bytecode in code {
+	    self.instruction(out, &bytecode.raw);
+	    write_usize(out, bytecode.line);
+	    write_usize(out, bytecode.column);
+	}
+    }
+
+    /// Adds a procedure to the function table, after the procedures it makes, and writes its index
+    fn prototype(&mut self, out: &mut Vec<u8>, prototype: &Prototype) {
+	let mut entry = Vec::new();
+	self.symbol(&mut entry, &prototype.args);
+	self.shape(&mut entry, &prototype.shape);
+	self.code(&mut entry, &prototype.code);
+	self.functions.extend_from_slice(&entry);
+	write_usize(out, self.function_count);
+	self.function_count += 1;
+    }
+
+    fn shape(&mut self, out: &mut Vec<u8>, shape: &FunctionShape) {
+	self.symbol(out, shape.get_args());
+	for parameters in [shape.get_optional(), shape.get_keywords()] {
+	    write_usize(out, parameters.len());
+	    for parameter in parameters {
+		self.constant(out, parameter.get_name());
+		match parameter.get_default() {
+		    Some(default) => {
+			out.push(1);
+			self.sexpr(out, default);
+		    },
+		    None => out.push(0),
+		}
+	    }
+	}
+	self.optional(out, shape.get_rest());
+    }
+
+    fn span(&mut self, out: &mut Vec<u8>, span: &Span) {
+	match &span.file {
+	    Some(file) => self.constant(out, file),
+	    None => write_u32(out, NO_FILE),
+	}
+	write_usize(out, span.offset);
+	write_usize(out, span.line);
+	write_usize(out, span.column);
+    }
+
+    fn sexprs(&mut self, out: &mut Vec<u8>, sexprs: &[Sexpr]) {
+	write_usize(out, sexprs.len());
+	for sexpr in sexprs {
+	    self.sexpr(out, sexpr);
+	}
+    }
+
+    fn sexpr(&mut self, out: &mut Vec<u8>, sexpr: &Sexpr) {
+	match sexpr {
+	    Sexpr::Atom(atom, _) => {
+		out.push(0);
+		self.atom(out, atom);
+	    },
+	    Sexpr::List(list, _) => {
+		out.push(1);
+		self.sexprs(out, list);
+	    },
+	    Sexpr::QuotedList(list, _) => {
+		out.push(2);
+		self.sexprs(out, list);
+	    },
+	    Sexpr::VectorList(list, _) => {
+		out.push(3);
+		self.sexprs(out, list);
+	    },
+	    Sexpr::Quasiquote(sexpr, _) => {
+		out.push(4);
+		self.sexpr(out, sexpr);
+	    },
+	    Sexpr::Unquote(sexpr, _) => {
+		out.push(5);
+		self.sexpr(out, sexpr);
+	    },
+	    Sexpr::UnquoteSplicing(sexpr, _) => {
+		out.push(6);
+		self.sexpr(out, sexpr);
+	    },
+	}
+	self.span(out, sexpr.span());
+    }
+
+    fn atom(&mut self, out: &mut Vec<u8>, atom: &Atom) {
+	match atom {
+	    Atom::String(string) => {
+		out.push(0);
+		self.constant(out, string);
+	    },
+	    Atom::Integer(integer) => {
+		out.push(1);
+		self.constant(out, integer);
+	    },
+	    Atom::Float(float) => {
+		out.push(2);
+		out.extend_from_slice(&float.to_bits().to_le_bytes());
+	    },
+	    Atom::Boolean(boolean) => {
+		out.push(3);
+		out.push(*boolean as u8);
+	    },
+	    Atom::Symbol(symbol) => {
+		out.push(4);
+		self.symbol(out, symbol);
+	    },
+	    Atom::Keyword(keyword) => {
+		out.push(5);
+		self.constant(out, keyword);
+	    },
+	    Atom::Char(c) => {
+		out.push(6);
+		write_u32(out, *c as u32);
+	    },
+	    Atom::QuotedSymbol(symbol) => {
+		out.push(7);
+		self.symbol(out, symbol);
+	    },
+	    Atom::Null => out.push(8),
+	    Atom::Placeholder => out.push(9),
+	}
+    }
+
+    fn instruction(&mut self, out: &mut Vec<u8>, raw: &RawBytecode) {
+	match raw {
+	    RawBytecode::PushString(string) => {
+		out.push(0);
+		self.constant(out, string);
+	    },
+	    RawBytecode::PushInteger(integer) => {
+		out.push(1);
+		self.constant(out, integer);
+	    },
+	    RawBytecode::PushFloat(float) => {
+		out.push(2);
+		out.extend_from_slice(&float.to_bits().to_le_bytes());
+	    },
+	    RawBytecode::PushBoolean(boolean) => {
+		out.push(3);
+		out.push(*boolean as u8);
+	    },
+	    RawBytecode::PushSymbol(symbol) => {
+		out.push(4);
+		self.symbol(out, symbol);
+	    },
+	    RawBytecode::PushChar(c) => {
+		out.push(5);
+		write_u32(out, *c as u32);
+	    },
+	    RawBytecode::PushNil => out.push(6),
+	    RawBytecode::PushQuoted(sexpr) => {
+		out.push(7);
+		self.sexpr(out, sexpr);
+	    },
+	    RawBytecode::Pop => out.push(8),
+	    RawBytecode::Dup => out.push(9),
+	    RawBytecode::Store => out.push(10),
+	    RawBytecode::Load => out.push(11),
+	    RawBytecode::Set => out.push(12),
+	    RawBytecode::BindKeyword => out.push(13),
+	    RawBytecode::Reserve(count) => {
+		out.push(14);
+		write_usize(out, *count);
+	    },
+	    RawBytecode::LoadLocal(slot) => {
+		out.push(15);
+		write_usize(out, *slot);
+	    },
+	    RawBytecode::StoreLocal(slot) => {
+		out.push(16);
+		write_usize(out, *slot);
+	    },
+	    RawBytecode::LoadUpvalue(index) => {
+		out.push(17);
+		write_usize(out, *index);
+	    },
+	    RawBytecode::SetUpvalue(index) => {
+		out.push(18);
+		write_usize(out, *index);
+	    },
+	    RawBytecode::MakeCell => out.push(19),
+	    RawBytecode::LoadCell => out.push(20),
+	    RawBytecode::SetCell => out.push(21),
+	    RawBytecode::Call(count, name) => {
+		out.push(22);
+		write_usize(out, *count);
+		self.symbol(out, name);
+	    },
+	    RawBytecode::TailCall(count, name) => {
+		out.push(23);
+		write_usize(out, *count);
+		self.symbol(out, name);
+	    },
+	    RawBytecode::CallWithEscape => out.push(24),
+	    RawBytecode::Return => out.push(25),
+	    RawBytecode::Jump(offset) => {
+		out.push(26);
+		write_usize(out, *offset);
+	    },
+	    RawBytecode::JumpIfFalse(offset) => {
+		out.push(27);
+		write_usize(out, *offset);
+	    },
+	    RawBytecode::PushFrame => out.push(28),
+	    RawBytecode::PopFrame => out.push(29),
+	    RawBytecode::MakeClosure(prototype, captures) => {
+		out.push(30);
+		self.prototype(out, prototype);
+		write_usize(out, captures.len());
+		for capture in captures {
+		    match capture {
+			Capture::Local(slot) => {
+			    out.push(0);
+			    write_usize(out, *slot);
+			},
+			Capture::Upvalue(index) => {
+			    out.push(1);
+			    write_usize(out, *index);
+			},
+		    }
+		}
+	    },
+	    RawBytecode::MakeVector(count) => {
+		out.push(31);
+		write_usize(out, *count);
+	    },
+	    RawBytecode::MakeStruct(count) => {
+		out.push(32);
+		write_usize(out, *count);
+	    },
+	    RawBytecode::StructAccess => out.push(33),
+	    RawBytecode::StructStore => out.push(34),
+	    RawBytecode::MakeEnum(count) => {
+		out.push(35);
+		write_usize(out, *count);
+	    },
+	    RawBytecode::EnumAccess => out.push(36),
+	    RawBytecode::EnumStore => out.push(37),
+	    RawBytecode::MatchPattern(pattern) => {
+		out.push(38);
+		self.sexpr(out, pattern);
+	    },
+	    RawBytecode::NoMatch => out.push(39),
+	    RawBytecode::PushHandler(bindings) => {
+		out.push(40);
+		write_usize(out, bindings.len());
+		for binding in bindings {
+		    out.push(binding.message as u8);
+		    out.push(binding.backtrace as u8);
+		    write_usize(out, binding.handler);
+		}
+	    },
+	    RawBytecode::PopHandler => out.push(41),
+	    RawBytecode::PushCleanup(offset) => {
+		out.push(42);
+		write_usize(out, *offset);
+	    },
+	    RawBytecode::PopCleanup => out.push(43),
+	    RawBytecode::Reraise => out.push(44),
+	    RawBytecode::Error(count) => {
+		out.push(45);
+		write_usize(out, *count);
+	    },
+	    RawBytecode::Walk(sexpr) => {
+		out.push(46);
+		self.sexpr(out, sexpr);
+	    },
+	}
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    constants: Vec<String>,
+    /// The file names of spans, so forms from one file share it
+    files: HashMap<u32, Arc<str>>,
+    functions: Vec<Prototype>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+	let end = self.position.checked_add(count)?;
+	let bytes = self.bytes.get(self.position..end)?;
+	self.position = end;
+	Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+	Some(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+	match self.u8()? {
+	    0 => Some(false),
+	    1 => Some(true),
+	    _ => None,
+	}
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+	Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+	Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+	usize::try_from(self.u64()?).ok()
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+	let count = self.usize()?;
+	self.take(count)
+    }
+
+    fn char(&mut self) -> Option<char> {
+	char::from_u32(self.u32()?)
+    }
+
+    fn float(&mut self) -> Option<f64> {
+	Some(f64::from_bits(self.u64()?))
+    }
+
+    fn constant(&mut self) -> Option<String> {
+	let index = self.u32()?;
+	self.constants.get(index as usize).cloned()
+    }
+
+    fn symbol(&mut self) -> Option<Vec<String>> {
+	(0..self.usize()?).map(|_| self.constant()).collect()
+    }
+
+    fn optional(&mut self) -> Option<Option<String>> {
+	match self.bool()? {
+	    true => Some(Some(self.constant()?)),
+	    false => Some(None),
+	}
+    }
+
+    fn code(&mut self) -> Option<Vec<Bytecode>> {
+	let mut code = Vec::new();
+	for _ in 0..self.usize()? {
+	    let raw = self.instruction()?;
+	    let line = self.usize()?;
+	    let column = self.usize()?;
+	    code.push(Bytecode::new(raw, line, column));
+	}
+	Some(code)
+    }
+
+    fn prototype(&mut self) -> Option<Prototype> {
+	let args = self.symbol()?;
+	let shape = self.shape()?;
+	let code = self.code()?;
+	Some(Prototype {
+	    args,
+	    code,
+	    shape,
+	})
+    }
+
+    fn parameters(&mut self) -> Option<Vec<Parameter>> {
+	let mut parameters = Vec::new();
+	for _ in 0..self.usize()? {
+	    let name = self.constant()?;
+	    let default = match self.bool()? {
+		true => Some(self.sexpr()?),
+		false => None,
+	    };
+	    parameters.push(Parameter::new(name, default));
+	}
+	Some(parameters)
+    }
+
+    fn shape(&mut self) -> Option<FunctionShape> {
+	let args = self.symbol()?;
+	let optional = self.parameters()?;
+	let keywords = self.parameters()?;
+	let rest = self.optional()?;
+	Some(FunctionShape::new(args).with_optional(optional).with_keywords(keywords).with_rest(rest))
+    }
+
+    fn span(&mut self) -> Option<Span> {
+	let index = self.u32()?;
+	let file = match index {
+	    NO_FILE => None,
+	    index => match self.files.get(&index) {
+		Some(file) => Some(file.clone()),
+		None => {
+		    let file: Arc<str> = Arc::from(self.constants.get(index as usize)?.as_str());
+		    self.files.insert(index, file.clone());
+		    Some(file)
+		},
+	    },
+	};
+	let offset = self.usize()?;
+	let line = self.usize()?;
+	let column = self.usize()?;
+	Some(Span {
+	    file,
+	    offset,
+	    line,
+	    column,
+	})
+    }
+
+    fn sexprs(&mut self) -> Option<Vec<Sexpr>> {
+	(0..self.usize()?).map(|_| self.sexpr()).collect()
+    }
+
+    fn sexpr(&mut self) -> Option<Sexpr> {
+	let sexpr = match self.u8()? {
+	    0 => {
+		let atom = self.atom()?;
+		Sexpr::Atom(atom, self.span()?)
+	    },
+	    1 => {
+		let list = self.sexprs()?;
+		Sexpr::List(list, self.span()?)
+	    },
+	    2 => {
+		let list = self.sexprs()?;
+		Sexpr::QuotedList(list, self.span()?)
+	    },
+	    3 => {
+		let list = self.sexprs()?;
+		Sexpr::VectorList(list, self.span()?)
+	    },
+	    4 => {
+		let sexpr = self.sexpr()?;
+		Sexpr::Quasiquote(Box::new(sexpr), self.span()?)
+	    },
+	    5 => {
+		let sexpr = self.sexpr()?;
+		Sexpr::Unquote(Box::new(sexpr), self.span()?)
+	    },
+	    6 => {
+		let sexpr = self.sexpr()?;
+		Sexpr::UnquoteSplicing(Box::new(sexpr), self.span()?)
+	    },
+	    _ => return None,
+	};
+	Some(sexpr)
+    }
+
+    fn atom(&mut self) -> Option<Atom> {
+	let atom = match self.u8()? {
+	    0 => Atom::String(self.constant()?),
+	    1 => Atom::Integer(self.constant()?),
+	    2 => Atom::Float(self.float()?),
+	    3 => Atom::Boolean(self.bool()?),
+	    4 => Atom::Symbol(self.symbol()?),
+	    5 => Atom::Keyword(self.constant()?),
+	    6 => Atom::Char(self.char()?),
+	    7 => Atom::QuotedSymbol(self.symbol()?),
+	    8 => Atom::Null,
+	    9 => Atom::Placeholder,
+	    _ => return None,
+	};
+	Some(atom)
+    }
+
+    fn instruction(&mut self) -> Option<RawBytecode> {
+	let raw = match self.u8()? {
+	    0 => RawBytecode::PushString(self.constant()?),
+	    1 => RawBytecode::PushInteger(self.constant()?),
+	    2 => RawBytecode::PushFloat(self.float()?),
+	    3 => RawBytecode::PushBoolean(self.bool()?),
+	    4 => RawBytecode::PushSymbol(self.symbol()?),
+	    5 => RawBytecode::PushChar(self.char()?),
+	    6 => RawBytecode::PushNil,
+	    7 => RawBytecode::PushQuoted(self.sexpr()?),
+	    8 => RawBytecode::Pop,
+	    9 => RawBytecode::Dup,
+	    10 => RawBytecode::Store,
+	    11 => RawBytecode::Load,
+	    12 => RawBytecode::Set,
+	    13 => RawBytecode::BindKeyword,
+	    14 => RawBytecode::Reserve(self.usize()?),
+	    15 => RawBytecode::LoadLocal(self.usize()?),
+	    16 => RawBytecode::StoreLocal(self.usize()?),
+	    17 => RawBytecode::LoadUpvalue(self.usize()?),
+	    18 => RawBytecode::SetUpvalue(self.usize()?),
+	    19 => RawBytecode::MakeCell,
+	    20 => RawBytecode::LoadCell,
+	    21 => RawBytecode::SetCell,
+	    22 => RawBytecode::Call(self.usize()?, self.symbol()?),
+	    23 => RawBytecode::TailCall(self.usize()?, self.symbol()?),
+	    24 => RawBytecode::CallWithEscape,
+	    25 => RawBytecode::Return,
+	    26 => RawBytecode::Jump(self.usize()?),
+	    27 => RawBytecode::JumpIfFalse(self.usize()?),
+	    28 => RawBytecode::PushFrame,
+	    29 => RawBytecode::PopFrame,
+	    30 => {
+		let index = self.usize()?;
+		let prototype = self.functions.get(index)?.clone();
+		let mut captures = Vec::new();
+		for _ in 0..self.usize()? {
+		    let capture = match self.u8()? {
+			0 => Capture::Local(self.usize()?),
+			1 => Capture::Upvalue(self.usize()?),
+			_ => return None,
+		    };
+		    captures.push(capture);
+		}
+		RawBytecode::MakeClosure(Box::new(prototype), captures)
+	    },
+	    31 => RawBytecode::MakeVector(self.usize()?),
+	    32 => RawBytecode::MakeStruct(self.usize()?),
+	    33 => RawBytecode::StructAccess,
+	    34 => RawBytecode::StructStore,
+	    35 => RawBytecode::MakeEnum(self.usize()?),
+	    36 => RawBytecode::EnumAccess,
+	    37 => RawBytecode::EnumStore,
+	    38 => RawBytecode::MatchPattern(self.sexpr()?),
+	    39 => RawBytecode::NoMatch,
+	    40 => {
+		let mut bindings = Vec::new();
+		for _ in 0..self.usize()? {
+		    let message = self.bool()?;
+		    let backtrace = self.bool()?;
+		    let handler = self.usize()?;
+		    bindings.push(CatchBinding {
+			message,
+			backtrace,
+			handler,
+		    });
+		}
+		RawBytecode::PushHandler(bindings)
+	    },
+	    41 => RawBytecode::PopHandler,
+	    42 => RawBytecode::PushCleanup(self.usize()?),
+	    43 => RawBytecode::PopCleanup,
+	    44 => RawBytecode::Reraise,
+	    45 => RawBytecode::Error(self.usize()?),
+	    46 => RawBytecode::Walk(self.sexpr()?),
+	    _ => return None,
+	};
+	Some(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::bytecode::compiler::compile;
+    use crate::interpreter::walkthrough::tests::new_context;
+
+    fn compile_source(source: &str) -> Vec<Vec<Bytecode>> {
+	let mut context = new_context();
+	let file = crate::parser::parse_with_context(source, "test.lpy", &mut context.get_macros(), &context).expect("parse error");
+	file.into_iter().map(|sexpr| compile(&sexpr, &mut context).expect("compile error")).collect()
+    }
+
+    const SOURCE: &str = "(define (adder n [step 1]) (lambda (x) (+ x n step)))
+(match '(1 \"two\" #\\3) ['(a . rest) rest] [_ 0.5])
+(try (error 'who \"bad\") ((catch 'who message) message) (finally #t))";
+
+    #[test]
+    fn test_encode_decode() {
+	let forms = compile_source(SOURCE);
+	let bytes = encode(&forms, hash(SOURCE.as_bytes()), 7);
+	assert_eq!(decode(&bytes, hash(SOURCE.as_bytes()), 7), Some(forms));
+    }
+
+    #[test]
+    fn test_stale_hash_is_rejected() {
+	let forms = compile_source(SOURCE);
+	let bytes = encode(&forms, hash(SOURCE.as_bytes()), 7);
+	assert_eq!(decode(&bytes, hash(b"(define changed 1)"), 7), None);
+    }
+
+    #[test]
+    fn test_other_macros_are_rejected() {
+	let forms = compile_source(SOURCE);
+	let bytes = encode(&forms, hash(SOURCE.as_bytes()), 7);
+	assert_eq!(decode(&bytes, hash(SOURCE.as_bytes()), 8), None);
+    }
+
+    #[test]
+    fn test_other_version_is_rejected() {
+	let forms = compile_source(SOURCE);
+	let mut bytes = encode(&forms, hash(SOURCE.as_bytes()), 7);
+	bytes[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+	assert_eq!(decode(&bytes, hash(SOURCE.as_bytes()), 7), None);
+    }
+
+    #[test]
+    fn test_truncated_file_is_rejected() {
+	let forms = compile_source(SOURCE);
+	let bytes = encode(&forms, hash(SOURCE.as_bytes()), 7);
+	for length in [0, 4, 8, bytes.len() / 2, bytes.len() - 1] {
+	    assert_eq!(decode(&bytes[..length], hash(SOURCE.as_bytes()), 7), None, "cut at {}", length);
+	}
+	let mut longer = bytes.clone();
+	longer.push(0);
+	assert_eq!(decode(&longer, hash(SOURCE.as_bytes()), 7), None);
+    }
+}
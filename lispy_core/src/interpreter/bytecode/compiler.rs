@@ -0,0 +1,1007 @@
+use std::collections::HashSet;
+use crate::parser::{Atom, Sexpr};
+use crate::interpreter::{Exception, HelperResult};
+use crate::interpreter::context::Context;
+use crate::interpreter::value::function::FunctionShape;
+use crate::interpreter::walkthrough;
+use super::{verifier, Bytecode, Capture, CatchBinding, Prototype, RawBytecode};
+
+/// How a name in scope is reached
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    Slot(usize),
+    /// Defined in a frame by name, as the variables of a `match` pattern are
+    Frame,
+}
+
+/// Where the code being compiled finds a variable
+enum Variable {
+    /// A local slot and whether it holds a cell
+    Local(usize, bool),
+    Upvalue(usize),
+    /// Looked up by name in the frames and modules
+    Named,
+}
+
+/// A procedure being compiled, or a top level form
+struct Unit {
+    code: Vec<Bytecode>,
+    /// Whether variables go in local slots, otherwise they are all defined in frames by name
+    slotted: bool,
+    scope: Vec<(String, Binding)>,
+    slots: usize,
+    /// The slots closures capture, which hold cells
+    boxed: HashSet<usize>,
+    /// Where the closure being made gets each of its cells
+    captures: Vec<Capture>,
+    /// Set when a closure captured a slot whose code was emitted without a cell
+    recompile: bool,
+}
+
+impl Unit {
+    fn new(slotted: bool, boxed: HashSet<usize>) -> Self {
+	Unit {
+	    code: Vec::new(),
+	    slotted,
+	    scope: Vec::new(),
+	    slots: 0,
+	    boxed,
+	    captures: Vec::new(),
+	    recompile: false,
+	}
+    }
+}
+
+/// Lowers forms into bytecode for the virtual machine.
+/// Every expression leaves exactly one value on the stack, forms without a value leave nil.
+/// Procedures keep their variables in local slots, and closures capture the ones they use in cells.
+/// Top level forms and procedures that need their variables by name define them in the frames of the context instead
+pub struct Compiler<'a> {
+    context: &'a mut Context,
+    units: Vec<Unit>,
+    line: usize,
+    column: usize,
+}
+
+/// Compiles a form into code that returns its value
+pub fn compile(sexpr: &Sexpr, context: &mut Context) -> HelperResult<Vec<Bytecode>> {
+    let mut compiler = Compiler::new(context);
+    compiler.compile_expr(sexpr)?;
+    compiler.emit(RawBytecode::Return);
+    let code = compiler.units.pop().unwrap().code;
+    verifier::verify(&code, 0, context)?;
+    Ok(code)
+}
+
+/// Whether code can only reach the variables around it by name. The tree walker runs quasiquotes, guards,
+/// parameter defaults and the forms that declare things, and `call` looks its procedure up by name
+fn needs_frames(sexpr: &Sexpr) -> bool {
+    match sexpr {
+	Sexpr::Quasiquote(_, _) => true,
+	Sexpr::List(list, _) => {
+	    if let Some(Sexpr::Atom(Atom::Symbol(head), _)) = list.first() {
+		match head[0].as_str() {
+		    "call" | "import" | "import-from" | "module" | "struct" | "enum" | "handler-bind" | "restart-case" | "?" => return true,
+		    "lambda" | "define" if matches!(list.get(1), Some(Sexpr::List(header, _)) if header.iter().any(|p| matches!(p, Sexpr::List(_, _)))) => return true,
+		    _ => {},
+		}
+	    }
+	    list.iter().any(needs_frames)
+	},
+	Sexpr::QuotedList(list, _) | Sexpr::VectorList(list, _) => list.iter().any(needs_frames),
+	_ => false,
+    }
+}
+
+/// The name a body level `define` defines
+fn defined_name(sexpr: &Sexpr) -> Option<&String> {
+    let Sexpr::List(list, _) = sexpr else {
+	return None;
+    };
+    match list.as_slice() {
+	[Sexpr::Atom(Atom::Symbol(define), _), Sexpr::Atom(Atom::Symbol(name), _), ..] if define[0] == "define" => Some(&name[0]),
+	[Sexpr::Atom(Atom::Symbol(define), _), Sexpr::List(header, _), ..] if define[0] == "define" => match header.first() {
+	    Some(Sexpr::Atom(Atom::Symbol(name), _)) => Some(&name[0]),
+	    _ => None,
+	},
+	_ => None,
+    }
+}
+
+/// The variables a `match` pattern may define, a variant name may be among them
+fn pattern_variables(pattern: &Sexpr, variables: &mut Vec<String>) {
+    match pattern {
+	Sexpr::Atom(Atom::Symbol(name), _) if name[0] != "_" && name[0] != "." => variables.push(name[0].clone()),
+	Sexpr::List(list, _) => {
+	    for pattern in list.iter().skip(1) {
+		pattern_variables(pattern, variables);
+	    }
+	},
+	Sexpr::QuotedList(list, _) | Sexpr::VectorList(list, _) => {
+	    for pattern in list {
+		pattern_variables(pattern, variables);
+	    }
+	},
+	_ => {},
+    }
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(context: &'a mut Context) -> Self {
+	Compiler {
+	    context,
+	    units: vec![Unit::new(false, HashSet::new())],
+	    line: 0,
+	    column: 0,
+	}
+    }
+
+    fn unit(&mut self) -> &mut Unit {
+	self.units.last_mut().unwrap()
+    }
+
+    fn slotted(&self) -> bool {
+	self.units.last().unwrap().slotted
+    }
+
+    /// Adds an instruction at the position of the form being compiled and returns its index
+    fn emit(&mut self, raw: RawBytecode) -> usize {
+	let (line, column) = (self.line, self.column);
+	let code = &mut self.unit().code;
+	code.push(Bytecode::new(raw, line, column));
+	code.len() - 1
+    }
+
+    fn here(&self) -> usize {
+	self.units.last().unwrap().code.len()
+    }
+
+    /// Points the jump at `index` to `target`
+    fn patch(&mut self, index: usize, target: usize) {
+	match &mut self.unit().code[index].raw {
+	    RawBytecode::Jump(to) | RawBytecode::JumpIfFalse(to) | RawBytecode::PushCleanup(to) => *to = target,
+	    raw => unreachable!("{:?} is not a jump", raw),
+	}
+    }
+
+    fn error(&self, who: &str, message: &str) -> Box<Exception> {
+	Box::new(Exception::new(&vec![who], message, self.context))
+    }
+
+    /// Gives a name a new local slot in the innermost scope, making its cell if closures capture it
+    fn declare(&mut self, name: &str) -> usize {
+	let unit = self.unit();
+	let slot = unit.slots;
+	unit.slots += 1;
+	unit.scope.push((name.to_string(), Binding::Slot(slot)));
+	if unit.boxed.contains(&slot) {
+	    self.emit(RawBytecode::PushNil);
+	    self.emit(RawBytecode::MakeCell);
+	    self.emit(RawBytecode::StoreLocal(slot));
+	}
+	slot
+    }
+
+    /// Pops a value into a slot `declare` made
+    fn assign(&mut self, slot: usize) {
+	if self.unit().boxed.contains(&slot) {
+	    self.emit(RawBytecode::LoadLocal(slot));
+	    self.emit(RawBytecode::SetCell);
+	} else {
+	    self.emit(RawBytecode::StoreLocal(slot));
+	}
+    }
+
+    fn scope_depth(&self) -> usize {
+	self.units.last().unwrap().scope.len()
+    }
+
+    fn close_scope(&mut self, depth: usize) {
+	self.unit().scope.truncate(depth);
+    }
+
+    fn resolve(&mut self, name: &[String]) -> Variable {
+	if name.len() != 1 {
+	    return Variable::Named;
+	}
+	self.resolve_in(self.units.len() - 1, &name[0])
+    }
+
+    /// Finds a name in the scope of a unit or, capturing it, in the units around it
+    fn resolve_in(&mut self, unit: usize, name: &str) -> Variable {
+	let binding = self.units[unit].scope.iter().rev().find(|(n, _)| n == name).map(|(_, binding)| *binding);
+	match binding {
+	    Some(Binding::Slot(slot)) => return Variable::Local(slot, self.units[unit].boxed.contains(&slot)),
+	    Some(Binding::Frame) => return Variable::Named,
+	    None => {},
+	}
+	if unit == 0 {
+	    return Variable::Named;
+	}
+	let capture = match self.resolve_in(unit - 1, name) {
+	    Variable::Local(slot, boxed) => {
+		if !boxed {
+		    let outer = &mut self.units[unit - 1];
+		    outer.boxed.insert(slot);
+		    outer.recompile = true;
+		}
+		Capture::Local(slot)
+	    },
+	    Variable::Upvalue(index) => Capture::Upvalue(index),
+	    Variable::Named => return Variable::Named,
+	};
+	let captures = &mut self.units[unit].captures;
+	let index = match captures.iter().position(|c| *c == capture) {
+	    Some(index) => index,
+	    None => {
+		captures.push(capture);
+		captures.len() - 1
+	    },
+	};
+	Variable::Upvalue(index)
+    }
+
+    fn load_variable(&mut self, name: &[String]) {
+	match self.resolve(name) {
+	    Variable::Local(slot, boxed) => {
+		self.emit(RawBytecode::LoadLocal(slot));
+		if boxed {
+		    self.emit(RawBytecode::LoadCell);
+		}
+	    },
+	    Variable::Upvalue(index) => {
+		self.emit(RawBytecode::LoadUpvalue(index));
+	    },
+	    Variable::Named => {
+		self.emit(RawBytecode::PushSymbol(name.to_vec()));
+		self.emit(RawBytecode::Load);
+	    },
+	}
+    }
+
+    /// Pops a value into a variable that is already defined
+    fn set_variable(&mut self, name: &[String]) {
+	match self.resolve(name) {
+	    Variable::Local(slot, _) => self.assign(slot),
+	    Variable::Upvalue(index) => {
+		self.emit(RawBytecode::SetUpvalue(index));
+	    },
+	    Variable::Named => {
+		self.emit(RawBytecode::PushSymbol(name.to_vec()));
+		self.emit(RawBytecode::Set);
+	    },
+	}
+    }
+
+    pub fn compile_expr(&mut self, sexpr: &Sexpr) -> HelperResult<()> {
+	self.compile_at(sexpr, false)
+    }
+
+    /// Compiles an expression, a call in tail position becomes a `TailCall`
+    fn compile_at(&mut self, sexpr: &Sexpr, tail: bool) -> HelperResult<()> {
+	let span = sexpr.span();
+	if !span.is_known() {
+	    return self.compile_located(sexpr, tail);
+	}
+	let position = (self.line, self.column);
+	let previous = self.context.set_location(span.clone());
+	(self.line, self.column) = (span.line, span.column);
+	let result = self.compile_located(sexpr, tail);
+	(self.line, self.column) = position;
+	self.context.set_location(previous);
+	result
+    }
+
+    fn compile_located(&mut self, sexpr: &Sexpr, tail: bool) -> HelperResult<()> {
+	match sexpr {
+	    Sexpr::Atom(atom, _) => {
+		let raw = match atom {
+		    Atom::String(s) => RawBytecode::PushString(s.clone()),
+		    Atom::Integer(i) => RawBytecode::PushInteger(i.clone()),
+		    Atom::Float(f) => RawBytecode::PushFloat(*f),
+		    Atom::Boolean(b) => RawBytecode::PushBoolean(*b),
+		    Atom::Symbol(s) => {
+			self.load_variable(s);
+			return Ok(());
+		    },
+		    Atom::QuotedSymbol(s) => RawBytecode::PushSymbol(s.clone()),
+		    Atom::Char(c) => RawBytecode::PushChar(*c),
+		    Atom::Null => RawBytecode::PushNil,
+		    Atom::Keyword(_) => {
+			let empty: Vec<&str> = Vec::new();
+			return Err(Box::new(Exception::new(&empty, "keyword not allowed here", self.context)));
+		    },
+		    Atom::Placeholder => {
+			let empty: Vec<&str> = Vec::new();
+			return Err(Box::new(Exception::new(&empty, "not yet implemented", self.context)));
+		    },
+		};
+		self.emit(raw);
+	    },
+	    Sexpr::QuotedList(list, span) => {
+		self.emit(RawBytecode::PushQuoted(Sexpr::List(list.clone(), span.clone())));
+	    },
+	    Sexpr::VectorList(list, _) => {
+		for sexpr in list {
+		    self.compile_expr(sexpr)?;
+		}
+		self.emit(RawBytecode::MakeVector(list.len()));
+	    },
+	    Sexpr::List(list, _) => self.compile_list(list, sexpr, tail)?,
+	    Sexpr::Quasiquote(_, _) => {
+		self.emit(RawBytecode::Walk(sexpr.clone()));
+	    },
+	    Sexpr::Unquote(_, _) => return Err(self.error("unquote", "not inside a quasiquote")),
+	    Sexpr::UnquoteSplicing(_, _) => return Err(self.error("unquote-splicing", "not inside a quasiquote")),
+	}
+	Ok(())
+    }
+
+    fn compile_list(&mut self, list: &Vec<Sexpr>, sexpr: &Sexpr, tail: bool) -> HelperResult<()> {
+	let Some(head) = list.first() else {
+	    self.emit(RawBytecode::PushNil);
+	    return Ok(());
+	};
+	if let Sexpr::Atom(Atom::Symbol(s), _) = head {
+	    match s[0].as_str() {
+		"define" => return self.compile_define(list),
+		"lambda" => return self.compile_lambda(list),
+		"if" => return self.compile_if(list, tail),
+		"set!" => return self.compile_set(list),
+		"let" => return self.compile_let(list, tail),
+		"let*" | "letrec" | "letrec*" => return self.compile_sequential_let(list, tail),
+		"begin" => return self.compile_body(&list[1..], tail),
+		"try" => return self.compile_try(list),
+		"error" => return self.compile_error(list),
+		"cond" => return self.compile_cond(list, tail),
+		"call" => return self.compile_call_expr(list),
+		"match" => return self.compile_match(list, tail),
+		"while" => return self.compile_while(list),
+		// These only declare things when they run, so the tree walker does them
+		"import" | "import-from" | "module" | "struct" | "enum" | "handler-bind" | "restart-case" => {
+		    self.emit(RawBytecode::Walk(sexpr.clone()));
+		    return Ok(());
+		},
+		_ => {},
+	    }
+	}
+	self.compile_call(list, tail)
+    }
+
+    /// Compiles the expressions of a body in order, leaving the value of the last one.
+    /// The body's definitions get their slots first, so procedures in it can refer to each other
+    fn compile_body(&mut self, body: &[Sexpr], tail: bool) -> HelperResult<()> {
+	let Some((last, init)) = body.split_last() else {
+	    self.emit(RawBytecode::PushNil);
+	    return Ok(());
+	};
+	if self.slotted() {
+	    for name in body.iter().filter_map(defined_name) {
+		self.declare(name);
+	    }
+	}
+	for sexpr in init {
+	    self.compile_expr(sexpr)?;
+	    self.emit(RawBytecode::Pop);
+	}
+	self.compile_at(last, tail)
+    }
+
+    /// Compiles a body in a scope of its own so its internal definitions stay local to it
+    fn compile_scoped_body(&mut self, body: &[Sexpr], tail: bool) -> HelperResult<()> {
+	if self.slotted() {
+	    let depth = self.scope_depth();
+	    self.compile_body(body, tail)?;
+	    self.close_scope(depth);
+	    return Ok(());
+	}
+	self.emit(RawBytecode::PushFrame);
+	self.compile_body(body, tail)?;
+	self.emit(RawBytecode::PopFrame);
+	Ok(())
+    }
+
+    /// Compiles a procedure body as a unit of its own and emits the instruction that makes a closure of it.
+    /// The body is compiled again when a closure in it captures a slot, so that slot gets its cell from the start
+    fn compile_function(&mut self, args: Vec<String>, shape: FunctionShape, body: &[Sexpr]) -> HelperResult<()> {
+	let slotted = !body.iter().any(needs_frames);
+	let mut boxed = HashSet::new();
+	let unit = loop {
+	    self.units.push(Unit::new(slotted, boxed));
+	    let result = self.compile_procedure_body(&shape, body);
+	    let unit = self.units.pop().unwrap();
+	    result?;
+	    if !unit.recompile {
+		break unit;
+	    }
+	    boxed = unit.boxed;
+	};
+	self.emit(RawBytecode::MakeClosure(Box::new(Prototype {
+	    args,
+	    code: unit.code,
+	    shape,
+	}), unit.captures));
+	Ok(())
+    }
+
+    /// The parameters are bound by name when the procedure is called, a slotted body moves them into slots first
+    fn compile_procedure_body(&mut self, shape: &FunctionShape, body: &[Sexpr]) -> HelperResult<()> {
+	if self.slotted() {
+	    let reserve = self.emit(RawBytecode::Reserve(0));
+	    for name in shape.get_names() {
+		let slot = self.declare(&name);
+		self.emit(RawBytecode::PushSymbol(vec![name]));
+		self.emit(RawBytecode::Load);
+		self.assign(slot);
+	    }
+	    self.compile_body(body, true)?;
+	    let slots = self.unit().slots;
+	    self.unit().code[reserve].raw = RawBytecode::Reserve(slots);
+	} else {
+	    self.compile_body(body, true)?;
+	}
+	self.emit(RawBytecode::Return);
+	Ok(())
+    }
+
+    fn compile_define(&mut self, list: &Vec<Sexpr>) -> HelperResult<()> {
+	let name = match list.as_slice() {
+	    [_, Sexpr::Atom(Atom::Symbol(name), _), value] => {
+		self.compile_expr(value)?;
+		name[0].clone()
+	    },
+	    [_, Sexpr::List(header, _), body @ ..] if !header.is_empty() && !body.is_empty() => {
+		let name = match &header[0] {
+		    Sexpr::Atom(Atom::Symbol(s), _) => s[0].clone(),
+		    _ => return Err(self.error("define", "not a symbol")),
+		};
+		let (args, shape) = walkthrough::function_shape("define", &header[1..], self.context)?;
+		self.compile_function(args, shape, body)?;
+		name
+	    },
+	    _ => return Err(self.error("define", "unusual syntax")),
+	};
+	if self.slotted() {
+	    let declared = self.unit().scope.iter().rev().find(|(n, _)| *n == name).map(|(_, binding)| *binding);
+	    let slot = match declared {
+		Some(Binding::Slot(slot)) => slot,
+		_ => self.declare(&name),
+	    };
+	    self.assign(slot);
+	} else {
+	    self.emit(RawBytecode::PushSymbol(vec![name]));
+	    self.emit(RawBytecode::Store);
+	}
+	self.emit(RawBytecode::PushNil);
+	Ok(())
+    }
+
+    fn compile_lambda(&mut self, list: &Vec<Sexpr>) -> HelperResult<()> {
+	match list.as_slice() {
+	    [_, Sexpr::List(header, _), body @ ..] if !body.is_empty() => {
+		let (args, shape) = walkthrough::function_shape("lambda", header, self.context)?;
+		self.compile_function(args, shape, body)
+	    },
+	    [_, Sexpr::Atom(Atom::Symbol(rest), _), body @ ..] if !body.is_empty() => {
+		let shape = FunctionShape::new(Vec::new()).with_rest(Some(rest[0].clone()));
+		self.compile_function(Vec::new(), shape, body)
+	    },
+	    _ => Err(self.error("lambda", "unusual syntax")),
+	}
+    }
+
+    fn compile_if(&mut self, list: &Vec<Sexpr>, tail: bool) -> HelperResult<()> {
+	let [_, condition, consequent, alternate] = list.as_slice() else {
+	    return Err(self.error("if", "unusual syntax"));
+	};
+	self.compile_expr(condition)?;
+	let to_alternate = self.emit(RawBytecode::JumpIfFalse(0));
+	self.compile_at(consequent, tail)?;
+	let to_end = self.emit(RawBytecode::Jump(0));
+	self.patch(to_alternate, self.here());
+	self.compile_at(alternate, tail)?;
+	self.patch(to_end, self.here());
+	Ok(())
+    }
+
+    fn compile_set(&mut self, list: &Vec<Sexpr>) -> HelperResult<()> {
+	let [_, Sexpr::Atom(Atom::Symbol(name), _), value] = list.as_slice() else {
+	    return Err(self.error("set!", "unusual syntax"));
+	};
+	self.compile_expr(value)?;
+	self.set_variable(name);
+	self.emit(RawBytecode::PushNil);
+	Ok(())
+    }
+
+    /// `let` evaluates every value before binding any of them, the values are bound from the top of the stack down
+    fn compile_let(&mut self, list: &Vec<Sexpr>, tail: bool) -> HelperResult<()> {
+	match list.as_slice() {
+	    [_, Sexpr::Atom(Atom::Symbol(name), _), Sexpr::List(bindings, _), body @ ..] if !body.is_empty() => {
+		self.compile_named_let(&name[0], bindings, body, tail)
+	    },
+	    [_, Sexpr::List(bindings, _), body @ ..] if !body.is_empty() => {
+		let mut names = Vec::new();
+		for binding in bindings {
+		    let (name, value) = walkthrough::let_binding("let", binding, self.context)?;
+		    self.compile_expr(value)?;
+		    names.push(name[0].clone());
+		}
+		if self.slotted() {
+		    let depth = self.scope_depth();
+		    let slots = names.iter().map(|name| self.declare(name)).collect::<Vec<usize>>();
+		    for slot in slots.into_iter().rev() {
+			self.assign(slot);
+		    }
+		    self.compile_body(body, tail)?;
+		    self.close_scope(depth);
+		    return Ok(());
+		}
+		self.emit(RawBytecode::PushFrame);
+		for name in names.into_iter().rev() {
+		    self.emit(RawBytecode::PushSymbol(vec![name]));
+		    self.emit(RawBytecode::Store);
+		}
+		self.compile_body(body, tail)?;
+		self.emit(RawBytecode::PopFrame);
+		Ok(())
+	    },
+	    _ => Err(self.error("let", "unusual syntax")),
+	}
+    }
+
+    /// `letrec` and `letrec*` give every name its slot before any value is evaluated, so the values can refer to all of them
+    fn compile_sequential_let(&mut self, list: &Vec<Sexpr>, tail: bool) -> HelperResult<()> {
+	let Some(Sexpr::Atom(Atom::Symbol(who), _)) = list.first() else {
+	    return Err(self.error("let*", "unusual syntax"));
+	};
+	let [_, Sexpr::List(bindings, _), body @ ..] = list.as_slice() else {
+	    return Err(self.error(&who[0], "unusual syntax"));
+	};
+	if body.is_empty() {
+	    return Err(self.error(&who[0], "unusual syntax"));
+	}
+	let recursive = who[0] != "let*";
+	if !self.slotted() {
+	    // As on the walker, let* gives each binding a frame of its own
+	    let mut frames = 1;
+	    self.emit(RawBytecode::PushFrame);
+	    for binding in bindings {
+		let (name, value) = walkthrough::let_binding(&who[0], binding, self.context)?;
+		self.compile_expr(value)?;
+		if !recursive {
+		    self.emit(RawBytecode::PushFrame);
+		    frames += 1;
+		}
+		self.emit(RawBytecode::PushSymbol(vec![name[0].clone()]));
+		self.emit(RawBytecode::Store);
+	    }
+	    self.compile_body(body, tail)?;
+	    for _ in 0..frames {
+		self.emit(RawBytecode::PopFrame);
+	    }
+	    return Ok(());
+	}
+	let depth = self.scope_depth();
+	let mut slots = Vec::new();
+	if recursive {
+	    for binding in bindings {
+		let (name, _) = walkthrough::let_binding(&who[0], binding, self.context)?;
+		slots.push(self.declare(&name[0]));
+	    }
+	}
+	for (i, binding) in bindings.iter().enumerate() {
+	    let (name, value) = walkthrough::let_binding(&who[0], binding, self.context)?;
+	    self.compile_expr(value)?;
+	    let slot = if recursive {
+		slots[i]
+	    } else {
+		self.declare(&name[0])
+	    };
+	    self.assign(slot);
+	}
+	self.compile_body(body, tail)?;
+	self.close_scope(depth);
+	Ok(())
+    }
+
+    /// The loop procedure is bound only where its body can see it, then called with the values
+    fn compile_named_let(&mut self, name: &str, bindings: &[Sexpr], body: &[Sexpr], tail: bool) -> HelperResult<()> {
+	let mut args = Vec::new();
+	let mut values = Vec::new();
+	for binding in bindings {
+	    let (arg, value) = walkthrough::let_binding("let", binding, self.context)?;
+	    args.push(arg[0].clone());
+	    values.push(value);
+	}
+	if self.slotted() {
+	    let depth = self.scope_depth();
+	    let slot = self.declare(name);
+	    self.compile_function(args.clone(), FunctionShape::new(args), body)?;
+	    self.emit(RawBytecode::Dup);
+	    self.assign(slot);
+	    self.close_scope(depth);
+	} else {
+	    self.emit(RawBytecode::PushFrame);
+	    self.compile_function(args.clone(), FunctionShape::new(args), body)?;
+	    self.emit(RawBytecode::Dup);
+	    self.emit(RawBytecode::PushSymbol(vec![name.to_string()]));
+	    self.emit(RawBytecode::Store);
+	    self.emit(RawBytecode::PopFrame);
+	}
+	for value in values.iter() {
+	    self.compile_expr(value)?;
+	}
+	self.emit_call(values.len(), vec![name.to_string()], tail);
+	Ok(())
+    }
+
+    /// The clauses are established as handlers around the body. The finally clause follows the body and the clauses,
+    /// and a copy of it that raises the error again is where an error leaving them goes
+    fn compile_try(&mut self, list: &[Sexpr]) -> HelperResult<()> {
+	let rest = &list[1..];
+	let split = rest.iter().position(walkthrough::is_handler).unwrap_or(rest.len());
+	let (body, handlers) = rest.split_at(split);
+	if body.is_empty() {
+	    return Err(self.error("try", "unusual syntax"));
+	}
+	let (handlers, finally) = match handlers {
+	    [handlers @ .., Sexpr::List(finally, _)] if matches!(finally.first(), Some(Sexpr::Atom(Atom::Symbol(keyword), _)) if keyword[0] == "finally") => (handlers, Some(&finally[1..])),
+	    handlers => (handlers, None),
+	};
+	let cleanup = finally.map(|_| self.emit(RawBytecode::PushCleanup(0)));
+	let mut clauses = Vec::new();
+	for handler in handlers {
+	    clauses.push(self.compile_catch_matcher(handler)?);
+	}
+	let push_handler = self.emit(RawBytecode::PushHandler(Vec::new()));
+	self.compile_scoped_body(body, false)?;
+	self.emit(RawBytecode::PopHandler);
+	let mut ends = vec![self.emit(RawBytecode::Jump(0))];
+
+	let mut bindings = Vec::new();
+	for (binding, variables, body) in clauses {
+	    bindings.push(CatchBinding {
+		handler: self.here(),
+		..binding
+	    });
+	    self.compile_catch_body(&variables, body)?;
+	    ends.push(self.emit(RawBytecode::Jump(0)));
+	}
+	self.unit().code[push_handler].raw = RawBytecode::PushHandler(bindings);
+	for end in ends {
+	    self.patch(end, self.here());
+	}
+
+	if let (Some(cleanup), Some(finally)) = (cleanup, finally) {
+	    self.emit(RawBytecode::PopCleanup);
+	    self.compile_scoped_body(finally, false)?;
+	    self.emit(RawBytecode::Pop);
+	    let end = self.emit(RawBytecode::Jump(0));
+	    self.patch(cleanup, self.here());
+	    self.compile_scoped_body(finally, false)?;
+	    self.emit(RawBytecode::Pop);
+	    self.emit(RawBytecode::Reraise);
+	    self.patch(end, self.here());
+	}
+	Ok(())
+    }
+
+    /// Emits the matcher of a clause, its type or predicate, or `#t` for `catch-all`, returning what the clause binds and its body
+    fn compile_catch_matcher<'s>(&mut self, handler: &'s Sexpr) -> HelperResult<(CatchBinding, Vec<String>, &'s [Sexpr])> {
+	let Sexpr::List(handler, _) = handler else {
+	    return Err(self.error("try", "unusual syntax"));
+	};
+	let [Sexpr::List(clause, _), body @ ..] = handler.as_slice() else {
+	    return Err(self.error("try", "unusual syntax"));
+	};
+	let Some(Sexpr::Atom(Atom::Symbol(keyword), _)) = clause.first() else {
+	    return Err(self.error("try", "unusual syntax"));
+	};
+	if body.is_empty() {
+	    return Err(self.error("try", "unusual syntax"));
+	}
+	let variables = match (keyword[0].as_str(), clause.get(1)) {
+	    ("catch", Some(who)) | ("catch-if", Some(who)) => {
+		self.compile_expr(who)?;
+		&clause[2..]
+	    },
+	    ("catch-all", _) => {
+		self.emit(RawBytecode::PushBoolean(true));
+		&clause[1..]
+	    },
+	    _ => return Err(self.error("try", "unusual syntax")),
+	};
+	let variables = match variables {
+	    [Sexpr::Atom(Atom::Symbol(value_var), _)] => vec![value_var[0].clone()],
+	    [Sexpr::Atom(Atom::Symbol(value_var), _), Sexpr::Atom(Atom::Symbol(backtrace_var), _)] => vec![value_var[0].clone(), backtrace_var[0].clone()],
+	    _ => return Err(self.error("try", "unusual syntax")),
+	};
+	let binding = CatchBinding {
+	    message: keyword[0] == "catch",
+	    backtrace: variables.len() == 2,
+	    handler: 0,
+	};
+	Ok((binding, variables, body))
+    }
+
+    /// A clause is entered with its values on the stack, the backtrace on top
+    fn compile_catch_body(&mut self, variables: &[String], body: &[Sexpr]) -> HelperResult<()> {
+	if self.slotted() {
+	    let depth = self.scope_depth();
+	    let slots = variables.iter().map(|variable| self.declare(variable)).collect::<Vec<usize>>();
+	    for slot in slots.into_iter().rev() {
+		self.assign(slot);
+	    }
+	    self.compile_body(body, false)?;
+	    self.close_scope(depth);
+	    return Ok(());
+	}
+	self.emit(RawBytecode::PushFrame);
+	for variable in variables.iter().rev() {
+	    self.emit(RawBytecode::PushSymbol(vec![variable.clone()]));
+	    self.emit(RawBytecode::Store);
+	}
+	self.compile_body(body, false)?;
+	self.emit(RawBytecode::PopFrame);
+	Ok(())
+    }
+
+    fn compile_error(&mut self, list: &Vec<Sexpr>) -> HelperResult<()> {
+	let [_, who, message, fields @ ..] = list.as_slice() else {
+	    return Err(self.error("error", "unusual syntax"));
+	};
+	self.compile_expr(who)?;
+	self.compile_expr(message)?;
+	let mut count = 0;
+	let mut fields = fields.iter();
+	while let Some(field) = fields.next() {
+	    let (Sexpr::Atom(Atom::Keyword(name), _), Some(value)) = (field, fields.next()) else {
+		return Err(self.error("error", "unusual syntax"));
+	    };
+	    self.emit(RawBytecode::PushSymbol(vec![name.clone()]));
+	    self.compile_expr(value)?;
+	    count += 1;
+	}
+	self.emit(RawBytecode::Error(count));
+	Ok(())
+    }
+
+    fn compile_cond(&mut self, list: &[Sexpr], tail: bool) -> HelperResult<()> {
+	let mut ends = Vec::new();
+	let mut exhaustive = false;
+	for clause in &list[1..] {
+	    let Sexpr::List(clause, _) = clause else {
+		return Err(self.error("cond", "unusual syntax 1"));
+	    };
+	    let [condition, body] = clause.as_slice() else {
+		return Err(self.error("cond", "unusual syntax 2"));
+	    };
+	    match condition {
+		Sexpr::List(_, _) => {
+		    self.compile_expr(condition)?;
+		    let next = self.emit(RawBytecode::JumpIfFalse(0));
+		    self.compile_at(body, tail)?;
+		    ends.push(self.emit(RawBytecode::Jump(0)));
+		    self.patch(next, self.here());
+		},
+		Sexpr::Atom(Atom::Symbol(keyword), _) if keyword[0] == "else" => {
+		    self.compile_at(body, tail)?;
+		    exhaustive = true;
+		    break;
+		},
+		Sexpr::Atom(Atom::Symbol(_), _) => return Err(self.error("cond", "unusual syntax 3")),
+		Sexpr::Atom(Atom::Boolean(true), _) => {
+		    self.compile_at(body, tail)?;
+		    exhaustive = true;
+		    break;
+		},
+		Sexpr::Atom(Atom::Boolean(false), _) => {},
+		_ => return Err(self.error("cond", "unusual syntax 4")),
+	    }
+	}
+	if !exhaustive {
+	    self.emit(RawBytecode::PushNil);
+	}
+	for end in ends {
+	    self.patch(end, self.here());
+	}
+	Ok(())
+    }
+
+    /// `(call name args ...)` looks up the procedure from a symbol computed at run time
+    fn compile_call_expr(&mut self, list: &Vec<Sexpr>) -> HelperResult<()> {
+	let [_, name, args @ ..] = list.as_slice() else {
+	    return Err(self.error("call", "unusual syntax"));
+	};
+	self.compile_expr(name)?;
+	self.emit(RawBytecode::Load);
+	let count = self.compile_args(&vec![name.to_string()], args)?;
+	self.emit(RawBytecode::Call(count, vec![name.to_string()]));
+	Ok(())
+    }
+
+    fn compile_call(&mut self, list: &[Sexpr], tail: bool) -> HelperResult<()> {
+	let name = match &list[0] {
+	    Sexpr::Atom(Atom::Symbol(name), _) => name.clone(),
+	    head => vec![head.to_string()],
+	};
+	self.compile_expr(&list[0])?;
+	let count = self.compile_args(&name, &list[1..])?;
+	self.emit_call(count, name, tail);
+	Ok(())
+    }
+
+    fn emit_call(&mut self, count: usize, name: Vec<String>, tail: bool) {
+	if tail {
+	    self.emit(RawBytecode::TailCall(count, name));
+	} else {
+	    self.emit(RawBytecode::Call(count, name));
+	}
+    }
+
+    /// Emits the positional arguments and then the keyword arguments, returning how many positional ones there are.
+    /// Every keyword value is evaluated before any is bound, since a call among them would take the bound keywords
+    fn compile_args(&mut self, name: &Vec<String>, args: &[Sexpr]) -> HelperResult<usize> {
+	let mut count = 0;
+	let mut keywords = Vec::new();
+	let mut iterator = args.iter();
+	while let Some(sexpr) = iterator.next() {
+	    match sexpr {
+		Sexpr::Atom(Atom::Keyword(keyword), _) => {
+		    let Some(value) = iterator.next() else {
+			return Err(Box::new(Exception::new(name, "unusual syntax", self.context)));
+		    };
+		    keywords.push((keyword, value));
+		},
+		sexpr => {
+		    self.compile_expr(sexpr)?;
+		    count += 1;
+		},
+	    }
+	}
+	for (keyword, value) in keywords.iter() {
+	    self.emit(RawBytecode::PushSymbol(vec![keyword.to_string()]));
+	    self.compile_expr(value)?;
+	}
+	for _ in keywords.iter() {
+	    self.emit(RawBytecode::BindKeyword);
+	}
+	Ok(count)
+    }
+
+    /// Each case matches in a frame of its own, the value stays on the stack until a case takes it.
+    /// The variables a pattern defines are looked up by name in the case's body
+    fn compile_match(&mut self, list: &Vec<Sexpr>, tail: bool) -> HelperResult<()> {
+	let [_, value, cases @ ..] = list.as_slice() else {
+	    return Err(self.error("match", "unusual syntax"));
+	};
+	self.compile_expr(value)?;
+	let mut ends = Vec::new();
+	let mut exhaustive = false;
+	for case in cases {
+	    let Sexpr::List(case, _) = case else {
+		return Err(walkthrough::pattern_error(case, "unusual syntax", self.context));
+	    };
+	    let [pattern, body @ ..] = case.as_slice() else {
+		return Err(self.error("match", "unusual syntax"));
+	    };
+	    if body.is_empty() {
+		return Err(walkthrough::pattern_error(pattern, "case has no body", self.context));
+	    }
+	    if matches!(pattern, Sexpr::Atom(Atom::Symbol(keyword), _) if keyword[0] == "else") {
+		self.emit(RawBytecode::Pop);
+		self.compile_scoped_body(body, tail)?;
+		exhaustive = true;
+		break;
+	    }
+	    self.emit(RawBytecode::PushFrame);
+	    self.emit(RawBytecode::Dup);
+	    self.emit(RawBytecode::MatchPattern(pattern.clone()));
+	    let next = self.emit(RawBytecode::JumpIfFalse(0));
+	    self.emit(RawBytecode::Pop);
+	    let depth = self.scope_depth();
+	    if self.slotted() {
+		let mut variables = Vec::new();
+		pattern_variables(pattern, &mut variables);
+		self.unit().scope.extend(variables.into_iter().map(|variable| (variable, Binding::Frame)));
+	    }
+	    self.compile_body(body, tail)?;
+	    self.close_scope(depth);
+	    self.emit(RawBytecode::PopFrame);
+	    ends.push(self.emit(RawBytecode::Jump(0)));
+	    self.patch(next, self.here());
+	    self.emit(RawBytecode::PopFrame);
+	}
+	if !exhaustive {
+	    self.emit(RawBytecode::NoMatch);
+	}
+	for end in ends {
+	    self.patch(end, self.here());
+	}
+	Ok(())
+    }
+
+    /// The value of a `while` is the value of the last time its body ran, or nil
+    fn compile_while(&mut self, list: &Vec<Sexpr>) -> HelperResult<()> {
+	let [_, condition, body @ ..] = list.as_slice() else {
+	    return Err(self.error("while", "unusual syntax"));
+	};
+	if body.is_empty() {
+	    return Err(self.error("while", "unusual syntax"));
+	}
+	self.emit(RawBytecode::PushNil);
+	let start = self.here();
+	self.compile_expr(condition)?;
+	let exit = self.emit(RawBytecode::JumpIfFalse(0));
+	self.emit(RawBytecode::Pop);
+	self.compile_scoped_body(body, false)?;
+	self.emit(RawBytecode::Jump(start));
+	self.patch(exit, self.here());
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::interpreter::Exception;
+    use crate::interpreter::walkthrough::tests::{eval, new_context};
+    use super::*;
+
+    /// Like the walker's `eval`, but every form is compiled and run on the virtual machine
+    pub(crate) fn eval_vm(source: &str) -> Result<String, Box<Exception>> {
+	let mut context = new_context();
+	context.set_vm(true);
+	let file = crate::parser::parse_with_context(source, "test.lpy", &mut context.get_macros(), &context).expect("parse error");
+	let mut last = None;
+	for sexpr in file {
+	    let previous = context.set_location(sexpr.span().clone());
+	    let result = compile(&sexpr, &mut context).and_then(|bytecode| super::super::run(&bytecode, &mut context, &vec![]));
+	    context.set_location(previous);
+	    last = result?;
+	}
+	Ok(last.map(|value| value.to_string()).unwrap_or_default())
+    }
+
+    /// Runs `source` on both the virtual machine and the tree walker, they have to agree
+    fn assert_both(source: &str, expected: &str) {
+	assert_eq!(eval_vm(source).unwrap(), expected, "on the virtual machine");
+	assert_eq!(eval(source).unwrap(), expected, "on the tree walker");
+    }
+
+    #[test]
+    fn test_if() {
+	assert_both("(if (< 1 2) 'yes 'no)", "'yes");
+	assert_both("(if (> 1 2) 'yes (if #t 'nested 'no))", "'nested");
+    }
+
+    #[test]
+    fn test_let() {
+	assert_both("(let ((x 1) (y 2)) (let* ((z (+ x y)) (w (* z 2))) w))", "6");
+	assert_both("(let* ((x 1) (f (lambda () x)) (x 2)) (f))", "1");
+	assert_both("(define (shadowed) (let* ((x 1) (f (lambda () x)) (x 2)) (f)))\n(shadowed)", "1");
+	assert_both("(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1))))) (odd? (lambda (n) (if (= n 0) #f (even? (- n 1)))))) (even? 10))", "true");
+	assert_both("(let loop ((i 0) (sum 0)) (if (> i 4) sum (loop (+ i 1) (+ sum i))))", "10");
+    }
+
+    #[test]
+    fn test_lambda() {
+	assert_both("((lambda (x y) (- x y)) 5 3)", "2");
+	assert_both("(define (add a [b 10]) (+ a b))\n(+ (add 1) (add 1 2))", "14");
+	assert_both("(define (adder n) (lambda (x) (+ x n)))\n((adder 3) 4)", "7");
+    }
+
+    #[test]
+    fn test_match() {
+	assert_both("(match 5 [1 'one] [x (* x 2)])", "10");
+	assert_both("(match '(1 2 3) ['(a . rest) rest] [_ 0])", "'(2 3)");
+    }
+
+    #[test]
+    fn test_try() {
+	assert_both("(try (error 'who \"bad\") ((catch 'who message) message))", "bad");
+	assert_both("(define x 0)\n(try (set! x 1) (finally (set! x (+ x 1))))\nx", "2");
+	let e = eval_vm("(try (error 'other \"bad\") ((catch 'who message) message))").unwrap_err();
+	assert_eq!(e.to_string(), "test.lpy:1:6: 'other: bad");
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt::Write;
+
+use super::{Bytecode, Prototype, RawBytecode};
+
+/// Lists code one instruction a line, with its offset and the line and column it was compiled from,
+/// followed by the code of each procedure it makes
+pub fn disassemble(code: &[Bytecode]) -> String {
+    let mut out = String::new();
+    write_code(&mut out, code, 0);
+    out
+}
+
+/// The line and column of an instruction, `-` when the compiler didn't know where it came from
+pub fn position(instruction: &Bytecode) -> String {
+    if instruction.line == 0 {
+	"-".to_string()
+    } else {
+	format!("{}:{}", instruction.line, instruction.column)
+    }
+}
+
+/// One line of a listing, the offset, the position and the instruction
+pub fn line(offset: usize, instruction: &Bytecode) -> String {
+    format!("{:>5}  {:<9} {}", offset, position(instruction), instruction.raw)
+}
+
+fn write_code(out: &mut String, code: &[Bytecode], indent: usize) {
+    let mut prototypes: Vec<(usize, &Prototype)> = Vec::new();
+    for (offset, instruction) in code.iter().enumerate() {
+	let _ = writeln!(out, "{:indent$}{}", "", line(offset, instruction), indent = indent);
+	if let RawBytecode::MakeClosure(prototype, _) = &instruction.raw {
+	    prototypes.push((offset, prototype));
+	}
+    }
+    for (offset, prototype) in prototypes {
+	let _ = writeln!(out, "\n{:indent$}procedure {} made at {}:", "", prototype.shape, offset, indent = indent);
+	write_code(out, &prototype.code, indent + 4);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::bytecode::compiler::compile;
+    use crate::interpreter::bytecode::compiler::tests::eval_vm;
+    use crate::interpreter::walkthrough::tests::new_context;
+
+    fn disassemble_source(source: &str) -> String {
+	let mut context = new_context();
+	let file = crate::parser::parse_with_context(source, "test.lpy", &mut context.get_macros(), &context).expect("parse error");
+	file.into_iter().map(|sexpr| disassemble(&compile(&sexpr, &mut context).expect("compile error"))).collect()
+    }
+
+    #[test]
+    fn test_listing() {
+	let expected = "    0  1:2       PushSymbol +
+    1  1:2       Load
+    2  1:4       PushInteger 1
+    3  1:6       PushInteger 2
+    4  1:1       Call 2 +
+    5  -         Return
+";
+	assert_eq!(disassemble_source("(+ 1 2)"), expected);
+    }
+
+    #[test]
+    fn test_procedures_follow_indented() {
+	let listing = disassemble_source("(define (inc x) (+ x 1))");
+	let (outer, procedure) = listing.split_once("\nprocedure (x) made at 0:\n").expect("no procedure");
+	assert!(outer.starts_with("    0  1:1       MakeClosure (x)\n"));
+	assert!(procedure.lines().all(|line| line.starts_with("        ")));
+	assert!(procedure.contains("        8  1:17      TailCall 2 +\n"));
+    }
+
+    #[test]
+    fn test_disassemble_procedure_returns_listing() {
+	let listing = eval_vm("(define (inc x) (+ x 1))\n(disassemble inc)").unwrap();
+	assert!(listing.starts_with("procedure (x):\n    0  1:1       Reserve 1\n"));
+	assert!(listing.ends_with("    9  1:1       Return\n"));
+	assert_eq!(listing.lines().count(), 11);
+    }
+}
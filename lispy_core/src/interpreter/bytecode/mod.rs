@@ -1,6 +1,16 @@
 mod virtual_machine;
+pub mod compiler;
+pub mod cache;
+pub mod disassembler;
+pub mod verifier;
 
-use crate::interpreter::InterpreterResult;
+use std::sync::Arc;
+
+use crate::interpreter::{HelperResult, InterpreterResult};
+use crate::interpreter::value::Value;
+use crate::interpreter::value::function::FunctionShape;
+use crate::interpreter::walkthrough::TailResult;
+use crate::parser::{File, Sexpr, Span};
 
 use super::context::Context;
 
@@ -33,23 +43,189 @@ pub enum RawBytecode {
     PushBoolean(bool),
     PushSymbol(Vec<String>),
     PushChar(char),
+    PushNil,
+    /// Pushes quoted data
+    PushQuoted(Sexpr),
     Pop,
+    Dup,
     Store,
     Load,
+    /// Pops a symbol and a value and assigns the value to the innermost variable of that name
+    Set,
     BindKeyword,
-    Call(usize),
+    /// Pushes nil into each local slot, the first instruction of a procedure with slots
+    Reserve(usize),
+    LoadLocal(usize),
+    /// Pops a value into a local slot
+    StoreLocal(usize),
+    /// Pushes the value in one of the cells the running closure captured
+    LoadUpvalue(usize),
+    /// Pops a value into one of the cells the running closure captured
+    SetUpvalue(usize),
+    /// Pops a value and pushes a cell holding it, for variables closures capture
+    MakeCell,
+    /// Pops a cell and pushes the value in it
+    LoadCell,
+    /// Pops a cell and then a value and puts the value in the cell
+    SetCell,
+    /// Pops the arguments and then the function, the name is only for the backtrace
+    Call(usize, Vec<String>),
+    /// Like `Call` but returns the call for the caller of this procedure to make, so the stack does not grow
+    TailCall(usize, Vec<String>),
+    /// Pops a function and calls it with the continuation of this call
+    CallWithEscape,
     Return,
+    Jump(usize),
+    /// Pops a boolean and jumps if it is false
+    JumpIfFalse(usize),
+    PushFrame,
+    PopFrame,
+    /// Closes a compiled procedure over the frames visible where it is made and the cells it captures
+    MakeClosure(Box<Prototype>, Vec<Capture>),
+    MakeVector(usize),
     MakeStruct(usize),
     StructAccess,
     StructStore,
     MakeEnum(usize),
     EnumAccess,
     EnumStore,
+    /// Pops a value and pushes whether it matches the `match` pattern, defining what it binds in the innermost frame
+    MatchPattern(Sexpr),
+    /// Pops the value a `match` had no case for and raises an error
+    NoMatch,
+    /// Pops a matcher for each clause and establishes them as handlers, an error one of them catches
+    /// unwinds the stack and the frames to where they were and continues at the clause's offset with its values pushed
+    PushHandler(Vec<CatchBinding>),
+    PopHandler,
+    /// Unwinds to the offset on any error, escapes included, keeping the error for `Reraise`
+    PushCleanup(usize),
+    PopCleanup,
+    /// Raises the error the innermost cleanup was entered with again
+    Reraise,
+    /// Pops the keyword and value of each field, a message and a who and raises the error
+    Error(usize),
+    /// Evaluates a form with the tree walker, for forms that only declare things
+    Walk(Sexpr),
+}
+
+impl std::fmt::Display for RawBytecode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    RawBytecode::PushString(s) => write!(f, "PushString {:?}", s),
+	    RawBytecode::PushInteger(i) => write!(f, "PushInteger {}", i),
+	    RawBytecode::PushFloat(fl) => write!(f, "PushFloat {}", fl),
+	    RawBytecode::PushBoolean(b) => write!(f, "PushBoolean {}", if *b { "#t" } else { "#f" }),
+	    RawBytecode::PushSymbol(s) => write!(f, "PushSymbol {}", s.join(".")),
+	    RawBytecode::PushChar(c) => write!(f, "PushChar {:?}", c),
+	    RawBytecode::PushQuoted(sexpr) => write!(f, "PushQuoted {}", sexpr),
+	    RawBytecode::Reserve(n) => write!(f, "Reserve {}", n),
+	    RawBytecode::LoadLocal(slot) => write!(f, "LoadLocal {}", slot),
+	    RawBytecode::StoreLocal(slot) => write!(f, "StoreLocal {}", slot),
+	    RawBytecode::LoadUpvalue(index) => write!(f, "LoadUpvalue {}", index),
+	    RawBytecode::SetUpvalue(index) => write!(f, "SetUpvalue {}", index),
+	    RawBytecode::Call(n, name) => write!(f, "Call {} {}", n, name.join(".")),
+	    RawBytecode::TailCall(n, name) => write!(f, "TailCall {} {}", n, name.join(".")),
+	    RawBytecode::Jump(offset) => write!(f, "Jump {}", offset),
+	    RawBytecode::JumpIfFalse(offset) => write!(f, "JumpIfFalse {}", offset),
+	    RawBytecode::MakeClosure(prototype, captures) => {
+		write!(f, "MakeClosure ({})", prototype.args.join(" "))?;
+		for capture in captures {
+		    match capture {
+			Capture::Local(slot) => write!(f, " local {}", slot)?,
+			Capture::Upvalue(index) => write!(f, " upvalue {}", index)?,
+		    }
+		}
+		Ok(())
+	    },
+	    RawBytecode::MakeVector(n) => write!(f, "MakeVector {}", n),
+	    RawBytecode::MakeStruct(n) => write!(f, "MakeStruct {}", n),
+	    RawBytecode::MakeEnum(n) => write!(f, "MakeEnum {}", n),
+	    RawBytecode::MatchPattern(pattern) => write!(f, "MatchPattern {}", pattern),
+	    RawBytecode::PushHandler(bindings) => {
+		write!(f, "PushHandler")?;
+		for binding in bindings {
+		    let value = if binding.message { "message" } else { "condition" };
+		    let backtrace = if binding.backtrace { "+backtrace" } else { "" };
+		    write!(f, " {}{} -> {}", value, backtrace, binding.handler)?;
+		}
+		Ok(())
+	    },
+	    RawBytecode::PushCleanup(offset) => write!(f, "PushCleanup {}", offset),
+	    RawBytecode::Error(n) => write!(f, "Error {}", n),
+	    RawBytecode::Walk(sexpr) => write!(f, "Walk {}", sexpr),
+	    _ => write!(f, "{:?}", self),
+	}
+    }
+}
+
+/// A procedure the compiler lowered, its code ends with `Return`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Prototype {
+    pub args: Vec<String>,
+    pub code: Vec<Bytecode>,
+    pub shape: FunctionShape,
+}
+
+/// Where a closure gets each cell it captures from
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Capture {
+    /// A local slot of the procedure making the closure, holding a cell
+    Local(usize),
+    /// A cell the procedure making the closure captured itself
+    Upvalue(usize),
+}
 
+/// Where a compiled `try` clause starts and what it is entered with
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CatchBinding {
+    /// `catch` gets the message, `catch-all` and `catch-if` get the condition
+    pub message: bool,
+    /// Whether the backtrace is pushed after the value
+    pub backtrace: bool,
+    pub handler: usize,
 }
 
 
 pub fn run(bytecode: &[Bytecode], context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
-    let mut vm = virtual_machine::VirtualMachine::new(bytecode);
+    run_tail(bytecode, &[], context, module_name)?.finish(context)
+}
+
+/// Runs the code of a closure, leaving a call it makes in tail position to the caller
+pub fn run_tail(bytecode: &[Bytecode], upvalues: &[Value], context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    let mut vm = virtual_machine::VirtualMachine::new(bytecode, upvalues);
     vm.run(context, module_name)
 }
+
+/// Compiles each top level form of a file and runs it on the virtual machine
+pub fn run_file(file: File, context: &mut Context, module_name: &Vec<String>) -> HelperResult<()> {
+    compile_and_run(file, context, module_name)?;
+    Ok(())
+}
+
+/// Like `run_file`, but gives back the code of every form, for the module cache, or the error that stopped it
+pub fn compile_and_run(file: File, context: &mut Context, module_name: &Vec<String>) -> HelperResult<Vec<Vec<Bytecode>>> {
+    let mut forms = Vec::new();
+    for sexpr in file {
+	let previous = context.set_location(sexpr.span().clone());
+	let result = compiler::compile(&sexpr, context).and_then(|bytecode| {
+	    let result = run(&bytecode, context, module_name);
+	    forms.push(bytecode);
+	    result
+	});
+	context.set_location(previous);
+	result?;
+    }
+    Ok(forms)
+}
+
+/// Runs the top level forms of a file that were compiled before, `path` is where errors say they happened
+pub fn run_compiled(forms: &[Vec<Bytecode>], path: &str, context: &mut Context, module_name: &Vec<String>) -> HelperResult<()> {
+    let file: Arc<str> = Arc::from(path);
+    for bytecode in forms {
+	let previous = context.set_location(Span::new(Some(file.clone()), 0, 0));
+	let result = run(bytecode, context, module_name);
+	context.set_location(previous);
+	result?;
+    }
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use crate::interpreter::{Exception, HelperResult};
+use crate::interpreter::context::Context;
+
+use super::{Bytecode, Capture, RawBytecode};
+
+/// What the virtual machine has before an instruction runs on every path to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    depth: usize,
+    /// How many cleanups were entered with an error `Reraise` can raise again
+    cleanups: usize,
+}
+
+/// Checks that code can't take more from the stack than is on it, that paths meeting at an instruction
+/// leave the same number of values, that every jump and handler stays within the code and that locals and
+/// upvalues exist, so the virtual machine can run it without checking. The procedures it makes are checked too.
+pub fn verify(code: &[Bytecode], upvalues: usize, context: &Context) -> HelperResult<()> {
+    verify_code(code, upvalues).map_err(|message| Box::new(Exception::new(&vec!["verify"], &message, context)))
+}
+
+fn verify_code(code: &[Bytecode], upvalues: usize) -> Result<(), String> {
+    let slots = match code.first().map(|instruction| &instruction.raw) {
+	Some(RawBytecode::Reserve(slots)) => *slots,
+	_ => 0,
+    };
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    let mut pending = vec![(0, State { depth: 0, cleanups: 0 })];
+    while let Some((offset, state)) = pending.pop() {
+	// Running off the end returns nothing
+	if offset == code.len() {
+	    continue;
+	}
+	match states[offset] {
+	    Some(known) if known == state => continue,
+	    Some(known) if known.depth != state.depth => return Err(format!("paths meet at {} with {} and {} on the stack", offset, known.depth, state.depth)),
+	    Some(_) => return Err(format!("paths meet at {} in and out of a cleanup", offset)),
+	    None => states[offset] = Some(state),
+	}
+	pending.extend(successors(code, offset, state, slots, upvalues)?);
+    }
+    Ok(())
+}
+
+/// Where the code can go from the instruction at `offset` and with what
+fn successors(code: &[Bytecode], offset: usize, state: State, slots: usize, upvalues: usize) -> Result<Vec<(usize, State)>, String> {
+    let pop = |count: usize| match state.depth.checked_sub(count) {
+	Some(depth) if depth >= slots => Ok(depth),
+	_ => Err(format!("{} at {} needs {} on the stack and there are {}", code[offset].raw, offset, count, state.depth.saturating_sub(slots))),
+    };
+    let target = |target: usize| if target > code.len() {
+	Err(format!("{} at {} goes outside of the code", code[offset].raw, offset))
+    } else {
+	Ok(target)
+    };
+    let local = |slot: usize| if slot >= slots {
+	Err(format!("there is no local {} at {}", slot, offset))
+    } else {
+	Ok(())
+    };
+    let upvalue = |index: usize| if index >= upvalues {
+	Err(format!("there is no upvalue {} at {}", index, offset))
+    } else {
+	Ok(())
+    };
+    let next = |depth: usize| Ok(vec![(offset + 1, State { depth, ..state })]);
+
+    match &code[offset].raw {
+	RawBytecode::PushString(_) | RawBytecode::PushInteger(_) | RawBytecode::PushFloat(_) | RawBytecode::PushBoolean(_)
+	    | RawBytecode::PushSymbol(_) | RawBytecode::PushChar(_) | RawBytecode::PushNil | RawBytecode::PushQuoted(_) => next(state.depth + 1),
+	RawBytecode::Walk(_) => {
+	    // The tree walker only sees variables in frames, it can't reach a local slot
+	    if slots > 0 {
+		return Err(format!("Walk at {} is in a procedure with locals", offset));
+	    }
+	    next(state.depth + 1)
+	},
+	RawBytecode::Pop => next(pop(1)?),
+	RawBytecode::Dup => next(pop(1)? + 2),
+	RawBytecode::Store | RawBytecode::Set | RawBytecode::BindKeyword | RawBytecode::SetCell => next(pop(2)?),
+	RawBytecode::Load | RawBytecode::MakeCell | RawBytecode::LoadCell | RawBytecode::MatchPattern(_)
+	    | RawBytecode::CallWithEscape => next(pop(1)? + 1),
+	RawBytecode::Reserve(count) => {
+	    if offset != 0 {
+		return Err(format!("Reserve at {} is not the first instruction", offset));
+	    }
+	    next(state.depth.saturating_add(*count))
+	},
+	RawBytecode::LoadLocal(slot) => {
+	    local(*slot)?;
+	    next(state.depth + 1)
+	},
+	RawBytecode::StoreLocal(slot) => {
+	    local(*slot)?;
+	    next(pop(1)?)
+	},
+	RawBytecode::LoadUpvalue(index) => {
+	    upvalue(*index)?;
+	    next(state.depth + 1)
+	},
+	RawBytecode::SetUpvalue(index) => {
+	    upvalue(*index)?;
+	    next(pop(1)?)
+	},
+	RawBytecode::Call(count, _) => next(pop(count.saturating_add(1))? + 1),
+	RawBytecode::TailCall(count, _) => {
+	    pop(count.saturating_add(1))?;
+	    Ok(Vec::new())
+	},
+	RawBytecode::Return => {
+	    // What is returned is taken from the top, that must not be a local
+	    pop(1)?;
+	    Ok(Vec::new())
+	},
+	RawBytecode::Jump(to) => Ok(vec![(target(*to)?, state)]),
+	RawBytecode::JumpIfFalse(to) => {
+	    let state = State { depth: pop(1)?, ..state };
+	    Ok(vec![(offset + 1, state), (target(*to)?, state)])
+	},
+	RawBytecode::PushFrame | RawBytecode::PopFrame | RawBytecode::PopHandler | RawBytecode::PopCleanup => next(state.depth),
+	RawBytecode::MakeClosure(prototype, captures) => {
+	    for capture in captures {
+		match capture {
+		    Capture::Local(slot) => local(*slot)?,
+		    Capture::Upvalue(index) => upvalue(*index)?,
+		}
+	    }
+	    verify_code(&prototype.code, captures.len()).map_err(|message| format!("{} in the procedure made at {}", message, offset))?;
+	    next(state.depth + 1)
+	},
+	RawBytecode::MakeVector(count) => next(pop(*count)? + 1),
+	RawBytecode::MakeStruct(count) => next(pop(count.saturating_add(1))? + 1),
+	RawBytecode::MakeEnum(count) => next(pop(count.saturating_add(2))? + 1),
+	RawBytecode::StructAccess | RawBytecode::EnumAccess => next(pop(2)? + 1),
+	RawBytecode::StructStore | RawBytecode::EnumStore => next(pop(3)?),
+	RawBytecode::NoMatch => {
+	    pop(1)?;
+	    Ok(Vec::new())
+	},
+	RawBytecode::PushHandler(bindings) => {
+	    let depth = pop(bindings.len())?;
+	    let mut successors = vec![(offset + 1, State { depth, ..state })];
+	    // A clause is entered with the stack as it was once the matchers were taken and its values on top
+	    for binding in bindings {
+		let values = if binding.backtrace { 2 } else { 1 };
+		successors.push((target(binding.handler)?, State { depth: depth + values, ..state }));
+	    }
+	    Ok(successors)
+	},
+	RawBytecode::PushCleanup(to) => {
+	    Ok(vec![(offset + 1, state), (target(*to)?, State { cleanups: state.cleanups + 1, ..state })])
+	},
+	RawBytecode::Reraise => {
+	    if state.cleanups == 0 {
+		return Err(format!("Reraise at {} is not in a cleanup", offset));
+	    }
+	    Ok(Vec::new())
+	},
+	RawBytecode::Error(count) => {
+	    pop(count.saturating_mul(2).saturating_add(2))?;
+	    Ok(Vec::new())
+	},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::bytecode::{CatchBinding, Prototype};
+    use crate::interpreter::value::function::FunctionShape;
+    use crate::parser::{Atom, Sexpr, Span};
+
+    fn code(raws: Vec<RawBytecode>) -> Vec<Bytecode> {
+	raws.into_iter().map(|raw| Bytecode::new(raw, 0, 0)).collect()
+    }
+
+    fn rejects(raws: Vec<RawBytecode>, upvalues: usize, message: &str) {
+	match verify_code(&code(raws), upvalues) {
+	    Ok(()) => panic!("accepted code that should fail with {:?}", message),
+	    Err(error) => assert!(error.contains(message), "{:?} does not say {:?}", error, message),
+	}
+    }
+
+    #[test]
+    fn test_accepts_compiled_shapes() {
+	use RawBytecode::*;
+	assert_eq!(verify_code(&code(vec![PushNil, Return]), 0), Ok(()));
+	assert_eq!(verify_code(&code(vec![Reserve(1), PushNil, StoreLocal(0), LoadLocal(0), Return]), 0), Ok(()));
+	assert_eq!(verify_code(&code(vec![LoadUpvalue(0), Return]), 1), Ok(()));
+	assert_eq!(verify_code(&code(vec![PushCleanup(4), PopCleanup, PushNil, Return, Reraise]), 0), Ok(()));
+    }
+
+    #[test]
+    fn test_rejects_underflow() {
+	use RawBytecode::*;
+	rejects(vec![Pop, PushNil, Return], 0, "needs 1 on the stack and there are 0");
+	rejects(vec![Return], 0, "needs 1 on the stack");
+	// The locals are not values to take
+	rejects(vec![Reserve(2), PushNil, Call(2, vec!["f".to_string()]), Return], 0, "needs 3 on the stack and there are 1");
+	rejects(vec![Reserve(1), Return], 0, "needs 1 on the stack and there are 0");
+    }
+
+    #[test]
+    fn test_rejects_targets_outside_the_code() {
+	use RawBytecode::*;
+	rejects(vec![Jump(4), PushNil, Return], 0, "goes outside of the code");
+	rejects(vec![PushBoolean(true), JumpIfFalse(9), PushNil, Return], 0, "goes outside of the code");
+	rejects(vec![PushCleanup(7), PopCleanup, PushNil, Return], 0, "goes outside of the code");
+	let catch = CatchBinding { message: true, backtrace: false, handler: 9 };
+	rejects(vec![PushNil, PushHandler(vec![catch]), PopHandler, PushNil, Return], 0, "goes outside of the code");
+    }
+
+    #[test]
+    fn test_rejects_missing_local_or_upvalue() {
+	use RawBytecode::*;
+	rejects(vec![Reserve(1), LoadLocal(1), Return], 0, "there is no local 1");
+	rejects(vec![PushNil, StoreLocal(0), PushNil, Return], 0, "there is no local 0");
+	rejects(vec![LoadUpvalue(0), Return], 0, "there is no upvalue 0");
+	rejects(vec![PushNil, SetUpvalue(2), PushNil, Return], 2, "there is no upvalue 2");
+    }
+
+    #[test]
+    fn test_rejects_reserve_after_the_start() {
+	use RawBytecode::*;
+	rejects(vec![PushNil, Reserve(1), Return], 0, "is not the first instruction");
+    }
+
+    #[test]
+    fn test_rejects_reraise_outside_a_cleanup() {
+	use RawBytecode::*;
+	rejects(vec![Reraise], 0, "is not in a cleanup");
+	rejects(vec![PushCleanup(3), PopCleanup, Reraise, PushNil, Return], 0, "is not in a cleanup");
+    }
+
+    #[test]
+    fn test_rejects_paths_meeting_with_different_depths() {
+	use RawBytecode::*;
+	rejects(vec![PushBoolean(true), JumpIfFalse(3), PushNil, PushNil, Return], 0, "paths meet at 3 with");
+    }
+
+    #[test]
+    fn test_rejects_walk_with_locals() {
+	use RawBytecode::*;
+	let form = Sexpr::Atom(Atom::Null, Span::default());
+	assert_eq!(verify_code(&code(vec![Walk(form.clone()), Return]), 0), Ok(()));
+	rejects(vec![Reserve(1), Walk(form), Return], 0, "is in a procedure with locals");
+    }
+
+    #[test]
+    fn test_checks_the_procedures_made() {
+	use RawBytecode::*;
+	let prototype = Prototype {
+	    args: Vec::new(),
+	    code: code(vec![Return]),
+	    shape: FunctionShape::new(Vec::new()),
+	};
+	rejects(vec![MakeClosure(Box::new(prototype), Vec::new()), Return], 0, "in the procedure made at 0");
+    }
+}
@@ -1,10 +1,14 @@
 
 use crate::interpreter::value::{r#struct::Struct, r#enum::Enum};
 use crate::interpreter::{bytecode::Bytecode, value::Value, context::Context};
-use crate::interpreter::InterpreterResult;
-use crate::interpreter::bytecode::RawBytecode;
+use crate::interpreter::value::function::Function;
+use crate::interpreter::HelperResult;
+use crate::interpreter::bytecode::{disassembler, Capture, CatchBinding, RawBytecode};
+use crate::interpreter::condition::{self, ConditionMatcher, HandlerAction, HandlerCluster};
+use crate::interpreter::walkthrough::{self, Tail, TailCall, TailResult};
 use crate::interpreter::Exception;
 use crate::interpreter::kwargs::Kwargs;
+use crate::parser::Span;
 
 
 
@@ -14,24 +18,120 @@ use crate::interpreter::kwargs::Kwargs;
 
 
 
+/// Where an error raised in the extent of a handler or cleanup goes, with the stack height and frame depth to unwind to
+enum Unwind {
+    Catch {
+	cluster: usize,
+	clauses: Vec<CatchBinding>,
+	stack: usize,
+	frames: usize,
+    },
+    Cleanup {
+	handler: usize,
+	stack: usize,
+	frames: usize,
+    },
+}
+
 pub struct VirtualMachine<'a> {
     instructions: &'a [Bytecode],
+    upvalues: &'a [Value],
     pc: usize,
     stack: Vec<Value>,
     keywords: Option<Kwargs>, 
+    unwinding: Vec<Unwind>,
+    /// The errors cleanups were entered with, innermost last
+    pending: Vec<Exception>,
 }
 
 impl<'a> VirtualMachine<'a> {
-    pub fn new(instructions: &'a [Bytecode]) -> Self {
+    pub fn new(instructions: &'a [Bytecode], upvalues: &'a [Value]) -> Self {
 	VirtualMachine {
 	    instructions,
+	    upvalues,
 	    pc: 0,
 	    stack: Vec::new(),
 	    keywords: None,
+	    unwinding: Vec::new(),
+	    pending: Vec::new(),
+	}
+    }
+
+    /// Runs the instructions, dropping the frames they pushed if they are left by an error or a tail call
+    pub fn run(&mut self, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+	let depth = context.frame_depth();
+	let location = context.get_location().clone();
+	let value = self.run_instructions(context, module_name);
+	if !matches!(value, Ok(Tail::Done(_))) {
+	    context.truncate_frames(depth);
+	}
+	context.set_location(location);
+	value
+    }
+
+    /// Pops the arguments of a call, then the procedure, taking the keywords bound for it
+    fn pop_call(&mut self, arg_count: usize, name: &Vec<String>, context: &mut Context) -> HelperResult<(Function, Vec<Value>, Kwargs)> {
+	let kwarg = if let Some(keywords) = self.keywords.take() {
+	    keywords
+	} else {
+	    Kwargs::new()
+	};
+	let args = self.pop_many(arg_count);
+	let function = self.pop();
+	let function = walkthrough::get_procedure(&function, name, context)?;
+	Ok((function, args, kwarg))
+    }
+
+    /// Signals an error and unwinds to the innermost handler that catches it or cleanup, returning the error if there is none
+    fn unwind(&mut self, e: Box<Exception>, context: &mut Context) -> HelperResult<()> {
+	let e = condition::signal(e, context);
+	while let Some(unwind) = self.unwinding.pop() {
+	    match unwind {
+		Unwind::Catch { cluster, clauses, stack, frames } => {
+		    context.pop_handlers();
+		    let index = match condition::get_catcher(&e) {
+			Some((catcher, index)) if catcher == cluster => index,
+			_ => continue,
+		    };
+		    self.stack.truncate(stack);
+		    context.truncate_frames(frames);
+		    let clause = clauses[index];
+		    self.stack.push(if clause.message {
+			e.get_message()
+		    } else {
+			e.get_condition(context)
+		    });
+		    if clause.backtrace {
+			self.stack.push(e.get_backtrace_value(context));
+		    }
+		    self.pc = clause.handler;
+		    return Ok(());
+		},
+		Unwind::Cleanup { handler, stack, frames } => {
+		    self.stack.truncate(stack);
+		    context.truncate_frames(frames);
+		    self.pending.push(*e);
+		    self.pc = handler;
+		    return Ok(());
+		},
+	    }
 	}
+	Err(e)
+    }
+
+    fn pop(&mut self) -> Value {
+	self.stack.pop().expect("stack is empty")
     }
 
-    pub fn run(&mut self, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+    /// Pops the top `count` values in the order they were pushed
+    fn pop_many(&mut self, count: usize) -> Vec<Value> {
+	let start = self.stack.len().checked_sub(count).expect("stack is empty");
+	self.stack.split_off(start)
+    }
+
+    fn run_instructions(&mut self, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+	let file = context.get_location().file.clone();
+	let mut position = (0, 0);
 	while self.pc < self.instructions.len() {
 	    
 	    if crate::gc::is_gc_on() {
@@ -39,149 +139,401 @@ impl<'a> VirtualMachine<'a> {
 	    }
 	    
 	    let instruction = &self.instructions[self.pc];
-	    match instruction.get_raw() {
-		RawBytecode::PushString(s) => {
-		    self.stack.push(Value::new_string(s, context));
-		    self.pc += 1;
-		}
-		RawBytecode::PushInteger(i) => {
-		    self.stack.push(Value::new_integer(i));
-		    self.pc += 1;
-		}
-		RawBytecode::PushFloat(f) => {
-		    self.stack.push(Value::new_float(*f));
-		    self.pc += 1;
-		}
-		RawBytecode::PushBoolean(b) => {
-		    self.stack.push(Value::new_boolean(*b));
-		    self.pc += 1;
-		}
-		RawBytecode::PushSymbol(s) => {
-		    self.stack.push(Value::new_symbol(s.clone(), context));
-		    self.pc += 1;
-		}
-		RawBytecode::PushChar(c) => {
-		    self.stack.push(Value::new_char(*c));
-		    self.pc += 1;
-		}
-		RawBytecode::Pop => {
-		    self.stack.pop();
-		    self.pc += 1;
+	    // Errors and calls are reported at the form the instruction was compiled from
+	    if instruction.line != 0 && (instruction.line, instruction.column) != position {
+		position = (instruction.line, instruction.column);
+		context.set_location(Span::new(file.clone(), instruction.line, instruction.column));
+	    }
+	    if context.is_tracing() {
+		// The same line the disassembler lists, after the depth of the stack
+		eprintln!("trace depth {:<3} {}", self.stack.len(), disassembler::line(self.pc, instruction));
+	    }
+	    match self.step(context, module_name) {
+		Ok(Some(tail)) => return Ok(tail),
+		Ok(None) => {},
+		Err(e) => self.unwind(e, context)?,
+	    }
+	}
+	Ok(Tail::Done(None))
+    }
+
+    /// Runs the instruction at `pc`, returning what the code returns when it is the last one to run
+    fn step(&mut self, context: &mut Context, module_name: &Vec<String>) -> HelperResult<Option<Tail>> {
+	let instruction = &self.instructions[self.pc];
+	match instruction.get_raw() {
+	    RawBytecode::PushString(s) => {
+		self.stack.push(Value::new_string(s, context));
+		self.pc += 1;
+	    }
+	    RawBytecode::PushInteger(i) => {
+		self.stack.push(Value::new_integer(i));
+		self.pc += 1;
+	    }
+	    RawBytecode::PushFloat(f) => {
+		self.stack.push(Value::new_float(*f));
+		self.pc += 1;
+	    }
+	    RawBytecode::PushBoolean(b) => {
+		self.stack.push(Value::new_boolean(*b));
+		self.pc += 1;
+	    }
+	    RawBytecode::PushSymbol(s) => {
+		self.stack.push(Value::new_symbol(s.clone(), context));
+		self.pc += 1;
+	    }
+	    RawBytecode::PushChar(c) => {
+		self.stack.push(Value::new_char(*c));
+		self.pc += 1;
+	    }
+	    RawBytecode::PushNil => {
+		self.stack.push(Value::new_nil());
+		self.pc += 1;
+	    }
+	    RawBytecode::PushQuoted(sexpr) => {
+		self.stack.push(Value::from_sexpr(sexpr, context));
+		self.pc += 1;
+	    }
+	    RawBytecode::Pop => {
+		self.stack.pop();
+		self.pc += 1;
+	    }
+	    RawBytecode::Dup => {
+		let value = self.stack.last().expect("stack is empty").clone();
+		self.stack.push(value);
+		self.pc += 1;
+	    }
+	    RawBytecode::Store => {
+		let symbol = self.stack.pop().expect("stack is empty");
+		let value = self.stack.pop().expect("stack is empty");
+		let symbol = symbol.get_symbol(context)?;
+		context.bind(symbol, value);
+		self.pc += 1;
+	    }
+	    RawBytecode::Load => {
+		let symbol = self.stack.pop().expect("stack is empty");
+		let symbol = symbol.get_symbol(context)?;
+		let path = module_name.iter().chain(symbol.iter()).map(|s| s.clone()).collect();
+		let Some(value) = context.get(path)? else {
+		    return Err(Box::new(Exception::new(symbol, "symbol not found", context)));
+		};
+		self.stack.push(value);
+		self.pc += 1;
+	    }
+	    RawBytecode::Set => {
+		let symbol = self.pop();
+		let value = self.pop();
+		let symbol = symbol.get_symbol(context)?;
+		context.rebind(symbol, value);
+		self.pc += 1;
+	    }
+	    RawBytecode::BindKeyword => {
+		let value = self.stack.pop().expect("stack is empty");
+		let keyword = self.stack.pop().expect("stack is empty");
+		if let Some(ref mut keywords) = self.keywords {
+		    keywords.insert(keyword.get_symbol(context)?.last().unwrap().clone(), value);
+		} else {
+		    let mut keywords = Kwargs::new();
+		    keywords.insert(keyword.get_symbol(context)?.last().unwrap().clone(), value);
+		    self.keywords = Some(keywords);
 		}
-		RawBytecode::Store => {
-		    let symbol = self.stack.pop().expect("stack is empty");
-		    let value = self.stack.pop().expect("stack is empty");
-		    let symbol = symbol.get_symbol(context)?;
-		    context.bind(symbol, value);
+		self.pc += 1;
+	    }
+	    RawBytecode::Call(arg_count, name) => {
+		let (function, args, kwarg) = self.pop_call(*arg_count, name, context)?;
+		let result = function.call_from_bytecode(name, args, kwarg, context, module_name)?;
+		self.stack.push(result.unwrap_or_else(Value::new_nil));
+		self.pc += 1;
+	    }
+	    RawBytecode::TailCall(arg_count, name) => {
+		let (function, args, kargs) = self.pop_call(*arg_count, name, context)?;
+		return Ok(Some(Tail::Call(Box::new(TailCall {
+		    function,
+		    name: name.clone(),
+		    args,
+		    kargs,
+		    module_name: module_name.clone(),
+		    location: context.get_location().clone(),
+		}))));
+	    }
+	    RawBytecode::Reserve(slots) => {
+		self.stack.resize(self.stack.len() + slots, Value::new_nil());
+		self.pc += 1;
+	    }
+	    RawBytecode::LoadLocal(slot) => {
+		self.stack.push(self.stack[*slot].clone());
+		self.pc += 1;
+	    }
+	    RawBytecode::StoreLocal(slot) => {
+		self.stack[*slot] = self.pop();
+		self.pc += 1;
+	    }
+	    RawBytecode::LoadUpvalue(index) => {
+		let value = self.upvalues[*index].get_vector(context)?[0].clone();
+		self.stack.push(value);
+		self.pc += 1;
+	    }
+	    RawBytecode::SetUpvalue(index) => {
+		let value = self.pop();
+		let mut cell = self.upvalues[*index].clone();
+		cell.get_vector_mut(context)?[0] = value;
+		self.pc += 1;
+	    }
+	    RawBytecode::MakeCell => {
+		let value = self.pop();
+		self.stack.push(Value::new_vector(vec![value], context));
+		self.pc += 1;
+	    }
+	    RawBytecode::LoadCell => {
+		let cell = self.pop();
+		self.stack.push(cell.get_vector(context)?[0].clone());
+		self.pc += 1;
+	    }
+	    RawBytecode::SetCell => {
+		let mut cell = self.pop();
+		let value = self.pop();
+		cell.get_vector_mut(context)?[0] = value;
+		self.pc += 1;
+	    }
+	    RawBytecode::CallWithEscape => {
+		let function = self.stack.pop().expect("stack is empty");
+		let function = function.get_function(context)?;
+		let result = function.call_with_escape(context, module_name)?;
+		self.stack.push(result.unwrap_or_else(Value::new_nil));
+		self.pc += 1;
+	    }
+	    RawBytecode::Return => {
+		return Ok(Some(Tail::Done(self.stack.pop())));
+	    }
+	    RawBytecode::Jump(target) => {
+		self.pc = *target;
+	    }
+	    RawBytecode::JumpIfFalse(target) => {
+		let condition = self.pop();
+		if condition.get_boolean(context)? {
 		    self.pc += 1;
+		} else {
+		    self.pc = *target;
 		}
-		RawBytecode::Load => {
-		    let symbol = self.stack.pop().expect("stack is empty");
-		    let symbol = symbol.get_symbol(context)?;
-		    let path = module_name.iter().chain(symbol.iter()).map(|s| s.clone()).collect();
-		    let Some(value) = context.get(path) else {
-			return Err(Box::new(Exception::new(symbol, "symbol not found", context)));
-		    };
-		    self.stack.push(value);
-		    self.pc += 1;
+	    }
+	    RawBytecode::PushFrame => {
+		context.push_frame(None);
+		self.pc += 1;
+	    }
+	    RawBytecode::PopFrame => {
+		context.pop_frame();
+		self.pc += 1;
+	    }
+	    RawBytecode::MakeClosure(prototype, captures) => {
+		let upvalues = captures.iter().map(|capture| match capture {
+		    Capture::Local(slot) => self.stack[*slot].clone(),
+		    Capture::Upvalue(index) => self.upvalues[*index].clone(),
+		}).collect();
+		let function = Function::Bytecode(prototype.args.clone(), prototype.code.clone(), prototype.shape.clone(), Some(context.capture_environment()), upvalues);
+		self.stack.push(Value::new_function(function, context));
+		self.pc += 1;
+	    }
+	    RawBytecode::MakeVector(length) => {
+		let elements = self.pop_many(*length);
+		self.stack.push(Value::new_vector(elements, context));
+		self.pc += 1;
+	    }
+	    RawBytecode::MakeStruct(field_count) => {
+		let name = self.stack.pop().expect("stack is empty");
+		let name = name.get_symbol(context)?;
+		let mut fields = Vec::new();
+		for _ in 0..*field_count {
+		    fields.push(self.stack.pop().expect("stack is empty"));
 		}
-		RawBytecode::BindKeyword => {
-		    let value = self.stack.pop().expect("stack is empty");
-		    let keyword = self.stack.pop().expect("stack is empty");
-		    if let Some(ref mut keywords) = self.keywords {
-			keywords.insert(keyword.get_symbol(context)?.last().unwrap().clone(), value);
-		    } else {
-			let mut keywords = Kwargs::new();
-			keywords.insert(keyword.get_symbol(context)?.last().unwrap().clone(), value);
-			self.keywords = Some(keywords);
-		    }
-		    self.pc += 1;
+
+		let structure = Struct::new(context.get_or_create_type_symbol(name), fields.into_boxed_slice());
+		self.stack.push(Value::new_struct(structure, context));
+		self.pc += 1;
+	    }
+	    RawBytecode::StructAccess => {
+		let index = self.stack.pop().expect("stack is empty");
+		let index = index.get_integer(context)?;
+		let structure = self.stack.pop().expect("stack is empty");
+		let structure = structure.get_struct(context)?;
+		self.stack.push(structure.get_member(index.to_u64().unwrap() as usize, context)?.clone());
+		self.pc += 1;
+	    }
+	    RawBytecode::StructStore => {
+		let index = self.stack.pop().expect("stack is empty");
+		let index = index.get_integer(context)?;
+		let value = self.stack.pop().expect("stack is empty");
+		let mut structure = self.stack.pop().expect("stack is empty");
+		let structure = structure.get_struct_mut(context)?;
+		structure.set_member(index.to_u64().unwrap() as usize, value.clone(), context)?;
+		self.pc += 1;
+	    }
+	    RawBytecode::MakeEnum(field_count) => {
+		let name = self.stack.pop().expect("stack is empty");
+		let variant = self.stack.pop().expect("stack is empty");
+		let variant = variant.get_symbol(context)?;
+		let name = name.get_symbol(context)?;
+		let mut fields = Vec::new();
+		for _ in 0..*field_count {
+		    fields.push(self.stack.pop().expect("stack is empty"));
 		}
-		RawBytecode::Call(arg_count) => {
-		    let function = self.stack.pop().expect("stack is empty");
-		    let function = function.get_function(context)?;
-		    let kwarg = if let Some(keywords) = self.keywords.take() {
-			keywords
+
+		let enumeration = Enum::new(context.get_or_create_type_symbol(name), context.get_or_create_type_symbol(variant), fields.into_boxed_slice());
+		self.stack.push(Value::new_enum(enumeration, context));
+		self.pc += 1;
+	    },
+	    RawBytecode::EnumAccess => {
+		let index = self.stack.pop().expect("stack is empty");
+		let index = index.get_integer(context)?;
+		let enumeration = self.stack.pop().expect("stack is empty");
+		let enumeration = enumeration.get_enum(context)?;
+		self.stack.push(enumeration.get_member(index.to_u64().unwrap() as usize, context)?.clone());
+		self.pc += 1;
+	    },
+	    RawBytecode::EnumStore => {
+		let index = self.stack.pop().expect("stack is empty");
+		let index = index.get_integer(context)?;
+		let value = self.stack.pop().expect("stack is empty");
+		let mut enumeration = self.stack.pop().expect("stack is empty");
+		let enumeration = enumeration.get_enum_mut(context)?;
+		enumeration.set_member(index.to_u64().unwrap() as usize, value.clone(), context)?;
+		self.pc += 1;
+	    },
+	    RawBytecode::MatchPattern(pattern) => {
+		let value = self.pop();
+		let matched = walkthrough::match_pattern(pattern, &value, context, module_name)?;
+		self.stack.push(Value::new_boolean(matched));
+		self.pc += 1;
+	    },
+	    RawBytecode::NoMatch => {
+		let value = self.pop();
+		return Err(Box::new(Exception::new(&vec!["match"], &format!("no case matches {} and no else branch", value), context)));
+	    },
+	    RawBytecode::PushHandler(clauses) => {
+		let matchers = self.pop_many(clauses.len());
+		let mut handlers = Vec::new();
+		for (i, (clause, matcher)) in clauses.iter().zip(matchers.iter()).enumerate() {
+		    let matcher = if clause.message {
+			ConditionMatcher::Who(matcher.get_symbol(context)?.clone())
+		    } else if matcher.is_function() {
+			ConditionMatcher::Predicate(matcher.clone())
+		    } else if matcher.is_boolean() {
+			ConditionMatcher::Any
 		    } else {
-			Kwargs::new()
+			return Err(Box::new(Exception::new(&vec!["try"], "not a procedure", context)));
 		    };
-		    let mut args = Vec::new();
-		    for _ in 0..*arg_count {
-			args.push(self.stack.pop().expect("stack is empty"));
-		    }
-		    //TODO add function name to call so that we can get the function name in the exception
-		    let result = function.call_from_bytecode(&vec![], args, kwarg, context, module_name)?;
-		    if let Some(result) = result {
-			self.stack.push(result);
-		    }
-		    self.pc += 1;
+		    handlers.push((matcher, HandlerAction::Catch(i)));
 		}
-		RawBytecode::Return => {
-		    return Ok(self.stack.pop());
+		let cluster = HandlerCluster::new(handlers);
+		self.unwinding.push(Unwind::Catch {
+		    cluster: cluster.get_id(),
+		    clauses: clauses.clone(),
+		    stack: self.stack.len(),
+		    frames: context.frame_depth(),
+		});
+		context.push_handlers(cluster);
+		self.pc += 1;
+	    },
+	    RawBytecode::PopHandler => {
+		self.unwinding.pop();
+		context.pop_handlers();
+		self.pc += 1;
+	    },
+	    RawBytecode::PushCleanup(handler) => {
+		self.unwinding.push(Unwind::Cleanup {
+		    handler: *handler,
+		    stack: self.stack.len(),
+		    frames: context.frame_depth(),
+		});
+		self.pc += 1;
+	    },
+	    RawBytecode::PopCleanup => {
+		self.unwinding.pop();
+		self.pc += 1;
+	    },
+	    RawBytecode::Reraise => {
+		return Err(Box::new(self.pending.pop().expect("no error to raise again")));
+	    },
+	    RawBytecode::Error(field_count) => {
+		let fields = self.pop_many(field_count * 2);
+		let message = self.pop();
+		let who = self.pop();
+		let message = message.get_string(context)?.clone();
+		let who = who.get_symbol(context)?;
+		let mut exception = Exception::new(who, &message, context);
+		for field in fields.chunks(2) {
+		    let name = field[0].get_symbol(context)?.join(".");
+		    exception = exception.with_field(&name, field[1].clone());
 		}
-		RawBytecode::MakeStruct(field_count) => {
-		    let name = self.stack.pop().expect("stack is empty");
-		    let name = name.get_symbol(context)?;
-		    let mut fields = Vec::new();
-		    for _ in 0..*field_count {
-			fields.push(self.stack.pop().expect("stack is empty"));
-		    }
-
-		    let structure = Struct::new(context.get_or_create_type_symbol(name), fields.into_boxed_slice());
-		    self.stack.push(Value::new_struct(structure, context));
-		    self.pc += 1;
-		}
-		RawBytecode::StructAccess => {
-		    let index = self.stack.pop().expect("stack is empty");
-		    let index = index.get_integer(context)?;
-		    let structure = self.stack.pop().expect("stack is empty");
-		    let structure = structure.get_struct(context)?;
-		    self.stack.push(structure.get_member(index.to_u64().unwrap() as usize, context)?.clone());
-		    self.pc += 1;
-		}
-		RawBytecode::StructStore => {
-		    let index = self.stack.pop().expect("stack is empty");
-		    let index = index.get_integer(context)?;
-		    let value = self.stack.pop().expect("stack is empty");
-		    let mut structure = self.stack.pop().expect("stack is empty");
-		    let structure = structure.get_struct_mut(context)?;
-		    structure.set_member(index.to_u64().unwrap() as usize, value.clone(), context)?;
-		    self.pc += 1;
-		}
-		RawBytecode::MakeEnum(field_count) => {
-		    let name = self.stack.pop().expect("stack is empty");
-		    let variant = self.stack.pop().expect("stack is empty");
-		    let variant = variant.get_symbol(context)?;
-		    let name = name.get_symbol(context)?;
-		    let mut fields = Vec::new();
-		    for _ in 0..*field_count {
-			fields.push(self.stack.pop().expect("stack is empty"));
-		    }
-
-		    let enumeration = Enum::new(context.get_or_create_type_symbol(name), context.get_or_create_type_symbol(variant), fields.into_boxed_slice());
-		    self.stack.push(Value::new_enum(enumeration, context));
-		    self.pc += 1;
-		},
-		RawBytecode::EnumAccess => {
-		    let index = self.stack.pop().expect("stack is empty");
-		    let index = index.get_integer(context)?;
-		    let enumeration = self.stack.pop().expect("stack is empty");
-		    let enumeration = enumeration.get_enum(context)?;
-		    self.stack.push(enumeration.get_member(index.to_u64().unwrap() as usize, context)?.clone());
-		    self.pc += 1;
-		},
-		RawBytecode::EnumStore => {
-		    let index = self.stack.pop().expect("stack is empty");
-		    let index = index.get_integer(context)?;
-		    let value = self.stack.pop().expect("stack is empty");
-		    let mut enumeration = self.stack.pop().expect("stack is empty");
-		    let enumeration = enumeration.get_enum_mut(context)?;
-		    enumeration.set_member(index.to_u64().unwrap() as usize, value.clone(), context)?;
-		    self.pc += 1;
-		},
-	    }
+		return Err(condition::signal(Box::new(exception), context));
+	    },
+	    RawBytecode::Walk(sexpr) => {
+		let value = walkthrough::walk_through(sexpr, context, module_name)?;
+		self.stack.push(value.unwrap_or_else(Value::new_nil));
+		self.pc += 1;
+	    },
 	}
 	Ok(None)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::bytecode::compiler::tests::eval_vm;
+    use crate::interpreter::bytecode::compiler::compile;
+    use crate::interpreter::bytecode::{Bytecode, RawBytecode};
+    use crate::interpreter::walkthrough::tests::new_context;
+
+    /// Whether any instruction, those of the procedures it makes included, satisfies `predicate`
+    fn emits(code: &[Bytecode], predicate: &dyn Fn(&RawBytecode) -> bool) -> bool {
+	code.iter().any(|bytecode| match bytecode.get_raw() {
+	    RawBytecode::MakeClosure(prototype, _) => emits(&prototype.code, predicate),
+	    raw => predicate(raw),
+	})
+    }
+
+    fn compile_source(source: &str) -> Vec<Bytecode> {
+	let mut context = new_context();
+	let file = crate::parser::parse_with_context(source, "test.lpy", &mut context.get_macros(), &context).expect("parse error");
+	file.into_iter().flat_map(|sexpr| compile(&sexpr, &mut context).expect("compile error")).collect()
+    }
+
+    #[test]
+    fn test_closures_share_captured_cell() {
+	let source = "(define (use-both bump peek) (bump) (bump) (peek))
+(define (counter)
+  (let ((n 0))
+    (use-both (lambda () (set! n (+ n 1)) n) (lambda () n))))
+(counter)";
+	let code = compile_source(source);
+	assert!(emits(&code, &|raw| matches!(raw, RawBytecode::MakeCell)));
+	assert!(emits(&code, &|raw| matches!(raw, RawBytecode::SetUpvalue(_))));
+	assert!(emits(&code, &|raw| matches!(raw, RawBytecode::LoadUpvalue(_))));
+	assert_eq!(eval_vm(source).unwrap(), "2");
+    }
+
+    #[test]
+    fn test_deep_self_tail_call() {
+	let source = "(define (count n acc) (if (= n 0) acc (count (- n 1) (+ acc 1))))
+(count 100000 0)";
+	assert!(emits(&compile_source(source), &|raw| matches!(raw, RawBytecode::TailCall(..))));
+	// A stack this small overflows long before 100000 nested calls
+	let result = std::thread::Builder::new()
+	    .stack_size(1 << 20)
+	    .spawn(move || eval_vm(source).map_err(|e| e.to_string()))
+	    .unwrap()
+	    .join()
+	    .expect("the stack grew with the tail calls");
+	assert_eq!(result.unwrap(), "100000");
+    }
+
+    #[test]
+    fn test_try_finally_unwinds() {
+	let source = "(define cleaned 0)
+(define (descend n)
+  (try (if (= n 0) (error 'bottom \"reached\") (+ 1 (descend (- n 1))))
+    (finally (set! cleaned (+ cleaned 1)))))
+(define caught (+ 1 (try (descend 5) ((catch 'bottom message) 10))))
+`(,caught ,cleaned)";
+	// Every finally ran once and the handler saw a stack with nothing of the calls it unwound
+	assert_eq!(eval_vm(source).unwrap(), "'(11 6)");
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::{Exception, HelperResult};
+use super::context::Context;
+use super::kwargs::Kwargs;
+use super::value::Value;
+
+static NEXT_CLUSTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How a handler recognises the conditions it is for
+#[derive(Clone)]
+pub enum ConditionMatcher {
+    /// Exceptions whose who is the symbol
+    Who(Vec<String>),
+    /// Conditions the procedure returns true for
+    Predicate(Value),
+    Any,
+}
+
+impl ConditionMatcher {
+    fn matches(&self, exception: &Exception, condition: &Value, context: &mut Context) -> HelperResult<bool> {
+	match self {
+	    ConditionMatcher::Who(who) => Ok(exception.get_who(context) == who),
+	    ConditionMatcher::Predicate(predicate) => {
+		let result = predicate.get_function(context)?.call_raw(vec![condition.clone()], Kwargs::new(), context, &vec![])?;
+		match result {
+		    Some(result) => result.get_boolean(context),
+		    None => Ok(false),
+		}
+	    },
+	    ConditionMatcher::Any => Ok(true),
+	}
+    }
+}
+
+#[derive(Clone)]
+pub enum HandlerAction {
+    /// Calls the procedure with the condition where it was signalled, returning declines to handle it
+    Call(Value),
+    /// Unwinds to the clause of a `try` with this index
+    Catch(usize),
+}
+
+/// The handlers established by one `handler-bind` or `try`
+#[derive(Clone)]
+pub struct HandlerCluster {
+    id: usize,
+    handlers: Vec<(ConditionMatcher, HandlerAction)>,
+}
+
+impl HandlerCluster {
+    pub fn new(handlers: Vec<(ConditionMatcher, HandlerAction)>) -> Self {
+	HandlerCluster {
+	    id: NEXT_CLUSTER.fetch_add(1, Ordering::Relaxed),
+	    handlers,
+	}
+    }
+
+    pub fn get_id(&self) -> usize {
+	self.id
+    }
+
+    /// Runs the matching handlers in order, stopping at a `try` clause that will catch the condition
+    fn run(&self, exception: &Exception, condition: &Value, context: &mut Context) -> HelperResult<Option<(usize, usize)>> {
+	for (matcher, action) in self.handlers.iter() {
+	    if !matcher.matches(exception, condition, context)? {
+		continue;
+	    }
+	    match action {
+		HandlerAction::Call(function) => {
+		    function.get_function(context)?.call_raw(vec![condition.clone()], Kwargs::new(), context, &vec![])?;
+		},
+		HandlerAction::Catch(clause) => return Ok(Some((self.id, *clause))),
+	    }
+	}
+	Ok(None)
+    }
+}
+
+/// Offers an exception to the established handlers, innermost first, before anything is unwound.
+/// Returns the exception to propagate, which is a handler's own error or escape if one left that way.
+///
+/// Procedures and the virtual machine signal an error where it happens. The tree walker's own errors, an unbound
+/// symbol or a value of the wrong type, are signalled as they leave the innermost procedure call, `try`, `handler-bind`
+/// or `restart-case`, which sees the same handlers and restarts but after the `let` and body frames in between are left.
+pub fn signal(mut exception: Box<Exception>, context: &mut Context) -> Box<Exception> {
+    if exception.signaled || exception.is_escape() {
+	return exception;
+    }
+    exception.signaled = true;
+    let condition = exception.get_condition(context);
+    match run_handlers(&exception, &condition, context) {
+	Ok(catcher) => {
+	    exception.catcher = catcher;
+	    exception
+	},
+	Err(e) => e,
+    }
+}
+
+/// Signals a copy of an exception that was already handled once, as when a handler raises it again
+pub fn resignal(exception: &Exception, context: &mut Context) -> Box<Exception> {
+    let mut exception = Box::new(exception.clone());
+    exception.signaled = false;
+    exception.catcher = None;
+    signal(exception, context)
+}
+
+/// The `try` clause that will catch the exception, as the id of its cluster and the index of the clause
+pub fn get_catcher(exception: &Exception) -> Option<(usize, usize)> {
+    exception.catcher
+}
+
+fn run_handlers(exception: &Exception, condition: &Value, context: &mut Context) -> HelperResult<Option<(usize, usize)>> {
+    let mut index = context.get_handlers().len();
+    while index > 0 {
+	index -= 1;
+	let cluster = context.get_handlers()[index].clone();
+	// A handler runs with only the clusters outside of its own, so signalling from it does not recurse
+	let inner = context.split_handlers(index);
+	let result = cluster.run(exception, condition, context);
+	context.restore_handlers(inner);
+	if let Some(catcher) = result? {
+	    return Ok(Some(catcher));
+	}
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::bytecode::compiler::tests::eval_vm;
+    use crate::interpreter::walkthrough::tests::eval;
+
+    #[test]
+    fn test_handler_bind_runs_before_unwinding() {
+	let source = "(define seen 0)
+(define (count c) (set! seen (+ seen 1)))
+(try (handler-bind (('other count) ('who count) (exception? count)) (error 'who \"bad\"))
+  ((catch 'who message) `(,message ,seen)))";
+	// Returning from a handler declines, so the try still catches the condition
+	assert_eq!(eval(source).unwrap(), "'(bad 2)");
+	assert_eq!(eval_vm(source).unwrap(), "'(bad 2)");
+    }
+
+    #[test]
+    fn test_invoke_restart() {
+	let source = "(handler-bind ((#t (lambda (c) (invoke-restart 'use-value 5))))
+  (restart-case (+ 1 (error 'who \"bad\")) (skip () 0) (use-value (v) (* v 2))))";
+	assert_eq!(eval(source).unwrap(), "10");
+	assert_eq!(eval_vm(source).unwrap(), "10");
+	let e = eval("(restart-case (invoke-restart 'missing) (skip () 0))").unwrap_err();
+	assert_eq!(e.to_string(), "test.lpy:1:15: 'invoke-restart: no restart named missing");
+	let source = "(restart-case (invoke-restart :restart 'skip) (skip () 0))";
+	assert_eq!(eval(source).unwrap(), "0");
+	assert_eq!(eval_vm(source).unwrap(), "0");
+	assert_eq!(eval("(invoke-restart)").unwrap_err().to_string(), "test.lpy:1:1: 'invoke-restart: wrong number of arguments");
+    }
+
+    #[test]
+    fn test_walker_errors_are_signalled_inside_restart_case() {
+	let source = "(handler-bind ((#t (lambda (c) (invoke-restart 'use-value 5))))
+  (restart-case undefined-variable (use-value (v) v)))";
+	assert_eq!(eval(source).unwrap(), "5");
+	assert_eq!(eval_vm(source).unwrap(), "5");
+    }
+}
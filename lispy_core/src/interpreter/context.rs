@@ -4,66 +4,143 @@ use crate::gc::{self, Gc};
 use crate::interpreter::value::Value;
 use crate::interpreter::module::Module;
 use crate::parser::r#macro::Macro;
+use crate::parser::Span;
 use crate::stdlib::get_stdlib;
-use std::sync::{Arc, RwLock, RwLockWriteGuard, TryLockError};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
 use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::cell::RefCell;
 
 
 use super::{HelperResult, Exception};
+use super::condition::HandlerCluster;
 use super::value::GcValue;
 
+/// A frame of variables. Clones share the same variables, so closures see each other's assignments
 #[derive(Debug, Clone)]
 pub struct ContextFrame {
-    pub bindings: HashMap<String, Value>,
+    bindings: Arc<RwLock<HashMap<String, Value>>>,
 }
 
 impl ContextFrame {
     pub fn new() -> Self {
 	ContextFrame {
-	    bindings: HashMap::new(),
+	    bindings: Arc::new(RwLock::new(HashMap::new())),
 	}
     }
     pub fn new_with_bindings(bindings: HashMap<String, Value>) -> Self {
 	ContextFrame {
-	    bindings,
+	    bindings: Arc::new(RwLock::new(bindings)),
 	}
     }
 
-    pub fn get(&self, name: &str) -> Option<&Value> {
-	let value = self.bindings.get(name);
-        value
+    pub fn get(&self, name: &str) -> Option<Value> {
+	self.bindings.read().unwrap().get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+	self.bindings.read().unwrap().contains_key(name)
+    }
+
+    pub fn define(&self, name: &str, value: Value) {
+	self.bindings.write().unwrap().insert(name.to_string(), value);
+    }
+
+    /// The values are copied out first since marking a closure can come back to this frame
+    fn values(&self) -> Vec<Value> {
+	self.bindings.read().unwrap().values().cloned().collect()
     }
 
     pub fn mark(&self) {
-	for (_, value) in self.bindings.iter() {
+	for value in self.values() {
 	    value.mark();
 	}
     }
 
     pub fn unmark(&self) {
-	for (_, value) in self.bindings.iter() {
+	for value in self.values() {
 	    value.unmark();
 	}
     }
 
     pub fn protect(&self) {
-	for (_, value) in self.bindings.iter() {
+	for value in self.values() {
 	    value.protect();
 	}
     }
 
     pub fn merge_frame(&mut self, frame: ContextFrame) {
-	for (name, value) in frame.bindings {
-	    self.bindings.insert(name, value);
+	for (name, value) in frame.bindings.read().unwrap().iter() {
+	    self.define(name, value.clone());
 	}
     }
 
     pub fn rebind(&mut self, name: &str, value: Value) {
-	self.bindings.insert(name.to_string(), value);
+	self.define(name, value);
+    }
+}
+
+/// The chain of frames a closure was created in, outermost first
+#[derive(Debug, Clone)]
+pub struct Environment {
+    frames: Vec<ContextFrame>,
+}
+
+impl Environment {
+    pub fn mark(&self) {
+	for frame in self.frames.iter() {
+	    frame.mark();
+	}
+    }
+
+    pub fn unmark(&self) {
+	for frame in self.frames.iter() {
+	    frame.unmark();
+	}
+    }
+
+    pub fn protect(&self) {
+	for frame in self.frames.iter() {
+	    frame.protect();
+	}
+    }
+}
+
+/// A record of an active procedure call, kept so that exceptions can carry a backtrace
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    pub name: Vec<String>,
+    pub module: Vec<String>,
+    pub kind: &'static str,
+    pub location: Span,
+}
+
+impl CallFrame {
+    pub fn new(name: Vec<String>, module: Vec<String>, kind: &'static str, location: Span) -> Self {
+	CallFrame {
+	    name,
+	    module,
+	    kind,
+	    location,
+	}
+    }
+}
+
+impl std::fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	write!(f, "{} ({})", self.name.join("."), self.kind)?;
+	if !self.module.is_empty() {
+	    write!(f, " in {}", self.module.join("."))?;
+	}
+	if self.location.is_known() {
+	    write!(f, " called at {}", self.location)?;
+	}
+	Ok(())
     }
 }
 
+static NEXT_ESCAPE_POINT: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Context {
     gc_lock: Arc<RwLock<()>>,
     sender: Sender<Gc<GcValue>>,
@@ -77,6 +154,15 @@ pub struct Context {
     files_to_modules: Arc<RwLock<HashMap<String, usize>>>,
     paths_to_modules: Arc<RwLock<HashMap<Vec<String>, usize>>>,
     modules: Arc<RwLock<Vec<Module>>>,
+    location: Span,
+    call_stack: Vec<CallFrame>,
+    escape_points: Vec<usize>,
+    handlers: Vec<HandlerCluster>,
+    restarts: Vec<(String, usize)>,
+    /// Whether the virtual machine logs each instruction it runs
+    trace: bool,
+    /// Whether files run on the virtual machine, imported modules then run there too
+    vm: bool,
 }
 
 impl Context {
@@ -94,6 +180,13 @@ impl Context {
 	    files_to_modules: Arc::new(RwLock::new(HashMap::new())),
 	    paths_to_modules: Arc::new(RwLock::new(HashMap::new())),
 	    modules: Arc::new(RwLock::new(Vec::new())),
+	    location: Span::default(),
+	    call_stack: Vec::new(),
+	    escape_points: Vec::new(),
+	    handlers: Vec::new(),
+	    restarts: Vec::new(),
+	    trace: false,
+	    vm: false,
 	};
 	let string_name = Value::new_symbol(vec!["string".to_string()], &mut ctx);
 	let integer_name = Value::new_symbol(vec!["integer".to_string()], &mut ctx);
@@ -144,7 +237,13 @@ impl Context {
 	    files_to_modules: Arc::new(RwLock::new(HashMap::new())),
 	    paths_to_modules: Arc::new(RwLock::new(HashMap::new())),
 	    modules: Arc::new(RwLock::new(Vec::new())),
-
+	    location: Span::default(),
+	    call_stack: Vec::new(),
+	    escape_points: Vec::new(),
+	    handlers: Vec::new(),
+	    restarts: Vec::new(),
+	    trace: false,
+	    vm: false,
 	};
 	
 	let stdlib = get_stdlib(&mut ctx);
@@ -165,10 +264,105 @@ impl Context {
 	ctx
     }
 
+    /// Sets the source location currently being evaluated and returns the previous one
+    pub fn set_location(&mut self, location: Span) -> Span {
+	std::mem::replace(&mut self.location, location)
+    }
+
+    pub fn get_location(&self) -> &Span {
+	&self.location
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+	self.trace = trace;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+	self.trace
+    }
+
+    pub fn set_vm(&mut self, vm: bool) {
+	self.vm = vm;
+    }
+
+    pub fn is_using_vm(&self) -> bool {
+	self.vm
+    }
+
+    pub fn push_call(&mut self, frame: CallFrame) {
+	self.call_stack.push(frame);
+    }
+
+    pub fn pop_call(&mut self) -> Option<CallFrame> {
+	self.call_stack.pop()
+    }
+
+    /// The active calls, outermost first
+    pub fn get_call_stack(&self) -> &[CallFrame] {
+	&self.call_stack
+    }
+
+    /// Opens a point that a continuation can escape to and returns its id
+    pub fn push_escape_point(&mut self) -> usize {
+	let id = NEXT_ESCAPE_POINT.fetch_add(1, Ordering::Relaxed);
+	self.escape_points.push(id);
+	id
+    }
+
+    pub fn pop_escape_point(&mut self) {
+	self.escape_points.pop();
+    }
+
+    /// Whether the escape point `id` is still on this thread's stack
+    pub fn is_escape_point_active(&self, id: usize) -> bool {
+	self.escape_points.contains(&id)
+    }
+
+    pub fn push_handlers(&mut self, cluster: HandlerCluster) {
+	self.handlers.push(cluster);
+    }
+
+    pub fn pop_handlers(&mut self) {
+	self.handlers.pop();
+    }
+
+    /// The established handler clusters, outermost first
+    pub fn get_handlers(&self) -> &[HandlerCluster] {
+	&self.handlers
+    }
+
+    /// Removes the clusters from `index` on, so a handler runs with only those established outside of it
+    pub fn split_handlers(&mut self, index: usize) -> Vec<HandlerCluster> {
+	self.handlers.split_off(index)
+    }
+
+    pub fn restore_handlers(&mut self, handlers: Vec<HandlerCluster>) {
+	self.handlers.extend(handlers);
+    }
+
+    /// Makes the restart `name` available, invoking it escapes to the escape point `id`
+    pub fn push_restart(&mut self, name: String, id: usize) {
+	self.restarts.push((name, id));
+    }
+
+    pub fn pop_restart(&mut self) {
+	self.restarts.pop();
+    }
+
+    /// The escape point of the innermost active restart called `name`
+    pub fn find_restart(&self, name: &str) -> Option<usize> {
+	self.restarts.iter().rev().find(|(restart, _)| restart == name).map(|(_, id)| *id)
+    }
+
+    /// The names of the active restarts, innermost first
+    pub fn get_restart_names(&self) -> Vec<String> {
+	self.restarts.iter().rev().map(|(name, _)| name.clone()).collect()
+    }
+
     pub fn push_frame(&mut self, frame: Option<ContextFrame>) {
 	match frame {
 	    Some(frame) => self.frames.push(frame),
-	    None => self.frames.push(ContextFrame { bindings: HashMap::new() }),
+	    None => self.frames.push(ContextFrame::new()),
 	}
     }
 
@@ -176,16 +370,35 @@ impl Context {
 	self.frames.pop()
     }
 
-    pub fn copy_frame(&mut self) -> ContextFrame {
-	let frame = self.frames.last().unwrap().clone();
-	frame
+    /// How many frames are visible, so that code which can be left by an error can drop the frames it pushed
+    pub fn frame_depth(&self) -> usize {
+	self.frames.len()
     }
 
-    pub fn copy_frame_at(&self, pos: usize) -> ContextFrame {
-	self.frames[pos].clone()
+    pub fn truncate_frames(&mut self, depth: usize) {
+	self.frames.truncate(depth);
     }
 
-    fn get_from_frame(&self, name: &str) -> Option<&Value> {
+    /// The frames visible from here, for a closure to keep
+    pub fn capture_environment(&self) -> Environment {
+	Environment {
+	    frames: self.frames.clone(),
+	}
+    }
+
+    /// Makes `environment` plus a new frame the visible frames and returns the ones it replaces
+    pub fn enter_environment(&mut self, environment: &Environment) -> Vec<ContextFrame> {
+	let mut frames = environment.frames.clone();
+	frames.push(ContextFrame::new());
+	std::mem::replace(&mut self.frames, frames)
+    }
+
+    /// Restores the frames `enter_environment` replaced
+    pub fn leave_environment(&mut self, frames: Vec<ContextFrame>) {
+	self.frames = frames;
+    }
+
+    fn get_from_frame(&self, name: &str) -> Option<Value> {
         for frame in self.frames.iter().rev() {
             if let Some(value) = frame.get(name) {
                 return Some(value);
@@ -195,7 +408,7 @@ impl Context {
     }
 
     pub fn is_bound(&self, name: &Vec<String>) -> bool {
-	self.get(name.clone()).is_some()
+	matches!(self.get(name.clone()), Ok(Some(_)))
     }
 
     fn lookup_module_in_path(&self, name: &[String]) -> Option<usize> {
@@ -208,12 +421,13 @@ impl Context {
 	None
     }
 
-    pub fn get(&self, name: Vec<String>) -> Option<Value> {
+    /// Looks a name up in the frames and then in the modules, loading a module can fail
+    pub fn get(&self, name: Vec<String>) -> HelperResult<Option<Value>> {
 	//println!("get: {:?}", name);
 
 	let value = self.get_from_frame(&name.last().unwrap());
 	if value.is_some() {
-	    return value.cloned();
+	    return Ok(value);
 	}
 
 	let mut slice_index = 0;
@@ -230,19 +444,19 @@ impl Context {
 		continue;
 	    };
 
-	    let value = module.get(&name.last().unwrap(), self);
+	    let value = module.get(&name.last().unwrap(), self)?;
 	    if value.is_some() {
-		return value;
+		return Ok(value);
 	    }
 
 	    slice_index += 1;
 	}
 	
-	None
+	Ok(None)
     }
 
     pub fn define(&mut self, name: &str, value: Value) {
-	self.frames.last_mut().unwrap().bindings.insert(name.to_string(), value);
+	self.frames.last().unwrap().define(name, value);
     }
 
     pub fn bind(&mut self, name: &Vec<String>, value: Value) {
@@ -258,9 +472,9 @@ impl Context {
 
     pub fn rebind(&mut self, name: &Vec<String>, value: Value) {
 	if name.len() == 1 {
-	    for frame in self.frames.iter_mut().rev() {
-		if frame.bindings.contains_key(&name[0]) {
-		    frame.bindings.insert(name[0].to_string(), value);
+	    for frame in self.frames.iter().rev() {
+		if frame.contains(&name[0]) {
+		    frame.define(&name[0], value);
 		    return;
 		}
 	    }
@@ -304,9 +518,7 @@ impl Context {
 		crate::ffi::load_dynamic_lib(self, file_path_str)
 		    .map_err(|err| Box::new(Exception::new(&vec!["import"], &format!("{}", err), self)))?
 	    }
-	    _ => {
-		Module::new(file_path_str, path.clone())
-	    }
+	    _ => Module::new(file_path_str, path.clone()),
 	};
 
 	let index = self.modules.read().unwrap().len();
@@ -499,6 +711,16 @@ impl Context {
 	self.macros.write().unwrap()
     }
 
+    /// The macros for looking at only, other readers are not kept waiting
+    pub fn read_macros(&self) -> RwLockReadGuard<'_, HashSet<Macro>> {
+	self.macros.read().unwrap()
+    }
+
+    /// Gives this context macros of its own, the contexts it was cloned from no longer share them
+    pub fn set_macros(&mut self, macros: HashSet<Macro>) {
+	self.macros = Arc::new(RwLock::new(macros));
+    }
+
     pub fn add_dynamic_lib(&self, lib: libloading::Library) {
 	let mut dynamic_libraries = self.dynamic_libraries.write().unwrap();
 	dynamic_libraries.push(lib);
@@ -592,7 +814,8 @@ impl Clone for Context {
 	Context {
 	    gc_lock: self.gc_lock.clone(),
 	    sender: self.sender.clone(),
-	    frames: Vec::new(),
+	    // The global frame with the standard library is shared, everything else is left behind
+	    frames: self.frames.first().cloned().into_iter().collect(),
 	    type_table: self.type_table.clone(),
 	    symbols_to_table: self.symbols_to_table.clone(),
 	    enum_idicies: self.enum_idicies.clone(),
@@ -601,6 +824,13 @@ impl Clone for Context {
 	    files_to_modules: self.files_to_modules.clone(),
 	    paths_to_modules: self.paths_to_modules.clone(),
 	    modules: self.modules.clone(),
+	    location: self.location.clone(),
+	    call_stack: Vec::new(),
+	    escape_points: Vec::new(),
+	    handlers: Vec::new(),
+	    restarts: Vec::new(),
+	    trace: self.trace,
+	    vm: self.vm,
 	}
     }
 }
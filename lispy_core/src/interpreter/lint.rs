@@ -0,0 +1,212 @@
+use std::collections::{HashMap, HashSet};
+use crate::parser::{Atom, File, Sexpr, Span};
+
+/// A problem found in a file before it is run
+#[derive(Debug, Clone)]
+pub struct Warning {
+    location: Span,
+    message: String,
+}
+
+impl Warning {
+    fn new(location: &Span, message: String) -> Self {
+	Warning {
+	    location: location.clone(),
+	    message,
+	}
+    }
+
+    pub fn get_location(&self) -> &Span {
+	&self.location
+    }
+
+    pub fn get_message(&self) -> &str {
+	&self.message
+    }
+}
+
+impl std::error::Error for Warning {}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	if self.location.is_known() {
+	    write!(f, "{}: ", self.location)?;
+	}
+	write!(f, "warning: {}", self.message)
+    }
+}
+
+/// The enums and structs a file defines, by their unqualified names
+#[derive(Default)]
+struct Types {
+    /// Each enum's variants in the order they were declared
+    enums: HashMap<String, Vec<String>>,
+    structs: HashSet<String>,
+}
+
+impl Types {
+    fn collect(sexpr: &Sexpr, types: &mut Types) {
+	let Sexpr::List(list, _) = sexpr else {
+	    return;
+	};
+	match list.as_slice() {
+	    [Sexpr::Atom(Atom::Symbol(keyword), _), Sexpr::Atom(Atom::Symbol(name), _), variants @ ..] if keyword[0] == "enum" => {
+		let variants = variants.iter().filter_map(|variant| match variant {
+		    Sexpr::List(variant, _) => match variant.first() {
+			Some(Sexpr::Atom(Atom::Symbol(variant), _)) => variant.last().cloned(),
+			_ => None,
+		    },
+		    _ => None,
+		}).collect();
+		types.enums.insert(name.last().cloned().unwrap(), variants);
+	    },
+	    [Sexpr::Atom(Atom::Symbol(keyword), _), Sexpr::Atom(Atom::Symbol(name), _), ..] if keyword[0] == "struct" => {
+		types.structs.insert(name.last().cloned().unwrap());
+	    },
+	    _ => {
+		for sexpr in list {
+		    Types::collect(sexpr, types);
+		}
+	    },
+	}
+    }
+
+    /// Whether a pattern matches every value it can be given
+    fn is_irrefutable(&self, pattern: &Sexpr) -> bool {
+	match pattern {
+	    Sexpr::Atom(Atom::Symbol(_), _) => true,
+	    Sexpr::List(list, _) => match list.as_slice() {
+		[Sexpr::Atom(Atom::Symbol(name), _), fields @ ..] => {
+		    self.structs.contains(name.last().unwrap()) && fields.iter().all(|field| self.is_irrefutable(field))
+		},
+		_ => false,
+	    },
+	    _ => false,
+	}
+    }
+
+    /// The enum and variant an `(enum variant pattern ...)` pattern is for
+    fn enum_pattern<'a>(&self, pattern: &'a Sexpr) -> Option<(&'a String, &'a String, &'a [Sexpr])> {
+	let Sexpr::List(list, _) = pattern else {
+	    return None;
+	};
+	match list.as_slice() {
+	    [Sexpr::Atom(Atom::Symbol(name), _), Sexpr::Atom(Atom::Symbol(variant), _), fields @ ..] if self.enums.contains_key(name.last().unwrap()) => {
+		Some((name.last().unwrap(), variant.last().unwrap(), fields))
+	    },
+	    _ => None,
+	}
+    }
+}
+
+/// Checks every `match` in a file, warning when one over an enum the file defines leaves out variants
+/// and when a clause can never be reached because the clauses before it already match everything it would.
+/// Only the enums declared in the file itself are known, a `match` over an enum from an imported module is not checked.
+pub fn check_matches(file: &File) -> Vec<Warning> {
+    let mut types = Types::default();
+    for sexpr in file.get_body() {
+	Types::collect(sexpr, &mut types);
+    }
+    let mut warnings = Vec::new();
+    for sexpr in file.get_body() {
+	check_sexpr(sexpr, &types, &mut warnings);
+    }
+    warnings
+}
+
+fn check_sexpr(sexpr: &Sexpr, types: &Types, warnings: &mut Vec<Warning>) {
+    match sexpr {
+	Sexpr::List(list, span) => {
+	    if let [Sexpr::Atom(Atom::Symbol(keyword), _), _, cases @ ..] = list.as_slice() {
+		if keyword.len() == 1 && keyword[0] == "match" {
+		    check_match(cases, span, types, warnings);
+		}
+	    }
+	    for sexpr in list {
+		check_sexpr(sexpr, types, warnings);
+	    }
+	},
+	Sexpr::QuotedList(list, _) | Sexpr::VectorList(list, _) => {
+	    for sexpr in list {
+		check_sexpr(sexpr, types, warnings);
+	    }
+	},
+	Sexpr::Quasiquote(sexpr, _) | Sexpr::Unquote(sexpr, _) | Sexpr::UnquoteSplicing(sexpr, _) => check_sexpr(sexpr, types, warnings),
+	Sexpr::Atom(_, _) => {},
+    }
+}
+
+fn check_match(cases: &[Sexpr], span: &Span, types: &Types, warnings: &mut Vec<Warning>) {
+    let patterns = cases.iter().filter_map(|case| match case {
+	Sexpr::List(case, _) => case.first(),
+	_ => None,
+    }).collect::<Vec<&Sexpr>>();
+    let enumeration = patterns.iter().find_map(|pattern| types.enum_pattern(pattern).map(|(name, _, _)| name));
+
+    let mut covered = HashSet::new();
+    let mut exhaustive = false;
+    for pattern in patterns {
+	if exhaustive {
+	    warnings.push(Warning::new(pattern.span(), format!("unreachable clause {}", pattern)));
+	    continue;
+	}
+	if types.is_irrefutable(pattern) {
+	    exhaustive = true;
+	    continue;
+	}
+	let Some((name, variant, fields)) = types.enum_pattern(pattern) else {
+	    continue;
+	};
+	if Some(name) != enumeration {
+	    continue;
+	}
+	let variants = &types.enums[name];
+	if !variants.contains(variant) {
+	    warnings.push(Warning::new(pattern.span(), format!("{} has no variant {}", name, variant)));
+	    continue;
+	}
+	if covered.contains(variant) {
+	    warnings.push(Warning::new(pattern.span(), format!("unreachable clause {}", pattern)));
+	    continue;
+	}
+	if fields.iter().all(|field| types.is_irrefutable(field)) {
+	    covered.insert(variant);
+	    exhaustive = variants.iter().all(|variant| covered.contains(variant));
+	}
+    }
+
+    if let Some(name) = enumeration {
+	if !exhaustive {
+	    let missing = types.enums[name].iter().filter(|variant| !covered.contains(variant)).cloned().collect::<Vec<String>>();
+	    warnings.push(Warning::new(span, format!("match on {} does not cover {}", name, missing.join(", "))));
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::parser::parse;
+    use super::*;
+
+    #[test]
+    fn test_match_exhaustiveness() {
+	let source = "(enum shape (circle r) (square s) (dot))
+(match x [(shape circle r) r] [(shape circle 1) 1] [(shape square _) 0])
+(match x [(shape dot) 0] [else 1] [(shape square s) s])";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let warnings = check_matches(&file);
+	let messages = warnings.iter().map(|warning| warning.get_message()).collect::<Vec<&str>>();
+	assert_eq!(messages, vec![
+	    "unreachable clause (shape circle 1)",
+	    "match on shape does not cover dot",
+	    "unreachable clause (shape square s)",
+	]);
+    }
+
+    #[test]
+    fn test_imported_enums_are_not_checked() {
+	let file = parse("(match x [(shape circle r) r])", "test.lpy", &mut HashSet::new()).unwrap();
+	assert!(check_matches(&file).is_empty());
+    }
+}
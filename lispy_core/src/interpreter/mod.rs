@@ -4,18 +4,28 @@ pub mod kwargs;
 pub mod context;
 pub mod walkthrough;
 pub mod bytecode;
+pub mod condition;
+pub mod lint;
 
 use std::{error::Error, ffi::c_char};
 use crate::interpreter::value::Value;
-use self::context::Context;
+use self::context::{CallFrame, Context};
+use crate::parser::Span;
 
 pub type InterpreterResult = Result<Option<Value>, Box<Exception>>;
 pub type HelperResult<T> = std::result::Result<T, Box<Exception>>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Exception {
     who: Value, // Symbol
     message: Value, // String
+    location: Span,
+    backtrace: Vec<CallFrame>,
+    escape: Option<(usize, Value)>,
+    payload: Option<Value>,
+    fields: Vec<(String, Value)>,
+    signaled: bool,
+    catcher: Option<(usize, usize)>,
 }
 
 impl Exception {
@@ -26,15 +36,118 @@ impl Exception {
 	Exception {
 	    who,
 	    message,
+	    location: context.get_location().clone(),
+	    backtrace: context.get_call_stack().to_vec(),
+	    escape: None,
+	    payload: None,
+	    fields: Vec::new(),
+	    signaled: false,
+	    catcher: None,
 	}
     }
 
+    /// An exception for `raise`, handlers see `value` instead of the exception itself
+    pub fn new_raise(value: Value, context: &Context) -> Self {
+	let message = format!("uncaught value {}", value);
+	let mut exception = Exception::new(&vec!["raise"], &message, context);
+	exception.payload = Some(value);
+	exception
+    }
+
+    /// An exception for a failed io operation, carrying the `errno` and `kind` of the error as fields
+    pub fn new_io<S: AsRef<str>>(who: &Vec<S>, error: &std::io::Error, context: &Context) -> Self {
+	let errno = match error.raw_os_error() {
+	    Some(errno) => Value::new_integer(&errno.to_string()),
+	    None => Value::new_nil(),
+	};
+	let kind = format!("{:?}", error.kind());
+	Exception::new(who, &error.to_string(), context)
+	    .with_field("errno", errno)
+	    .with_field("kind", Value::new_symbol(vec![kind], context))
+    }
+
+    /// Attaches a named value to the exception, for handlers to inspect instead of parsing the message
+    pub fn with_field(mut self, name: &str, value: Value) -> Self {
+	self.fields.push((name.to_string(), value));
+	self
+    }
+
+    pub fn get_field(&self, name: &str) -> Option<&Value> {
+	self.fields.iter().find(|(field, _)| field == name).map(|(_, value)| value)
+    }
+
+    pub fn get_fields(&self) -> &[(String, Value)] {
+	&self.fields
+    }
+
+    /// The value given to `raise`, if the exception came from it
+    pub fn get_payload(&self) -> Option<&Value> {
+	self.payload.as_ref()
+    }
+
+    /// What handlers are given, the raised value or else the exception itself as a value
+    pub fn get_condition(&self, context: &Context) -> Value {
+	match &self.payload {
+	    Some(value) => value.clone(),
+	    None => Value::new_rust_value(Box::new(self.clone()), context),
+	}
+    }
+
+    /// The symbol naming who raised the exception
+    pub fn get_who_value(&self) -> Value {
+	self.who.clone()
+    }
+
+    /// Not an error but a continuation being invoked, it unwinds to the escape point `id` and returns `value` from there
+    pub fn new_escape(id: usize, value: Value, context: &Context) -> Self {
+	let mut exception = Exception::new(&vec!["continuation"], "continuation invoked outside of its extent", context);
+	exception.escape = Some((id, value));
+	exception
+    }
+
+    /// The escape point this exception unwinds to, if it is an escape
+    pub fn get_escape(&self) -> Option<usize> {
+	self.escape.as_ref().map(|(id, _)| *id)
+    }
+
+    pub fn is_escape(&self) -> bool {
+	self.escape.is_some()
+    }
+
+    /// The value passed to the continuation, if this is an escape
+    pub fn into_escape_value(self) -> Option<Value> {
+	self.escape.map(|(_, value)| value)
+    }
+
     pub fn get_who(&self, context: &mut Context) -> &Vec<String> {
 	self.who.get_symbol(context).expect("who is not a symbol")
     }
     pub fn get_message(&self) -> Value {
 	self.message.clone()
     }
+    /// The source location that was being evaluated when the exception was created
+    pub fn get_location(&self) -> &Span {
+	&self.location
+    }
+    /// The calls that were active when the exception was created, outermost first
+    pub fn get_backtrace(&self) -> &[CallFrame] {
+	&self.backtrace
+    }
+    /// Prints the exception to stderr followed by its backtrace, innermost call first
+    pub fn report(&self) {
+	eprintln!("{}", self);
+	for frame in self.backtrace.iter().rev() {
+	    eprintln!("    at {}", frame);
+	}
+    }
+    /// The backtrace as a list of strings, innermost call first
+    pub fn get_backtrace_value(&self, context: &Context) -> Value {
+	let mut out = Value::new_nil();
+	for frame in self.backtrace.iter() {
+	    out = Value::new_pair(Value::new_string_from_string(frame.to_string(), context), out, context);
+	}
+	out
+    }
 
     #[no_mangle]
     pub extern "C" fn exception_new(who: *mut *mut c_char, symbol_len: usize, symbol_lens: *mut usize, message: *mut c_char, string_len: usize, context: *mut Context) -> *mut Exception {
@@ -44,6 +157,13 @@ impl Exception {
 	let exception = Box::new(Exception {
 	    who,
 	    message,
+	    location: context.get_location().clone(),
+	    backtrace: context.get_call_stack().to_vec(),
+	    escape: None,
+	    payload: None,
+	    fields: Vec::new(),
+	    signaled: false,
+	    catcher: None,
 	});
 	Box::into_raw(exception)
     }
@@ -54,6 +174,31 @@ impl Error for Exception {}
 
 impl std::fmt::Display for Exception {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	if self.location.is_known() {
+	    write!(f, "{}: ", self.location)?;
+	}
 	write!(f, "{}: {}", self.who, self.message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::walkthrough::tests::new_context;
+
+    #[test]
+    fn test_io_exception_fields() {
+	let context = new_context();
+	let error = std::io::Error::from_raw_os_error(13);
+	let exception = Exception::new_io(&vec!["file", "open"], &error, &context);
+	assert_eq!(exception.get_field("errno").unwrap().to_string(), "13");
+	assert_eq!(exception.get_field("kind").unwrap().to_string(), "'PermissionDenied");
+
+	// An error that didn't come from the operating system has no errno
+	let error = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "cut short");
+	let exception = Exception::new_io(&vec!["network", "read"], &error, &context);
+	assert!(exception.get_field("errno").unwrap().is_nil());
+	assert_eq!(exception.get_field("kind").unwrap().to_string(), "'UnexpectedEof");
+	assert!(exception.get_field("path").is_none());
+    }
+}
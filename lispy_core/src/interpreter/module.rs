@@ -2,6 +2,8 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::cell::RefCell;
 use crate::interpreter::value::Value;
+use crate::interpreter::bytecode;
+use crate::interpreter::{walkthrough, Exception, HelperResult};
 
 use super::context::{ContextFrame, Context};
 
@@ -43,35 +45,63 @@ impl Module {
     }
 
     #[inline]
-    fn load(&self, context: &Context) {
+    fn load(&self, context: &Context) -> HelperResult<()> {
 	let mut new_self = None;
 	match &mut *self.raw_module.borrow_mut() {
 	    RawModule::File(path, module_path) => {
-		if let Ok(file_content) = std::fs::read_to_string(&path) {
-		    let mut context = context.clone();
-		    let file = crate::parser::parse(&file_content, &mut context.get_macros()).expect("parse error");
+		let mut context = context.clone();
+		let file_content = std::fs::read_to_string(&path).map_err(|err| Box::new(Exception::new(&vec!["import"], &format!("{}", err), &context)))?;
+		let macros = context.read_macros().clone();
+		// The module is expanded with the macros of its importer, its cache is only fresh for the same ones
+		let macros_hash = bytecode::cache::macros_hash(&macros);
+		let cached = if context.is_using_vm() {
+		    bytecode::cache::load(std::path::Path::new(path), &file_content, macros_hash, &context)
+		} else {
+		    None
+		};
+		if let Some(forms) = cached {
 		    context.push_frame(None);
-		    crate::interpreter::walkthrough::run(file, &mut context, &module_path).expect("run error");
-		    let frame = context.pop_frame().expect("pop error");
-		    let frame = Arc::new(frame);
-		    new_self = Some(RawModule::Loaded {
-			frame,
-		    });
+		    bytecode::run_compiled(&forms, path, &mut context, module_path)?;
+		} else {
+		    let file = crate::parser::parse_with_context(&file_content, path, &mut context.get_macros(), &context).map_err(|err| Box::new(Exception::new(&vec!["import"], &format!("{}", err), &context)))?;
+		    context.push_frame(None);
+		    if context.is_using_vm() {
+			// The cache only has code, a file that defines or redefines macros has to be expanded again to define them
+			let cacheable = {
+			    let after = context.read_macros();
+			    after.len() == macros.len() && after.iter().all(|m| macros.get(m).is_some_and(|before| before.same_definition(m)))
+			};
+			let forms = bytecode::compile_and_run(file, &mut context, module_path)?;
+			if cacheable {
+			    bytecode::cache::store(std::path::Path::new(path), &file_content, macros_hash, &forms);
+			}
+		    } else {
+			for sexpr in file {
+			    walkthrough::walk_through(&sexpr, &mut context, module_path)?;
+			}
+		    }
 		}
+		let frame = context.pop_frame().expect("pop error");
+		let frame = Arc::new(frame);
+		new_self = Some(RawModule::Loaded {
+		    frame,
+		});
 	    }
 	    RawModule::Loaded { .. } => {}
 	}
 	if let Some(new_self) = new_self {
 	    *self.raw_module.borrow_mut() = new_self;
 	}
+	Ok(())
     }
 
-    pub fn get(&self, name: &str, context: &Context) -> Option<Value> {
-	self.load(context);
+    /// Looks up a name in the module, running the module first if it has not been loaded yet
+    pub fn get(&self, name: &str, context: &Context) -> HelperResult<Option<Value>> {
+	self.load(context)?;
 	match &*self.raw_module.borrow() {
 	    RawModule::File(_, _) => unreachable!(),
 	    RawModule::Loaded { frame } => {
-		return frame.get(name).cloned();
+		return Ok(frame.get(name));
 	    }
 	}
     }
@@ -114,3 +144,41 @@ impl Module {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::bytecode::cache::cache_path;
+    use crate::interpreter::walkthrough::tests::{eval_in, new_context};
+
+    /// Runs `source` on the virtual machine in a context of its own, as a new run of lispy would
+    fn run(source: &str) -> String {
+	let mut context = new_context();
+	context.set_vm(true);
+	eval_in(source, &mut context).unwrap()
+    }
+
+    #[test]
+    fn test_module_cache_depends_on_importer_macros() {
+	let directory = std::env::temp_dir().join(format!("lispy-module-test-{}", std::process::id()));
+	std::fs::create_dir_all(&directory).unwrap();
+	let plain = directory.join("plain.lpy");
+	let redefines = directory.join("redefines.lpy");
+	std::fs::write(&plain, "(define two (twice 1))").unwrap();
+	// Same number of macros as before, but one of them means something else
+	std::fs::write(&redefines, "(define-syntax-rule (twice x) (+ x (* 2 x)))\n(define three (twice 1))").unwrap();
+	let imports = format!("(import {:?} 'plain)
+(import {:?} 'redefines)
+(+ (* 10 plain.two) redefines.three)", plain.to_str().unwrap(), redefines.to_str().unwrap());
+
+	let doubled = run(&format!("(define-syntax-rule (twice x) (* 2 x))\n{}", imports));
+	let cached = (cache_path(&plain).exists(), cache_path(&redefines).exists());
+	// The cache of plain was written with the other twice, it must not be used
+	let tripled = run(&format!("(define-syntax-rule (twice x) (* 3 x))\n{}", imports));
+	let doubled_again = run(&format!("(define-syntax-rule (twice x) (* 2 x))\n{}", imports));
+	std::fs::remove_dir_all(&directory).unwrap();
+	assert_eq!(cached, (true, false));
+	assert_eq!(doubled, "23");
+	assert_eq!(tripled, "33");
+	assert_eq!(doubled_again, "23");
+    }
+}
@@ -52,7 +52,7 @@ impl Enum {
 	&self.members
     }
 
-    pub fn create_functions(module_name: &Vec<String>, name: &Vec<String>, variants: &Vec<Vec<String>>, member_names: Vec<Vec<Vec<String>>>, context: &mut Context) {
+    pub fn create_functions(module_name: &Vec<String>, name: &Vec<String>, variants: &Vec<Vec<String>>, member_names: Vec<Vec<Vec<String>>>, context: &mut Context) -> HelperResult<()> {
 	if variants.len() != member_names.len() {
 	    panic!("variants and member_names must have the same length");
 	}
@@ -70,7 +70,7 @@ impl Enum {
 	    constructor_bytecode.push(Bytecode::new(RawBytecode::PushSymbol(type_name.clone()), 0, 0));
 	    constructor_bytecode.push(Bytecode::new(RawBytecode::MakeEnum(member_names.len()), 0, 0));
 	    constructor_bytecode.push(Bytecode::new(RawBytecode::Return, 0, 0));
-	    let constructor = Function::Bytecode(member_names.iter().map(|v| v.join(".")).collect(), constructor_bytecode, constructor_shape);
+	    let constructor = Function::new_bytecode(member_names.iter().map(|v| v.join(".")).collect(), constructor_bytecode, constructor_shape, None, Vec::new(), context)?;
 
 	    let constructor_name = name.last().cloned().unwrap() + "-" + &variant.last().cloned().unwrap();
 	    
@@ -97,7 +97,7 @@ impl Enum {
 		    Bytecode::new(RawBytecode::EnumAccess, 0, 0),
 		    Bytecode::new(RawBytecode::Return, 0, 0),
 		];
-		let accessor = Function::Bytecode(vec![name[0].clone()], accessor_bytecode, accessor_shapes[i].clone());
+		let accessor = Function::new_bytecode(vec![name[0].clone()], accessor_bytecode, accessor_shapes[i].clone(), None, Vec::new(), context)?;
 		context.define(&member[0], Value::new_function(accessor, context));
 	    }
 
@@ -119,10 +119,11 @@ impl Enum {
 		    Bytecode::new(RawBytecode::EnumStore, 0, 0),
 		    Bytecode::new(RawBytecode::Return, 0, 0),
 		];
-		let setter = Function::Bytecode(vec![name[0].clone(), "value".to_string()], setter_bytecode, setter_shapes[i].clone());
+		let setter = Function::new_bytecode(vec![name[0].clone(), "value".to_string()], setter_bytecode, setter_shapes[i].clone(), None, Vec::new(), context)?;
 		context.define(&member[0], Value::new_function(setter, context));
 	    }
 	}
+	Ok(())
     }
 
 }
@@ -5,13 +5,15 @@ use crate::interpreter::{self, HelperResult};
 
 use crate::interpreter::bytecode::Bytecode;
 use crate::interpreter::kwargs::Kwargs;
-use crate::interpreter::{context::{ContextFrame, Context}, Exception, InterpreterResult};
+use crate::interpreter::{context::{CallFrame, Context, Environment}, Exception, InterpreterResult};
 use crate::interpreter::value::Value;
+use crate::interpreter::walkthrough::{Tail, TailResult};
+use crate::parser::Span;
 
 #[repr(C)]
 pub enum CFunctionOutput {
     Value(Value),
-    Exception(Exception),
+    Exception(Box<Exception>),
     Blank,
 }
 
@@ -39,7 +41,7 @@ impl CFunctionOutput {
     pub extern "C" fn set_exception_value(&mut self, exception: *mut Exception) {
 	unsafe {
 	    let exception = Box::from_raw(exception);
-	    *self = CFunctionOutput::Exception(*exception);
+	    *self = CFunctionOutput::Exception(exception);
 	}
     }
 }
@@ -47,254 +49,188 @@ impl CFunctionOutput {
 
 #[derive(Clone)]
 pub enum Function {
-    Tree(Vec<String>, Sexpr, ContextFrame, FunctionShape),
+    Tree(Vec<String>, Sexpr, Environment, FunctionShape),
     Native(fn(&mut Context, Vec<Value>, Kwargs) -> HelperResult<Value>, FunctionShape),
-    Bytecode(Vec<String>, Vec<Bytecode>, FunctionShape),
+    /// Compiled code, closures made by the compiler keep the frames they were made in and the cells they capture
+    Bytecode(Vec<String>, Vec<Bytecode>, FunctionShape, Option<Environment>, Vec<Value>),
     CNative(unsafe extern "C" fn(*mut Context, *mut *mut Value, usize, *mut Kwargs, *mut CFunctionOutput), FunctionShape),
+    /// The continuation of an escape point, calling it returns its argument from that point
+    Continuation(usize),
 }
 
 impl Function {
+    /// Makes a procedure from code that is checked by the verifier first
+    pub fn new_bytecode(args: Vec<String>, code: Vec<Bytecode>, shape: FunctionShape, environment: Option<Environment>, upvalues: Vec<Value>, context: &Context) -> HelperResult<Function> {
+	interpreter::bytecode::verifier::verify(&code, upvalues.len(), context)?;
+	Ok(Function::Bytecode(args, code, shape, environment, upvalues))
+    }
+
     pub fn protect(&self) {
 	match self {
-	    Function::Tree(_, _, frame, _) => {
-		frame.protect();
+	    Function::Tree(_, _, environment, _) => {
+		environment.protect();
+	    },
+	    Function::Bytecode(_, _, _, environment, upvalues) => {
+		if let Some(environment) = environment {
+		    environment.protect();
+		}
+		for upvalue in upvalues {
+		    upvalue.protect();
+		}
 	    },
 	    _ => {},
 	}
     }
 
-    pub fn call_raw(&self, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+    fn kind(&self) -> &'static str {
 	match self {
-	    Function::Tree(fun_args, body, frame, _) => {
-		let frame = frame.clone();
-		context.push_frame(Some(frame));
-		for (arg, value) in fun_args.iter().zip(args.iter()) {
-		    context.define(arg, value.clone());
-		}
-		for (arg, value) in kargs.iter() {
-		    context.define(arg, value.clone());
-		}
-		let new_module_name = module_name.clone().into_iter().rev().skip(1).rev().collect();
-		let value = interpreter::walkthrough::walk_through(&body, context, &new_module_name);
-		context.pop_frame();
-		value
-	    },
-	    Function::Native(f, _) => {
-		Ok(Some(f(context, args, kargs)?))
-	    },
-	    Function::Bytecode(fun_args, bytecode, _) => {
-		let frame = ContextFrame::new();
-		context.push_frame(Some(frame));
-		for (arg, value) in fun_args.iter().zip(args.iter()) {
-		    context.define(arg, value.clone());
-		}
-		for (arg, value) in kargs.iter() {
-		    context.define(arg, value.clone());
-		}
-		let new_module_name = module_name.clone().into_iter().rev().skip(1).rev().collect();
-		let value = interpreter::bytecode::run(&bytecode.as_slice(), context, &new_module_name);
-		context.pop_frame();
-		value
-	    },
-	    Function::CNative(f, _) => {
-		let mut args = args.clone().into_iter().map(|x| Box::into_raw(Box::new(x))).collect::<Vec<*mut Value>>();
-		let mut kargs = kargs;
-		let context = context;
-		let mut output = CFunctionOutput::Blank;
-		unsafe {
-		    f(context, args.as_mut_ptr(), args.len(), &mut kargs, &mut output);
-		};
+	    Function::Tree(..) => "tree",
+	    Function::Native(..) => "native",
+	    Function::Bytecode(..) => "bytecode",
+	    Function::CNative(..) => "c-native",
+	    Function::Continuation(..) => "continuation",
+	}
+    }
 
-		match output {
-		    CFunctionOutput::Value(value) => Ok(Some(value)),
-		    CFunctionOutput::Exception(exception) => Err(Box::new(exception)),
-		    CFunctionOutput::Blank => {
-			let empty: Vec<&str> = Vec::new();
-			Err(Box::new(Exception::new(&empty, "C function didn't return a value", &context)))
-		    },
-		}
+    fn call_frame(&self, name: &[String], module_name: &[String], location: Span) -> CallFrame {
+	let name = if name.is_empty() {
+	    vec!["<procedure>".to_string()]
+	} else {
+	    name.to_vec()
+	};
+	CallFrame::new(name, module_name.to_vec(), self.kind(), location)
+    }
 
-	    },
+    /// Records a call frame for the duration of the call so exceptions can report a backtrace
+    fn with_call_frame(&self, name: &[String], context: &mut Context, module_name: &[String], body: impl FnOnce(&mut Context) -> InterpreterResult) -> InterpreterResult {
+	let frame = self.call_frame(name, module_name, context.get_location().clone());
+	context.push_call(frame);
+	// Errors from a native procedure are signalled here, before the caller is unwound
+	let value = body(context).map_err(|e| interpreter::condition::signal(e, context));
+	context.pop_call();
+	value
+    }
+
+    /// Calls this function with the continuation of the call, the value passed to the continuation is returned if it is invoked
+    pub fn call_with_escape(&self, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+	let id = context.push_escape_point();
+	let continuation = Value::new_function(Function::Continuation(id), context);
+	let value = self.call_raw(vec![continuation], Kwargs::new(), context, module_name);
+	context.pop_escape_point();
+	match value {
+	    Err(e) if e.get_escape() == Some(id) => Ok(e.into_escape_value()),
+	    value => value,
 	}
     }
-    
+
+    /// Unwinds to the escape point `id` with the first argument, the continuation is one-shot so the point must still be active
+    fn escape(id: usize, args: Vec<Value>, kargs: &Kwargs, context: &mut Context) -> HelperResult<Value> {
+	if args.len() > 1 || kargs.len() > 0 {
+	    return Err(Box::new(Exception::new(&vec!["continuation"], "wrong number of arguments", context)));
+	}
+	if !context.is_escape_point_active(id) {
+	    return Err(Box::new(Exception::new(&vec!["continuation"], "continuation is no longer active", context)));
+	}
+	let value = args.into_iter().next().unwrap_or_else(Value::new_nil);
+	Err(Box::new(Exception::new_escape(id, value, context)))
+    }
+
+    pub fn call_raw(&self, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+	self.with_call_frame(&[], context, module_name, |context| self.apply_raw(args, kargs, context, module_name))
+    }
+
     pub fn call(&self, name: &Vec<String>, list: &[Sexpr], context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
-	match self {
-	    Function::Tree(fun_args, body, frame, shape) => {
-		let mut args = Vec::new();
-		let mut keyword_args = Kwargs::new();
-		let mut iterator = list.iter();
-		while let Some(sexpr) = iterator.next() {
-		    match sexpr {
-			Sexpr::Atom(Atom::Keyword(k)) => {
-			    if let Some(value) = iterator.next() {
-				match interpreter::walkthrough::walk_through(value, context, module_name)? {
-				    Some(value) => {
-					keyword_args.insert(k.clone(), value);
-				    }
-				    None => {
-					return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				    }
-				}
-			    } else {
-				return Err(Box::new(Exception::new(&name, "unusual syntax", context)));
-			    }
-			}
-			s => {
-			    match interpreter::walkthrough::walk_through(s, context, module_name)? {
-				Some(value) => {
-				    args.push(value);
-				}
-				None => {
-				    return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				}
-			    }
-			}
-		    }
-		}
-		shape.check(&name, &args, &keyword_args, context)?;
-        
-		context.push_frame(Some(frame.clone()));
+	let (args, keyword_args) = self.evaluate_args(name, list, context, module_name)?;
+	self.call_from_bytecode(name, args, keyword_args, context, module_name)
+    }
 
-		for (arg, value) in fun_args.iter().zip(args.iter()) {
-		    context.define(arg, value.clone());
-		}
-		for (arg, value) in keyword_args.iter() {
-		    context.define(arg, value.clone());
-		}
-		let value = if name.len() == 1 {
-		    interpreter::walkthrough::walk_through(&body, context, module_name)
-		} else {
-		    let new_module_name = name.clone().into_iter().rev().skip(1).rev().collect();
-		    interpreter::walkthrough::walk_through(&body, context, &new_module_name)
-		};
-		context.pop_frame();
-		value
-	    },
-	    Function::Native(f, shape) => {
-		let mut args = Vec::new();
-		let mut keyword_args = Kwargs::new();
-		let mut iterator = list.iter();
-		while let Some(sexpr) = iterator.next() {
-		    match sexpr {
-			Sexpr::Atom(Atom::Keyword(k)) => {
-			    if let Some(value) = iterator.next() {
-				match interpreter::walkthrough::walk_through(value, context, module_name)? {
-				    Some(value) => {
-					keyword_args.insert(k.clone(), value);
-				    }
-				    None => {
-					return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				    }
-				}
-			    } else {
-				return Err(Box::new(Exception::new(&name, "unusual syntax", context)));
+    /// Evaluates the arguments of a call written as `list`, keywords go into the `Kwargs`
+    pub fn evaluate_args(&self, name: &Vec<String>, list: &[Sexpr], context: &mut Context, module_name: &Vec<String>) -> HelperResult<(Vec<Value>, Kwargs)> {
+	let mut args = Vec::new();
+	let mut keyword_args = Kwargs::new();
+	let mut iterator = list.iter();
+	while let Some(sexpr) = iterator.next() {
+	    match sexpr {
+		Sexpr::Atom(Atom::Keyword(k), _) => {
+		    if let Some(value) = iterator.next() {
+			match interpreter::walkthrough::walk_through(value, context, module_name)? {
+			    Some(value) => {
+				keyword_args.insert(k.clone(), value);
 			    }
-			}
-			s => {
-			    match interpreter::walkthrough::walk_through(s, context, module_name)? {
-				Some(value) => {
-				    args.push(value);
-				}
-				None => {
-				    return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				}
+			    None => {
+				return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
 			    }
 			}
+		    } else {
+			return Err(Box::new(Exception::new(&name, "unusual syntax", context)));
 		    }
 		}
-		shape.check(&name, &args, &keyword_args, context)?;
-		Ok(Some(f(context, args, keyword_args)?))
-	    },
-	    Function::Bytecode(fun_args, bytecode, shape) => {
-		let mut args = Vec::new();
-		let mut keyword_args = Kwargs::new();
-		let mut iterator = list.iter();
-		while let Some(sexpr) = iterator.next() {
-		    match sexpr {
-			Sexpr::Atom(Atom::Keyword(k)) => {
-			    if let Some(value) = iterator.next() {
-				match interpreter::walkthrough::walk_through(value, context, module_name)? {
-				    Some(value) => {
-					keyword_args.insert(k.clone(), value);
-				    }
-				    None => {
-					return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				    }
-				}
-			    } else {
-				return Err(Box::new(Exception::new(&name, "unusual syntax", context)));
-			    }
+		s => {
+		    match interpreter::walkthrough::walk_through(s, context, module_name)? {
+			Some(value) => {
+			    args.push(value);
 			}
-			s => {
-			    match interpreter::walkthrough::walk_through(s, context, module_name)? {
-				Some(value) => {
-				    args.push(value);
-				}
-				None => {
-				    return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				}
-			    }
+			None => {
+			    return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
 			}
 		    }
 		}
-		
-		shape.check(&name, &args, &keyword_args, context)?;
+	    }
+	}
+	Ok((args, keyword_args))
+    }
 
-		context.push_frame(None);
+    pub fn call_from_bytecode(&self, name: &Vec<String>, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+	self.with_call_frame(name, context, module_name, |context| {
+	    let location = context.get_location().clone();
+	    let value = self.apply_tail_calls(name, args, kargs, context, module_name);
+	    context.set_location(location);
+	    value
+	})
+    }
 
-		for (arg, value) in fun_args.iter().zip(args.iter()) {
-		    context.define(arg, value.clone());
-		}
-		for (arg, value) in keyword_args.iter() {
-		    context.define(arg, value.clone());
-		}
-		
-		let value = if name.len() == 1 {
-		    interpreter::bytecode::run(&bytecode.as_slice(), context, module_name)
-		} else {
-		    let new_module_name = name.clone().into_iter().rev().skip(1).rev().collect();
-		    interpreter::bytecode::run(&bytecode.as_slice(), context, &new_module_name)
-		};
-        
-		context.pop_frame();
+    /// A call in tail position takes over this call's frame, so recursion in tail position runs in constant space
+    fn apply_tail_calls(&self, name: &Vec<String>, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+	let mut tail = self.apply(name, args, kargs, context, module_name)?;
+	while let Tail::Call(call) = tail {
+	    context.pop_call();
+	    context.push_call(call.function.call_frame(&call.name, &call.module_name, call.location.clone()));
+	    context.set_location(call.location);
+	    tail = call.function.apply(&call.name, call.args, call.kargs, context, &call.module_name)?;
+	}
+	tail.finish(context)
+    }
+
+    /// Procedures passed to the standard library are called without the name they were written under,
+    /// a mismatch in their arguments is reported for `procedure`
+    fn apply_raw(&self, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+	let name = vec!["procedure".to_string()];
+	match self {
+	    Function::Tree(_, body, environment, shape) => {
+		shape.check(&name, &args, &kargs, context)?;
+
+		let frames = context.enter_environment(environment);
+		let new_module_name = module_name.clone().into_iter().rev().skip(1).rev().collect();
+		let value = shape.bind(args, &kargs, context, &new_module_name)
+		    .and_then(|_| interpreter::walkthrough::walk_through(&body, context, &new_module_name));
+		context.leave_environment(frames);
 		value
 	    },
+	    Function::Native(f, shape) => {
+		shape.check(&name, &args, &kargs, context)?;
+		Ok(Some(f(context, args, kargs)?))
+	    },
+	    Function::Bytecode(_, _, shape, _, _) => {
+		shape.check(&name, &args, &kargs, context)?;
+
+		let new_module_name = module_name.clone().into_iter().rev().skip(1).rev().collect();
+		self.run_bytecode(args, kargs, context, &new_module_name)?.finish(context)
+	    },
 	    Function::CNative(f, shape) => {
-		let mut args = Vec::new();
-		let mut keyword_args = Kwargs::new();
-		let mut iterator = list.iter();
-		while let Some(sexpr) = iterator.next() {
-		    match sexpr {
-			Sexpr::Atom(Atom::Keyword(k)) => {
-			    if let Some(value) = iterator.next() {
-				match interpreter::walkthrough::walk_through(value, context, module_name)? {
-				    Some(value) => {
-					keyword_args.insert(k.clone(), value);
-				    }
-				    None => {
-					return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				    }
-				}
-			    } else {
-				return Err(Box::new(Exception::new(&name, "unusual syntax", context)));
-			    }
-			}
-			s => {
-			    match interpreter::walkthrough::walk_through(s, context, module_name)? {
-				Some(value) => {
-				    args.push(value);
-				}
-				None => {
-				    return Err(Box::new(Exception::new(&name, "expression didn't result in a value", context)));
-				}
-			    }
-			}
-		    }
-		}
-		shape.check(&name, &args, &keyword_args, context)?;
+		shape.check(&name, &args, &kargs, context)?;
+
 		let mut args = args.clone().into_iter().map(|x| Box::into_raw(Box::new(x))).collect::<Vec<*mut Value>>();
-		let mut kargs = keyword_args;
+		let mut kargs = kargs;
 		let context = context;
 		let mut output = CFunctionOutput::Blank;
 		unsafe {
@@ -303,63 +239,73 @@ impl Function {
 
 		match output {
 		    CFunctionOutput::Value(value) => Ok(Some(value)),
-		    CFunctionOutput::Exception(exception) => Err(Box::new(exception)),
+		    CFunctionOutput::Exception(exception) => Err(exception),
 		    CFunctionOutput::Blank => {
 			let empty: Vec<&str> = Vec::new();
 			Err(Box::new(Exception::new(&empty, "C function didn't return a value", &context)))
 		    },
 		}
+
+	    },
+	    Function::Continuation(id) => {
+		Ok(Some(Function::escape(*id, args, &kargs, context)?))
+	    },
+	}
+    }
+    
+    /// Binds the arguments of compiled code in the frames it closed over, or in a frame of its own, and runs it
+    fn run_bytecode(&self, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+	let Function::Bytecode(_, bytecode, shape, environment, upvalues) = self else {
+	    unreachable!("only compiled procedures run bytecode")
+	};
+	let frames = match environment {
+	    Some(environment) => Some(context.enter_environment(environment)),
+	    None => {
+		context.push_frame(None);
+		None
+	    },
+	};
+	let value = shape.bind(args, &kargs, context, module_name)
+	    .and_then(|_| interpreter::bytecode::run_tail(bytecode.as_slice(), upvalues, context, module_name));
+	match frames {
+	    Some(frames) => context.leave_environment(frames),
+	    None => {
+		context.pop_frame();
 	    },
 	}
+	value
     }
 
-    pub fn call_from_bytecode(&self, name: &Vec<String>, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+    fn apply(&self, name: &Vec<String>, args: Vec<Value>, kargs: Kwargs, context: &mut Context, module_name: &Vec<String>) -> TailResult {
 	match self {
-	    Function::Tree(fun_args, body, frame, shape) => {
-		context.push_frame(Some(frame.clone()));
+	    Function::Tree(_, body, environment, shape) => {
+		shape.check(&name, &args, &kargs, context)?;
 
-		for (arg, value) in fun_args.iter().zip(args.iter()) {
-		    context.define(arg, value.clone());
-		}
-		for (arg, value) in kargs.iter() {
-		    context.define(arg, value.clone());
-		}
+		let frames = context.enter_environment(environment);
 
-		shape.check(&name, &args, &kargs, context)?;
-        
-		let value = if name.len() == 1 {
-		    interpreter::walkthrough::walk_through(&body, context, module_name)
+		let new_module_name = if name.len() == 1 {
+		    module_name.clone()
 		} else {
-		    let new_module_name = name.clone().into_iter().rev().skip(1).rev().collect();
-		    interpreter::walkthrough::walk_through(&body, context, &new_module_name)
+		    name.clone().into_iter().rev().skip(1).rev().collect()
 		};
-		context.pop_frame();
+		let value = shape.bind(args, &kargs, context, &new_module_name)
+		    .and_then(|_| interpreter::walkthrough::walk_through_tail(&body, context, &new_module_name));
+		context.leave_environment(frames);
 		value
 	    },
 	    Function::Native(f, shape) => {
 		shape.check(&name, &args, &kargs, context)?;
-		Ok(Some(f(context, args, kargs)?))
+		Ok(Tail::Done(Some(f(context, args, kargs)?)))
 	    }
-	    Function::Bytecode(fun_args, bytecode, shape) => {
+	    Function::Bytecode(_, _, shape, _, _) => {
 		shape.check(&name, &args, &kargs, context)?;
 
-		context.push_frame(None);
-
-		for (arg, value) in fun_args.iter().zip(args.iter()) {
-		    context.define(arg, value.clone());
-		}
-		for (arg, value) in kargs.iter() {
-		    context.define(arg, value.clone());
-		}
-
-		let value = if name.len() == 1 {
-		    interpreter::bytecode::run(&bytecode.as_slice(), context, module_name)
+		let new_module_name = if name.len() == 1 {
+		    module_name.clone()
 		} else {
-		    let new_module_name = name.clone().into_iter().rev().skip(1).rev().collect();
-		    interpreter::bytecode::run(&bytecode.as_slice(), context, &new_module_name)
+		    name.clone().into_iter().rev().skip(1).rev().collect()
 		};
-		context.pop_frame();
-		value
+		self.run_bytecode(args, kargs, context, &new_module_name)
 	    },
 	    Function::CNative(f, shape) => {
 		shape.check(&name, &args, &kargs, context)?;
@@ -373,14 +319,17 @@ impl Function {
 		};
 
 		match output {
-		    CFunctionOutput::Value(value) => Ok(Some(value)),
-		    CFunctionOutput::Exception(exception) => Err(Box::new(exception)),
+		    CFunctionOutput::Value(value) => Ok(Tail::Done(Some(value))),
+		    CFunctionOutput::Exception(exception) => Err(exception),
 		    CFunctionOutput::Blank => {
 			let empty: Vec<&str> = Vec::new();
 			Err(Box::new(Exception::new(&empty, "C function didn't return a value", &context)))
 		    },
 		}
 	    }
+	    Function::Continuation(id) => {
+		Ok(Tail::Done(Some(Function::escape(*id, args, &kargs, context)?)))
+	    }
 	}
     }
 
@@ -409,7 +358,7 @@ impl Function {
 	    Err(err) => {
 		unsafe {
 		    println!("Error: {:?}", err);
-		    *output = CFunctionOutput::Exception(*err);
+		    *output = CFunctionOutput::Exception(err);
 		}
 	    },
 	    Ok(function) => {
@@ -417,7 +366,7 @@ impl Function {
 		    Err(err) => {
 			unsafe {
 			    println!("Error: {:?}", err);
-			    *output = CFunctionOutput::Exception(*err);
+			    *output = CFunctionOutput::Exception(err);
 			}
 		    },
 		    Ok(Some(value)) => {
@@ -429,7 +378,7 @@ impl Function {
 			unsafe {
 			    let empty: Vec<&str> = Vec::new();
 			    println!("Error: Function didn't result into a value");
-			    *output = CFunctionOutput::Exception(Exception::new(&empty, "Function didn't result into a value", context));
+			    *output = CFunctionOutput::Exception(Box::new(Exception::new(&empty, "Function didn't result into a value", context)));
 			}
 		    }
 		}
@@ -458,36 +407,169 @@ impl Function {
     }
 }*/
 
-#[derive(Clone)]
+/// A parameter that may be left out of a call, `default` is evaluated in the callee when it is
+#[derive(Debug, PartialEq, Clone)]
+pub struct Parameter {
+    name: String,
+    default: Option<Sexpr>,
+}
+
+impl Parameter {
+    pub fn new(name: String, default: Option<Sexpr>) -> Self {
+	Parameter {
+	    name,
+	    default,
+	}
+    }
+
+    pub fn get_name(&self) -> &str {
+	&self.name
+    }
+
+    pub fn get_default(&self) -> Option<&Sexpr> {
+	self.default.as_ref()
+    }
+}
+
+impl std::fmt::Display for Parameter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match &self.default {
+	    Some(default) => write!(f, "[{} {}]", self.name, default),
+	    None => write!(f, "{}", self.name),
+	}
+    }
+}
+
+/// The parameters of a function: required ones, then optional ones, keyword-only ones and a rest list.
+/// Required and optional parameters can also be passed by keyword.
+#[derive(Debug, PartialEq, Clone)]
 pub struct FunctionShape {
     args: Box<Vec<String>>,
+    optional: Vec<Parameter>,
+    keywords: Vec<Parameter>,
+    rest: Option<String>,
 }
 
 impl FunctionShape {
     pub fn new(args: Vec<String>) -> Self {
 	FunctionShape {
 	    args: Box::new(args),
+	    optional: Vec::new(),
+	    keywords: Vec::new(),
+	    rest: None,
+	}
+    }
+
+    pub fn with_optional(mut self, optional: Vec<Parameter>) -> Self {
+	self.optional = optional;
+	self
+    }
+
+    pub fn with_keywords(mut self, keywords: Vec<Parameter>) -> Self {
+	self.keywords = keywords;
+	self
+    }
+
+    pub fn with_rest(mut self, rest: Option<String>) -> Self {
+	self.rest = rest;
+	self
+    }
+
+    pub fn get_args(&self) -> &[String] {
+	&self.args
+    }
+
+    pub fn get_optional(&self) -> &[Parameter] {
+	&self.optional
+    }
+
+    pub fn get_keywords(&self) -> &[Parameter] {
+	&self.keywords
+    }
+
+    pub fn get_rest(&self) -> Option<&String> {
+	self.rest.as_ref()
+    }
+
+    /// The names `bind` defines, in the order it defines them
+    pub fn get_names(&self) -> Vec<String> {
+	self.args.iter().cloned()
+	    .chain(self.optional.iter().map(|p| p.name.clone()))
+	    .chain(self.keywords.iter().map(|p| p.name.clone()))
+	    .chain(self.rest.iter().cloned())
+	    .collect()
+    }
+
+    /// Whether `keyword` names a parameter that wasn't already filled by one of `positional` arguments
+    fn accepts_keyword(&self, keyword: &str, positional: usize) -> bool {
+	let position = self.args.iter()
+	    .chain(self.optional.iter().map(|p| &p.name))
+	    .position(|name| name == keyword);
+	match position {
+	    Some(i) => i >= positional,
+	    None => self.keywords.iter().any(|p| p.name == keyword),
 	}
     }
 
-    pub fn check(&self, name: &Vec<String>, args: &Vec<Value>, keyword_args: &Kwargs, context: &mut Context) -> HelperResult<()> {
-	if self.args.len() != args.len() + keyword_args.len() {
+    pub fn check(&self, name: &Vec<String>, args: &[Value], keyword_args: &Kwargs, context: &mut Context) -> HelperResult<()> {
+	if self.rest.is_none() && args.len() > self.args.len() + self.optional.len() {
 	    Err(Box::new(Exception::new(name, "wrong number of arguments", context)))?;
 	}
 
-	for (i, arg) in self.args.iter().enumerate() {
-	    if i < args.len() {
-		continue;
-	    } else {
-		if !keyword_args.contains_key(arg) {
-		    Err(Box::new(Exception::new(name, "invalid keyword", context)))?;
-		}
+	for (key, _) in keyword_args.iter() {
+	    if !self.accepts_keyword(key, args.len()) {
+		Err(Box::new(Exception::new(name, "invalid keyword", context)))?;
+	    }
+	}
+
+	for arg in self.args.iter().skip(args.len()) {
+	    if !keyword_args.contains_key(arg) {
+		Err(Box::new(Exception::new(name, "wrong number of arguments", context)))?;
 	    }
 	}
 
 	Ok(())
     }
 
+    /// Defines the parameters in the innermost frame, evaluating the defaults of those left out in order
+    pub fn bind(&self, args: Vec<Value>, kargs: &Kwargs, context: &mut Context, module_name: &Vec<String>) -> HelperResult<()> {
+	let mut args = args.into_iter();
+	for arg in self.args.iter() {
+	    if let Some(value) = args.next().or_else(|| kargs.get(arg).cloned()) {
+		context.define(arg, value);
+	    }
+	}
+	for parameter in self.optional.iter() {
+	    let value = match args.next() {
+		Some(value) => value,
+		None => self.default(parameter, kargs, context, module_name)?,
+	    };
+	    context.define(&parameter.name, value);
+	}
+	for parameter in self.keywords.iter() {
+	    let value = self.default(parameter, kargs, context, module_name)?;
+	    context.define(&parameter.name, value);
+	}
+	if let Some(rest) = &self.rest {
+	    let rest_list = Value::new_list(args.collect(), context);
+	    context.define(rest, rest_list);
+	}
+	Ok(())
+    }
+
+    fn default(&self, parameter: &Parameter, kargs: &Kwargs, context: &mut Context, module_name: &Vec<String>) -> HelperResult<Value> {
+	if let Some(value) = kargs.get(&parameter.name) {
+	    return Ok(value.clone());
+	}
+	let Some(default) = &parameter.default else {
+	    return Ok(Value::new_nil());
+	};
+	match interpreter::walkthrough::walk_through(default, context, module_name)? {
+	    Some(value) => Ok(value),
+	    None => Err(Box::new(Exception::new(&vec![parameter.name.as_str()], "default didn't result in a value", context))),
+	}
+    }
+
     #[no_mangle]
     pub extern "C" fn new_function_shape(args: *mut *mut c_char, len: usize, str_lens: *mut usize) -> *mut FunctionShape {
 	let mut arg_vec = Vec::new();
@@ -495,21 +577,76 @@ impl FunctionShape {
 	    for i in 0..len {
 		let arg = *args.offset(i as isize);
 		let len = *str_lens.offset(i as isize);
-		let mut buf = Vec::new();
-		for j in 0..len {
-		    buf.push(u8::from_ne_bytes((*arg.offset(j as isize)).to_ne_bytes()));//todo convert into byte then get u8
-		}
-		let string = std::str::from_utf8(&buf).expect("not a valid utf8 string");
-		arg_vec.push(string.to_string());
+		arg_vec.push(c_name(arg, len));
 	    }
 	}
 	let shape = FunctionShape::new(arg_vec);
 	Box::into_raw(Box::new(shape))
     }
+
+    /// Adds an optional parameter, the C function sees only the arguments actually passed
+    #[no_mangle]
+    pub extern "C" fn function_shape_add_optional(&mut self, name: *mut c_char, len: usize) {
+	self.optional.push(Parameter::new(c_name(name, len), None));
+    }
+
+    /// Adds a keyword-only parameter, the C function finds it in the kwargs when it was passed
+    #[no_mangle]
+    pub extern "C" fn function_shape_add_keyword(&mut self, name: *mut c_char, len: usize) {
+	self.keywords.push(Parameter::new(c_name(name, len), None));
+    }
+
+    /// Lets the function take any number of extra positional arguments
+    #[no_mangle]
+    pub extern "C" fn function_shape_set_rest(&mut self, name: *mut c_char, len: usize) {
+	self.rest = Some(c_name(name, len));
+    }
+}
+
+fn c_name(name: *mut c_char, len: usize) -> String {
+    let mut buf = Vec::new();
+    for j in 0..len {
+	buf.push(u8::from_ne_bytes(unsafe { *name.add(j) }.to_ne_bytes()));//todo convert into byte then get u8
+    }
+    std::str::from_utf8(&buf).expect("not a valid utf8 string").to_string()
 }
 
 impl std::fmt::Display for FunctionShape {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-	write!(f, "({})", self.args.join(" "))
+	let mut parameters = self.args.to_vec();
+	parameters.extend(self.optional.iter().map(|p| p.to_string()));
+	if !self.keywords.is_empty() {
+	    parameters.push("#:key".to_string());
+	    parameters.extend(self.keywords.iter().map(|p| p.to_string()));
+	}
+	if let Some(rest) = &self.rest {
+	    parameters.push("#:rest".to_string());
+	    parameters.push(rest.clone());
+	}
+	write!(f, "({})", parameters.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::kwargs::Kwargs;
+    use crate::interpreter::walkthrough::tests::{eval, eval_in, new_context};
+    use crate::parser::Span;
+
+    #[test]
+    fn test_tail_calls_restore_the_location() {
+	let mut context = new_context();
+	eval_in("(define (g) 1)\n(define (f) (g))", &mut context).unwrap();
+	let f = context.get(vec!["f".to_string()]).unwrap().expect("f is not defined");
+	context.set_location(Span::new(None, 7, 3));
+	f.get_function(&context).unwrap().call_from_bytecode(&vec!["f".to_string()], Vec::new(), Kwargs::new(), &mut context, &vec![]).unwrap();
+	assert_eq!((context.get_location().line, context.get_location().column), (7, 3));
+    }
+
+    #[test]
+    fn test_deep_self_recursion() {
+	let source = "(define (count n) (if (= n 0) 'done (count (- n 1))))
+(count 1000000)";
+	assert_eq!(eval(source).unwrap(), "'done");
     }
 }
@@ -1,6 +1,6 @@
 use std::{any::Any, ffi::{CString, c_char, c_void}};
 use rug::Integer;
-use crate::parser::Sexpr;
+use crate::parser::{Sexpr, Atom, Span};
 use crate::interpreter::HelperResult;
 use crate::interpreter::value::r#struct::Struct;
 use crate::interpreter::value::r#enum::Enum;
@@ -8,7 +8,7 @@ use crate::interpreter::value::function::{Function, FunctionShape, CFunctionOutp
 use crate::interpreter::kwargs::Kwargs;
 
 
-use crate::gc::Gc;
+use crate::gc::{Gc, Mark};
 
 use super::{context::Context, Exception};
 
@@ -230,6 +230,110 @@ impl Value {
 	    raw: RawValue::Gc(gc_object),
 	}
     }
+    pub fn get_sexpr(&self, context: &Context) -> HelperResult<&Sexpr> {
+	let empty: Vec<&str> = Vec::new();
+	match self.raw {
+	    RawValue::Gc(ref gc) => {
+		match gc.get() {
+		    GcValue::Sexpr(ref s) => Ok(s),
+		    _ => Err(Box::new(Exception::new(&empty, "not a sexpr", context))),
+		}
+	    },
+	    _ => Err(Box::new(Exception::new(&empty, "not a sexpr", context))),
+	}
+    }
+    pub fn is_sexpr(&self) -> bool {
+	match self.raw {
+	    RawValue::Gc(ref gc) => matches!(gc.get(), GcValue::Sexpr(_)),
+	    _ => false,
+	}
+    }
+
+    /// Turns a form into the data it stands for when quoted
+    pub fn from_sexpr(sexpr: &Sexpr, context: &Context) -> Self {
+	let tagged = |tag: &str, value: Value| {
+	    let tag = Value::new_symbol(vec![tag.to_string()], context);
+	    Value::new_list(vec![tag, value], context)
+	};
+	match sexpr {
+	    Sexpr::Atom(atom, _) => match atom {
+		Atom::String(s) => Value::new_string(s, context),
+		Atom::Integer(i) => Value::new_integer(i),
+		Atom::Float(f) => Value::new_float(*f),
+		Atom::Boolean(b) => Value::new_boolean(*b),
+		Atom::Symbol(s) => Value::new_symbol(s.clone(), context),
+		Atom::QuotedSymbol(s) => tagged("quote", Value::new_symbol(s.clone(), context)),
+		Atom::Keyword(k) => Value::new_symbol(vec![format!(":{}", k)], context),
+		Atom::Char(c) => Value::new_char(*c),
+		Atom::Null => Value::new_nil(),
+		Atom::Placeholder => Value::new_symbol(vec!["...".to_string()], context),
+	    },
+	    Sexpr::List(list, _) => Value::new_list(list.iter().map(|s| Value::from_sexpr(s, context)).collect(), context),
+	    Sexpr::QuotedList(list, _) => {
+		let list = Value::new_list(list.iter().map(|s| Value::from_sexpr(s, context)).collect(), context);
+		tagged("quote", list)
+	    },
+	    Sexpr::VectorList(list, _) => Value::new_vector(list.iter().map(|s| Value::from_sexpr(s, context)).collect(), context),
+	    Sexpr::Quasiquote(sexpr, _) => tagged("quasiquote", Value::from_sexpr(sexpr, context)),
+	    Sexpr::Unquote(sexpr, _) => tagged("unquote", Value::from_sexpr(sexpr, context)),
+	    Sexpr::UnquoteSplicing(sexpr, _) => tagged("unquote-splicing", Value::from_sexpr(sexpr, context)),
+	}
+    }
+
+    /// Turns data back into the form it stands for, every node gets `span`
+    pub fn to_sexpr(&self, span: &Span, context: &Context) -> HelperResult<Sexpr> {
+	let atom = |atom: Atom| Ok(Sexpr::Atom(atom, span.clone()));
+	match &self.raw {
+	    RawValue::Integer(i) => atom(Atom::Integer(i.to_string())),
+	    RawValue::Float(f) => atom(Atom::Float(*f)),
+	    RawValue::Boolean(b) => atom(Atom::Boolean(*b)),
+	    RawValue::Nil => atom(Atom::Null),
+	    RawValue::Char(c) => atom(Atom::Char(*c)),
+	    RawValue::Gc(gc) => match gc.get() {
+		GcValue::Sexpr(s) => Ok(s.clone()),
+		GcValue::String(s) => atom(Atom::String(s.clone())),
+		GcValue::Symbol(s) if s.len() == 1 && s[0] == "..." => atom(Atom::Placeholder),
+		GcValue::Symbol(s) if s.len() == 1 && s[0].len() > 1 && s[0].starts_with(':') => atom(Atom::Keyword(s[0][1..].to_string())),
+		GcValue::Symbol(s) => atom(Atom::Symbol(s.clone())),
+		GcValue::Vector(v) => {
+		    let list = v.iter().map(|v| v.to_sexpr(span, context)).collect::<HelperResult<Vec<Sexpr>>>()?;
+		    Ok(Sexpr::VectorList(list, span.clone()))
+		},
+		GcValue::Pair(_) => {
+		    let mut list = Vec::new();
+		    let mut current = self;
+		    while current.is_pair() {
+			let (car, cdr) = current.get_pair(context)?;
+			list.push(car.to_sexpr(span, context)?);
+			current = cdr;
+		    }
+		    if !current.is_nil() {
+			return Err(Box::new(Exception::new(&vec!["to-sexpr"], "improper list", context)));
+		    }
+		    let tag = match list.as_slice() {
+			[Sexpr::Atom(Atom::Symbol(tag), _), _] if tag.len() == 1 => tag[0].clone(),
+			_ => String::new(),
+		    };
+		    match tag.as_str() {
+			"quote" => match list.pop().unwrap() {
+			    Sexpr::Atom(Atom::Symbol(s), span) => Ok(Sexpr::Atom(Atom::QuotedSymbol(s), span)),
+			    Sexpr::List(l, span) => Ok(Sexpr::QuotedList(l, span)),
+			    Sexpr::Atom(Atom::Null, span) => Ok(Sexpr::QuotedList(Vec::new(), span)),
+			    quoted => {
+				list.push(quoted);
+				Ok(Sexpr::List(list, span.clone()))
+			    },
+			},
+			"quasiquote" => Ok(Sexpr::Quasiquote(Box::new(list.pop().unwrap()), span.clone())),
+			"unquote" => Ok(Sexpr::Unquote(Box::new(list.pop().unwrap()), span.clone())),
+			"unquote-splicing" => Ok(Sexpr::UnquoteSplicing(Box::new(list.pop().unwrap()), span.clone())),
+			_ => Ok(Sexpr::List(list, span.clone())),
+		    }
+		},
+		_ => Err(Box::new(Exception::new(&vec!["to-sexpr"], "value cannot be turned into code", context))),
+	    },
+	}
+    }
 
     pub fn new_function(value: Function, context: &Context) -> Self {
 	let gc_object = Gc::new(GcValue::Function(value));
@@ -307,6 +411,15 @@ impl Value {
 	}
     }
 
+    /// Builds a proper list, an empty list is nil
+    pub fn new_list(values: Vec<Value>, context: &Context) -> Self {
+	let mut out = Value::new_nil();
+	for value in values.into_iter().rev() {
+	    out = Value::new_pair(value, out, context);
+	}
+	out
+    }
+
     pub fn new_pair(car: Value, cdr: Value, context: &Context) -> Self {
 	let gc_object = Gc::new(GcValue::Pair((Box::new(car), Box::new(cdr))));
 	context.send_gc(gc_object.clone());
@@ -544,6 +657,10 @@ impl Value {
     pub fn mark(&self) {
 	match self.raw {
 	    RawValue::Gc(ref gc) => {
+		// Already visited, closures and the frames they capture can refer to each other
+		if gc.marked() != Mark::White {
+		    return;
+		}
 		gc.mark();
 		match gc.get() {
 		    GcValue::Vector(ref list) => {
@@ -553,8 +670,16 @@ impl Value {
 		    },
 		    GcValue::Function(ref f) => {
 			match f {
-			    Function::Tree(_, _, frame, _) => {
-				frame.mark();
+			    Function::Tree(_, _, environment, _) => {
+				environment.mark();
+			    }
+			    Function::Bytecode(_, _, _, environment, upvalues) => {
+				if let Some(environment) = environment {
+				    environment.mark();
+				}
+				for upvalue in upvalues {
+				    upvalue.mark();
+				}
 			    }
 			    _ => {}
 			}
@@ -574,6 +699,9 @@ impl Value {
     pub fn unmark(&self) {
 	match self.raw {
 	    RawValue::Gc(ref gc) => {
+		if gc.marked() == Mark::White {
+		    return;
+		}
 		gc.unmark();
 		match gc.get() {
 		    GcValue::Vector(ref list) => {
@@ -583,8 +711,16 @@ impl Value {
 		    },
 		    GcValue::Function(ref f) => {
 			match f {
-			    Function::Tree(_, _, frame, _) => {
-				frame.unmark();
+			    Function::Tree(_, _, environment, _) => {
+				environment.unmark();
+			    }
+			    Function::Bytecode(_, _, _, environment, upvalues) => {
+				if let Some(environment) = environment {
+				    environment.unmark();
+				}
+				for upvalue in upvalues {
+				    upvalue.unmark();
+				}
 			    }
 			    _ => {},
 			}
@@ -1046,15 +1182,22 @@ impl std::fmt::Display for GcValue {
 		write!(f, "<procedure>")
 	    },
 	    GcValue::Pair((car, cdr)) => {
-		write!(f, "'(")?;
-		let mut current = Some(car.as_ref());
-		while let Some(car) = current {
-		    write!(f, "{}", car)?;
-		    if cdr.is_pair() {
-			current = Some(cdr.get_pair(&mut Context::default()).expect("cdr is not a pair").0);
-		    } else {
-			write!(f, " {}", cdr)?;
+		write!(f, "'({}", car)?;
+		let mut cdr = cdr.as_ref();
+		loop {
+		    match &cdr.raw {
+			RawValue::Nil => break,
+			RawValue::Gc(gc) => {
+			    if let GcValue::Pair((car, next)) = gc.get() {
+				write!(f, " {}", car)?;
+				cdr = next;
+				continue;
+			    }
+			},
+			_ => {},
 		    }
+		    write!(f, " . {}", cdr)?;
+		    break;
 		}
 		write!(f, ")")
 	    },
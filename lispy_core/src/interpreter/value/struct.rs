@@ -43,7 +43,11 @@ impl Struct {
 	self.name_index
     }
 
-    pub fn create_functions(module_name: &Vec<String>, name: &Vec<String>, member_names: Vec<Vec<String>>, context: &mut Context) {
+    pub fn get_members(&self) -> &[Value] {
+	&self.members
+    }
+
+    pub fn create_functions(module_name: &Vec<String>, name: &Vec<String>, member_names: Vec<Vec<String>>, context: &mut Context) -> HelperResult<()> {
 	let type_name = module_name.iter().chain(name.iter()).map(|s| s.clone()).collect();
 	context.get_or_create_type_symbol(&type_name);
 	let constructor_shape = FunctionShape::new(member_names.iter().map(|v| v.join(".")).collect());
@@ -54,7 +58,7 @@ impl Struct {
 	constructor_bytecode.push(Bytecode::new(RawBytecode::PushSymbol(type_name.clone()), 0, 0));
 	constructor_bytecode.push(Bytecode::new(RawBytecode::MakeStruct(member_names.len()), 0, 0));
 	constructor_bytecode.push(Bytecode::new(RawBytecode::Return, 0, 0));
-	let constructor = Function::Bytecode(member_names.iter().map(|v| v.join(".")).collect(), constructor_bytecode, constructor_shape);
+	let constructor = Function::new_bytecode(member_names.iter().map(|v| v.join(".")).collect(), constructor_bytecode, constructor_shape, None, Vec::new(), context)?;
 	context.define(&name[0], Value::new_function(constructor, context));
 
 	let mut accessor_shapes = Vec::new();
@@ -73,7 +77,7 @@ impl Struct {
 		Bytecode::new(RawBytecode::StructAccess, 0, 0),
 		Bytecode::new(RawBytecode::Return, 0, 0),
 	    ];
-	    let accessor = Function::Bytecode(vec![name[0].clone()], accessor_bytecode, accessor_shapes[i].clone());
+	    let accessor = Function::new_bytecode(vec![name[0].clone()], accessor_bytecode, accessor_shapes[i].clone(), None, Vec::new(), context)?;
 	    context.define(&member[0], Value::new_function(accessor, context));
 	}
 
@@ -95,8 +99,9 @@ impl Struct {
 		Bytecode::new(RawBytecode::StructStore, 0, 0),
 		Bytecode::new(RawBytecode::Return, 0, 0),
 	    ];
-	    let setter = Function::Bytecode(vec![name[0].clone(), "value".to_string()], setter_bytecode, setter_shapes[i].clone());
+	    let setter = Function::new_bytecode(vec![name[0].clone(), "value".to_string()], setter_bytecode, setter_shapes[i].clone(), None, Vec::new(), context)?;
 	    context.define(&member[0], Value::new_function(setter, context));
 	} 
+	Ok(())
     }
 }
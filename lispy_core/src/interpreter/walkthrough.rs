@@ -1,20 +1,17 @@
 use crate::parser::{File, Sexpr, Atom};
 use super::Exception;
+use super::condition::{self, ConditionMatcher, HandlerAction, HandlerCluster};
 use super::context::Context;
 use super::module::Module;
-use super::value::{Value, function::{Function, FunctionShape}, r#struct::Struct, r#enum::Enum};
-use super::InterpreterResult;
-
-pub fn run(file: File, context: &mut Context, module_name: &Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+use super::value::{Value, function::{Function, FunctionShape, Parameter}, r#struct::Struct, r#enum::Enum};
+use super::kwargs::Kwargs;
+use super::{InterpreterResult, HelperResult};
+use crate::parser::Span;
 
+/// Runs each top level form of a file, stopping at the first uncaught exception
+pub fn run(file: File, context: &mut Context, module_name: &Vec<String>) -> HelperResult<()> {
     for sexpr in file {
-	match walk_through(&sexpr, context, module_name) {
-	    Err(e) => {
-		println!("{}", e);
-		break;
-	    }
-	    _ => {},
-	}
+	walk_through(&sexpr, context, module_name)?;
     }
     Ok(())
 }
@@ -22,12 +19,64 @@ pub fn run(file: File, context: &mut Context, module_name: &Vec<String>) -> Resu
 
 
 
+/// What is left of a form evaluated in tail position
+pub enum Tail {
+    Done(Option<Value>),
+    /// A call whose arguments are evaluated, the caller makes it once its own frames are gone
+    Call(Box<TailCall>),
+}
+
+pub struct TailCall {
+    pub function: Function,
+    pub name: Vec<String>,
+    pub args: Vec<Value>,
+    pub kargs: Kwargs,
+    pub module_name: Vec<String>,
+    pub location: Span,
+}
+
+impl Tail {
+    /// Makes the pending call, if any
+    pub fn finish(self, context: &mut Context) -> InterpreterResult {
+	match self {
+	    Tail::Done(value) => Ok(value),
+	    Tail::Call(call) => call.function.call_from_bytecode(&call.name, call.args, call.kargs, context, &call.module_name),
+	}
+    }
+}
+
+pub type TailResult = HelperResult<Tail>;
+
 pub fn walk_through(sexpr: &Sexpr, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     if crate::gc::is_gc_on() {
 	context.garbage_collect();
     }
-    match sexpr {
-        Sexpr::Atom(atom) => {
+    if !sexpr.span().is_known() {
+	return walk_through_located(sexpr, context, module_name)?.finish(context);
+    }
+    let previous = context.set_location(sexpr.span().clone());
+    let out = walk_through_located(sexpr, context, module_name).and_then(|tail| tail.finish(context));
+    context.set_location(previous);
+    out
+}
+
+/// Evaluates a form in tail position, a call there is left to the caller so that it does not grow the stack
+pub fn walk_through_tail(sexpr: &Sexpr, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    if crate::gc::is_gc_on() {
+	context.garbage_collect();
+    }
+    if !sexpr.span().is_known() {
+	return walk_through_located(sexpr, context, module_name);
+    }
+    let previous = context.set_location(sexpr.span().clone());
+    let out = walk_through_located(sexpr, context, module_name);
+    context.set_location(previous);
+    out
+}
+
+fn walk_through_located(sexpr: &Sexpr, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    let value = match sexpr {
+        Sexpr::Atom(atom, _) => {
             match atom {
                 Atom::String(s) => {
 		    let string = Value::new_string(s, context);
@@ -44,7 +93,7 @@ pub fn walk_through(sexpr: &Sexpr, context: &mut Context, module_name: &Vec<Stri
                 }
                 Atom::Symbol(s) => {
 		    let path = module_name.iter().chain(s.iter()).map(|s| s.clone()).collect();
-                    match context.get(path) {
+                    match context.get(path)? {
                         Some(value) => Ok(Some(value.clone())),
 			None => Err(Box::new(Exception::new(&module_name.iter().chain(s.iter()).map(|s| s.clone()).collect(), "not bound", context))),
                     }
@@ -68,26 +117,10 @@ pub fn walk_through(sexpr: &Sexpr, context: &mut Context, module_name: &Vec<Stri
 		}
             }
         },
-        Sexpr::QuotedList(list) => {
-            let mut output = Vec::new();
-            for sexpr in list {
-            match walk_through(sexpr, context, module_name)? {
-                Some(value) => {
-                    output.push(value);
-                }
-                None => {
-                    let empty: Vec<&str> = Vec::new();
-                    return Err(Box::new(Exception::new(&empty, "expression didn't result in a value", context)));
-                }
-            }
-            }
-            let mut pair = Value::new_pair(output.pop().unwrap(), Value::new_nil(), context);
-            for value in output.iter().rev() {
-                pair = Value::new_pair(value.clone(), pair, context);
-            }
-            Ok(Some(pair))
+        Sexpr::QuotedList(list, _) => {
+            Ok(Some(Value::new_list(list.iter().map(|sexpr| Value::from_sexpr(sexpr, context)).collect(), context)))
         },
-        Sexpr::VectorList(list) => {
+        Sexpr::VectorList(list, _) => {
             let mut output = Vec::new();
             for sexpr in list {
                 match walk_through(sexpr, context, module_name)? {
@@ -102,46 +135,59 @@ pub fn walk_through(sexpr: &Sexpr, context: &mut Context, module_name: &Vec<Stri
             }
             Ok(Some(Value::new_vector(output, context)))
         }
-        Sexpr::List(list) => {
-            walk_through_list(list, context, module_name)
+        Sexpr::List(list, _) => {
+            return walk_through_list(list, context, module_name);
         }
-    }
+	Sexpr::Quasiquote(template, _) => {
+	    Ok(Some(walk_through_quasiquote(template, 1, context, module_name)?))
+	}
+	Sexpr::Unquote(_, _) => {
+	    Err(Box::new(Exception::new(&vec!["unquote"], "not inside a quasiquote", context)))
+	}
+	Sexpr::UnquoteSplicing(_, _) => {
+	    Err(Box::new(Exception::new(&vec!["unquote-splicing"], "not inside a quasiquote", context)))
+	}
+    };
+    value.map(Tail::Done)
 }
 
-fn walk_through_list(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+fn walk_through_list(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
     if list.is_empty() {
-	    return Ok(None);
+	    return Ok(Tail::Done(None));
     }
-    if let Sexpr::Atom(Atom::Symbol(s)) = &list[0] {
-        match s[0].as_str() {
+    if let Sexpr::Atom(Atom::Symbol(s), _) = &list[0] {
+        let value = match s[0].as_str() {
             "define" => walk_through_define(list, context, module_name),
             "lambda" => walk_through_lambda(list, context, module_name),
-            "if" => walk_through_if(list, context, module_name),
+            "if" => return walk_through_if(list, context, module_name),
             "set!" => walk_through_set(list, context, module_name),
-            "let" => walk_through_let(list, context, module_name),
-            "begin" => walk_through_begin(list, context, module_name),
+            "let" => return walk_through_let(list, context, module_name),
+            "let*" | "letrec" | "letrec*" => return walk_through_sequential_let(list, context, module_name),
+            "begin" => return walk_through_begin(list, context, module_name),
             "import" => walk_through_import(list, context, module_name),
             "import-from" => walk_through_import_from(list, context, module_name),
             "module" => walk_through_module(list, context, module_name),
             "try" => walk_through_try(list, context, module_name),
             "error" => walk_through_error(list, context, module_name),
-	    "cond" => walk_through_cond(list, context, module_name),
-	    "call" => walk_through_call_expr(list, context, module_name),
+	    "handler-bind" => walk_through_handler_bind(list, context, module_name),
+	    "restart-case" => walk_through_restart_case(list, context, module_name),
+	    "cond" => return walk_through_cond(list, context, module_name),
+	    "call" => return walk_through_call_expr(list, context, module_name),
 	    "struct" => walk_through_struct(list, context, module_name),
 	    "enum" => walk_through_enum(list, context, module_name),
-	    "match" => walk_through_type_case(list, context, module_name),
+	    "match" => return walk_through_type_case(list, context, module_name),
 	    "while" => walk_through_while(list, context, module_name),
-            _ => walk_through_call(list, context, module_name),
-        }
+            _ => return walk_through_call(list, context, module_name),
+        };
+        value.map(Tail::Done)
     } else {
-	    let empty: Vec<&str> = Vec::new();
-	    Err(Box::new(Exception::new(&empty, "unreachable", context)))
+	walk_through_call(list, context, module_name)
     }
 }
 
 fn walk_through_define(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, Sexpr::Atom(Atom::Symbol(name)), value] => {
+	[_, Sexpr::Atom(Atom::Symbol(name), _), value] => {
 	    let value = walk_through(value, context, module_name)?;
 	    match value {
 		Some(value) => {
@@ -153,18 +199,14 @@ fn walk_through_define(list: &Vec<Sexpr>, context: &mut Context, module_name: &V
 		}
 	    }
 	}
-	[_, Sexpr::List(header), body] => {
+	[_, Sexpr::List(header, span), body @ ..] if !header.is_empty() && !body.is_empty() => {
 	    let name = match &header[0] {
-		Sexpr::Atom(Atom::Symbol(s)) => &s[0],
+		Sexpr::Atom(Atom::Symbol(s), _) => &s[0],
 		_ => return Err(Box::new(Exception::new(&vec!["define"], "not a symbol", context)))
 	    };
-	    let args = header.iter().skip(1).map(|sexpr| match sexpr {
-		Sexpr::Atom(Atom::Symbol(s)) => Ok(s[0].clone()),
-		_ => Err(Box::new(Exception::new(&vec!["define"], "not a symbol", context))),
-	    }).collect::<Vec<Result<String, Box<Exception>>>>();
-	    let args = args.into_iter().collect::<Result<Vec<String>, Box<Exception>>>()?;
+	    let (args, shape) = function_shape("define", &header[1..], context)?;
 
-	    let function = Function::Tree(args.clone(), body.clone(), context.copy_frame(), FunctionShape::new(args));
+	    let function = Function::Tree(args, body_expression(body, span), context.capture_environment(), shape);
 	    let function = Value::new_function(function, context);
 	    context.define(&name, function);
 	    Ok(None)
@@ -175,30 +217,78 @@ fn walk_through_define(list: &Vec<Sexpr>, context: &mut Context, module_name: &V
 
 fn walk_through_lambda(list: &Vec<Sexpr>, context: &mut Context, _: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, Sexpr::List(header), body] => {
-	    let args = header.iter().map(|sexpr| match sexpr {
-		Sexpr::Atom(Atom::Symbol(s)) => Ok(s[0].clone()),
-		_ => Err(Box::new(Exception::new(&vec!["lambda"], "not a symbol", context))),
-	    }).collect::<Vec<Result<String, Box<Exception>>>>();
-	    let args = args.into_iter().collect::<Result<Vec<String>, Box<Exception>>>()?;
-
-	    let function = Function::Tree(args.clone(), body.clone(), context.copy_frame(), FunctionShape::new(args));
+	[_, Sexpr::List(header, span), body @ ..] if !body.is_empty() => {
+	    let (args, shape) = function_shape("lambda", header, context)?;
+
+	    let function = Function::Tree(args, body_expression(body, span), context.capture_environment(), shape);
+	    Ok(Some(Value::new_function(function, context)))
+	},
+	[_, Sexpr::Atom(Atom::Symbol(rest), span), body @ ..] if !body.is_empty() => {
+	    let shape = FunctionShape::new(Vec::new()).with_rest(Some(rest[0].clone()));
+
+	    let function = Function::Tree(Vec::new(), body_expression(body, span), context.capture_environment(), shape);
 	    Ok(Some(Value::new_function(function, context)))
 	},
 	_ => Err(Box::new(Exception::new(&vec!["lambda"], "unusual syntax", context))),
     }
 }
 
-fn walk_through_if(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+/// Reads a parameter list: `name` is required, `[name default]` is optional,
+/// everything after `#:key` is keyword-only and `#:rest name` or `. name` collects the remaining arguments
+pub fn function_shape(who: &str, parameters: &[Sexpr], context: &mut Context) -> HelperResult<(Vec<String>, FunctionShape)> {
+    let mut args = Vec::new();
+    let mut optional = Vec::new();
+    let mut keywords = Vec::new();
+    let mut rest = None;
+    let mut keyword_only = false;
+    let mut iterator = parameters.iter();
+    while let Some(parameter) = iterator.next() {
+	match parameter {
+	    Sexpr::Atom(Atom::Symbol(s), _) if s[0] == "#:key" => keyword_only = true,
+	    Sexpr::Atom(Atom::Symbol(s), _) if s[0] == "#:rest" || s[0] == "." => {
+		match (iterator.next(), iterator.next()) {
+		    (Some(Sexpr::Atom(Atom::Symbol(name), _)), None) => rest = Some(name[0].clone()),
+		    _ => return Err(Box::new(Exception::new(&vec![who], "rest parameter must be a single symbol at the end", context))),
+		}
+	    },
+	    Sexpr::Atom(Atom::Symbol(s), _) if keyword_only => keywords.push(Parameter::new(s[0].clone(), None)),
+	    Sexpr::Atom(Atom::Symbol(s), _) => {
+		if !optional.is_empty() {
+		    return Err(Box::new(Exception::new(&vec![who], "required parameter after an optional one", context)));
+		}
+		args.push(s[0].clone());
+	    },
+	    Sexpr::List(pair, _) => match pair.as_slice() {
+		[Sexpr::Atom(Atom::Symbol(name), _), default] => {
+		    let parameter = Parameter::new(name[0].clone(), Some(default.clone()));
+		    if keyword_only {
+			keywords.push(parameter);
+		    } else {
+			optional.push(parameter);
+		    }
+		},
+		_ => return Err(Box::new(Exception::new(&vec![who], "optional parameter must be [name default]", context))),
+	    },
+	    _ => return Err(Box::new(Exception::new(&vec![who], "not a symbol", context))),
+	}
+    }
+    let shape = FunctionShape::new(args.clone())
+	.with_optional(optional)
+	.with_keywords(keywords)
+	.with_rest(rest);
+    Ok((args, shape))
+}
+
+fn walk_through_if(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
     match list.as_slice() {
 	[_, condition, consequent, alternate] => {
 	    let condition = walk_through(condition, context, module_name)?;
 	    match condition {
 		Some(value) => {
 		    if value.get_boolean(context)? {
-			walk_through(consequent, context, module_name)
+			walk_through_tail(consequent, context, module_name)
 		    } else {
-			walk_through(alternate, context, module_name)
+			walk_through_tail(alternate, context, module_name)
 		    }
 		}
 		_ => Err(Box::new(Exception::new(&vec!["if"], "expression didn't result in a value", context)))
@@ -210,7 +300,7 @@ fn walk_through_if(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<S
 
 fn walk_through_set(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, Sexpr::Atom(Atom::Symbol(name)), value] => {
+	[_, Sexpr::Atom(Atom::Symbol(name), _), value] => {
 	    let value = walk_through(value, context, module_name)?;
 	    match value {
 		Some(value) => {
@@ -226,32 +316,23 @@ fn walk_through_set(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<
     }
 }
 
-fn walk_through_let(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+/// `let` evaluates every value before binding any of them, `(let name (bindings) body)` is a named let
+fn walk_through_let(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
     match list.as_slice() {
-	[_, Sexpr::List(bindings), body] => {
-	    context.push_frame(None);
+	[_, Sexpr::Atom(Atom::Symbol(name), _), Sexpr::List(bindings, span), body @ ..] if !body.is_empty() => {
+	    walk_through_named_let(&name[0], bindings, span, body, context, module_name)
+	}
+	[_, Sexpr::List(bindings, _), body @ ..] if !body.is_empty() => {
+	    let mut values = Vec::new();
 	    for binding in bindings {
-		match binding {
-		    Sexpr::List(sets) => {
-			match sets.as_slice() {
-			    [Sexpr::Atom(Atom::Symbol(name)), value] => {
-				let value = walk_through(value, context, module_name)?;
-				match value {
-				    Some(value) => {
-					context.define(&name[0], value);
-				    }
-				    None => {
-					return Err(Box::new(Exception::new(&vec!["let"], "expression didn't result in a value", context)));
-				    }
-				}
-			    }
-			    _ => return Err(Box::new(Exception::new(&vec!["let"], "unusual syntax 1", context))),
-			}
-		    }
-		    _ => return Err(Box::new(Exception::new(&vec!["let"], "unusual syntax 2", context))),
-		}
+		let (name, value) = let_binding("let", binding, context)?;
+		values.push((name, let_value("let", value, context, module_name)?));
 	    }
-	    let value = walk_through(body, context, module_name);
+	    context.push_frame(None);
+	    for (name, value) in values {
+		context.define(&name[0], value);
+	    }
+	    let value = walk_through_body(body, context, module_name);
 	    context.pop_frame();
 	    value
 	}
@@ -259,12 +340,126 @@ fn walk_through_let(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<
     }
 }
 
-fn walk_through_begin(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
-    let mut output = None;
-    for sexpr in list.iter().skip(1) {
-	output = walk_through(sexpr, context, module_name)?;
+/// `let*`, `letrec` and `letrec*` bind in order, so each value sees the variables before it.
+/// `let*` binds each variable in a new frame, functions in a `letrec` can call each other since they share one frame
+fn walk_through_sequential_let(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    let Some(Sexpr::Atom(Atom::Symbol(who), _)) = list.first() else {
+	return Err(Box::new(Exception::new(&vec!["let*"], "unusual syntax", context)));
+    };
+    let who = who[0].as_str();
+    match list.as_slice() {
+	[_, Sexpr::List(bindings, _), body @ ..] if !body.is_empty() => {
+	    // A closure made by a let* value keeps seeing the binding it was made under, even if a later one shadows it
+	    let sequential = who == "let*";
+	    let mut frames = 1;
+	    context.push_frame(None);
+	    let value = bindings.iter().try_for_each(|binding| {
+		let (name, value) = let_binding(who, binding, context)?;
+		let value = let_value(who, value, context, module_name)?;
+		if sequential {
+		    context.push_frame(None);
+		    frames += 1;
+		}
+		context.define(&name[0], value);
+		Ok(())
+	    }).and_then(|_| walk_through_body(body, context, module_name));
+	    for _ in 0..frames {
+		context.pop_frame();
+	    }
+	    value
+	}
+	_ => Err(Box::new(Exception::new(&vec![who], "unusual syntax", context)))
+    }
+}
+
+/// Binds a loop function named `name` over the body and calls it with the initial values
+fn walk_through_named_let(name: &str, bindings: &[Sexpr], span: &Span, body: &[Sexpr], context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    let mut args = Vec::new();
+    let mut values = Vec::new();
+    for binding in bindings {
+	let (arg, value) = let_binding("let", binding, context)?;
+	args.push(arg[0].clone());
+	values.push(let_value("let", value, context, module_name)?);
+    }
+    // The loop function lives in a frame of its own that it captures, so it can call itself
+    context.push_frame(None);
+    let function = Function::Tree(args.clone(), body_expression(body, span), context.capture_environment(), FunctionShape::new(args));
+    let function_value = Value::new_function(function.clone(), context);
+    context.define(name, function_value);
+    context.pop_frame();
+    Ok(Tail::Call(Box::new(TailCall {
+	function,
+	name: vec![name.to_string()],
+	args: values,
+	kargs: Kwargs::new(),
+	module_name: module_name.clone(),
+	location: context.get_location().clone(),
+    })))
+}
+
+pub fn let_binding<'a>(who: &str, binding: &'a Sexpr, context: &mut Context) -> HelperResult<(&'a Vec<String>, &'a Sexpr)> {
+    match binding {
+	Sexpr::List(binding, _) => match binding.as_slice() {
+	    [Sexpr::Atom(Atom::Symbol(name), _), value] => Ok((name, value)),
+	    _ => Err(Box::new(Exception::new(&vec![who], "unusual syntax 1", context))),
+	},
+	_ => Err(Box::new(Exception::new(&vec![who], "unusual syntax 2", context))),
+    }
+}
+
+fn let_value(who: &str, value: &Sexpr, context: &mut Context, module_name: &Vec<String>) -> HelperResult<Value> {
+    match walk_through(value, context, module_name)? {
+	Some(value) => Ok(value),
+	None => Err(Box::new(Exception::new(&vec![who], "expression didn't result in a value", context))),
+    }
+}
+
+fn walk_through_begin(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    walk_through_body(&list[1..], context, module_name)
+}
+
+/// Runs the expressions of a body in order with the last one in tail position.
+/// Definitions in the body go into the innermost frame, so callers that don't push one use `walk_through_scoped_body`
+fn walk_through_body(body: &[Sexpr], context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    let Some((last, body)) = body.split_last() else {
+	return Ok(Tail::Done(None));
+    };
+    for sexpr in body {
+	walk_through(sexpr, context, module_name)?;
+    }
+    walk_through_tail(last, context, module_name)
+}
+
+/// Runs a body in a frame of its own so its internal definitions stay local to it
+fn walk_through_scoped_body(body: &[Sexpr], context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    context.push_frame(None);
+    let value = walk_through_body(body, context, module_name);
+    context.pop_frame();
+    value
+}
+
+/// A function body as a single expression, several expressions are run like a `begin`
+pub fn body_expression(body: &[Sexpr], span: &Span) -> Sexpr {
+    match body {
+	[expression] => expression.clone(),
+	_ => {
+	    let mut list = vec![Sexpr::Atom(Atom::Symbol(vec!["begin".to_string()]), span.clone())];
+	    list.extend(body.iter().cloned());
+	    Sexpr::List(list, span.clone())
+	},
+    }
+}
+
+/// Whether `sexpr` is a `((catch ...) body ...)` handler of a `try`
+pub fn is_handler(sexpr: &Sexpr) -> bool {
+    let Sexpr::List(handler, _) = sexpr else {
+	return false;
+    };
+    match handler.first() {
+	Some(Sexpr::List(clause, _)) => matches!(clause.first(), Some(Sexpr::Atom(Atom::Symbol(keyword), _)) if matches!(keyword[0].as_str(), "catch" | "catch-all" | "catch-if")),
+	Some(Sexpr::Atom(Atom::Symbol(keyword), _)) => keyword[0] == "finally",
+	_ => false,
     }
-    Ok(output)
 }
 
 fn walk_through_import(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
@@ -306,8 +501,8 @@ fn walk_through_import(list: &Vec<Sexpr>, context: &mut Context, module_name: &V
 	    }
 
 	    let file = std::fs::read_to_string(file_path).map_err(|err| Box::new(Exception::new(&vec!["import"], &format!("{}", err), context)))?;
-	    let file = crate::parser::parse(&file, &mut context.get_macros()).map_err(|err| Box::new(Exception::new(&vec!["import"], &format!("{}", err), context)))?;
-	    run(file, context, module_name).map_err(|err| Box::new(Exception::new(&vec!["import"], &format!("{}", err), context)))?;
+	    let file = crate::parser::parse_with_context(&file, &file_path.to_string_lossy(), &mut context.get_macros(), context).map_err(|err| Box::new(Exception::new(&vec!["import"], &format!("{}", err), context)))?;
+	    run(file, context, module_name)?;
 	    
 	    Ok(None)
 	}
@@ -381,72 +576,240 @@ fn walk_through_module(list: &Vec<Sexpr>, context: &mut Context, module_name: &V
 
 fn walk_through_try(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, body, handlers @ ..] => {
-	    let value = walk_through(body, context, module_name);
-	    match value {
-		Ok(value) => return Ok(value),
-		Err(e) => {
-		    for handler in handlers {
-			if let Sexpr::List(handler) = handler {
-			    if handler.len() != 2 {
-				return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
-			    }
-			    let Sexpr::List(clause) = &handler[0] else {
-				return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
-			    };
-			    let Sexpr::Atom(Atom::Symbol(keyword)) = &clause[0] else {
-				return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
-			    };
-			    
-			    match keyword[0].as_str() {
-				"catch" => {},
-				_ => return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context))),
-			    }
-			    let who = walk_through(&clause[1], context, module_name)?.ok_or(Box::new(Exception::new(&vec!["try"], "not a symbol", context)))?;
-			    let who = who.get_symbol(context)?;
-			    let Sexpr::Atom(Atom::Symbol(message_var)) = &clause[2] else {
-				return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
-			    };
-					
-			    match handler.as_slice() {
-				[_, body] => {
-				    if e.get_who(context) == who {
-					context.push_frame(None);
-					context.define(&message_var[0], e.get_message());
-					let value = walk_through(body, context, module_name);
-					context.pop_frame();
-					return value;
-				    }
-				},
-				_ => return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)))
-			    }
-			}
-		    }
-		    Err(e)
-		}
+	[_, rest @ ..] => {
+	    let split = rest.iter().position(is_handler).unwrap_or(rest.len());
+	    let (body, handlers) = rest.split_at(split);
+	    if body.is_empty() {
+		return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
+	    }
+	    let (handlers, finally) = match handlers {
+		[handlers @ .., Sexpr::List(finally, _)] if matches!(finally.first(), Some(Sexpr::Atom(Atom::Symbol(keyword), _)) if keyword[0] == "finally") => (handlers, Some(&finally[1..])),
+		handlers => (handlers, None),
+	    };
+	    let value = walk_through_try_handlers(body, handlers, context, module_name);
+	    // The finally clause runs however the body was left, an error in it replaces the result
+	    match finally {
+		Some(finally) => {
+		    walk_through_scoped_body(finally, context, module_name).and_then(|value| value.finish(context))?;
+		    value
+		},
+		None => value,
 	    }
 	}
 	_ => Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)))
     }
 }
 
+/// A handler clause of a `try`, its matcher is evaluated when the `try` is entered
+struct CatchClause<'a> {
+    matcher: ConditionMatcher,
+    binds_message: bool,
+    value_var: &'a str,
+    backtrace_var: Option<&'a str>,
+    body: &'a [Sexpr],
+}
+
+fn catch_clause<'a>(handler: &'a Sexpr, context: &mut Context, module_name: &Vec<String>) -> HelperResult<CatchClause<'a>> {
+    let Sexpr::List(handler, _) = handler else {
+	return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
+    };
+    let [Sexpr::List(clause, _), body @ ..] = handler.as_slice() else {
+	return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
+    };
+    let Some(Sexpr::Atom(Atom::Symbol(keyword), _)) = clause.first() else {
+	return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
+    };
+    if body.is_empty() {
+	return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context)));
+    }
+
+    // catch binds the message, catch-all and catch-if bind the raised value or the exception object
+    let (matcher, variables) = match (keyword[0].as_str(), clause.get(1)) {
+	("catch", Some(who)) => {
+	    let who = walk_through(who, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["try"], "not a symbol", context)))?;
+	    let who = who.get_symbol(context)?.clone();
+	    (ConditionMatcher::Who(who), &clause[2..])
+	},
+	("catch-all", _) => (ConditionMatcher::Any, &clause[1..]),
+	("catch-if", Some(predicate)) => {
+	    let predicate = walk_through(predicate, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["try"], "not a procedure", context)))?;
+	    if !predicate.is_function() {
+		return Err(Box::new(Exception::new(&vec!["try"], "not a procedure", context)));
+	    }
+	    (ConditionMatcher::Predicate(predicate), &clause[2..])
+	},
+	_ => return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context))),
+    };
+    let (value_var, backtrace_var) = match variables {
+	[Sexpr::Atom(Atom::Symbol(value_var), _)] => (&value_var[0], None),
+	[Sexpr::Atom(Atom::Symbol(value_var), _), Sexpr::Atom(Atom::Symbol(backtrace_var), _)] => (&value_var[0], Some(backtrace_var[0].as_str())),
+	_ => return Err(Box::new(Exception::new(&vec!["try"], "unusual syntax", context))),
+    };
+    Ok(CatchClause {
+	matcher,
+	binds_message: keyword[0] == "catch",
+	value_var,
+	backtrace_var,
+	body,
+    })
+}
+
+fn walk_through_try_handlers(body: &[Sexpr], handlers: &[Sexpr], context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+    let clauses = handlers.iter().map(|handler| catch_clause(handler, context, module_name)).collect::<HelperResult<Vec<CatchClause>>>()?;
+    // The clauses are established as handlers, so that a condition they catch is not offered to handlers outside of the try
+    let cluster = HandlerCluster::new(clauses.iter().enumerate().map(|(i, clause)| (clause.matcher.clone(), HandlerAction::Catch(i))).collect());
+    let id = cluster.get_id();
+    context.push_handlers(cluster);
+    let value = walk_through_scoped_body(body, context, module_name).and_then(|value| value.finish(context));
+    let value = value.map_err(|e| condition::signal(e, context));
+    context.pop_handlers();
+
+    let e = match value {
+	Ok(value) => return Ok(value),
+	Err(e) => e,
+    };
+    let clause = match condition::get_catcher(&e) {
+	Some((cluster, index)) if cluster == id => &clauses[index],
+	_ => return Err(e),
+    };
+    let value = if clause.binds_message {
+	e.get_message()
+    } else {
+	e.get_condition(context)
+    };
+    context.push_frame(None);
+    context.define(clause.value_var, value);
+    if let Some(backtrace_var) = clause.backtrace_var {
+	let backtrace = e.get_backtrace_value(context);
+	context.define(backtrace_var, backtrace);
+    }
+    let value = walk_through_body(clause.body, context, module_name).and_then(|value| value.finish(context));
+    context.pop_frame();
+    value
+}
+
+/// `(handler-bind ((type handler) ...) body ...)` calls the handlers where a matching condition is signalled,
+/// a type is a symbol naming who raised an exception, a predicate on the condition or `#t` for any condition
+fn walk_through_handler_bind(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+    match list.as_slice() {
+	[_, Sexpr::List(bindings, _), body @ ..] if !body.is_empty() => {
+	    let mut handlers = Vec::new();
+	    for binding in bindings {
+		let Sexpr::List(binding, _) = binding else {
+		    return Err(Box::new(Exception::new(&vec!["handler-bind"], "unusual syntax", context)));
+		};
+		let [matcher, handler] = binding.as_slice() else {
+		    return Err(Box::new(Exception::new(&vec!["handler-bind"], "unusual syntax", context)));
+		};
+		let matcher = walk_through(matcher, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["handler-bind"], "not a condition type", context)))?;
+		let matcher = if matcher.is_symbol() {
+		    ConditionMatcher::Who(matcher.get_symbol(context)?.clone())
+		} else if matcher.is_function() {
+		    ConditionMatcher::Predicate(matcher)
+		} else if matcher.is_boolean() && matcher.get_boolean(context)? {
+		    ConditionMatcher::Any
+		} else {
+		    return Err(Box::new(Exception::new(&vec!["handler-bind"], "not a condition type", context)));
+		};
+		let handler = walk_through(handler, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["handler-bind"], "not a procedure", context)))?;
+		if !handler.is_function() {
+		    return Err(Box::new(Exception::new(&vec!["handler-bind"], "not a procedure", context)));
+		}
+		handlers.push((matcher, HandlerAction::Call(handler)));
+	    }
+
+	    context.push_handlers(HandlerCluster::new(handlers));
+	    let value = walk_through_scoped_body(body, context, module_name).and_then(|value| value.finish(context));
+	    // Conditions that were not signalled on the way up are offered before the handlers are gone
+	    let value = value.map_err(|e| condition::signal(e, context));
+	    context.pop_handlers();
+	    value
+	}
+	_ => Err(Box::new(Exception::new(&vec!["handler-bind"], "unusual syntax", context))),
+    }
+}
+
+/// `(restart-case form (name (parameters ...) body ...) ...)` evaluates form with the restarts available,
+/// invoking one unwinds to here and returns the value of its body
+fn walk_through_restart_case(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+    match list.as_slice() {
+	[_, form, clauses @ ..] => {
+	    let mut restarts = Vec::new();
+	    for clause in clauses {
+		let Sexpr::List(clause, span) = clause else {
+		    return Err(Box::new(Exception::new(&vec!["restart-case"], "unusual syntax", context)));
+		};
+		let [Sexpr::Atom(Atom::Symbol(name), _), Sexpr::List(parameters, _), body @ ..] = clause.as_slice() else {
+		    return Err(Box::new(Exception::new(&vec!["restart-case"], "unusual syntax", context)));
+		};
+		if body.is_empty() {
+		    return Err(Box::new(Exception::new(&vec!["restart-case"], "unusual syntax", context)));
+		}
+		let (args, shape) = function_shape("restart-case", parameters, context)?;
+		let function = Function::Tree(args, body_expression(body, span), context.capture_environment(), shape);
+		restarts.push((name.clone(), function));
+	    }
+
+	    let ids = restarts.iter().map(|(name, _)| {
+		let id = context.push_escape_point();
+		context.push_restart(name.join("."), id);
+		id
+	    }).collect::<Vec<usize>>();
+	    let value = walk_through(form, context, module_name);
+	    // Conditions that were not signalled on the way up are offered while the restarts can still be invoked
+	    let value = value.map_err(|e| condition::signal(e, context));
+	    for _ in ids.iter() {
+		context.pop_restart();
+		context.pop_escape_point();
+	    }
+
+	    let e = match value {
+		Err(e) => e,
+		value => return value,
+	    };
+	    match e.get_escape().and_then(|id| ids.iter().position(|restart| *restart == id)) {
+		Some(index) => {
+		    let args = e.into_escape_value().unwrap_or_else(Value::new_nil);
+		    let args = args.get_vector(context)?.clone();
+		    let (name, function) = &restarts[index];
+		    function.call_from_bytecode(name, args, Kwargs::new(), context, module_name)
+		},
+		None => Err(e),
+	    }
+	}
+	_ => Err(Box::new(Exception::new(&vec!["restart-case"], "unusual syntax", context))),
+    }
+}
+
 fn walk_through_error(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, who, Sexpr::Atom(Atom::String(message))] => {
+	[_, who, message, fields @ ..] => {
 	    let who = walk_through(who, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["error"], "not a symbol", context)))?;
+	    let message = walk_through(message, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["error"], "not a string", context)))?;
+	    let message = message.get_string(context)?.clone();
 	    let who = who.get_symbol(context)?;
+	    let mut exception = Exception::new(&who, &message, context);
 
-	    Err(Box::new(Exception::new(&who, &message, context)))
+	    // Keyword arguments become fields of the exception
+	    let mut fields = fields.iter();
+	    while let Some(field) = fields.next() {
+		let (Sexpr::Atom(Atom::Keyword(name), _), Some(value)) = (field, fields.next()) else {
+		    return Err(Box::new(Exception::new(&vec!["error"], "unusual syntax", context)));
+		};
+		let value = walk_through(value, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["error"], "expression didn't result in a value", context)))?;
+		exception = exception.with_field(name, value);
+	    }
+	    Err(condition::signal(Box::new(exception), context))
 	}
 	_ => Err(Box::new(Exception::new(&vec!["error"], "unusual syntax", context))),
     }
 }
 
-fn walk_through_cond(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+fn walk_through_cond(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
     match list.as_slice() {
 	[_, clauses @ ..] => {
 	    for clause in clauses {
-		let Sexpr::List(clause) = clause else {
+		let Sexpr::List(clause, _) = clause else {
 		    return Err(Box::new(Exception::new(&vec!["cond"], "unusual syntax 1", context)));
 		};
 		if clause.len() != 2 {
@@ -454,19 +817,19 @@ fn walk_through_cond(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec
 		}
 		match clause.as_slice() {
 		    [condition, body] => {
-			if let Sexpr::List(_) = condition {
+			if let Sexpr::List(_, _) = condition {
 			    let condition = walk_through(condition, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["cond"], "expression didn't result in a value", context)))?;
 			    if condition.get_boolean(context)? {
-				return walk_through(body, context, module_name);
+				return walk_through_tail(body, context, module_name);
 			    }
-			} else if let Sexpr::Atom(Atom::Symbol(keyword)) = condition {
+			} else if let Sexpr::Atom(Atom::Symbol(keyword), _) = condition {
 			    match keyword[0].as_str() {
-				"else" => return walk_through(body, context, module_name),
+				"else" => return walk_through_tail(body, context, module_name),
 				_ => return Err(Box::new(Exception::new(&vec!["cond"], "unusual syntax 3", context))),
 			    }
-			} else if let Sexpr::Atom(Atom::Boolean(b)) = condition {
+			} else if let Sexpr::Atom(Atom::Boolean(b), _) = condition {
 			    if *b {
-				return walk_through(body, context, module_name);
+				return walk_through_tail(body, context, module_name);
 			    }
 			} else {
 			    return Err(Box::new(Exception::new(&vec!["cond"], "unusual syntax 4", context)));
@@ -475,27 +838,26 @@ fn walk_through_cond(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec
 		    _ => return Err(Box::new(Exception::new(&vec!["cond"], "unusual syntax 5", context))),
 		}
 	    }
-	    Ok(None)
+	    Ok(Tail::Done(None))
 	},
 	_ => Err(Box::new(Exception::new(&vec!["cond"], "unusual syntax 6", context))),
     }
 }
 
-fn walk_through_call_expr(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+fn walk_through_call_expr(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
     match list.as_slice() {
 	[_, name, args @ ..] => {
 	    let name = walk_through(name, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["call"], "not a symbol", context)))?;
 	    let name = name.get_symbol(context)?;
 
 	    let path = module_name.iter().chain(name.iter()).map(|s| s.clone()).collect();
-	    let function = match context.get(path) {
-		Some(f) => {
-		    f.get_function(context)?.clone()
-		},
+	    let function = match context.get(path)? {
+		Some(f) => get_procedure(&f, name, context)?,
 		None => return Err(Box::new(Exception::new(&name, "not bound", context)))
 	    };
 
-	    function.call(&name, &args.to_vec(), context, module_name)
+	    let name = name.clone();
+	    tail_call(function, name, args, context, module_name)
 	}
 	_ => Err(Box::new(Exception::new(&vec!["call"], "unusual syntax", context))),
     }
@@ -503,14 +865,14 @@ fn walk_through_call_expr(list: &Vec<Sexpr>, context: &mut Context, module_name:
 
 fn walk_through_struct(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, Sexpr::Atom(Atom::Symbol(name)), Sexpr::List(fields)] => {
+	[_, Sexpr::Atom(Atom::Symbol(name), _), Sexpr::List(fields, _)] => {
 	    let fields = fields.iter().map(|sexpr| match sexpr {
-		Sexpr::Atom(Atom::Symbol(s)) => Ok(s.clone()),
+		Sexpr::Atom(Atom::Symbol(s), _) => Ok(s.clone()),
 		_ => Err(Box::new(Exception::new(&vec!["struct"], "not a symbol", context))),
 	    }).collect::<Vec<Result<Vec<String>, Box<Exception>>>>();
 	    let fields = fields.into_iter().collect::<Result<Vec<Vec<String>>, Box<Exception>>>()?;
 
-	    Struct::create_functions(module_name, &name, fields, context);
+	    Struct::create_functions(module_name, &name, fields, context)?;
 	    Ok(None)
 	},
 	_ => Err(Box::new(Exception::new(&vec!["struct"], "unusual syntax", context))),
@@ -519,16 +881,16 @@ fn walk_through_struct(list: &Vec<Sexpr>, context: &mut Context, module_name: &V
 
 fn walk_through_enum(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, Sexpr::Atom(Atom::Symbol(name)), variants @ ..] => {
+	[_, Sexpr::Atom(Atom::Symbol(name), _), variants @ ..] => {
 	    let mut variant_names = Vec::new();
 	    let mut variant_fields: Vec<Vec<Vec<String>>> = Vec::new();
 	    for variant in variants {
 		match variant {
-		    Sexpr::List(variant) => {
+		    Sexpr::List(variant, _) => {
 			match variant.as_slice() {
-			    [Sexpr::Atom(Atom::Symbol(variant_name)), fields @ ..] => {
+			    [Sexpr::Atom(Atom::Symbol(variant_name), _), fields @ ..] => {
 				let fields = fields.iter().map(|sexpr| match sexpr {
-				    Sexpr::Atom(Atom::Symbol(s)) => Ok(s.clone()),
+				    Sexpr::Atom(Atom::Symbol(s), _) => Ok(s.clone()),
 				    _ => Err(Box::new(Exception::new(&vec!["enum"], "not a symbol", context))),
 				}).collect::<Vec<Result<Vec<String>, Box<Exception>>>>();
 				let fields = fields.into_iter().collect::<Result<Vec<Vec<String>>, Box<Exception>>>()?;
@@ -545,7 +907,7 @@ fn walk_through_enum(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec
 		panic!("variant names and fields are not the same length");
 	    }
 	    
-	    Enum::create_functions(module_name, &name, &variant_names, variant_fields, context);
+	    Enum::create_functions(module_name, &name, &variant_names, variant_fields, context)?;
 	    
 	    Ok(None)
 	},
@@ -553,166 +915,199 @@ fn walk_through_enum(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec
     }
 }
 
-fn walk_through_type_case(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
+fn walk_through_type_case(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
     match list.as_slice() {
 	[_, value, cases @ ..] => {
 	    let value = walk_through(value, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["match"], "not a value", context)))?;
-	    let value = value.clone();
-	    let value_type_index = value.get_type_index();
 	    for case in cases {
-		let Sexpr::List(case) = case else {
-		    return Err(Box::new(Exception::new(&vec!["match"], "unusual syntax 1", context)));
+		let Sexpr::List(case, _) = case else {
+		    return Err(pattern_error(case, "unusual syntax", context));
 		};
-		match case.as_slice() {
-		    [Sexpr::List(clause), body] => {
-			let [Sexpr::Atom(Atom::Symbol(type_name)), fields @ ..] = clause.as_slice() else {
-			    return Err(Box::new(Exception::new(&vec!["match"], "unusual syntax 3", context)));
-			};
-			let type_name_index = if let Some(index) = context.get_type_index(&type_name) {
-			    index
-			} else {
-			    let type_name_with_module = module_name.iter().chain(type_name.iter()).map(|s| s.clone()).collect();
-			    if let Some(index) = context.get_type_index(&type_name_with_module) {
-				index
-			    } else {
-				return Err(Box::new(Exception::new(&vec!["match"], "type not found", context)));
-			    }
-			};
-			if value_type_index == type_name_index {
-			    context.push_frame(None);
-
-			    let index = if let Some(index) = context.get_type_index(&type_name) {
-				index
-			    } else {
-				let type_name_with_module = module_name.iter().chain(type_name.iter()).map(|s| s.clone()).collect();
-				if let Some(index) = context.get_type_index(&type_name_with_module) {
-				    index
-				} else {
-				    return Err(Box::new(Exception::new(&vec!["match"], "type not found", context)));
-				}
-			    };
-			    
-			    if context.is_enum(index) {
-				let Sexpr::Atom(Atom::Symbol(variant_name)) = &fields[0] else {
-				    return Err(Box::new(Exception::new(&vec!["match"], "unusual syntax 4", context)));
-				};
-				let enumeration = value.get_enum(context)?;
-				let variant_type_index = context.get_type_index(&variant_name).unwrap();
-				if enumeration.get_variant_index() != variant_type_index {
-				    context.pop_frame();
-				    continue;
-				}
-
-				for (i, field) in fields.iter().skip(1).enumerate() {
-				    let Sexpr::Atom(Atom::Symbol(field_name)) = field else {
-					return Err(Box::new(Exception::new(&vec!["match"], "unusual syntax 5", context)));
-				    };
-				    let field_value = enumeration.get_member(i, context)?;
-				    context.define(&field_name[0], field_value.clone());
-				}
-			    } else {
-				let structure = value.get_struct(context)?;
-				for (i, field) in fields.iter().enumerate() {
-				    let Sexpr::Atom(Atom::Symbol(field_name)) = field else {
-					return Err(Box::new(Exception::new(&vec!["match"], "unusual syntax 6", context)));
-				    };
-				    let field_value = structure.get_member(i, context)?;
-				    context.define(&field_name[0], field_value.clone());
-				}
-			    }
-			    let value = walk_through(body, context, module_name);
-			    context.pop_frame();
-			    return value;
-			} else {
-			    continue;
-			}
-		    },
-		    [Sexpr::Atom(Atom::Symbol(else_symbol)), body] => {
-			if else_symbol[0] != "else" {
-			    context.push_frame(None);
-			    context.define(&else_symbol[0], value.clone());
-			    let value = walk_through(body, context, module_name);
-			    context.pop_frame();
-			    return value;
-			}
-			let value = walk_through(body, context, module_name);
+		let [pattern, body @ ..] = case.as_slice() else {
+		    return Err(Box::new(Exception::new(&vec!["match"], "unusual syntax", context)));
+		};
+		if body.is_empty() {
+		    return Err(pattern_error(pattern, "case has no body", context));
+		}
+		if let Sexpr::Atom(Atom::Symbol(else_symbol), _) = pattern {
+		    if else_symbol[0] == "else" {
+			return walk_through_scoped_body(body, context, module_name);
+		    }
+		}
+
+		context.push_frame(None);
+		match match_pattern(pattern, &value, context, module_name) {
+		    Ok(true) => {
+			let value = walk_through_body(body, context, module_name);
+			context.pop_frame();
 			return value;
 		    },
-		    [Sexpr::Atom(Atom::Boolean(b)), body] => {
-			match value.get_boolean(context) {
-			    Ok(value) => {
-				if value == *b {
-				    let value = walk_through(body, context, module_name);
-				    return value;
-				}
-			    }
-			    Err(_) => { continue; },
-			}
+		    Ok(false) => {
+			context.pop_frame();
 		    },
-		    [Sexpr::Atom(Atom::Integer(i)), body] => {
-			match value.get_integer(context) {
-			    Ok(value) => {
-				if value.to_string().as_str() == i {
-				    let value = walk_through(body, context, module_name);
-				    return value;
-				}
-			    }
-			    Err(_) => { continue; },
-			}
+		    Err(e) => {
+			context.pop_frame();
+			return Err(e);
 		    },
-		    [Sexpr::Atom(Atom::Char(c)), body] => {
-			match value.get_char(context) {
-			    Ok(value) => {
-				if value == *c {
-				    let value = walk_through(body, context, module_name);
-				    return value;
-				}
-			    }
-			    Err(_) => { continue; },
-			}
-		    },
-		    [Sexpr::Atom(Atom::String(s)), body] => {
-			match value.get_string(context) {
-			    Ok(value) => {
-				if value == s {
-				    let value = walk_through(body, context, module_name);
-				    return value;
-				}
-			    }
-			    Err(_) => { continue; },
-			}
-		    },
-		    [Sexpr::Atom(Atom::Null), body] => {
-			if value.is_nil() {
-			    let value = walk_through(body, context, module_name);
-			    return value;
-			}
-		    },
-		    [Sexpr::VectorList(_), _] => {
-			todo!("matching on vectors");
-		    },
-		    [Sexpr::QuotedList(_), _] => {
-			todo!("matching on lists");
-		    },
-		    _ => return Err(Box::new(Exception::new(&vec!["match"], "unusual syntax 8", context))),
 		}
 	    }
-	    Err(Box::new(Exception::new(&vec!["match"], "no matching case and no else branch", context)))
+	    Err(Box::new(Exception::new(&vec!["match"], &format!("no case matches {} and no else branch", value), context)))
 	}
-	_ => Err(Box::new(Exception::new(&vec!["match"], "unusual syntax 9", context))),
+	_ => Err(Box::new(Exception::new(&vec!["match"], "unusual syntax", context))),
+    }
+}
+
+/// An error in a pattern, located at the pattern rather than the whole match
+pub fn pattern_error(pattern: &Sexpr, message: &str, context: &mut Context) -> Box<Exception> {
+    let previous = context.set_location(pattern.span().clone());
+    let exception = Exception::new(&vec!["match"], message, context);
+    context.set_location(previous);
+    Box::new(exception)
+}
+
+fn pattern_type_index(pattern: &Sexpr, type_name: &[String], context: &mut Context, module_name: &[String]) -> HelperResult<usize> {
+    if let Some(index) = context.get_type_index(&type_name.to_vec()) {
+	return Ok(index);
+    }
+    let type_name_with_module = module_name.iter().chain(type_name.iter()).cloned().collect();
+    context.get_type_index(&type_name_with_module).ok_or_else(|| pattern_error(pattern, "type not found", context))
+}
+
+/// Matches `value` against a `match` pattern, defining the variables the pattern binds in the current frame.
+/// Symbols bind, `_` matches anything, literals compare, `(type pattern ...)` destructures a struct or enum,
+/// `'(pattern ... . rest)` and `#(pattern ... . rest)` destructure lists and vectors and `(? predicate pattern ...)` guards
+pub fn match_pattern(pattern: &Sexpr, value: &Value, context: &mut Context, module_name: &Vec<String>) -> HelperResult<bool> {
+    match pattern {
+	Sexpr::Atom(Atom::Symbol(name), _) => {
+	    if name[0] != "_" {
+		context.define(&name[0], value.clone());
+	    }
+	    Ok(true)
+	},
+	Sexpr::Atom(Atom::Boolean(b), _) => Ok(value.is_boolean() && value.get_boolean(context)? == *b),
+	Sexpr::Atom(Atom::Integer(i), _) => Ok(value.is_integer() && value.get_integer(context)?.to_string() == *i),
+	Sexpr::Atom(Atom::Float(f), _) => Ok(value.is_float() && value.get_float(context)? == *f),
+	Sexpr::Atom(Atom::Char(c), _) => Ok(value.is_char() && value.get_char(context)? == *c),
+	Sexpr::Atom(Atom::String(s), _) => Ok(value.is_string() && value.get_string(context)? == s),
+	Sexpr::Atom(Atom::QuotedSymbol(symbol), _) => Ok(value.is_symbol() && value.get_symbol(context)? == symbol),
+	Sexpr::Atom(Atom::Null, _) => Ok(value.is_nil()),
+	Sexpr::List(list, _) => match list.as_slice() {
+	    [Sexpr::Atom(Atom::Symbol(guard), _), predicate, patterns @ ..] if guard[0] == "?" => {
+		let predicate = walk_through(predicate, context, module_name)?.ok_or_else(|| pattern_error(pattern, "guard is not a procedure", context))?;
+		let result = predicate.get_function(context)?.call_raw(vec![value.clone()], Kwargs::new(), context, module_name)?;
+		let passed = match result {
+		    Some(result) => result.get_boolean(context)?,
+		    None => false,
+		};
+		if !passed {
+		    return Ok(false);
+		}
+		for pattern in patterns {
+		    if !match_pattern(pattern, value, context, module_name)? {
+			return Ok(false);
+		    }
+		}
+		Ok(true)
+	    },
+	    [Sexpr::Atom(Atom::Symbol(type_name), _), fields @ ..] => {
+		let type_index = pattern_type_index(pattern, type_name, context, module_name)?;
+		if value.get_type_index() != type_index {
+		    return Ok(false);
+		}
+		let members = if context.is_enum(type_index) {
+		    let Some(Sexpr::Atom(Atom::Symbol(variant_name), _)) = fields.first() else {
+			return Err(pattern_error(pattern, "enum pattern has no variant", context));
+		    };
+		    let variant_index = pattern_type_index(pattern, variant_name, context, module_name)?;
+		    let enumeration = value.get_enum(context)?;
+		    if enumeration.get_variant_index() != variant_index {
+			return Ok(false);
+		    }
+		    enumeration.get_members().to_vec()
+		} else {
+		    value.get_struct(context)?.get_members().to_vec()
+		};
+		let fields = if context.is_enum(type_index) { &fields[1..] } else { fields };
+		if fields.len() != members.len() {
+		    return Err(pattern_error(pattern, &format!("pattern has {} fields but the value has {}", fields.len(), members.len()), context));
+		}
+		for (field, member) in fields.iter().zip(members.iter()) {
+		    if !match_pattern(field, member, context, module_name)? {
+			return Ok(false);
+		    }
+		}
+		Ok(true)
+	    },
+	    _ => Err(pattern_error(pattern, "unusual syntax", context)),
+	},
+	Sexpr::QuotedList(patterns, _) => {
+	    let mut value = value.clone();
+	    let mut patterns = patterns.iter();
+	    while let Some(element) = patterns.next() {
+		if let Sexpr::Atom(Atom::Symbol(dot), _) = element {
+		    if dot[0] == "." {
+			let (Some(rest), None) = (patterns.next(), patterns.next()) else {
+			    return Err(pattern_error(pattern, "expected one pattern after .", context));
+			};
+			return match_pattern(rest, &value, context, module_name);
+		    }
+		}
+		if !value.is_pair() {
+		    return Ok(false);
+		}
+		let (car, cdr) = value.get_pair(context)?;
+		let (car, cdr) = (car.clone(), cdr.clone());
+		if !match_pattern(element, &car, context, module_name)? {
+		    return Ok(false);
+		}
+		value = cdr;
+	    }
+	    Ok(value.is_nil())
+	},
+	Sexpr::VectorList(patterns, _) => {
+	    if !value.is_vector() {
+		return Ok(false);
+	    }
+	    let elements = value.get_vector(context)?.clone();
+	    let (patterns, rest) = match patterns.iter().position(|p| matches!(p, Sexpr::Atom(Atom::Symbol(dot), _) if dot[0] == ".")) {
+		Some(dot) if dot + 2 == patterns.len() => (&patterns[..dot], Some(&patterns[dot + 1])),
+		Some(_) => return Err(pattern_error(pattern, "expected one pattern after .", context)),
+		None => (patterns.as_slice(), None),
+	    };
+	    let fits = match rest {
+		Some(_) => elements.len() >= patterns.len(),
+		None => elements.len() == patterns.len(),
+	    };
+	    if !fits {
+		return Ok(false);
+	    }
+	    for (element, value) in patterns.iter().zip(elements.iter()) {
+		if !match_pattern(element, value, context, module_name)? {
+		    return Ok(false);
+		}
+	    }
+	    match rest {
+		Some(rest) => {
+		    let rest_value = Value::new_vector(elements[patterns.len()..].to_vec(), context);
+		    match_pattern(rest, &rest_value, context, module_name)
+		},
+		None => Ok(true),
+	    }
+	},
+	_ => Err(pattern_error(pattern, "unusual syntax", context)),
     }
 }
 
 fn walk_through_while(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
     match list.as_slice() {
-	[_, condition, body] => {
+	[_, condition, body @ ..] if !body.is_empty() => {
 	    let mut value = Some(Value::new_nil());
 	    loop {
 		let condition = walk_through(condition, context, module_name)?;
 		match condition {
 		    Some(v) => {
 			if v.get_boolean(context)? {
-			    value = walk_through(body, context, module_name)?;
+			    value = walk_through_scoped_body(body, context, module_name)?.finish(context)?;
 			} else {
 			    break;
 			}
@@ -729,19 +1124,225 @@ fn walk_through_while(list: &Vec<Sexpr>, context: &mut Context, module_name: &Ve
 }
 	
 
-fn walk_through_call(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> InterpreterResult {
-    if let Sexpr::Atom(Atom::Symbol(name)) = &list[0] {
+fn walk_through_call(list: &Vec<Sexpr>, context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    if let Sexpr::Atom(Atom::Symbol(name), _) = &list[0] {
 	let path = module_name.iter().chain(name.iter()).map(|s| s.clone()).collect();
-        let function = match context.get(path) {
-            Some(f) => {
-                f.get_function(context)?.clone()
-            },
+        let function = match context.get(path)? {
+            Some(f) => get_procedure(&f, name, context)?,
             None => return Err(Box::new(Exception::new(&name, "not bound", context)))
         };
-        function.call(&name, &list.as_slice()[1..], context, module_name)
+        tail_call(function, name.clone(), &list[1..], context, module_name)
 
     } else {
-        let empty: Vec<&str> = Vec::new();
-        Err(Box::new(Exception::new(&empty, "unreachable", context)))
+	// Any other expression in head position is evaluated for the procedure to call
+	let name = vec![list[0].to_string()];
+	let function = walk_through(&list[0], context, module_name)?.ok_or_else(|| Box::new(Exception::new(&name, "expression didn't result in a value", context)))?;
+	let function = get_procedure(&function, &name, context)?;
+	tail_call(function, name, &list[1..], context, module_name)
+    }
+}
+
+/// The function a value in head position calls, naming the value's type when it is not a procedure
+pub fn get_procedure(value: &Value, name: &Vec<String>, context: &mut Context) -> HelperResult<Function> {
+    if value.is_function() {
+	return Ok(value.get_function(context)?.clone());
+    }
+    let type_name = context.get_type_symbol(value.get_type_index()).get_symbol(context)?.join(".");
+    Err(Box::new(Exception::new(name, &format!("not a procedure, got {} {}", type_name, value), context)))
+}
+
+/// Evaluates the arguments of a call, the call itself is made by whoever finishes the `Tail`
+fn tail_call(function: Function, name: Vec<String>, args: &[Sexpr], context: &mut Context, module_name: &Vec<String>) -> TailResult {
+    let (args, kargs) = function.evaluate_args(&name, args, context, module_name)?;
+    Ok(Tail::Call(Box::new(TailCall {
+	function,
+	name,
+	args,
+	kargs,
+	module_name: module_name.clone(),
+	location: context.get_location().clone(),
+    })))
+}
+
+fn tagged_list(tag: &str, value: Value, context: &Context) -> Value {
+    let tag = Value::new_symbol(vec![tag.to_string()], context);
+    Value::new_list(vec![tag, value], context)
+}
+
+/// Builds the data for a quasiquote template, `depth` counts how many quasiquotes enclose it
+fn walk_through_quasiquote(template: &Sexpr, depth: usize, context: &mut Context, module_name: &Vec<String>) -> HelperResult<Value> {
+    match template {
+	Sexpr::Unquote(sexpr, _) if depth == 1 => {
+	    walk_through(sexpr, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["unquote"], "expression didn't result in a value", context)))
+	},
+	Sexpr::Unquote(sexpr, _) => {
+	    let value = walk_through_quasiquote(sexpr, depth - 1, context, module_name)?;
+	    Ok(tagged_list("unquote", value, context))
+	},
+	Sexpr::UnquoteSplicing(_, _) if depth == 1 => {
+	    Err(Box::new(Exception::new(&vec!["unquote-splicing"], "not inside a list", context)))
+	},
+	Sexpr::UnquoteSplicing(sexpr, _) => {
+	    let value = walk_through_quasiquote(sexpr, depth - 1, context, module_name)?;
+	    Ok(tagged_list("unquote-splicing", value, context))
+	},
+	Sexpr::Quasiquote(sexpr, _) => {
+	    let value = walk_through_quasiquote(sexpr, depth + 1, context, module_name)?;
+	    Ok(tagged_list("quasiquote", value, context))
+	},
+	Sexpr::List(list, _) => {
+	    let values = walk_through_quasiquote_items(list, depth, context, module_name)?;
+	    Ok(Value::new_list(values, context))
+	},
+	Sexpr::QuotedList(list, _) => {
+	    let values = walk_through_quasiquote_items(list, depth, context, module_name)?;
+	    let list = Value::new_list(values, context);
+	    Ok(tagged_list("quote", list, context))
+	},
+	Sexpr::VectorList(list, _) => {
+	    let values = walk_through_quasiquote_items(list, depth, context, module_name)?;
+	    Ok(Value::new_vector(values, context))
+	},
+	Sexpr::Atom(_, _) => Ok(Value::from_sexpr(template, context)),
+    }
+}
+
+fn walk_through_quasiquote_items(list: &[Sexpr], depth: usize, context: &mut Context, module_name: &Vec<String>) -> HelperResult<Vec<Value>> {
+    let mut values = Vec::new();
+    for sexpr in list {
+	match sexpr {
+	    Sexpr::UnquoteSplicing(sexpr, _) if depth == 1 => {
+		let value = walk_through(sexpr, context, module_name)?.ok_or(Box::new(Exception::new(&vec!["unquote-splicing"], "expression didn't result in a value", context)))?;
+		if value.is_vector() {
+		    values.extend(value.get_vector(context)?.iter().cloned());
+		    continue;
+		}
+		let mut current = value;
+		while current.is_pair() {
+		    let (car, cdr) = current.get_pair(context)?;
+		    values.push(car.clone());
+		    let cdr = cdr.clone();
+		    current = cdr;
+		}
+		if !current.is_nil() {
+		    return Err(Box::new(Exception::new(&vec!["unquote-splicing"], "value is not a list", context)));
+		}
+	    },
+	    sexpr => values.push(walk_through_quasiquote(sexpr, depth, context, module_name)?),
+	}
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, RwLock};
+    use super::*;
+
+    pub(crate) fn new_context() -> Context {
+	let (sender, receiver) = std::sync::mpsc::channel();
+	// Nothing collects in tests, the receiver is kept so that values can still be sent to the collector
+	std::mem::forget(receiver);
+	Context::new(Arc::new(RwLock::new(())), sender, HashSet::new())
+    }
+
+    /// Runs every form of `source` with the tree walker, giving what the last one printed as or the error that stopped it
+    pub(crate) fn eval_in(source: &str, context: &mut Context) -> Result<String, Box<Exception>> {
+	let file = crate::parser::parse_with_context(source, "test.lpy", &mut context.get_macros(), context).expect("parse error");
+	let mut last = None;
+	for sexpr in file {
+	    last = walk_through(&sexpr, context, &vec![])?;
+	}
+	Ok(last.map(|value| value.to_string()).unwrap_or_default())
+    }
+
+    pub(crate) fn eval(source: &str) -> Result<String, Box<Exception>> {
+	eval_in(source, &mut new_context())
+    }
+
+    #[test]
+    fn test_backtrace_of_nested_calls() {
+	let source = "(define (inner) (error 'inner \"boom\"))
+(define (outer) (inner) 1)
+(define (top) (outer) 2)
+(top)";
+	let e = eval(source).unwrap_err();
+	assert_eq!(e.to_string(), "test.lpy:1:17: 'inner: boom");
+	let frames = e.get_backtrace().iter().map(|frame| frame.to_string()).collect::<Vec<String>>();
+	assert_eq!(frames, vec![
+	    "top (tree) called at test.lpy:4:1",
+	    "outer (tree) called at test.lpy:3:15",
+	    "inner (tree) called at test.lpy:2:17",
+	]);
+    }
+
+    #[test]
+    fn test_closures_share_captured_binding() {
+	let source = "(define (make-counter) (let ((n 0)) `(,(lambda () (set! n (+ n 1)) n) ,(lambda () n))))
+(define counter (make-counter))
+((car counter))
+((car counter))
+(define other (make-counter))
+((car other))
+((car (cdr counter)))";
+	assert_eq!(eval(source).unwrap(), "2");
+    }
+
+    #[test]
+    fn test_sequential_let_closure_keeps_its_binding() {
+	assert_eq!(eval("(let* ((x 1) (f (lambda () x)) (x 2)) (f))").unwrap(), "1");
+	assert_eq!(eval("(let* ((x 1) (f (lambda () x)) (x 2)) x)").unwrap(), "2");
+	assert_eq!(eval("(letrec* ((f (lambda () x)) (x 2)) (f))").unwrap(), "2");
+    }
+
+    /// Runs `source` on the tree walker and on the virtual machine, they have to agree
+    fn assert_match(source: &str, expected: &str) {
+	assert_eq!(eval(source).unwrap(), expected, "on the tree walker");
+	assert_eq!(crate::interpreter::bytecode::compiler::tests::eval_vm(source).unwrap(), expected, "on the virtual machine");
+    }
+
+    #[test]
+    fn test_match_nested_patterns() {
+	assert_match("(match '(1 (2 3) 4) ['(a '(b c) d) `(,a ,b ,c ,d)] [_ 'no])", "'(1 2 3 4)");
+	assert_match("(match '(1 #(2 3)) ['(a #(b c)) `(,a ,b ,c)] [_ 'no])", "'(1 2 3)");
+	assert_match("(match '(1 (2)) ['(a '(b c)) 'three] [_ 'no])", "'no");
+    }
+
+    #[test]
+    fn test_match_quoted_list_with_rest() {
+	assert_match("(match '(1 2 3 4) ['(a b . rest) rest] [_ 'no])", "'(3 4)");
+	assert_match("(match '(1 2) ['(a b . rest) rest] [_ 'no])", "nil");
+	assert_match("(match '(1) ['(a b . rest) rest] [_ 'no])", "'no");
+    }
+
+    #[test]
+    fn test_match_vector_patterns() {
+	assert_match("(match #(1 2 3) [#(a b c) `(,a ,b ,c)] [_ 'no])", "'(1 2 3)");
+	assert_match("(match #(1 2 3) [#(a . rest) rest] [_ 'no])", "#(2 3)");
+	assert_match("(match #(1 2) [#(a b c) 'three] [_ 'short])", "'short");
+	assert_match("(match '(1 2 3) [#(a b c) 'vector] [_ 'list])", "'list");
+    }
+
+    #[test]
+    fn test_match_guards() {
+	assert_match("(match 5 [(? integer? n) (* n 2)] [_ 'no])", "10");
+	assert_match("(match 5 [(? (lambda (n) (> n 10)) n) 'big] [n 'small])", "'small");
+	assert_match("(match \"s\" [(? integer? n) 'int] [_ 'other])", "'other");
+	assert_match("(match '(3 4) ['((? integer? a) b) (* a b)] [_ 'no])", "12");
+    }
+
+    #[test]
+    fn test_quoted_list_is_a_list() {
+	let mut context = new_context();
+	let file = crate::parser::parse_with_context("'(1 2 3)", "test.lpy", &mut context.get_macros(), &context).expect("parse error");
+	let sexpr = file.into_iter().next().unwrap();
+	let value = walk_through(&sexpr, &mut context, &vec![]).unwrap().unwrap();
+	assert!(value.is_pair());
+	assert!(!value.is_sexpr());
+	assert_eq!(value.to_string(), "'(1 2 3)");
+	assert_eq!(eval("(car (cdr '(1 2 3)))").unwrap(), "2");
+	assert_eq!(eval("(pair? '(1 2 3))").unwrap(), "true");
+	assert_eq!(eval("(sexpr? '(1 2 3))").unwrap(), "false");
     }
 }
@@ -9,17 +9,14 @@ use std::collections::HashSet;
 
 
 
-pub fn run_from_file(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let file_content = std::fs::read_to_string(file_name)?;
-    let mut macros = HashSet::new();
-    let file = parser::parse(&file_content, &mut macros)?;
-
+/// Creates a context with the garbage collector running, sending on the returned channel stops the collector
+fn start_context(_so_load_path: &str) -> Result<(interpreter::context::Context, std::sync::mpsc::Sender<()>), Box<dyn std::error::Error>> {
     let (tx, rx) = std::sync::mpsc::channel();
     let lock = std::sync::Arc::new(std::sync::RwLock::new(()));
     
     let gc_table = gc::GcTable::new(lock.clone(), rx);
 
-    let mut context = interpreter::context::Context::new(lock, tx, macros);
+    let mut context = interpreter::context::Context::new(lock, tx, HashSet::new());
 
     let (tx, rx) = std::sync::mpsc::channel();
     
@@ -29,11 +26,112 @@ pub fn run_from_file(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn
     });
 
     crate::ffi::load_dynamic_libs(&mut context, "ffi", ".")?;
-    interpreter::walkthrough::run(file, &mut context, &vec![])?;
+    Ok((context, tx))
+}
+
+pub fn run_from_file(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let file_content = std::fs::read_to_string(file_name)?;
+
+    let (mut context, tx) = start_context(so_load_path)?;
+    let file = parser::parse_with_context(&file_content, file_name, &mut context.get_macros(), &context)?;
+    for warning in interpreter::lint::check_matches(&file) {
+	eprintln!("{}", warning);
+    }
+    let result = interpreter::walkthrough::run(file, &mut context, &vec![]);
 
     //interpreter::walk_through::run(file, &mut context, &vec!["main".to_string()])?;
     
     tx.send(()).unwrap();
-    std::process::exit(0);// Thi is needed due to threads that are not joined
+    exit_with(result);// Thi is needed due to threads that are not joined
     //Ok(())
 }
+
+/// Runs a file on the virtual machine, compiling each top level form to bytecode before it runs
+pub fn run_from_file_with_vm(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    run_with_vm(file_name, so_load_path, false)
+}
+
+/// Like `run_from_file_with_vm`, logging each instruction the virtual machine runs and the height of its stack
+pub fn trace_file(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    run_with_vm(file_name, so_load_path, true)
+}
+
+fn run_with_vm(file_name: &str, so_load_path: &str, trace: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let file_content = std::fs::read_to_string(file_name)?;
+
+    let (mut context, tx) = start_context(so_load_path)?;
+    context.set_trace(trace);
+    context.set_vm(true);
+    let file = parser::parse_with_context(&file_content, file_name, &mut context.get_macros(), &context)?;
+    for warning in interpreter::lint::check_matches(&file) {
+	eprintln!("{}", warning);
+    }
+    let result = interpreter::bytecode::run_file(file, &mut context, &vec![]);
+
+    tx.send(()).unwrap();
+    exit_with(result);
+}
+
+/// Exits once a file has run, reporting an uncaught exception and failing if there was one
+fn exit_with(result: interpreter::HelperResult<()>) -> ! {
+    match result {
+	Ok(()) => std::process::exit(0),
+	Err(e) => {
+	    e.report();
+	    std::process::exit(1);
+	},
+    }
+}
+
+/// Checks a file without running it, failing if there is anything to warn about
+pub fn lint_file(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let file_content = std::fs::read_to_string(file_name)?;
+
+    let (context, tx) = start_context(so_load_path)?;
+    let file = parser::parse_with_context(&file_content, file_name, &mut context.get_macros(), &context)?;
+    let warnings = interpreter::lint::check_matches(&file);
+    for warning in warnings.iter() {
+	eprintln!("{}", warning);
+    }
+
+    tx.send(()).unwrap();
+    match warnings.len() {
+	0 => Ok(()),
+	1 => Err("1 warning".into()),
+	n => Err(format!("{} warnings", n).into()),
+    }
+}
+
+/// Prints the bytecode each top level form of a file compiles to without running it
+pub fn disassemble_file(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let file_content = std::fs::read_to_string(file_name)?;
+
+    let (mut context, tx) = start_context(so_load_path)?;
+    let file = parser::parse_with_context(&file_content, file_name, &mut context.get_macros(), &context)?;
+    for sexpr in file {
+	let previous = context.set_location(sexpr.span().clone());
+	let bytecode = interpreter::bytecode::compiler::compile(&sexpr, &mut context);
+	context.set_location(previous);
+	println!("{}: {}", sexpr.span(), sexpr);
+	match bytecode {
+	    Ok(bytecode) => print!("{}", interpreter::bytecode::disassembler::disassemble(&bytecode)),
+	    Err(e) => println!("{}", e),
+	}
+	println!();
+    }
+
+    tx.send(()).unwrap();
+    Ok(())
+}
+
+/// Prints every top level form of a file after macro expansion
+pub fn expand_file(file_name: &str, so_load_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let file_content = std::fs::read_to_string(file_name)?;
+
+    let (context, tx) = start_context(so_load_path)?;
+    let file = parser::parse_with_context(&file_content, file_name, &mut context.get_macros(), &context)?;
+    print!("{}", file);
+
+    tx.send(()).unwrap();
+    Ok(())
+}
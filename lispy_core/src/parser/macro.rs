@@ -1,20 +1,40 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
-use crate::parser::{Sexpr, Atom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::parser::{Sexpr, Atom, Span};
+use crate::interpreter::context::Context;
+use crate::interpreter::kwargs::Kwargs;
+use crate::interpreter::value::Value;
+use crate::interpreter::walkthrough::walk_through;
+use crate::stdlib::get_stdlib;
 
+#[derive(Clone)]
 pub struct Macro {
-    header: Vec<Sexpr>,
-    body: Sexpr,
-    variables: Vec<Vec<String>>,
-    //bound_positions: HashSet<(usize, usize)>,
+    name: Vec<String>,
+    kind: MacroKind,
+}
+
+#[derive(Clone, PartialEq)]
+enum MacroKind {
+    /// `define-syntax-rule`, a single header of fixed shape
+    Rule {
+	header: Vec<Sexpr>,
+	body: Sexpr,
+	variables: Vec<Vec<String>>,
+	//bound_positions: HashSet<(usize, usize)>,
+    },
+    /// `define-syntax` with `syntax-rules`
+    Rules(SyntaxRules),
+    /// `define-macro`, a `lambda` form the interpreter runs on the arguments as data
+    Procedure(Sexpr),
 }
 
 impl Macro {
-    pub fn new(header: Vec<Sexpr>, body: Sexpr) -> Self {
+    pub fn new(header: Vec<Sexpr>, body: Sexpr) -> Result<Self, String> {
 	let mut symbol_positions = HashMap::new();
 	let mut possible_variables = Vec::new();
-	Macro::get_possible_variables(&header, &mut possible_variables, &mut symbol_positions, 0);
+	Macro::get_possible_variables(&header, &mut possible_variables, &mut symbol_positions, 0)?;
 	let mut variables = HashSet::new();
 	Macro::find_variable_positions(&body, &mut possible_variables, &mut variables);
 
@@ -31,83 +51,154 @@ impl Macro {
 	for i in variable_positions {
 	    variables.push(possible_variables[i].clone());
 	}
+	let name = match header.first() {
+	    Some(Sexpr::Atom(Atom::Symbol(s), _)) => s.clone(),
+	    _ => return Err("the name of a macro must be a symbol".to_string()),
+	};
+	Ok(Macro {
+	    name,
+	    kind: MacroKind::Rule {
+		header,
+		body,
+		variables,
+		//bound_positions,
+	    },
+	})
+    }
+
+    pub fn new_syntax_rules(name: Vec<String>, rules: SyntaxRules) -> Self {
 	Macro {
-	    header,
-	    body,
-	    variables,
-	    //bound_positions,
+	    name,
+	    kind: MacroKind::Rules(rules),
 	}
     }
 
-    fn get_possible_variables<'a>(header: &'a Vec<Sexpr>, variables: &mut Vec<&'a Vec<String>>, bound_positions: &mut HashMap<&'a Vec<String>, (usize, usize)>, level: usize) {
+    pub fn new_procedure(name: Vec<String>, transformer: Sexpr) -> Self {
+	Macro {
+	    name,
+	    kind: MacroKind::Procedure(transformer),
+	}
+    }
+
+    pub fn get_name(&self) -> &Vec<String> {
+	&self.name
+    }
+
+    fn get_possible_variables<'a>(header: &'a Vec<Sexpr>, variables: &mut Vec<&'a Vec<String>>, bound_positions: &mut HashMap<&'a Vec<String>, (usize, usize)>, level: usize) -> Result<(), String> {
 	for (i, sexpr) in header.iter().enumerate() {
 	    match sexpr {
-		Sexpr::Atom(Atom::Symbol(s)) => {
+		Sexpr::Atom(Atom::Symbol(s), _) => {
 		    if bound_positions.contains_key(s) {
-			todo!("make an error");
+			return Err(format!("{} appears more than once in the macro header", s.join(".")));
 		    }
 		    variables.push(s);
 		    bound_positions.insert(s, (level, i));
 		},
-		Sexpr::List(l) => {
-		    Macro::get_possible_variables(l, variables, bound_positions, level + 1);
+		Sexpr::List(l, _) => {
+		    Macro::get_possible_variables(l, variables, bound_positions, level + 1)?;
 		},
 		_ => {},
 	    }
 	}
+	Ok(())
     }
 
     fn find_variable_positions<'a>(body: &'a Sexpr, possible_variables: &mut Vec<&'a Vec<String>>, variables: &mut HashSet<&'a Vec<String>>) {
 	match body {
-	    Sexpr::Atom(Atom::Symbol(s)) => {
+	    Sexpr::Atom(Atom::Symbol(s), _) => {
 		variables.insert(s);
 	    },
-	    Sexpr::List(l) => {
+	    Sexpr::List(l, _) | Sexpr::QuotedList(l, _) | Sexpr::VectorList(l, _) => {
 		for sexpr in l {
 		    Macro::find_variable_positions(sexpr, possible_variables, variables);
 		}
 	    },
+	    Sexpr::Quasiquote(sexpr, _) | Sexpr::Unquote(sexpr, _) | Sexpr::UnquoteSplicing(sexpr, _) => {
+		Macro::find_variable_positions(sexpr, possible_variables, variables);
+	    },
 	    _ => {},
 	}
     }
 
-    fn expand(&self, bindings: &MacroContext, list: Vec<Sexpr>) -> Option<Sexpr> {
-	let mut redirect_bindings = HashMap::new();
-	let level = 0;
-	Macro::bind_header(&self.header, &self.variables, &bindings, &list, level, &mut redirect_bindings)?;
+    /// `macros` are the macros defined so far, a transformer expanding macros itself sees those
+    fn expand(&self, list: Vec<Sexpr>, span: &Span, evaluator: Option<&mut Context>, macros: &HashSet<Macro>) -> Option<Sexpr> {
+	let mark = fresh_mark();
+	match &self.kind {
+	    MacroKind::Rule { header, body, variables } => {
+		let mut redirect_bindings = HashMap::new();
+		Macro::bind_header(header, variables, &list, &mut redirect_bindings)?;
+
+		Some(Macro::expand_body(body, &redirect_bindings, mark))
+	    },
+	    MacroKind::Rules(rules) => {
+		match list.first() {
+		    Some(Sexpr::Atom(Atom::Symbol(s), _)) if unmarked(s) == self.name => rules.expand(&list, span, mark),
+		    _ => None,
+		}
+	    },
+	    MacroKind::Procedure(transformer) => {
+		match list.first() {
+		    Some(Sexpr::Atom(Atom::Symbol(s), _)) if unmarked(s) == self.name => {},
+		    _ => return None,
+		}
+		let expanded = match evaluator {
+		    Some(context) => {
+			context.set_macros(macros.clone());
+			self.transform(transformer, &list[1..], span, context)
+		    },
+		    None => Err("procedural macros can only be expanded by the interpreter".to_string()),
+		};
+		Some(expanded.unwrap_or_else(|message| syntax_error(&self.name.join("."), message, span)))
+	    },
+	}
+    }
 
-	Macro::expand_body(&self.body, &self.variables, &bindings, &mut redirect_bindings, level) 
+    /// Calls the transformer with the arguments as data and turns what it returns back into code
+    fn transform(&self, transformer: &Sexpr, args: &[Sexpr], span: &Span, context: &mut Context) -> Result<Sexpr, String> {
+	let previous = context.set_location(span.clone());
+	let output = self.call_transformer(transformer, args, span, context);
+	context.set_location(previous);
+	output
+    }
+
+    fn call_transformer(&self, transformer: &Sexpr, args: &[Sexpr], span: &Span, context: &mut Context) -> Result<Sexpr, String> {
+	let function = walk_through(transformer, context, &vec![])
+	    .map_err(|e| e.to_string())?
+	    .ok_or("transformer didn't result in a value".to_string())?;
+	let function = function.get_function(context).map_err(|e| e.to_string())?.clone();
+	let args = args.iter().map(|arg| Value::from_sexpr(arg, context)).collect();
+	let output = function.call_from_bytecode(&self.name, args, Kwargs::new(), context, &vec![])
+	    .map_err(|e| e.to_string())?
+	    .ok_or("transformer didn't result in a value".to_string())?;
+	output.to_sexpr(span, context).map_err(|e| e.to_string())
     }
     
     fn bind_header<'a>(header: &'a Vec<Sexpr>,
 		  variables: &Vec<Vec<String>>,
-		  bindings: &MacroContext,
 		  source: &'a Vec<Sexpr>,
-		  level: usize,
 		  redirect_bindings: &mut HashMap<&'a Vec<String>, Sexpr>) -> Option<()> {
 	if header.len() != source.len() {
 	    return None;
 	}
 	for (header, source) in header.iter().zip(source.iter()) {
 	    match (header, &source) {
-		(Sexpr::List(l1), Sexpr::List(l2)) => {
-		    if let Some(()) = Macro::bind_header(l1, variables, bindings, l2, level + 1, redirect_bindings) {
+		(Sexpr::List(l1, _), Sexpr::List(l2, _)) => {
+		    if let Some(()) = Macro::bind_header(l1, variables, l2, redirect_bindings) {
 			continue;
 		    } else {
 			return None;
 		    }
 		},
-		(Sexpr::Atom(Atom::Symbol(s1)), Sexpr::Atom(Atom::Symbol(s2))) => {
-		    if bindings.is_bound(&s2) && variables.contains(s1) {
+		(Sexpr::Atom(Atom::Symbol(s1), _), Sexpr::Atom(Atom::Symbol(s2), _)) => {
+		    if variables.contains(s1) {
 			redirect_bindings.insert(s1, source.clone());
-		    } else if s1 != s2 {
+		    } else if *s1 != unmarked(s2) {
 			return None;
 		    }
 		},
-		(Sexpr::Atom(Atom::Symbol(s1)), x) => {
+		(Sexpr::Atom(Atom::Symbol(s1), _), x) => {
 		    if variables.contains(s1) {
 			redirect_bindings.insert(s1, (*x).clone());
-			return Some(());
 		    } else {
 			return None;
 		    }
@@ -120,136 +211,389 @@ impl Macro {
 	Some(())
     }
 
-    fn expand_body<'a>(body: &'a Sexpr,
-	      variables: &Vec<Vec<String>>,
-	      bindings: &MacroContext,
-	      redirect_bindings: &mut HashMap<&'a Vec<String>, Sexpr>,
-              level: usize) -> Option<Sexpr> {
+    /// Fills in the body with what the header bound, every other symbol is marked as introduced by this expansion
+    fn expand_body(body: &Sexpr, redirect_bindings: &HashMap<&Vec<String>, Sexpr>, mark: usize) -> Sexpr {
+	let expand_all = |list: &Vec<Sexpr>| {
+	    list.iter().map(|sexpr| Macro::expand_body(sexpr, redirect_bindings, mark)).collect()
+	};
 	match body {
-	    Sexpr::Atom(Atom::Symbol(s)) => {
-		if let Some(x) = redirect_bindings.get(s) {
-		    Some(x.clone())
-		} else {
-		    if bindings.is_bound(s) {
-			let mangled_name = s.iter().map(|s| format!("{}-{}", s, level)).collect::<Vec<String>>();
-			redirect_bindings.insert(s, Sexpr::Atom(Atom::Symbol(mangled_name.clone())));
-			Some(Sexpr::Atom(Atom::Symbol(mangled_name)))
-		    } else {
-			Some(Sexpr::Atom(Atom::Symbol(s.clone())))
-		    }
+	    Sexpr::Atom(Atom::Symbol(s), span) => {
+		match redirect_bindings.get(s) {
+		    Some(x) => x.clone(),
+		    None => Sexpr::Atom(Atom::Symbol(mark_symbol(s, mark)), span.clone()),
 		}
 	    },
-	    Sexpr::List(l) => Macro::expand_body_list(l, variables, bindings, redirect_bindings, level),
-	    _ => {
-		Some(body.clone())
-	    },
+	    Sexpr::List(l, span) => Sexpr::List(expand_all(l), span.clone()),
+	    Sexpr::QuotedList(l, span) => Sexpr::QuotedList(expand_all(l), span.clone()),
+	    Sexpr::VectorList(l, span) => Sexpr::VectorList(expand_all(l), span.clone()),
+	    Sexpr::Quasiquote(sexpr, span) => Sexpr::Quasiquote(Box::new(Macro::expand_body(sexpr, redirect_bindings, mark)), span.clone()),
+	    Sexpr::Unquote(sexpr, span) => Sexpr::Unquote(Box::new(Macro::expand_body(sexpr, redirect_bindings, mark)), span.clone()),
+	    Sexpr::UnquoteSplicing(sexpr, span) => Sexpr::UnquoteSplicing(Box::new(Macro::expand_body(sexpr, redirect_bindings, mark)), span.clone()),
+	    _ => body.clone(),
 	}
     }
 
-    fn expand_body_list<'a>(list: &'a Vec<Sexpr>,
-		   variables: &Vec<Vec<String>>,
-		   bindings: &MacroContext,
-		   redirect_bindings: &mut HashMap<&'a Vec<String>, Sexpr>,
-			    level: usize) -> Option<Sexpr> {
-	if let Sexpr::Atom(Atom::Symbol(s)) = &list[0] {
-	    match s[0].as_str() {
-		"define" => {
-		    let mut new_list = Vec::new();
-		    new_list.push(Sexpr::Atom(Atom::Symbol(s.clone())));
-		    new_list.push(list[1].clone());
-		    let expanded = Macro::expand_body(&list[2], variables, bindings, redirect_bindings, level);
-		    new_list.push(expanded?);
-		    Some(Sexpr::List(new_list))
-		},
-		"let" => {
-		    let let_bindings = &list[1];
-		    let Sexpr::List(let_bindings) = let_bindings else {
-			return None;
-		    };
-		    let body = &list[2];
-		    let mut new_bindings = Vec::new();
-		    for binding in let_bindings {
-			if let Sexpr::List(l) = binding {
-			    let mut new_binding = Vec::new();
-			    let Sexpr::Atom(Atom::Symbol(s)) = &l[0] else {
-				return None;
-			    };
-			    if bindings.is_bound(s) {
-				let mangled_name = s.iter().map(|s| format!("{}-{}", s, level)).collect::<Vec<String>>();
-				redirect_bindings.insert(s, Sexpr::Atom(Atom::Symbol(mangled_name.clone())));
-				new_binding.push(Sexpr::Atom(Atom::Symbol(mangled_name)));
-			    } else {
-				new_binding.push(Sexpr::Atom(Atom::Symbol(s.clone())));
-			    }
-			    new_binding.push(Macro::expand_body(&l[1], variables, bindings, redirect_bindings, level)?);
-			    new_bindings.push(Sexpr::List(new_binding));
-			} else {
-			    return None;
-			}
-		    }
-		    let expanded_body = Macro::expand_body(body, variables, bindings, redirect_bindings, level)?;
-		    let mut new_list = Vec::new();
-		    new_list.push(Sexpr::Atom(Atom::Symbol(s.clone())));
-		    new_list.push(Sexpr::List(new_bindings));
-		    new_list.push(expanded_body);
-		    Some(Sexpr::List(new_list))
-		},
-		"try" => {
-		    todo!();
-		},
-		_ => {
-		    let mut new_list = Vec::new();
-		    new_list.push(Sexpr::Atom(Atom::Symbol(s.clone())));
-		    for sexpr in list.iter().skip(1) {
-			let expanded = Macro::expand_body(sexpr, variables, bindings, redirect_bindings, level);
-			new_list.push(expanded?);
-		    }
-		    Some(Sexpr::List(new_list))
-		},
-	    }
-	} else {
-	    None
-	}
+}
+
+/// A form that raises `message` when it is evaluated, so a failed expansion is reported where it was written
+fn syntax_error(who: &str, message: impl Into<String>, span: &Span) -> Sexpr {
+    Sexpr::List(vec![
+	Sexpr::Atom(Atom::Symbol(vec!["error".to_string()]), span.clone()),
+	Sexpr::Atom(Atom::QuotedSymbol(vec![who.to_string()]), span.clone()),
+	Sexpr::Atom(Atom::String(message.into()), span.clone()),
+    ], span.clone())
+}
+
+static NEXT_MARK: AtomicUsize = AtomicUsize::new(1);
+
+/// Every macro expansion gets its own mark so that the symbols it introduces can be told apart
+fn fresh_mark() -> usize {
+    NEXT_MARK.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Marks a symbol as introduced by the expansion with `mark`.
+/// `{` can never appear in a symbol written in source, so a marked name can not clash with a user's name.
+fn mark_symbol(symbol: &[String], mark: usize) -> Vec<String> {
+    let mut symbol = symbol.to_vec();
+    if let Some(last) = symbol.last_mut() {
+	last.push_str(&format!("{{{}}}", mark));
     }
+    symbol
+}
+
+/// A symbol no user code can name, for bindings made by procedural macros
+pub fn gensym(base: &str) -> Vec<String> {
+    mark_symbol(&[base.to_string()], fresh_mark())
+}
 
+fn is_marked(symbol: &[String]) -> bool {
+    symbol.iter().any(|s| s.contains('{'))
+}
+
+/// The symbol as it was written in the macro definition
+fn unmarked(symbol: &[String]) -> Vec<String> {
+    symbol.iter().map(|s| match s.find('{') {
+	Some(i) => s[..i].to_string(),
+	None => s.clone(),
+    }).collect()
+}
+
+impl Macro {
+    /// Whether both define the same name the same way, equality only looks at the name
+    pub fn same_definition(&self, other: &Macro) -> bool {
+	self.name == other.name && self.kind == other.kind
+    }
+
+    /// The definition written out, the same text for the same definition from one run to the next
+    pub fn definition(&self) -> String {
+	let forms = |sexprs: &[Sexpr]| sexprs.iter().map(|sexpr| sexpr.to_string()).collect::<Vec<_>>().join(" ");
+	let kind = match &self.kind {
+	    MacroKind::Rule { header, body, .. } => format!("rule ({}) {}", forms(header), body),
+	    MacroKind::Rules(rules) => {
+		let literals = rules.literals.iter().map(|literal| literal.join(".")).collect::<Vec<_>>().join(" ");
+		let clauses = rules.rules.iter().map(|(pattern, template)| format!("({} {})", pattern, template)).collect::<Vec<_>>().join(" ");
+		format!("syntax-rules ({}) {}", literals, clauses)
+	    },
+	    MacroKind::Procedure(transformer) => format!("procedure {}", transformer),
+	};
+	format!("{} {}", self.name.join("."), kind)
+    }
 }
 
 impl PartialEq for Macro {
     fn eq(&self, other: &Self) -> bool {
-	self.header == other.header
+	self.name == other.name
     }
 }
 
 impl Hash for Macro {
     fn hash<H: Hasher>(&self, state: &mut H) {
-	self.header.hash(state);
+	self.name.hash(state);
     }
 }
 
 impl Eq for Macro {
 }
 
+/// What a pattern variable matched, one level of `Many` for every ellipsis it sits under
+#[derive(Debug, Clone)]
+enum Binding {
+    One(Sexpr),
+    Many(Vec<Binding>),
+}
+
+/// A `(syntax-rules (literal ...) (pattern template) ...)` transformer
+#[derive(Clone, PartialEq)]
+pub struct SyntaxRules {
+    literals: Vec<Vec<String>>,
+    rules: Vec<(Sexpr, Sexpr)>,
+}
+
+impl SyntaxRules {
+    /// Builds the transformer from the body of a `syntax-rules` form, including the `syntax-rules` keyword
+    fn new(form: &Vec<Sexpr>) -> Option<Self> {
+	match form.as_slice() {
+	    [Sexpr::Atom(Atom::Symbol(keyword), _), Sexpr::List(literals, _), clauses @ ..] if keyword[0] == "syntax-rules" => {
+		let literals = literals.iter().map(|literal| match literal {
+		    Sexpr::Atom(Atom::Symbol(s), _) => Some(s.clone()),
+		    _ => None,
+		}).collect::<Option<Vec<Vec<String>>>>()?;
+		let mut rules = Vec::new();
+		for clause in clauses {
+		    match clause {
+			Sexpr::List(clause, _) if clause.len() == 2 => {
+			    let Sexpr::List(_, _) = &clause[0] else {
+				return None;
+			    };
+			    rules.push((clause[0].clone(), clause[1].clone()));
+			},
+			_ => return None,
+		    }
+		}
+		Some(SyntaxRules {
+		    literals,
+		    rules,
+		})
+	    },
+	    _ => None,
+	}
+    }
+
+    /// Tries every clause in order and expands the template of the first one that matches
+    fn expand(&self, form: &[Sexpr], span: &Span, mark: usize) -> Option<Sexpr> {
+	for (pattern, template) in self.rules.iter() {
+	    let Sexpr::List(pattern, _) = pattern else {
+		continue;
+	    };
+	    let mut bindings = HashMap::new();
+	    // The keyword position is never matched against
+	    if self.match_list(&pattern[1..], &form[1..], &mut bindings) {
+		return self.expand_template(template, &bindings, mark).map(|expanded| match expanded {
+		    Sexpr::List(list, _) => Sexpr::List(list, span.clone()),
+		    expanded => expanded,
+		});
+	    }
+	}
+	None
+    }
+
+    fn is_wildcard(symbol: &[String]) -> bool {
+	symbol.len() == 1 && symbol[0] == "_"
+    }
+
+    fn is_ellipsis(sexpr: Option<&Sexpr>) -> bool {
+	matches!(sexpr, Some(Sexpr::Atom(Atom::Placeholder, _)))
+    }
+
+    fn match_pattern(&self, pattern: &Sexpr, form: &Sexpr, bindings: &mut HashMap<Vec<String>, Binding>) -> bool {
+	match (pattern, form) {
+	    (Sexpr::Atom(Atom::Symbol(s), _), _) if SyntaxRules::is_wildcard(s) => true,
+	    (Sexpr::Atom(Atom::Symbol(s), _), form) if self.literals.contains(s) => {
+		matches!(form, Sexpr::Atom(Atom::Symbol(f), _) if unmarked(f) == *s)
+	    },
+	    (Sexpr::Atom(Atom::Symbol(s), _), form) => {
+		bindings.insert(s.clone(), Binding::One(form.clone()));
+		true
+	    },
+	    (Sexpr::Atom(p, _), Sexpr::Atom(f, _)) => p == f,
+	    (Sexpr::List(p, _), Sexpr::List(f, _)) => self.match_list(p, f, bindings),
+	    (Sexpr::QuotedList(p, _), Sexpr::QuotedList(f, _)) => self.match_list(p, f, bindings),
+	    (Sexpr::VectorList(p, _), Sexpr::VectorList(f, _)) => self.match_list(p, f, bindings),
+	    _ => false,
+	}
+    }
+
+    fn match_list(&self, patterns: &[Sexpr], forms: &[Sexpr], bindings: &mut HashMap<Vec<String>, Binding>) -> bool {
+	let ellipsis = (0..patterns.len()).find(|i| SyntaxRules::is_ellipsis(patterns.get(i + 1)));
+	let Some(ellipsis) = ellipsis else {
+	    return patterns.len() == forms.len()
+		&& patterns.iter().zip(forms.iter()).all(|(p, f)| self.match_pattern(p, f, bindings));
+	};
+	let before = &patterns[..ellipsis];
+	let repeated = &patterns[ellipsis];
+	let after = &patterns[ellipsis + 2..];
+	if forms.len() < before.len() + after.len() {
+	    return false;
+	}
+	let repeat_end = forms.len() - after.len();
+	if !before.iter().zip(forms.iter()).all(|(p, f)| self.match_pattern(p, f, bindings)) {
+	    return false;
+	}
+	if !after.iter().zip(forms[repeat_end..].iter()).all(|(p, f)| self.match_pattern(p, f, bindings)) {
+	    return false;
+	}
+	let mut matches = Vec::new();
+	for form in forms[before.len()..repeat_end].iter() {
+	    let mut inner = HashMap::new();
+	    if !self.match_pattern(repeated, form, &mut inner) {
+		return false;
+	    }
+	    matches.push(inner);
+	}
+	let mut variables = Vec::new();
+	self.pattern_variables(repeated, &mut variables);
+	for variable in variables {
+	    let repeated = matches.iter_mut().map(|m| m.remove(&variable).unwrap_or(Binding::Many(Vec::new()))).collect();
+	    bindings.insert(variable, Binding::Many(repeated));
+	}
+	true
+    }
+
+    fn pattern_variables(&self, pattern: &Sexpr, variables: &mut Vec<Vec<String>>) {
+	match pattern {
+	    Sexpr::Atom(Atom::Symbol(s), _) if !SyntaxRules::is_wildcard(s) && !self.literals.contains(s) => {
+		variables.push(s.clone());
+	    },
+	    Sexpr::List(l, _) | Sexpr::QuotedList(l, _) | Sexpr::VectorList(l, _) => {
+		for sexpr in l {
+		    self.pattern_variables(sexpr, variables);
+		}
+	    },
+	    _ => {},
+	}
+    }
+
+    fn expand_template(&self, template: &Sexpr, bindings: &HashMap<Vec<String>, Binding>, mark: usize) -> Option<Sexpr> {
+	match template {
+	    Sexpr::Atom(Atom::Symbol(s), _) => match bindings.get(s) {
+		Some(Binding::One(sexpr)) => Some(sexpr.clone()),
+		// A repeated variable used without an ellipsis
+		Some(Binding::Many(_)) => None,
+		None => Some(Sexpr::Atom(Atom::Symbol(mark_symbol(s, mark)), template.span().clone())),
+	    },
+	    Sexpr::List(l, span) => Some(Sexpr::List(self.expand_template_list(l, bindings, mark)?, span.clone())),
+	    Sexpr::QuotedList(l, span) => Some(Sexpr::QuotedList(self.expand_template_list(l, bindings, mark)?, span.clone())),
+	    Sexpr::VectorList(l, span) => Some(Sexpr::VectorList(self.expand_template_list(l, bindings, mark)?, span.clone())),
+	    Sexpr::Quasiquote(sexpr, span) => Some(Sexpr::Quasiquote(Box::new(self.expand_template(sexpr, bindings, mark)?), span.clone())),
+	    Sexpr::Unquote(sexpr, span) => Some(Sexpr::Unquote(Box::new(self.expand_template(sexpr, bindings, mark)?), span.clone())),
+	    Sexpr::UnquoteSplicing(sexpr, span) => Some(Sexpr::UnquoteSplicing(Box::new(self.expand_template(sexpr, bindings, mark)?), span.clone())),
+	    Sexpr::Atom(_, _) => Some(template.clone()),
+	}
+    }
+
+    fn expand_template_list(&self, templates: &[Sexpr], bindings: &HashMap<Vec<String>, Binding>, mark: usize) -> Option<Vec<Sexpr>> {
+	let mut out = Vec::new();
+	let mut i = 0;
+	while i < templates.len() {
+	    let template = &templates[i];
+	    let mut depth = 0;
+	    while SyntaxRules::is_ellipsis(templates.get(i + depth + 1)) {
+		depth += 1;
+	    }
+	    if depth == 0 {
+		out.push(self.expand_template(template, bindings, mark)?);
+	    } else {
+		self.expand_repeated(template, depth, bindings, mark, &mut out)?;
+	    }
+	    i += depth + 1;
+	}
+	Some(out)
+    }
+
+    /// Expands `template` followed by `depth` ellipses, once for every match of the repeated variables in it
+    fn expand_repeated(&self, template: &Sexpr, depth: usize, bindings: &HashMap<Vec<String>, Binding>, mark: usize, out: &mut Vec<Sexpr>) -> Option<()> {
+	let mut variables = Vec::new();
+	SyntaxRules::template_symbols(template, &mut variables);
+	let repeated = variables.into_iter().filter_map(|v| match bindings.get(&v) {
+	    Some(Binding::Many(matches)) => Some((v, matches)),
+	    _ => None,
+	}).collect::<Vec<_>>();
+	// An ellipsis has to follow something that was matched under one
+	let count = repeated.first()?.1.len();
+	if repeated.iter().any(|(_, matches)| matches.len() != count) {
+	    return None;
+	}
+	for i in 0..count {
+	    let mut inner = bindings.clone();
+	    for (variable, matches) in repeated.iter() {
+		inner.insert(variable.clone(), matches[i].clone());
+	    }
+	    if depth == 1 {
+		out.push(self.expand_template(template, &inner, mark)?);
+	    } else {
+		self.expand_repeated(template, depth - 1, &inner, mark, out)?;
+	    }
+	}
+	Some(())
+    }
+
+    fn template_symbols(template: &Sexpr, symbols: &mut Vec<Vec<String>>) {
+	match template {
+	    Sexpr::Atom(Atom::Symbol(s), _) if !symbols.contains(s) => {
+		symbols.push(s.clone());
+	    },
+	    Sexpr::List(l, _) | Sexpr::QuotedList(l, _) | Sexpr::VectorList(l, _) => {
+		for sexpr in l {
+		    SyntaxRules::template_symbols(sexpr, symbols);
+		}
+	    },
+	    Sexpr::Quasiquote(sexpr, _) | Sexpr::Unquote(sexpr, _) | Sexpr::UnquoteSplicing(sexpr, _) => {
+		SyntaxRules::template_symbols(sexpr, symbols);
+	    },
+	    _ => {},
+	}
+    }
+}
+
+/// The local bindings in scope while expanding, mapping each name to the name it has at runtime
 struct MacroContext {
-    bindings: Vec<HashSet<Vec<String>>>,
+    bindings: Vec<HashMap<Vec<String>, Vec<String>>>,
+    /// Runs the transformers of procedural macros
+    evaluator: Option<Context>,
 }
 
 impl MacroContext {
     pub fn new() -> Self {
 	MacroContext {
-	    bindings: vec![HashSet::new()],
+	    bindings: vec![HashMap::new()],
+	    evaluator: None,
+	}
+    }
+
+    /// The transformers see the standard library and the modules `context` has loaded.
+    /// The caller may hold the lock on the macros of `context` while expanding, so the evaluator
+    /// gets its own copy of `macros` and code calling macroexpand at expansion time does not wait on it.
+    pub fn with_evaluator(context: Option<&Context>, macros: &HashSet<Macro>) -> Self {
+	let evaluator = context.map(|context| {
+	    let mut evaluator = context.clone();
+	    evaluator.set_macros(macros.clone());
+	    let stdlib = get_stdlib(&mut evaluator);
+	    evaluator.push_frame(Some(stdlib));
+	    evaluator
+	});
+	MacroContext {
+	    bindings: vec![HashMap::new()],
+	    evaluator,
 	}
     }
 
     pub fn bind(&mut self, variable: Vec<String>) {
-	self.bindings.last_mut().unwrap().insert(variable);
+	self.bind_as(variable.clone(), variable);
+    }
+
+    pub fn bind_as(&mut self, variable: Vec<String>, name: Vec<String>) {
+	self.bindings.last_mut().unwrap().insert(variable, name);
     }
 
     pub fn is_bound(&self, variable: &Vec<String>) -> bool {
-	self.bindings.iter().any(|b| b.contains(variable))
+	self.bindings.iter().any(|b| b.contains_key(variable))
+    }
+
+    /// Whether a binding other than a top level definition is in scope, these shadow macros
+    pub fn is_locally_bound(&self, variable: &Vec<String>) -> bool {
+	self.bindings.iter().skip(1).any(|b| b.contains_key(variable))
+    }
+
+    pub fn resolve(&self, variable: &Vec<String>) -> Option<&Vec<String>> {
+	self.bindings.iter().rev().find_map(|b| b.get(variable))
+    }
+
+    pub fn is_top_level(&self) -> bool {
+	self.bindings.len() == 1
     }
 
     pub fn push(&mut self) {
-	self.bindings.push(HashSet::new());
+	self.bindings.push(HashMap::new());
     }
 
     pub fn pop(&mut self) {
@@ -257,9 +601,31 @@ impl MacroContext {
     }
 }
 
-pub fn expand(mut source: Vec<Sexpr>, macros: &mut HashSet<Macro>) -> Vec<Sexpr> {
-    let mut bindings = MacroContext::new();
-    expand_real(&mut bindings, &mut source, macros)
+/// Expands every macro use in `source`, then renames what the expansions introduced so they stay hygienic.
+/// Procedural macros need `context` to run their transformers.
+pub fn expand(source: Vec<Sexpr>, macros: &mut HashSet<Macro>, context: Option<&Context>) -> Vec<Sexpr> {
+    let mut bindings = MacroContext::with_evaluator(context, macros);
+    let expanded = expand_real(&mut bindings, &source, macros);
+    let mut scopes = MacroContext::new();
+    expanded.iter().map(|sexpr| resolve(&mut scopes, sexpr)).collect()
+}
+
+/// Fully expands `form` without keeping the macros it defines, nothing is left if it only defined macros
+pub fn expand_form(form: &Sexpr, macros: &HashSet<Macro>, context: Option<&Context>) -> Option<Sexpr> {
+    let mut macros = macros.clone();
+    expand(vec![form.clone()], &mut macros, context).pop()
+}
+
+/// Expands `form` if it is a macro use, the macro uses in the expansion are left alone
+pub fn expand_once(form: &Sexpr, macros: &HashSet<Macro>, context: Option<&Context>) -> Sexpr {
+    let Sexpr::List(list, span) = form else {
+	return form.clone();
+    };
+    let mut bindings = MacroContext::with_evaluator(context, macros);
+    match macros.iter().find_map(|macro_| macro_.expand(list.clone(), span, bindings.evaluator.as_mut(), macros)) {
+	Some(expanded) => resolve(&mut MacroContext::new(), &expanded),
+	None => form.clone(),
+    }
 }
 
 fn expand_real<'a>(bindings: &mut MacroContext, source: &'a Vec<Sexpr>, macros: &mut HashSet<Macro>) -> Vec<Sexpr> {
@@ -275,10 +641,63 @@ fn expand_real<'a>(bindings: &mut MacroContext, source: &'a Vec<Sexpr>, macros:
     output
 }
 
+/// Expands the expressions of a body, internal definitions bind in the innermost scope
+fn expand_body(bindings: &mut MacroContext, body: &[Sexpr], macros: &mut HashSet<Macro>) -> Vec<Sexpr> {
+    let out = expand_real(bindings, &body.to_vec(), macros);
+    if out.is_empty() {
+	body.to_vec()
+    } else {
+	out
+    }
+}
+
+/// Whether `sexpr` is a `((catch ...) body ...)` handler or `(finally ...)` clause of a `try`, the forms before the first one are its body
+fn is_handler(sexpr: &Sexpr) -> bool {
+    let Sexpr::List(handler, _) = sexpr else {
+	return false;
+    };
+    match handler.first() {
+	Some(Sexpr::List(clause, _)) => matches!(clause.first(), Some(Sexpr::Atom(Atom::Symbol(keyword), _)) if matches!(unmarked(keyword)[0].as_str(), "catch" | "catch-all" | "catch-if")),
+	Some(Sexpr::Atom(Atom::Symbol(keyword), _)) => unmarked(keyword)[0] == "finally",
+	_ => false,
+    }
+}
+
+/// Binds the variables of a `match` pattern and expands the predicates of its guards
+fn expand_pattern(bindings: &mut MacroContext, pattern: &Sexpr, macros: &mut HashSet<Macro>) -> Sexpr {
+    match pattern {
+	Sexpr::Atom(Atom::Symbol(s), _) => {
+	    if !matches!(unmarked(s)[0].as_str(), "_" | ".") {
+		bindings.bind(s.clone());
+	    }
+	    pattern.clone()
+	},
+	Sexpr::List(list, span) => match list.as_slice() {
+	    [guard @ Sexpr::Atom(Atom::Symbol(s), _), predicate, patterns @ ..] if unmarked(s)[0] == "?" => {
+		let mut out = vec![guard.clone(), expand_real_single(bindings, predicate, macros).unwrap_or_else(|| predicate.clone())];
+		out.extend(patterns.iter().map(|p| expand_pattern(bindings, p, macros)));
+		Sexpr::List(out, span.clone())
+	    },
+	    [type_name, fields @ ..] => {
+		let mut out = vec![type_name.clone()];
+		out.extend(fields.iter().map(|p| expand_pattern(bindings, p, macros)));
+		Sexpr::List(out, span.clone())
+	    },
+	    [] => pattern.clone(),
+	},
+	Sexpr::QuotedList(list, span) => Sexpr::QuotedList(list.iter().map(|p| expand_pattern(bindings, p, macros)).collect(), span.clone()),
+	Sexpr::VectorList(list, span) => Sexpr::VectorList(list.iter().map(|p| expand_pattern(bindings, p, macros)).collect(), span.clone()),
+	_ => pattern.clone(),
+    }
+}
+
 fn expand_real_single<'a> (bindings: &mut MacroContext, sexpr: &'a Sexpr, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
     match sexpr {
-	Sexpr::List(l) => {
-	    expand_list(bindings, l, macros)
+	Sexpr::List(l, span) => {
+	    expand_list(bindings, l, span, macros)
+	},
+	Sexpr::Quasiquote(template, span) => {
+	    Some(Sexpr::Quasiquote(Box::new(expand_template(bindings, template, macros)), span.clone()))
 	},
 	x => {
 	    Some(x.clone())
@@ -286,225 +705,682 @@ fn expand_real_single<'a> (bindings: &mut MacroContext, sexpr: &'a Sexpr, macros
     }
 }
 
-fn expand_list<'a>(bindings: &mut MacroContext, list: &'a Vec<Sexpr>, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+/// Expands macro uses inside the unquoted parts of a quasiquote template
+fn expand_template(bindings: &mut MacroContext, template: &Sexpr, macros: &mut HashSet<Macro>) -> Sexpr {
+    match template {
+	Sexpr::List(l, span) => Sexpr::List(l.iter().map(|s| expand_template(bindings, s, macros)).collect(), span.clone()),
+	Sexpr::VectorList(l, span) => Sexpr::VectorList(l.iter().map(|s| expand_template(bindings, s, macros)).collect(), span.clone()),
+	Sexpr::Unquote(sexpr, span) => {
+	    let sexpr = expand_real_single(bindings, sexpr, macros).unwrap_or_else(|| (**sexpr).clone());
+	    Sexpr::Unquote(Box::new(sexpr), span.clone())
+	},
+	Sexpr::UnquoteSplicing(sexpr, span) => {
+	    let sexpr = expand_real_single(bindings, sexpr, macros).unwrap_or_else(|| (**sexpr).clone());
+	    Sexpr::UnquoteSplicing(Box::new(sexpr), span.clone())
+	},
+	x => x.clone(),
+    }
+}
+
+fn expand_list<'a>(bindings: &mut MacroContext, list: &'a Vec<Sexpr>, span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
     if list.is_empty() {
 		return None;
     }
-    if let Sexpr::Atom(Atom::Symbol(s)) = &list[0] {
-	match s[0].as_str() {
-	    "define" => {
-		let body = expand_define(bindings, list, macros);
-		let new_list = vec![Sexpr::Atom(Atom::Symbol(vec!["define".to_string()])), list[1].clone(), body];
-		Some(Sexpr::List(new_list))
-	    },
-	    "define-syntax-rule" => expand_define_syntax_rule(bindings, list, macros),
-	    "let" => {
-		//println!("{:?}\n", list);
-		match list.as_slice() {
-		    [_, Sexpr::List(let_bindings), body] => {
-			bindings.push();
-			let mut new_bindings = Vec::new();
-			for binding in let_bindings {
-			    match binding {
-				Sexpr::List(sets) => {
-				    match sets.as_slice() {
-					[Sexpr::Atom(Atom::Symbol(s)), body] => {
-					    bindings.bind(s.clone());
-					    if let Some(expanded) = expand_real_single(bindings, body, macros) {
-						new_bindings.push(Sexpr::List(vec![Sexpr::Atom(Atom::Symbol(s.clone())), expanded]));
-					    }
-					},
-					_ => {
-					    println!("{:?}", sets);
-					    todo!("make an error")
-					},
-				    }
-				},
-				_ => todo!("make an error"),
-			    }
-			}
-			let new_list = if let Some(expanded_body) = expand_real_single(bindings, body, macros) {
-			    vec![Sexpr::Atom(Atom::Symbol(vec!["let".to_string()])), Sexpr::List(new_bindings), expanded_body]
-			} else {
-			    vec![Sexpr::Atom(Atom::Symbol(vec!["let".to_string()])), Sexpr::List(new_bindings), body.clone()]
-			};
-			//println!("{:?}", new_list);
-			let out = Sexpr::List(new_list);
-			bindings.pop();
-			Some(out)
-		    },
-		    _ => todo!("make an error"),
-		}
-	    },
+    if let Sexpr::Atom(Atom::Symbol(s), _) = &list[0] {
+	if bindings.is_locally_bound(s) {
+	    return try_expand_macro(bindings, list, span, macros);
+	}
+	match unmarked(s)[0].as_str() {
+	    "define" => expand_define(bindings, list, span, macros),
+	    "define-syntax-rule" => expand_define_syntax_rule(bindings, list, span, macros),
+	    "define-syntax" => expand_define_syntax(list, span, macros),
+	    "define-macro" => expand_define_macro(bindings, list, span, macros),
+	    "define-for-syntax" | "begin-for-syntax" => expand_for_syntax(bindings, list, span, macros),
+	    "let" | "let*" | "letrec" | "letrec*" => expand_let(bindings, list, span, macros),
 	    "match" => {
-		//println!("{:?}", list);
 		match list.as_slice() {
 		    [_, value, cases @ ..] => {
 			let expanded_value = expand_real_single(bindings, value, macros);
 			let mut new_cases = Vec::new();
 			for case in cases {
 			    bindings.push();
-			    let Sexpr::List(case) = case else {
-				todo!("make an error");
+			    let Sexpr::List(case, case_span) = case else {
+				bindings.pop();
+				return Some(syntax_error("match", format!("match clause {} must be (pattern body ...)", case), span));
 			    };
 			    match case.as_slice() {
-				[Sexpr::List(clause), body] => {
-				    let clause_iter = clause.iter().skip(1);
-				    for clause in clause_iter {
-					let Sexpr::Atom(Atom::Symbol(s)) = clause else {
-					    todo!("make an error");
-					};
-					bindings.bind(s.clone());
-				    }
-				    let expanded_body = expand_real_single(bindings, body, macros);
-				    new_cases.push(Sexpr::List(vec![Sexpr::List(clause.clone()), expanded_body?]));
+				[Sexpr::Atom(Atom::Symbol(s), _), body @ ..] if !body.is_empty() && unmarked(s)[0] == "else" => {
+				    let mut new_case = vec![case[0].clone()];
+				    new_case.extend(expand_body(bindings, body, macros));
+				    new_cases.push(Sexpr::List(new_case, case_span.clone()));
 				},
-				[Sexpr::Atom(Atom::Symbol(s)), body] => {
-				    match s[0].as_str() {
-					"else" => {
-					    let expanded_body = expand_real_single(bindings, body, macros);
-					    new_cases.push(Sexpr::List(vec![Sexpr::Atom(Atom::Symbol(s.clone())), expanded_body?]));
-					},
-					_ => {
-					    bindings.bind(s.clone());
-					    let expanded_body = expand_real_single(bindings, body, macros);
-					    new_cases.push(Sexpr::List(vec![Sexpr::Atom(Atom::Symbol(s.clone())), expanded_body?]));
-					}
-				    }
+				[pattern, body @ ..] if !body.is_empty() => {
+				    let mut new_case = vec![expand_pattern(bindings, pattern, macros)];
+				    new_case.extend(expand_body(bindings, body, macros));
+				    new_cases.push(Sexpr::List(new_case, case_span.clone()));
 				},
-				[Sexpr::Atom(_), body] => {
-				    let expanded_body = expand_real_single(bindings, body, macros);
-				    new_cases.push(Sexpr::List(vec![case[0].clone(), expanded_body?]));
-				}, 
-				[Sexpr::QuotedList(_), _] => {
-				    todo!("quoted list in match");
+				_ => {
+				    bindings.pop();
+				    return Some(syntax_error("match", format!("match clause {} must be (pattern body ...)", Sexpr::List(case.clone(), case_span.clone())), span));
 				},
-				[Sexpr::VectorList(_), _] => {
-				    todo!("vector list in match");
-				}, 
-
-				_ => todo!("make an error"),
 			    }
 			    bindings.pop();
 			}
-			let mut out = vec![list[0].clone(), expanded_value?];
+			let Some(expanded_value) = expanded_value else {
+			    return Some(syntax_error("match", format!("{} has no value to match on", value), span));
+			};
+			let mut out = vec![list[0].clone(), expanded_value];
 			out.extend(new_cases);
-			//println!("{:?}", out);
-			Some(Sexpr::List(out))
+			Some(Sexpr::List(out, span.clone()))
 		    },
-		    _ => todo!("make an error"),
+		    _ => Some(syntax_error("match", "match must be (match value (pattern body ...) ...)", span)),
 		}
 	    }
 	    "try" => {
 		match list.as_slice() {
-		    [_, body, handlers @ ..] => {
+		    [_, rest @ ..] => {
+			let split = rest.iter().position(is_handler).unwrap_or(rest.len());
+			let (body, handlers) = rest.split_at(split);
 			let mut new_handlers = Vec::new();
-			let expanded_body = expand_real_single(bindings, body, macros);
+			bindings.push();
+			let expanded_body = expand_body(bindings, body, macros);
+			bindings.pop();
 			for handler in handlers {
 			    bindings.push();
-			    let Sexpr::List(handler) = handler else {
-				todo!("make an error");
+			    let Sexpr::List(handler, handler_span) = handler else {
+				bindings.pop();
+				return Some(syntax_error("try", format!("{} must be a handler after the body of try", handler), span));
 			    };
 			    match handler.as_slice() {
-				[Sexpr::List(clause), body] => {
-				    let Sexpr::Atom(Atom::Symbol(s)) = &clause[0] else {
-					todo!("make an error");
+				[Sexpr::List(clause, clause_span), body @ ..] => {
+				    let mut new_clause = clause.clone();
+				    let keyword = match clause.first() {
+					Some(Sexpr::Atom(Atom::Symbol(keyword), _)) => unmarked(keyword)[0].clone(),
+					_ => {
+					    bindings.pop();
+					    return Some(syntax_error("try", format!("{} must start with catch, catch-all or catch-if", Sexpr::List(clause.clone(), clause_span.clone())), span));
+					},
 				    };
-				    bindings.bind(s.clone());
-				    let expanded_body = expand_real_single(bindings, body, macros);
-				    new_handlers.push(Sexpr::List(vec![Sexpr::List(clause.clone()), expanded_body?]));
+				    // The predicate of catch-if is evaluated outside of the handler
+				    if let ("catch-if", Some(predicate)) = (keyword.as_str(), clause.get(1)) {
+					new_clause[1] = expand_real_single(bindings, predicate, macros).unwrap_or_else(|| predicate.clone());
+				    }
+				    let first_variable = if keyword == "catch-all" { 1 } else { 2 };
+				    for variable in clause.iter().skip(first_variable) {
+					let Sexpr::Atom(Atom::Symbol(s), _) = variable else {
+					    bindings.pop();
+					    return Some(syntax_error("try", format!("{} in {} must be a symbol", variable, Sexpr::List(clause.clone(), clause_span.clone())), span));
+					};
+					bindings.bind(s.clone());
+				    }
+				    let mut new_handler = vec![Sexpr::List(new_clause, clause_span.clone())];
+				    new_handler.extend(expand_body(bindings, body, macros));
+				    new_handlers.push(Sexpr::List(new_handler, handler_span.clone()));
+				},
+				[finally @ Sexpr::Atom(Atom::Symbol(_), _), body @ ..] => {
+				    let mut new_handler = vec![finally.clone()];
+				    new_handler.extend(expand_body(bindings, body, macros));
+				    new_handlers.push(Sexpr::List(new_handler, handler_span.clone()));
+				},
+				_ => {
+				    bindings.pop();
+				    return Some(syntax_error("try", format!("{} must be a handler after the body of try", Sexpr::List(handler.clone(), handler_span.clone())), span));
 				},
-				_ => todo!("make an error"),
 			    }
 			    bindings.pop();
 			}
-			let mut out = vec![list[0].clone(), expanded_body?];
+			let mut out = vec![list[0].clone()];
+			out.extend(expanded_body);
 			out.extend(new_handlers);
-			Some(Sexpr::List(out))
+			Some(Sexpr::List(out, span.clone()))
 		    },
-		    _ => todo!("make an error"),
+		    _ => Some(syntax_error("try", "try must be (try body ... handler ...)", span)),
 		}
 	    },
-	    _ => try_expand_macro(bindings, list, macros),
+	    _ => try_expand_macro(bindings, list, span, macros),
 	}
     } else {
-	return Some(Sexpr::List(list.clone()));
+	return Some(Sexpr::List(list.clone(), span.clone()));
     }
 }
 
-fn expand_define<'a>(bindings: &mut MacroContext, list: &'a Vec<Sexpr>, macros: &mut HashSet<Macro>) -> Sexpr {
-    match list.as_slice() {
-	[_, Sexpr::Atom(Atom::Symbol(s)), body] => {
+fn expand_define(bindings: &mut MacroContext, list: &Vec<Sexpr>, span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+    let body = match list.as_slice() {
+	[_, Sexpr::Atom(Atom::Symbol(s), _), body] => {
 	    bindings.push();
-	    let out = match body {
-		Sexpr::Atom(_) => body.clone(),
-		Sexpr::List(l) => {
-		    let out = expand_real(bindings, l, macros);
-		    Sexpr::List(out)
-		},
-		_ => body.clone(),
-	    }; 
+	    let out = expand_real_single(bindings, body, macros).unwrap_or_else(|| body.clone());
 	    bindings.pop();
 	    bindings.bind(s.clone());
-	    out
+	    vec![out]
 	},
-	[_, Sexpr::List(l), body] => {
-	    let Sexpr::Atom(Atom::Symbol(s)) = &l[0] else {
-		todo!("make an error");
+	[_, Sexpr::List(l, _), body @ ..] => {
+	    let Some(Sexpr::Atom(Atom::Symbol(s), _)) = l.first() else {
+		return Some(syntax_error("define", format!("the name in {} must be a symbol", list[1]), span));
 	    };
 	    bindings.bind(s.clone());
 	    bindings.push();
 	    for sexpr in l.iter().skip(1) {
 		match sexpr {
-		    Sexpr::Atom(Atom::Symbol(s)) => {
+		    Sexpr::Atom(Atom::Symbol(s), _) => {
 			bindings.bind(s.clone());
 		    },
+		    // Optional parameters, `[name default]`
+		    Sexpr::List(pair, _) => {
+			if let Some(Sexpr::Atom(Atom::Symbol(s), _)) = pair.first() {
+			    bindings.bind(s.clone());
+			}
+		    },
 		    _ => {},
 		}
 	    }
+	    let out = expand_body(bindings, body, macros);
+	    bindings.pop();
+	    out
+	},
+	_ => return Some(syntax_error("define", "define must be (define name value) or (define (name parameter ...) body ...)", span)),
+    };
+    let mut out = vec![list[0].clone(), list[1].clone()];
+    out.extend(body);
+    Some(Sexpr::List(out, span.clone()))
+}
+
+/// The values of a `let` or named let are expanded before any variable is bound,
+/// those of a `let*` as each variable is bound and those of a `letrec` with all of them bound
+fn expand_let(bindings: &mut MacroContext, list: &Vec<Sexpr>, span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+    let Sexpr::Atom(Atom::Symbol(keyword), _) = &list[0] else {
+	return None;
+    };
+    let keyword = unmarked(keyword)[0].clone();
+    let (name, let_bindings, bindings_span, body) = match list.as_slice() {
+	[_, name @ Sexpr::Atom(Atom::Symbol(_), _), Sexpr::List(let_bindings, bindings_span), body @ ..] if keyword == "let" => (Some(name), let_bindings, bindings_span, body),
+	[_, Sexpr::List(let_bindings, bindings_span), body @ ..] => (None, let_bindings, bindings_span, body),
+	_ => return Some(syntax_error(&keyword, format!("{} must be ({} ((name value) ...) body ...)", keyword, keyword), span)),
+    };
+    let mut pairs = Vec::new();
+    for binding in let_bindings {
+	let Sexpr::List(pair, pair_span) = binding else {
+	    return Some(syntax_error(&keyword, format!("let binding {} must be (name value)", binding), span));
+	};
+	let [variable @ Sexpr::Atom(Atom::Symbol(s), _), value] = pair.as_slice() else {
+	    return Some(syntax_error(&keyword, format!("let binding {} must be (name value)", binding), span));
+	};
+	pairs.push((variable, s, value, pair_span));
+    }
+    let outer_values = if keyword == "let" {
+	Some(pairs.iter().map(|(_, _, value, _)| expand_real_single(bindings, value, macros).unwrap_or_else(|| (*value).clone())).collect::<Vec<Sexpr>>())
+    } else {
+	None
+    };
+    bindings.push();
+    if let Some(Sexpr::Atom(Atom::Symbol(s), _)) = name {
+	bindings.bind(s.clone());
+    }
+    if keyword.starts_with("letrec") {
+	for (_, s, _, _) in pairs.iter() {
+	    bindings.bind((*s).clone());
+	}
+    }
+    let mut new_bindings = Vec::new();
+    for (i, (variable, s, value, pair_span)) in pairs.into_iter().enumerate() {
+	let value = match &outer_values {
+	    Some(values) => values[i].clone(),
+	    None => expand_real_single(bindings, value, macros).unwrap_or_else(|| value.clone()),
+	};
+	bindings.bind(s.clone());
+	new_bindings.push(Sexpr::List(vec![variable.clone(), value], pair_span.clone()));
+    }
+    let mut out = vec![list[0].clone()];
+    out.extend(name.cloned());
+    out.push(Sexpr::List(new_bindings, bindings_span.clone()));
+    out.extend(expand_body(bindings, body, macros));
+    bindings.pop();
+    Some(Sexpr::List(out, span.clone()))
+}
+
+fn expand_define_syntax_rule<'a>(bindings: &mut MacroContext, list: &'a Vec<Sexpr>, span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+    match list.as_slice() {
+	[_, Sexpr::List(header, _), body] => {
 	    let out = match body {
-		Sexpr::Atom(_) => body.clone(),
-		Sexpr::List(l) => {
+		Sexpr::List(l, span) => {
 		    let out = expand_real(bindings, l, macros);
-		    Sexpr::List(out)
+		    Sexpr::List(out, span.clone())
 		},
+		Sexpr::Quasiquote(_, _) => expand_real_single(bindings, body, macros).unwrap_or_else(|| body.clone()),
 		_ => body.clone(),
 	    };
-	    bindings.pop();
-	    return out;
+	    match Macro::new(header.clone(), out) {
+		Ok(macro_) => {
+		    macros.replace(macro_);
+		},
+		Err(message) => return Some(syntax_error("define-syntax-rule", message, span)),
+	    }
 	},
-	_ => {
-	    println!("{:?}", list);
-	    todo!("make an error")
+	_ => return Some(syntax_error("define-syntax-rule", "define-syntax-rule must be (define-syntax-rule (name pattern ...) template)", span)),
+    }
+    None
+}
+
+fn expand_define_syntax(list: &Vec<Sexpr>, span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+    match list.as_slice() {
+	[_, Sexpr::Atom(Atom::Symbol(name), _), Sexpr::List(rules, _)] => {
+	    let Some(rules) = SyntaxRules::new(rules) else {
+		return Some(syntax_error("define-syntax", "syntax-rules must be (syntax-rules (literal ...) ((pattern ...) template) ...)", span));
+	    };
+	    macros.replace(Macro::new_syntax_rules(name.clone(), rules));
 	},
+	_ => return Some(syntax_error("define-syntax", "define-syntax must be (define-syntax name (syntax-rules ...))", span)),
     }
+    None
 }
 
-fn expand_define_syntax_rule<'a>(bindings: &mut MacroContext, list: &'a Vec<Sexpr>, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+fn expand_define_macro(bindings: &mut MacroContext, list: &Vec<Sexpr>, span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
     match list.as_slice() {
-	[_, Sexpr::List(header), body] => {
-	    let out = match body {
-		Sexpr::List(l) => {
-		    let out = expand_real(bindings, l, macros);
-		    Sexpr::List(out)
-		},
-		Sexpr::Atom(_) => body.clone(),
-		_ => todo!("make an error"),
+	[_, Sexpr::List(header, header_span), body] if !header.is_empty() => {
+	    let Sexpr::Atom(Atom::Symbol(name), _) = &header[0] else {
+		return Some(syntax_error("define-macro", format!("the name of a macro must be a symbol, not {}", header[0]), span));
 	    };
-	    let macro_ = Macro::new(header.clone(), out);
-	    macros.insert(macro_);
+	    let body = expand_real_single(bindings, body, macros).unwrap_or_else(|| body.clone());
+	    let transformer = Sexpr::List(vec![
+		Sexpr::Atom(Atom::Symbol(vec!["lambda".to_string()]), span.clone()),
+		Sexpr::List(header[1..].to_vec(), header_span.clone()),
+		body,
+	    ], span.clone());
+	    let transformer = resolve(&mut MacroContext::new(), &transformer);
+	    macros.replace(Macro::new_procedure(unmarked(name), transformer));
 	},
-	_ => todo!("make an error"),
+	_ => return Some(syntax_error("define-macro", "define-macro must be (define-macro (name parameter ...) body)", span)),
+    }
+    None
+}
+
+/// Runs `(define-for-syntax ...)` or the forms of `(begin-for-syntax form ...)` while expanding, so the transformers
+/// of procedural macros defined after them can use what they define. Nothing is left for the program to run,
+/// so what they define can't be used outside of transformers.
+fn expand_for_syntax(bindings: &mut MacroContext, list: &[Sexpr], span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+    let Sexpr::Atom(Atom::Symbol(keyword), keyword_span) = &list[0] else {
+	return None;
+    };
+    let keyword = unmarked(keyword)[0].clone();
+    let forms = if keyword == "define-for-syntax" {
+	let mut define = list.to_vec();
+	define[0] = Sexpr::Atom(Atom::Symbol(vec!["define".to_string()]), keyword_span.clone());
+	vec![Sexpr::List(define, span.clone())]
+    } else {
+	list[1..].to_vec()
+    };
+    for form in forms {
+	let Some(form) = expand_real_single(bindings, &form, macros) else {
+	    continue;
+	};
+	let form = resolve(&mut MacroContext::new(), &form);
+	let Some(context) = bindings.evaluator.as_mut() else {
+	    return Some(syntax_error(&keyword, "code for syntax can only be run by the interpreter", span));
+	};
+	context.set_macros(macros.clone());
+	let previous = context.set_location(span.clone());
+	let result = walk_through(&form, context, &vec![]);
+	context.set_location(previous);
+	if let Err(e) = result {
+	    return Some(syntax_error(&keyword, e.to_string(), span));
+	}
     }
     None
 }
 
-fn try_expand_macro<'a>(bindings: &'a mut MacroContext, list: &'a Vec<Sexpr>, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
-    for macro_ in macros.iter() {
-	let out = macro_.expand(bindings, list.clone());
-	if let Some(out) = out {
-	    return Some(out);
+fn try_expand_macro<'a>(bindings: &'a mut MacroContext, list: &'a Vec<Sexpr>, span: &Span, macros: &mut HashSet<Macro>) -> Option<Sexpr> {
+    let shadowed = match list.first() {
+	Some(Sexpr::Atom(Atom::Symbol(s), _)) => bindings.is_locally_bound(s),
+	_ => false,
+    };
+    let expanded = if shadowed {
+	None
+    } else {
+	macros.iter().find_map(|macro_| macro_.expand(list.clone(), span, bindings.evaluator.as_mut(), macros))
+    };
+    if let Some(expanded) = expanded {
+	// The expansion may itself use macros, including the one that produced it
+	return expand_real_single(bindings, &expanded, macros);
+    }
+    let list = list.iter().map(|sexpr| expand_real_single(bindings, sexpr, macros).unwrap_or_else(|| sexpr.clone())).collect();
+    Some(Sexpr::List(list, span.clone()))
+}
+
+/// Gives every symbol its runtime name once expansion is done.
+/// Binders an expansion introduced keep their marks, so they can neither capture nor be captured by user code.
+/// Free symbols an expansion introduced lose their marks and refer to the definitions around the macro,
+/// and a user binding that would shadow such a symbol is renamed instead.
+fn resolve(scopes: &mut MacroContext, sexpr: &Sexpr) -> Sexpr {
+    match sexpr {
+	Sexpr::Atom(Atom::Symbol(s), span) => Sexpr::Atom(Atom::Symbol(resolve_symbol(scopes, s)), span.clone()),
+	Sexpr::List(l, span) => resolve_list(scopes, l, span),
+	Sexpr::VectorList(l, span) => Sexpr::VectorList(l.iter().map(|s| resolve(scopes, s)).collect(), span.clone()),
+	Sexpr::Quasiquote(template, span) => Sexpr::Quasiquote(Box::new(resolve_quasiquote(scopes, template)), span.clone()),
+	Sexpr::Unquote(sexpr, span) => Sexpr::Unquote(Box::new(resolve(scopes, sexpr)), span.clone()),
+	Sexpr::UnquoteSplicing(sexpr, span) => Sexpr::UnquoteSplicing(Box::new(resolve(scopes, sexpr)), span.clone()),
+	_ => strip_marks(sexpr),
+    }
+}
+
+fn resolve_symbol(scopes: &MacroContext, symbol: &Vec<String>) -> Vec<String> {
+    match scopes.resolve(symbol) {
+	Some(name) => name.clone(),
+	None => unmarked(symbol),
+    }
+}
+
+/// Quoted data never refers to bindings, so it only loses its marks
+fn strip_marks(sexpr: &Sexpr) -> Sexpr {
+    match sexpr {
+	Sexpr::Atom(Atom::Symbol(s), span) => Sexpr::Atom(Atom::Symbol(unmarked(s)), span.clone()),
+	Sexpr::Atom(Atom::QuotedSymbol(s), span) => Sexpr::Atom(Atom::QuotedSymbol(unmarked(s)), span.clone()),
+	Sexpr::Atom(_, _) => sexpr.clone(),
+	Sexpr::List(l, span) => Sexpr::List(l.iter().map(strip_marks).collect(), span.clone()),
+	Sexpr::QuotedList(l, span) => Sexpr::QuotedList(l.iter().map(strip_marks).collect(), span.clone()),
+	Sexpr::VectorList(l, span) => Sexpr::VectorList(l.iter().map(strip_marks).collect(), span.clone()),
+	Sexpr::Quasiquote(sexpr, span) => Sexpr::Quasiquote(Box::new(strip_marks(sexpr)), span.clone()),
+	Sexpr::Unquote(sexpr, span) => Sexpr::Unquote(Box::new(strip_marks(sexpr)), span.clone()),
+	Sexpr::UnquoteSplicing(sexpr, span) => Sexpr::UnquoteSplicing(Box::new(strip_marks(sexpr)), span.clone()),
+    }
+}
+
+fn resolve_quasiquote(scopes: &mut MacroContext, template: &Sexpr) -> Sexpr {
+    match template {
+	Sexpr::List(l, span) => Sexpr::List(l.iter().map(|s| resolve_quasiquote(scopes, s)).collect(), span.clone()),
+	Sexpr::VectorList(l, span) => Sexpr::VectorList(l.iter().map(|s| resolve_quasiquote(scopes, s)).collect(), span.clone()),
+	Sexpr::Unquote(sexpr, span) => Sexpr::Unquote(Box::new(resolve(scopes, sexpr)), span.clone()),
+	Sexpr::UnquoteSplicing(sexpr, span) => Sexpr::UnquoteSplicing(Box::new(resolve(scopes, sexpr)), span.clone()),
+	_ => strip_marks(template),
+    }
+}
+
+/// Whether `sexpr` contains a symbol some expansion introduced under the name `name`
+fn introduces(sexpr: &Sexpr, name: &Vec<String>) -> bool {
+    match sexpr {
+	Sexpr::Atom(Atom::Symbol(s), _) => is_marked(s) && unmarked(s) == *name,
+	Sexpr::Atom(_, _) => false,
+	Sexpr::List(l, _) | Sexpr::QuotedList(l, _) | Sexpr::VectorList(l, _) => l.iter().any(|s| introduces(s, name)),
+	Sexpr::Quasiquote(s, _) | Sexpr::Unquote(s, _) | Sexpr::UnquoteSplicing(s, _) => introduces(s, name),
+    }
+}
+
+/// Renames the variables a `match` pattern binds, the body of the case is their scope
+fn resolve_pattern(scopes: &mut MacroContext, pattern: &Sexpr, scope: &[&Sexpr]) -> Sexpr {
+    match pattern {
+	Sexpr::Atom(Atom::Symbol(s), _) if matches!(unmarked(s)[0].as_str(), "_" | ".") => strip_marks(pattern),
+	Sexpr::Atom(Atom::Symbol(_), _) => bind_local(scopes, pattern, scope),
+	Sexpr::List(list, span) => match list.as_slice() {
+	    [guard @ Sexpr::Atom(Atom::Symbol(s), _), predicate, patterns @ ..] if unmarked(s)[0] == "?" => {
+		let mut out = vec![strip_marks(guard), resolve(scopes, predicate)];
+		out.extend(patterns.iter().map(|p| resolve_pattern(scopes, p, scope)));
+		Sexpr::List(out, span.clone())
+	    },
+	    [type_name, fields @ ..] => {
+		let mut out = vec![strip_marks(type_name)];
+		out.extend(fields.iter().map(|p| resolve_pattern(scopes, p, scope)));
+		Sexpr::List(out, span.clone())
+	    },
+	    [] => pattern.clone(),
+	},
+	Sexpr::QuotedList(list, span) => Sexpr::QuotedList(list.iter().map(|p| resolve_pattern(scopes, p, scope)).collect(), span.clone()),
+	Sexpr::VectorList(list, span) => Sexpr::VectorList(list.iter().map(|p| resolve_pattern(scopes, p, scope)).collect(), span.clone()),
+	_ => strip_marks(pattern),
+    }
+}
+
+/// Binds a local variable in the innermost scope and returns its runtime name, `scope` is the code it is visible in
+fn bind_local(scopes: &mut MacroContext, binder: &Sexpr, scope: &[&Sexpr]) -> Sexpr {
+    let Sexpr::Atom(Atom::Symbol(variable), span) = binder else {
+	return resolve(scopes, binder);
+    };
+    let name = if !is_marked(variable) && scope.iter().any(|s| introduces(s, variable)) {
+	mark_symbol(variable, fresh_mark())
+    } else {
+	variable.clone()
+    };
+    scopes.bind_as(variable.clone(), name.clone());
+    Sexpr::Atom(Atom::Symbol(name), span.clone())
+}
+
+/// Binds the parameters of a function in order, so a default can see the parameters before it
+fn bind_parameters(scopes: &mut MacroContext, parameters: &[Sexpr], body: &[Sexpr]) -> Vec<Sexpr> {
+    let mut out = Vec::new();
+    for (i, parameter) in parameters.iter().enumerate() {
+	let scope = parameters[i + 1..].iter().chain(body).collect::<Vec<&Sexpr>>();
+	match parameter {
+	    Sexpr::Atom(Atom::Symbol(s), _) if ["#:key", "#:rest", "."].contains(&unmarked(s)[0].as_str()) => {
+		out.push(strip_marks(parameter));
+	    },
+	    Sexpr::List(pair, span) if pair.len() == 2 => {
+		let default = resolve(scopes, &pair[1]);
+		let name = bind_local(scopes, &pair[0], &scope);
+		out.push(Sexpr::List(vec![name, default], span.clone()));
+	    },
+	    parameter => out.push(bind_local(scopes, parameter, &scope)),
 	}
     }
-    Some(Sexpr::List(list.clone()))
+    out
+}
+
+/// Resolves a body in order, so internal definitions are bound before the expressions after them
+fn resolve_body(scopes: &mut MacroContext, body: &[Sexpr]) -> Vec<Sexpr> {
+    body.iter().map(|sexpr| resolve(scopes, sexpr)).collect()
+}
+
+/// Binds the variables of a let form with the same scoping the interpreter gives them, see `expand_let`
+fn resolve_let(scopes: &mut MacroContext, keyword: &str, list: &[Sexpr]) -> Vec<Sexpr> {
+    let (name, let_bindings, bindings_span, body) = match list {
+	[_, name @ Sexpr::Atom(Atom::Symbol(_), _), Sexpr::List(let_bindings, bindings_span), body @ ..] if keyword == "let" => (Some(name), let_bindings, bindings_span, body),
+	[_, Sexpr::List(let_bindings, bindings_span), body @ ..] => (None, let_bindings, bindings_span, body),
+	_ => return list.iter().map(|s| resolve(scopes, s)).collect(),
+    };
+    let pairs = let_bindings.iter().map(|binding| match binding {
+	Sexpr::List(pair, pair_span) if pair.len() == 2 => Some((pair, pair_span)),
+	_ => None,
+    }).collect::<Vec<_>>();
+    let mut new_bindings = Vec::new();
+    let name = match keyword {
+	"let" => {
+	    let values = pairs.iter().zip(let_bindings).map(|(pair, binding)| match pair {
+		Some((pair, _)) => resolve(scopes, &pair[1]),
+		None => resolve(scopes, binding),
+	    }).collect::<Vec<Sexpr>>();
+	    scopes.push();
+	    let scope = body.iter().collect::<Vec<&Sexpr>>();
+	    let name = name.map(|name| bind_local(scopes, name, &scope));
+	    for (pair, value) in pairs.iter().zip(values) {
+		match pair {
+		    Some((pair, pair_span)) => {
+			let variable = bind_local(scopes, &pair[0], &scope);
+			new_bindings.push(Sexpr::List(vec![variable, value], (*pair_span).clone()));
+		    },
+		    None => new_bindings.push(value),
+		}
+	    }
+	    name
+	},
+	"let*" => {
+	    scopes.push();
+	    for (i, (pair, binding)) in pairs.iter().zip(let_bindings).enumerate() {
+		match pair {
+		    Some((pair, pair_span)) => {
+			let value = resolve(scopes, &pair[1]);
+			let scope = let_bindings[i + 1..].iter().chain(body).collect::<Vec<&Sexpr>>();
+			let variable = bind_local(scopes, &pair[0], &scope);
+			new_bindings.push(Sexpr::List(vec![variable, value], (*pair_span).clone()));
+		    },
+		    None => new_bindings.push(resolve(scopes, binding)),
+		}
+	    }
+	    None
+	},
+	_ => {
+	    // Every value of a letrec can refer to every variable
+	    scopes.push();
+	    let scope = let_bindings.iter().chain(body).collect::<Vec<&Sexpr>>();
+	    let variables = pairs.iter().map(|pair| pair.map(|(pair, _)| bind_local(scopes, &pair[0], &scope))).collect::<Vec<Option<Sexpr>>>();
+	    for ((pair, binding), variable) in pairs.iter().zip(let_bindings).zip(variables) {
+		match (pair, variable) {
+		    (Some((pair, pair_span)), Some(variable)) => {
+			let value = resolve(scopes, &pair[1]);
+			new_bindings.push(Sexpr::List(vec![variable, value], (*pair_span).clone()));
+		    },
+		    _ => new_bindings.push(resolve(scopes, binding)),
+		}
+	    }
+	    None
+	},
+    };
+    let mut out = vec![strip_marks(&list[0])];
+    out.extend(name);
+    out.push(Sexpr::List(new_bindings, bindings_span.clone()));
+    out.extend(resolve_body(scopes, body));
+    scopes.pop();
+    out
+}
+
+fn resolve_list(scopes: &mut MacroContext, list: &Vec<Sexpr>, span: &Span) -> Sexpr {
+    let head = match list.first() {
+	Some(Sexpr::Atom(Atom::Symbol(s), _)) if !scopes.is_bound(s) => unmarked(s),
+	_ => Vec::new(),
+    };
+    let keyword = |i: usize| strip_marks(&list[i]);
+    let out = match (head.first().map(|s| s.as_str()), list.as_slice()) {
+	(Some("define"), [_, Sexpr::Atom(Atom::Symbol(name), name_span), value]) => {
+	    let name = if scopes.is_top_level() {
+		unmarked(name)
+	    } else {
+		scopes.bind(name.clone());
+		name.clone()
+	    };
+	    let value = resolve(scopes, value);
+	    vec![keyword(0), Sexpr::Atom(Atom::Symbol(name), name_span.clone()), value]
+	},
+	(Some("define"), [_, Sexpr::List(header, header_span), body @ ..]) if !header.is_empty() => {
+	    let name = match &header[0] {
+		Sexpr::Atom(Atom::Symbol(name), name_span) if scopes.is_top_level() => Sexpr::Atom(Atom::Symbol(unmarked(name)), name_span.clone()),
+		Sexpr::Atom(Atom::Symbol(name), name_span) => {
+		    scopes.bind(name.clone());
+		    Sexpr::Atom(Atom::Symbol(name.clone()), name_span.clone())
+		},
+		name => resolve(scopes, name),
+	    };
+	    scopes.push();
+	    let mut new_header = vec![name];
+	    new_header.extend(bind_parameters(scopes, &header[1..], body));
+	    let mut out = vec![keyword(0), Sexpr::List(new_header, header_span.clone())];
+	    out.extend(resolve_body(scopes, body));
+	    scopes.pop();
+	    out
+	},
+	(Some("lambda"), [_, Sexpr::List(args, args_span), body @ ..]) => {
+	    scopes.push();
+	    let args = bind_parameters(scopes, args, body);
+	    let mut out = vec![keyword(0), Sexpr::List(args, args_span.clone())];
+	    out.extend(resolve_body(scopes, body));
+	    scopes.pop();
+	    out
+	},
+	(Some("lambda"), [_, rest @ Sexpr::Atom(Atom::Symbol(_), _), body @ ..]) => {
+	    scopes.push();
+	    let rest = bind_local(scopes, rest, &body.iter().collect::<Vec<&Sexpr>>());
+	    let mut out = vec![keyword(0), rest];
+	    out.extend(resolve_body(scopes, body));
+	    scopes.pop();
+	    out
+	},
+	(Some("restart-case"), [_, form, clauses @ ..]) => {
+	    let mut out = vec![keyword(0), resolve(scopes, form)];
+	    for clause in clauses {
+		let Sexpr::List(clause_list, clause_span) = clause else {
+		    out.push(resolve(scopes, clause));
+		    continue;
+		};
+		match clause_list.as_slice() {
+		    [name @ Sexpr::Atom(Atom::Symbol(_), _), Sexpr::List(parameters, parameters_span), body @ ..] => {
+			scopes.push();
+			let parameters = bind_parameters(scopes, parameters, body);
+			let mut new_clause = vec![strip_marks(name), Sexpr::List(parameters, parameters_span.clone())];
+			new_clause.extend(resolve_body(scopes, body));
+			scopes.pop();
+			out.push(Sexpr::List(new_clause, clause_span.clone()));
+		    },
+		    _ => out.push(resolve(scopes, clause)),
+		}
+	    }
+	    out
+	},
+	(Some("let" | "let*" | "letrec" | "letrec*"), [_, _, _, ..]) => resolve_let(scopes, &head[0], list),
+	(Some("match"), [_, value, cases @ ..]) => {
+	    let mut out = vec![keyword(0), resolve(scopes, value)];
+	    for case in cases {
+		let Sexpr::List(case_list, case_span) = case else {
+		    out.push(resolve(scopes, case));
+		    continue;
+		};
+		let [pattern, body @ ..] = case_list.as_slice() else {
+		    out.push(resolve(scopes, case));
+		    continue;
+		};
+		let scope = body.iter().collect::<Vec<&Sexpr>>();
+		scopes.push();
+		let pattern = match pattern {
+		    Sexpr::Atom(Atom::Symbol(s), _) if unmarked(s)[0] == "else" => strip_marks(pattern),
+		    pattern => resolve_pattern(scopes, pattern, &scope),
+		};
+		let mut new_case = vec![pattern];
+		new_case.extend(resolve_body(scopes, body));
+		scopes.pop();
+		out.push(Sexpr::List(new_case, case_span.clone()));
+	    }
+	    out
+	},
+	(Some("try"), [_, rest @ ..]) => {
+	    let split = rest.iter().position(is_handler).unwrap_or(rest.len());
+	    let (body, handlers) = rest.split_at(split);
+	    let mut out = vec![keyword(0)];
+	    scopes.push();
+	    out.extend(resolve_body(scopes, body));
+	    scopes.pop();
+	    for handler in handlers {
+		match handler {
+		    Sexpr::List(handler, handler_span) if matches!(handler.first(), Some(Sexpr::Atom(Atom::Symbol(_), _))) => {
+			let mut new_handler = vec![strip_marks(&handler[0])];
+			scopes.push();
+			new_handler.extend(resolve_body(scopes, &handler[1..]));
+			scopes.pop();
+			out.push(Sexpr::List(new_handler, handler_span.clone()));
+		    },
+		    Sexpr::List(handler, handler_span) if handler.len() >= 2 => {
+			let (clause, body) = (&handler[0], &handler[1..]);
+			let Sexpr::List(clause, clause_span) = clause else {
+			    out.push(Sexpr::List(handler.iter().map(|s| resolve(scopes, s)).collect(), handler_span.clone()));
+			    continue;
+			};
+			let scope = body.iter().collect::<Vec<&Sexpr>>();
+			let first_variable = match clause.first() {
+			    Some(Sexpr::Atom(Atom::Symbol(keyword), _)) if unmarked(keyword)[0] == "catch-all" => 1,
+			    _ => 2,
+			};
+			let mut new_clause = Vec::new();
+			scopes.push();
+			for (i, part) in clause.iter().enumerate() {
+			    match i {
+				0 => new_clause.push(strip_marks(part)),
+				i if i < first_variable => new_clause.push(resolve(scopes, part)),
+				_ => new_clause.push(bind_local(scopes, part, &scope)),
+			    }
+			}
+			let mut new_handler = vec![Sexpr::List(new_clause, clause_span.clone())];
+			new_handler.extend(resolve_body(scopes, body));
+			scopes.pop();
+			out.push(Sexpr::List(new_handler, handler_span.clone()));
+		    },
+		    handler => out.push(resolve(scopes, handler)),
+		}
+	    }
+	    out
+	},
+	_ => list.iter().map(|s| resolve(scopes, s)).collect(),
+    };
+    Sexpr::List(out, span.clone())
 }
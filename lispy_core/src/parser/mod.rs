@@ -2,9 +2,11 @@ pub mod r#macro;
 
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 use std::{str::FromStr, cell::RefCell};
 
 use self::r#macro::Macro;
+use crate::interpreter::context::Context;
 
 #[derive(Debug, PartialEq)]
 pub struct File {
@@ -19,6 +21,10 @@ impl File {
 	    body,
 	}
     }
+
+    pub fn get_body(&self) -> &[Sexpr] {
+	&self.body
+    }
 }
 
 impl std::fmt::Display for File {
@@ -44,15 +50,100 @@ impl Iterator for File {
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct ProtoFile {
     body: Vec<FileObject>,
 }
 
+#[derive(Debug, PartialEq)]
 pub enum FileObject {
     Sexpr(Sexpr),
     Comment,
 }
 
+/// Where a form was written in its source file.
+/// Spans are only metadata: they never take part in equality or hashing,
+/// so two forms that differ only in their position compare equal.
+#[derive(Debug, Clone, Default)]
+pub struct Span {
+    pub file: Option<Arc<str>>,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(file: Option<Arc<str>>, line: usize, column: usize) -> Self {
+	Span {
+	    file,
+	    offset: 0,
+	    line,
+	    column,
+	}
+    }
+
+    /// A span that only knows its byte offset, line and column are filled in by `locate`.
+    fn at(offset: usize) -> Self {
+	Span {
+	    file: None,
+	    offset,
+	    line: 0,
+	    column: 0,
+	}
+    }
+
+    pub fn is_known(&self) -> bool {
+	self.line != 0
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _: &Self) -> bool {
+	true
+    }
+}
+
+impl Hash for Span {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match &self.file {
+	    Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+	    None => write!(f, "{}:{}", self.line, self.column),
+	}
+    }
+}
+
+/// Byte offsets of the start of every line, used to turn parser offsets into lines and columns.
+struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(input: &str) -> Self {
+	let mut line_starts = vec![0];
+	for (i, c) in input.char_indices() {
+	    if c == '\n' {
+		line_starts.push(i + 1);
+	    }
+	}
+	LineIndex {
+	    line_starts,
+	}
+    }
+
+    fn line_column(&self, input: &str, offset: usize) -> (usize, usize) {
+	let line = match self.line_starts.binary_search(&offset) {
+	    Ok(line) => line,
+	    Err(line) => line - 1,
+	};
+	let column = input[self.line_starts[line]..offset].chars().count();
+	(line + 1, column + 1)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Atom {
     String(String),
@@ -122,38 +213,90 @@ impl Hash for Atom {
 
 #[derive(Debug, PartialEq, Clone, Hash)]
 pub enum Sexpr {
-    Atom(Atom),
-    List(Vec<Sexpr>),
-    QuotedList(Vec<Sexpr>),
-    VectorList(Vec<Sexpr>),
-    
+    Atom(Atom, Span),
+    List(Vec<Sexpr>, Span),
+    QuotedList(Vec<Sexpr>, Span),
+    VectorList(Vec<Sexpr>, Span),
+    /// `` `x ``: a template that is quoted except where it is unquoted
+    Quasiquote(Box<Sexpr>, Span),
+    /// `,x`: evaluated and inserted into the enclosing quasiquote
+    Unquote(Box<Sexpr>, Span),
+    /// `,@x`: evaluated and spliced into the enclosing quasiquote's list
+    UnquoteSplicing(Box<Sexpr>, Span),
+}
+
+impl Sexpr {
+    pub fn span(&self) -> &Span {
+	match self {
+	    Sexpr::Atom(_, span) => span,
+	    Sexpr::List(_, span) => span,
+	    Sexpr::QuotedList(_, span) => span,
+	    Sexpr::VectorList(_, span) => span,
+	    Sexpr::Quasiquote(_, span) => span,
+	    Sexpr::Unquote(_, span) => span,
+	    Sexpr::UnquoteSplicing(_, span) => span,
+	}
+    }
+
+    fn locate(&mut self, input: &str, index: &LineIndex, file: &Option<Arc<str>>) {
+	let (span, children) = match self {
+	    Sexpr::Atom(_, span) => (span, None),
+	    Sexpr::List(l, span) => (span, Some(l.iter_mut().collect::<Vec<_>>())),
+	    Sexpr::QuotedList(l, span) => (span, Some(l.iter_mut().collect())),
+	    Sexpr::VectorList(l, span) => (span, Some(l.iter_mut().collect())),
+	    Sexpr::Quasiquote(s, span) => (span, Some(vec![s.as_mut()])),
+	    Sexpr::Unquote(s, span) => (span, Some(vec![s.as_mut()])),
+	    Sexpr::UnquoteSplicing(s, span) => (span, Some(vec![s.as_mut()])),
+	};
+	let (line, column) = index.line_column(input, span.offset);
+	span.file = file.clone();
+	span.line = line;
+	span.column = column;
+	if let Some(children) = children {
+	    for child in children {
+		child.locate(input, index, file);
+	    }
+	}
+    }
 }
 
 impl std::fmt::Display for Sexpr {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 	match self {
-	    Sexpr::Atom(a) => write!(f, "{}", a),
-	    Sexpr::List(l) => {
+	    Sexpr::Atom(a, _) => write!(f, "{}", a),
+	    Sexpr::List(l, _) => {
 		write!(f, "(")?;
-		for sexpr in l.iter() {
-		    write!(f, "{} ", sexpr)?;
+		for (i, sexpr) in l.iter().enumerate() {
+		    if i > 0 {
+			write!(f, " ")?;
+		    }
+		    write!(f, "{}", sexpr)?;
 		}
 		write!(f, ")")
 	    },
-	    Sexpr::QuotedList(l) => {
+	    Sexpr::QuotedList(l, _) => {
 		write!(f, "'(")?;
-		for sexpr in l.iter() {
-		    write!(f, "{} ", sexpr)?;
+		for (i, sexpr) in l.iter().enumerate() {
+		    if i > 0 {
+			write!(f, " ")?;
+		    }
+		    write!(f, "{}", sexpr)?;
 		}
 		write!(f, ")")
 	    },
-	    Sexpr::VectorList(l) => {
+	    Sexpr::VectorList(l, _) => {
 		write!(f, "#(")?;
-		for sexpr in l.iter() {
-		    write!(f, "{} ", sexpr)?;
+		for (i, sexpr) in l.iter().enumerate() {
+		    if i > 0 {
+			write!(f, " ")?;
+		    }
+		    write!(f, "{}", sexpr)?;
 		}
 		write!(f, ")")
 	    },
+	    Sexpr::Quasiquote(s, _) => write!(f, "`{}", s),
+	    Sexpr::Unquote(s, _) => write!(f, ",{}", s),
+	    Sexpr::UnquoteSplicing(s, _) => write!(f, ",@{}", s),
 	}
 	}
 }
@@ -235,6 +378,7 @@ peg::parser!{
 	/ b:(boolean()) { Atom::Boolean(b) }
 	/ "nil" { Atom::Null }
 	/ "..." { Atom::Placeholder }
+	/ "." &[' '|'\t'|'\n'|'\r'] { Atom::Symbol(vec![".".to_string()]) }
 	/ q:(quoted_symbol()) { Atom::QuotedSymbol(q) }
 	/ s:(scoped_symbol()) { Atom::Symbol(s) }
 	pub(crate) rule paren_list() -> Vec<Sexpr>
@@ -250,10 +394,13 @@ peg::parser!{
 	pub(crate) rule vector_list() -> Vec<Sexpr>
 	    = ['#'] l:list() { l }
 	pub(crate) rule sexpr() -> Sexpr
-	    = q:quoted_list() { Sexpr::QuotedList(q) }
-	    / v:vector_list() { Sexpr::VectorList(v) }
-	    / a:atom() { Sexpr::Atom(a) }
-	/ l:list() { Sexpr::List(l) }
+	    = p:position!() q:quoted_list() { Sexpr::QuotedList(q, Span::at(p)) }
+	    / p:position!() ['`'] s:sexpr() { Sexpr::Quasiquote(Box::new(s), Span::at(p)) }
+	    / p:position!() [','] ['@'] s:sexpr() { Sexpr::UnquoteSplicing(Box::new(s), Span::at(p)) }
+	    / p:position!() [','] s:sexpr() { Sexpr::Unquote(Box::new(s), Span::at(p)) }
+	    / p:position!() v:vector_list() { Sexpr::VectorList(v, Span::at(p)) }
+	    / p:position!() a:atom() { Sexpr::Atom(a, Span::at(p)) }
+	/ p:position!() l:list() { Sexpr::List(l, Span::at(p)) }
 	rule comment() -> FileObject
 	    = ";" [^'\n']* ['\n'] { FileObject::Comment }
 	/ "#;" sexpr() { FileObject::Comment }
@@ -265,18 +412,32 @@ peg::parser!{
     }
 }
 
-pub fn parse(input: &str, macros: &mut HashSet<Macro>) -> Result<File, peg::error::ParseError<peg::str::LineCol>> {
+pub fn parse(input: &str, path: &str, macros: &mut HashSet<Macro>) -> Result<File, peg::error::ParseError<peg::str::LineCol>> {
+    parse_file(input, path, macros, None)
+}
+
+/// Like `parse`, but procedural macros are expanded by running their transformers in `context`
+pub fn parse_with_context(input: &str, path: &str, macros: &mut HashSet<Macro>, context: &Context) -> Result<File, peg::error::ParseError<peg::str::LineCol>> {
+    parse_file(input, path, macros, Some(context))
+}
+
+fn parse_file(input: &str, path: &str, macros: &mut HashSet<Macro>, context: Option<&Context>) -> Result<File, peg::error::ParseError<peg::str::LineCol>> {
     parser::file(input).map(|f| {
+	let index = LineIndex::new(input);
+	let path: Option<Arc<str>> = Some(Arc::from(path));
 
 	let file = File::new(f.body.into_iter().filter_map(|fo| {
 	    match fo {
-		FileObject::Sexpr(s) => Some(s),
+		FileObject::Sexpr(mut s) => {
+		    s.locate(input, &index, &path);
+		    Some(s)
+		},
 		FileObject::Comment => None,
 	    }
 	}).collect());
     //file
 	let File { body, .. } = file;
-	File::new(r#macro::expand(body, macros))
+	File::new(r#macro::expand(body, macros, context))
     })
 }
 
@@ -332,39 +493,420 @@ mod test {
 
     #[test]
     fn test_paren_list() {
-	assert_eq!(parser::paren_list("(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
+	assert_eq!(parser::paren_list("(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
     }
 
     #[test]
     fn test_bracket_list() {
-	assert_eq!(parser::bracket_list("[123 456]"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
+	assert_eq!(parser::bracket_list("[123 456]"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
     }
 
     #[test]
     fn test_brace_list() {
-	assert_eq!(parser::brace_list("{123 456}"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
+	assert_eq!(parser::brace_list("{123 456}"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
     }
 
     #[test]
     fn test_list() {
-	assert_eq!(parser::list("(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
-	assert_eq!(parser::list("[123 456]"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
-	assert_eq!(parser::list("{123 456}"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
+	assert_eq!(parser::list("(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
+	assert_eq!(parser::list("[123 456]"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
+	assert_eq!(parser::list("{123 456}"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
     }
 
     #[test]
     fn test_quoted_list() {
-	assert_eq!(parser::quoted_list("'(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
+	assert_eq!(parser::quoted_list("'(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
     }
 
     #[test]
     fn test_vector_list() {
-	assert_eq!(parser::vector_list("#(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))]));
+	assert_eq!(parser::vector_list("#(123 456)"), Ok(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())]));
     }
 
     #[test]
     fn test_file() {
-	assert_eq!(parser::file("(123 456)"), Ok(File::new(vec![Sexpr::List(vec![Sexpr::Atom(Atom::Integer("123".to_string())), Sexpr::Atom(Atom::Integer("456".to_string()))])])));
+	assert_eq!(parser::file("(123 456)"), Ok(ProtoFile { body: vec![FileObject::Sexpr(Sexpr::List(vec![Sexpr::Atom(Atom::Integer("123".to_string()), Span::default()), Sexpr::Atom(Atom::Integer("456".to_string()), Span::default())], Span::default()))] }));
 	}
 
+    #[test]
+    fn test_quasiquote() {
+	let x = Sexpr::Atom(Atom::Symbol(vec!["x".to_string()]), Span::default());
+	let xs = Sexpr::Atom(Atom::Symbol(vec!["xs".to_string()]), Span::default());
+	assert_eq!(parser::sexpr("`(a ,x ,@xs)"), Ok(Sexpr::Quasiquote(Box::new(Sexpr::List(vec![
+	    Sexpr::Atom(Atom::Symbol(vec!["a".to_string()]), Span::default()),
+	    Sexpr::Unquote(Box::new(x), Span::default()),
+	    Sexpr::UnquoteSplicing(Box::new(xs), Span::default()),
+	], Span::default())), Span::default())));
+    }
+
+    #[test]
+    fn test_syntax_rules() {
+	let source = "(define-syntax my-and (syntax-rules () [(_) #t] [(_ e) e] [(_ e1 e2 ...) (if e1 (my-and e2 ...) #f)]))
+(my-and a b c)
+(define-syntax flat (syntax-rules () [(_ (a b ...) ...) (list a ... b ... ...)]))
+(flat (1 2 3) (4 5))";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let expected = parse("(if a (if b c #f) #f)\n(list 1 4 2 3 5)", "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(file.body, expected.body);
+    }
+
+    #[test]
+    fn test_spans() {
+	let file = parse("(foo\n  (bar 1))", "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(l, span) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	assert_eq!((span.line, span.column), (1, 1));
+	assert_eq!(span.file.as_deref(), Some("test.lpy"));
+	assert_eq!((l[1].span().line, l[1].span().column), (2, 3));
+	let Sexpr::List(inner, _) = &l[1] else {
+	    panic!("expected a list");
+	};
+	assert_eq!((inner[1].span().line, inner[1].span().column), (2, 8));
+    }
+
+    /// Replaces a symbol throughout a form, for comparing expansions that contain marked names
+    fn rename(sexpr: &Sexpr, from: &str, to: &str) -> Sexpr {
+	match sexpr {
+	    Sexpr::Atom(Atom::Symbol(symbol), span) if symbol == &vec![from.to_string()] => Sexpr::Atom(Atom::Symbol(vec![to.to_string()]), span.clone()),
+	    Sexpr::List(list, span) => Sexpr::List(list.iter().map(|sexpr| rename(sexpr, from, to)).collect(), span.clone()),
+	    sexpr => sexpr.clone(),
+	}
+    }
+
+    #[test]
+    fn test_hygiene_introduced_binder() {
+	let source = "(define-syntax swap! (syntax-rules () [(_ a b) (let [(tmp a)] (begin (set! a b) (set! b tmp)))]))
+(swap! tmp other)";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(l, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	let Sexpr::List(bindings, _) = &l[1] else {
+	    panic!("expected a let");
+	};
+	let Sexpr::List(binding, _) = &bindings[0] else {
+	    panic!("expected a binding");
+	};
+	// The macro's tmp is renamed with a mark while the user's tmp is left alone
+	let Sexpr::Atom(Atom::Symbol(binder), _) = &binding[0] else {
+	    panic!("expected a symbol");
+	};
+	let [binder] = binder.as_slice() else {
+	    panic!("expected a plain symbol");
+	};
+	let mark = binder.strip_prefix("tmp{").and_then(|rest| rest.strip_suffix('}')).expect("binder has no mark");
+	assert!(mark.parse::<usize>().is_ok(), "{} is not a mark", mark);
+	let expected = parse("(let ((hidden tmp)) (begin (set! tmp other) (set! other hidden)))", "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(file.body[0], rename(&expected.body[0], "hidden", binder));
+    }
+
+    #[test]
+    fn test_hygiene_shadowed_free_identifier() {
+	let source = "(define-syntax my-or (syntax-rules () [(_ e1 e2) (let [(t e1)] (if t t e2))]))
+(let [(if #t) (t 1)] (my-or if t))";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(l, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	let Sexpr::List(bindings, _) = &l[1] else {
+	    panic!("expected a let");
+	};
+	let Sexpr::List(binding, _) = &bindings[0] else {
+	    panic!("expected a binding");
+	};
+	// The user's if is renamed so the if from the macro still means the special form
+	let Sexpr::Atom(Atom::Symbol(user_if), _) = &binding[0] else {
+	    panic!("expected a symbol");
+	};
+	assert_ne!(user_if, &vec!["if".to_string()]);
+	let Sexpr::List(expansion, _) = &l[2] else {
+	    panic!("expected the expansion");
+	};
+	let (Sexpr::List(inner, _), Sexpr::List(body, _)) = (&expansion[1], &expansion[2]) else {
+	    panic!("expected a let");
+	};
+	let Sexpr::List(inner, _) = &inner[0] else {
+	    panic!("expected a binding");
+	};
+	assert_eq!(inner[1], Sexpr::Atom(Atom::Symbol(user_if.clone()), Span::default()));
+	assert_eq!(body[0], Sexpr::Atom(Atom::Symbol(vec!["if".to_string()]), Span::default()));
+	// The macro's t does not capture the user's t
+	let Sexpr::List(user_t, _) = &bindings[1] else {
+	    panic!("expected a binding");
+	};
+	assert_eq!(body[3], user_t[0]);
+	assert_ne!(body[1], body[3]);
+    }
+
+    #[test]
+    fn test_hygiene_nested_expansions() {
+	let source = "(define-syntax my-or (syntax-rules () [(_) #f] [(_ e r ...) (let [(t e)] (if t t (my-or r ...)))]))
+(my-or a (my-or b c))";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let mut binders = Vec::new();
+	fn collect(sexpr: &Sexpr, binders: &mut Vec<Sexpr>) {
+	    if let Sexpr::List(l, _) = sexpr {
+		if let [Sexpr::Atom(Atom::Symbol(s), _), Sexpr::List(bindings, _), _] = l.as_slice() {
+		    if s[0] == "let" {
+			for binding in bindings {
+			    if let Sexpr::List(binding, _) = binding {
+				binders.push(binding[0].clone());
+			    }
+			}
+		    }
+		}
+		l.iter().for_each(|s| collect(s, binders));
+	    }
+	}
+	collect(&file.body[0], &mut binders);
+	let names = binders.iter().map(|s| s.to_string()).collect::<HashSet<String>>();
+	assert!(binders.len() >= 3);
+	assert_eq!(names.len(), binders.len());
+	assert!(!names.contains("t"));
+    }
+
+    #[test]
+    fn test_define_macro() {
+	let (sender, _receiver) = std::sync::mpsc::channel();
+	let context = Context::new(Arc::new(std::sync::RwLock::new(())), sender, HashSet::new());
+	let source = "(define-macro (twice x) `(begin ,x ,x))
+(twice (f))
+(define-macro (count-args a b c) (+ a (+ b c)))
+(count-args 1 2 3)";
+	let file = parse_with_context(source, "test.lpy", &mut HashSet::new(), &context).unwrap();
+	let expected = parse("(begin (f) (f))\n6", "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(file.body, expected.body);
+    }
+
+    #[test]
+    fn test_macroexpand() {
+	let mut macros = HashSet::new();
+	parse("(define-syntax my-and (syntax-rules () [(_) #t] [(_ e) e] [(_ e1 e2 ...) (if e1 (my-and e2 ...) #f)]))", "test.lpy", &mut macros).unwrap();
+	let form = parse("(my-and a b c)", "test.lpy", &mut HashSet::new()).unwrap().body.remove(0);
+	let once = parse("(if a (my-and b c) #f)", "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(r#macro::expand_once(&form, &macros, None), once.body[0]);
+	let full = parse("(if a (if b c #f) #f)", "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(r#macro::expand_form(&form, &macros, None), Some(full.body[0].clone()));
+    }
+
+    #[test]
+    fn test_body_with_internal_define() {
+	let source = "(define-syntax-rule (swap! a b) (let ((tmp a)) (set! a b) (set! b tmp)))
+(define (f) (define tmp 1) (define other 2) (swap! tmp other) tmp)";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(define, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	assert_eq!(define.len(), 6);
+	let Sexpr::List(internal, _) = &define[2] else {
+	    panic!("expected a define");
+	};
+	assert_eq!(internal[1], define[5]);
+	// The macro's tmp stays apart from the internal definition
+	let expected = parse("(let ((hidden tmp)) (set! tmp other) (set! other hidden))", "test.lpy", &mut HashSet::new()).unwrap();
+	let (Sexpr::List(swap, _), Sexpr::List(expected, _)) = (&define[4], &expected.body[0]) else {
+	    panic!("expected a let");
+	};
+	assert_eq!(swap[2], expected[2]);
+	assert_ne!(swap[1], expected[1]);
+    }
+
+    #[test]
+    fn test_malformed_internal_define() {
+	let file = parse("(lambda () (define x) x)\n(define (f) (define (1) 2) 3)", "test.lpy", &mut HashSet::new()).unwrap();
+	let (Sexpr::List(lambda, _), Sexpr::List(define, _)) = (&file.body[0], &file.body[1]) else {
+	    panic!("expected a list");
+	};
+	assert_eq!(lambda[2].to_string(), "(error 'define \"define must be (define name value) or (define (name parameter ...) body ...)\")");
+	assert_eq!(define[2].to_string(), "(error 'define \"the name in (1) must be a symbol\")");
+    }
+
+    #[test]
+    fn test_hygiene_let_forms() {
+	let source = "(define-syntax-rule (with-x body) (letrec* ((x 5)) body))
+(let loop ((x 99)) (with-x x))";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(named_let, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	assert_eq!(named_let[1], Sexpr::Atom(Atom::Symbol(vec!["loop".to_string()]), Span::default()));
+	let (Sexpr::List(bindings, _), Sexpr::List(inner, _)) = (&named_let[2], &named_let[3]) else {
+	    panic!("expected a named let");
+	};
+	let (Sexpr::List(binding, _), Sexpr::List(inner_bindings, _)) = (&bindings[0], &inner[1]) else {
+	    panic!("expected bindings");
+	};
+	// The body refers to the loop variable, not to the x the macro binds
+	assert_eq!(inner[2], binding[0]);
+	let Sexpr::List(inner_binding, _) = &inner_bindings[0] else {
+	    panic!("expected a binding");
+	};
+	assert_ne!(inner_binding[0], binding[0]);
+    }
+
+    #[test]
+    fn test_parameter_list() {
+	let file = parse("(define (f a [b a] #:key [c 1] #:rest d) a)\n(lambda (x . y) x)", "test.lpy", &mut HashSet::new()).unwrap();
+	let expected = Sexpr::List(vec![
+	    Sexpr::Atom(Atom::Symbol(vec!["x".to_string()]), Span::default()),
+	    Sexpr::Atom(Atom::Symbol(vec![".".to_string()]), Span::default()),
+	    Sexpr::Atom(Atom::Symbol(vec!["y".to_string()]), Span::default()),
+	], Span::default());
+	let Sexpr::List(lambda, _) = &file.body[1] else {
+	    panic!("expected a list");
+	};
+	assert_eq!(lambda[1], expected);
+	let Sexpr::List(define, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	assert_eq!(define[1].to_string(), "(f a (b a) #:key (c 1) #:rest d)");
+    }
+
+    #[test]
+    fn test_hygiene_finally() {
+	let source = "(define-syntax-rule (with-done body) (let ((done 1)) (try body (finally done))))
+(with-done done)";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(expanded, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	let (Sexpr::List(binding, _), Sexpr::List(body, _)) = (&expanded[1], &expanded[2]) else {
+	    panic!("expected a let");
+	};
+	let (Sexpr::List(binding, _), Sexpr::List(finally, _)) = (&binding[0], &body[2]) else {
+	    panic!("expected a try");
+	};
+	assert_eq!(finally[0], Sexpr::Atom(Atom::Symbol(vec!["finally".to_string()]), Span::default()));
+	// The finally clause sees the macro's done, the body the user's
+	assert_eq!(finally[1], binding[0]);
+	assert_ne!(body[1], binding[0]);
+    }
+
+    #[test]
+    fn test_hygiene_catch_all() {
+	let source = "(define-syntax-rule (ignoring body) (try body ((catch-all e) e)))
+(ignoring e)";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(expanded, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	let Sexpr::List(handler, _) = &expanded[2] else {
+	    panic!("expected a handler");
+	};
+	let Sexpr::List(clause, _) = &handler[0] else {
+	    panic!("expected a clause");
+	};
+	// The handler's e is the macro's binder, the body's e is the user's
+	assert_eq!(handler[1], clause[1]);
+	assert_ne!(expanded[1], clause[1]);
+    }
+
+    #[test]
+    fn test_hygiene_restart_case() {
+	let source = "(define-syntax-rule (with-default form) (restart-case form (use-value (v) v)))
+(with-default v)";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(expanded, _) = &file.body[0] else {
+	    panic!("expected a list");
+	};
+	let Sexpr::List(restart, _) = &expanded[2] else {
+	    panic!("expected a restart");
+	};
+	let Sexpr::List(parameters, _) = &restart[1] else {
+	    panic!("expected parameters");
+	};
+	assert_eq!(restart[0], Sexpr::Atom(Atom::Symbol(vec!["use-value".to_string()]), Span::default()));
+	assert_eq!(restart[2], parameters[0]);
+	assert_ne!(expanded[1], parameters[0]);
+    }
+
+    #[test]
+    fn test_hygiene_nested_patterns() {
+	let source = "(define-syntax-rule (first-or-x l) (let ((x 0)) (match l ['(x . _) x] [_ x])))
+(first-or-x '(1))";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	let Sexpr::List(expanded, _) = &file.body[0] else {
+	    panic!("expected a let");
+	};
+	let (Sexpr::List(bindings, _), Sexpr::List(matched, _)) = (&expanded[1], &expanded[2]) else {
+	    panic!("expected a match");
+	};
+	let (Sexpr::List(binding, _), Sexpr::List(first, _), Sexpr::List(second, _)) = (&bindings[0], &matched[2], &matched[3]) else {
+	    panic!("expected cases");
+	};
+	let Sexpr::QuotedList(pattern, _) = &first[0] else {
+	    panic!("expected a list pattern");
+	};
+	// The variable in the list pattern shadows the let, the wildcard case still sees the let
+	assert_eq!(first[1], pattern[0]);
+	assert_eq!(pattern[1], Sexpr::Atom(Atom::Symbol(vec![".".to_string()]), Span::default()));
+	assert_eq!(second[1], binding[0]);
+    }
+
+    /// The first symbol of each form, malformed definitions become calls to error
+    fn heads(file: &File) -> Vec<String> {
+	file.body.iter().map(|sexpr| match sexpr {
+	    Sexpr::List(l, _) => l[0].to_string(),
+	    sexpr => sexpr.to_string(),
+	}).collect()
+    }
+
+    #[test]
+    fn test_malformed_syntax_definitions() {
+	let source = "(define-syntax foo (syntax-rules))
+(define-syntax-rule (bar x x) x)
+(define-syntax-rule (5 x) x)
+(define-syntax baz)
+(define)
+(define x)
+(define (1) x)
+(define () x)
+(define x 1 2)";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(heads(&file), vec!["error"; 9]);
+	assert_eq!(file.body[1].to_string(), "(error 'define-syntax-rule \"x appears more than once in the macro header\")");
+	assert_eq!(file.body[6].to_string(), "(error 'define \"the name in (1) must be a symbol\")");
+    }
+
+    #[test]
+    fn test_malformed_match_and_catch() {
+	let source = "(match (define-syntax-rule (m) 1) [_ 1])
+(match x 5)
+(try (f) ((catch 'who 5) 1))";
+	let file = parse(source, "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(heads(&file), vec!["error", "error", "error"]);
+	assert_eq!(file.body[2].to_string(), "(error 'try \"5 in (catch 'who 5) must be a symbol\")");
+    }
+
+    #[test]
+    fn test_define_for_syntax() {
+	let (sender, _receiver) = std::sync::mpsc::channel();
+	let context = Context::new(Arc::new(std::sync::RwLock::new(())), sender, HashSet::new());
+	let source = "(define-for-syntax (double-form x) `(+ ,x ,x))
+(begin-for-syntax (define (swap-form a b) `(- ,b ,a)))
+(define-macro (double x) (double-form x))
+(define-macro (swap a b) (swap-form a b))
+(double (swap 1 2))
+(define-macro foo 1)";
+	let file = parse_with_context(source, "test.lpy", &mut HashSet::new(), &context).unwrap();
+	let expected = parse("(+ (- 2 1) (- 2 1))", "test.lpy", &mut HashSet::new()).unwrap();
+	assert_eq!(file.body[0], expected.body[0]);
+	assert_eq!(heads(&file), vec!["+", "error"]);
+    }
+
+    #[test]
+    fn test_transformer_calls_macroexpand() {
+	let (sender, _receiver) = std::sync::mpsc::channel();
+	let context = Context::new(Arc::new(std::sync::RwLock::new(())), sender, HashSet::new());
+	let source = "(define-syntax-rule (inc x) (+ x 1))
+(define-macro (expanded-inc x) `(quote ,(macroexpand `(inc ,x))))
+(begin-for-syntax (macroexpand-1 '(inc 2)))
+(expanded-inc 5)";
+	// The file is expanded while the lock on the context's macros is held, the way files are run
+	let file = parse_with_context(source, "test.lpy", &mut context.get_macros(), &context).unwrap();
+	assert_eq!(file.body[0].to_string(), "'(+ 5 1)");
+	assert!(context.get_macros().iter().any(|macro_| macro_.get_name() == &vec!["inc".to_string()]));
+    }
+
 }
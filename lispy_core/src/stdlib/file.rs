@@ -51,8 +51,8 @@ fn stdlib_open(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) ->
 	.write(write)
 	.create(create)
 	.append(append)
-	.open(filename)
-	.map_err(|err| Box::new(Exception::new(&vec!["file","open"], &format!("{}", err), context)))?;
+	.open(&filename)
+	.map_err(|err| Box::new(Exception::new_io(&vec!["file","open"], &err, context).with_field("path", Value::new_string(&filename, context))))?;
     let file = Box::new(Some(file));
     let file = Value::new_rust_value(file, context);
     Ok(file)
@@ -75,7 +75,7 @@ fn stdlib_read_string(context: &mut Context, args: Vec<Value>, keyword_args: Kwa
     let file = file.downcast_mut::<Option<std::fs::File>>().ok_or(Box::new(Exception::new(&vec!["file","read-string"], "file is not a file", context)))?;
     let file = file.as_mut().ok_or(Box::new(Exception::new(&vec!["file","read-string"], "file is closed", context)))?;
     let mut content = String::new();
-    file.read_to_string(&mut content).map_err(|err| Box::new(Exception::new(&vec!["file","read-string"], &format!("{}", err), context)))?;
+    file.read_to_string(&mut content).map_err(|err| Box::new(Exception::new_io(&vec!["file","read-string"], &err, context)))?;
     Ok(Value::new_string(&content, context))
 }
 
@@ -103,7 +103,7 @@ fn stdlib_write_string(context: &mut Context, args: Vec<Value>, keyword_args: Kw
     let file = file.get_rust_value_mut(context)?;
     let file = file.downcast_mut::<Option<std::fs::File>>().ok_or(Box::new(Exception::new(&vec!["file","write-string"], "file is not a file", context)))?;
     let file = file.as_mut().ok_or(Box::new(Exception::new(&vec!["file","write-string"], "file is closed", context)))?;
-    file.write_all(content.as_bytes()).map_err(|err| Box::new(Exception::new(&vec!["file","write-string"], &format!("{}", err), context)))?;
+    file.write_all(content.as_bytes()).map_err(|err| Box::new(Exception::new_io(&vec!["file","write-string"], &err, context)))?;
     Ok(Value::new_nil())
 }
 
@@ -139,3 +139,15 @@ pub fn get_file_library(context: &mut Context) -> Module {
     
     Module::new_loaded(frame)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::walkthrough::tests::eval;
+
+    #[test]
+    fn test_open_error_fields() {
+	let source = "(try (file.open \"/nonexistent-lispy-directory/missing.txt\" \"c\")
+  ((catch-all e) `(,(exception-who e) ,(exception-field e 'errno) ,(exception-field e 'kind) ,(exception-field e 'path))))";
+	assert_eq!(eval(source).unwrap(), "'('file.open 2 'NotFound /nonexistent-lispy-directory/missing.txt)");
+    }
+}
@@ -8,6 +8,7 @@ use std::io::Write;
 use std::io::Read;
 use std::io::BufRead;
 use crate::interpreter::Exception;
+use crate::interpreter::condition;
 use crate::interpreter::HelperResult;
 use crate::interpreter::kwargs::Kwargs;
 
@@ -2192,6 +2193,285 @@ fn stdlib_debug_display(context: &mut Context, args: Vec<Value>, keyword_args: K
     }
 }
 
+fn stdlib_is_sexpr_shape() -> FunctionShape {
+    FunctionShape::new(vec!["x".to_string()])
+}
+
+fn stdlib_is_sexpr(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let x = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("x").ok_or(Box::new(Exception::new(&vec!["sexpr?"], "expected x to be bound", context)))?
+    };
+    Ok(Value::new_boolean(x.is_sexpr()))
+}
+
+fn stdlib_sexpr_to_list_shape() -> FunctionShape {
+    FunctionShape::new(vec!["sexpr".to_string()])
+}
+
+fn stdlib_sexpr_to_list(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let sexpr = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("sexpr").ok_or(Box::new(Exception::new(&vec!["sexpr->list"], "expected sexpr to be bound", context)))?
+    };
+    let sexpr = sexpr.get_sexpr(context)?;
+    Ok(Value::from_sexpr(sexpr, context))
+}
+
+fn stdlib_list_to_sexpr_shape() -> FunctionShape {
+    FunctionShape::new(vec!["list".to_string()])
+}
+
+fn stdlib_list_to_sexpr(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let list = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("list").ok_or(Box::new(Exception::new(&vec!["list->sexpr"], "expected list to be bound", context)))?
+    };
+    let sexpr = list.to_sexpr(context.get_location(), context)?;
+    Ok(Value::new_sexpr(sexpr, context))
+}
+
+fn stdlib_disassemble_shape() -> FunctionShape {
+    FunctionShape::new(vec!["procedure".to_string()])
+}
+
+fn stdlib_disassemble(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let procedure = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("procedure").ok_or(Box::new(Exception::new(&vec!["disassemble"], "expected procedure to be bound", context)))?
+    };
+    match procedure.get_function(context)? {
+	Function::Bytecode(_, code, shape, _, _) => {
+	    let listing = format!("procedure {}:\n{}", shape, crate::interpreter::bytecode::disassembler::disassemble(code));
+	    Ok(Value::new_string_from_string(listing, context))
+	},
+	_ => Err(Box::new(Exception::new(&vec!["disassemble"], "not a compiled procedure", context))),
+    }
+}
+
+fn stdlib_macroexpand_shape() -> FunctionShape {
+    FunctionShape::new(vec!["form".to_string()])
+}
+
+fn stdlib_macroexpand(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let form = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("form").ok_or(Box::new(Exception::new(&vec!["macroexpand"], "expected form to be bound", context)))?
+    };
+    let form = if form.is_sexpr() {
+	form.get_sexpr(context)?.clone()
+    } else {
+	form.to_sexpr(context.get_location(), context)?
+    };
+    let macros = context.read_macros().clone();
+    match crate::parser::r#macro::expand_form(&form, &macros, Some(context)) {
+	Some(form) => Ok(Value::from_sexpr(&form, context)),
+	None => Ok(Value::new_nil()),
+    }
+}
+
+fn stdlib_macroexpand_1_shape() -> FunctionShape {
+    FunctionShape::new(vec!["form".to_string()])
+}
+
+fn stdlib_macroexpand_1(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let form = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("form").ok_or(Box::new(Exception::new(&vec!["macroexpand-1"], "expected form to be bound", context)))?
+    };
+    let form = if form.is_sexpr() {
+	form.get_sexpr(context)?.clone()
+    } else {
+	form.to_sexpr(context.get_location(), context)?
+    };
+    let macros = context.read_macros().clone();
+    let form = crate::parser::r#macro::expand_once(&form, &macros, Some(context));
+    Ok(Value::from_sexpr(&form, context))
+}
+
+fn stdlib_gensym_shape() -> FunctionShape {
+    FunctionShape::new(vec![])
+}
+
+fn stdlib_gensym(context: &mut Context, _args: Vec<Value>, _keyword_args: Kwargs) -> HelperResult<Value> {
+    Ok(Value::new_symbol(crate::parser::r#macro::gensym("g"), context))
+}
+
+fn stdlib_call_with_escape_shape() -> FunctionShape {
+    FunctionShape::new(vec!["procedure".to_string()])
+}
+
+/// `call/cc` only captures an escape: calling the continuation unwinds to the `call/cc` while that call has not returned.
+/// Re-entering it once it has returned is not supported, calling it then is an error.
+fn stdlib_call_with_escape(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let procedure = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("procedure").ok_or(Box::new(Exception::new(&vec!["call/cc"], "missing argument procedure", context)))?
+    };
+    let function = procedure.get_function(context)?;
+    Ok(function.call_with_escape(context, &vec![])?.unwrap_or_else(Value::new_nil))
+}
+
+fn stdlib_dynamic_wind_shape() -> FunctionShape {
+    FunctionShape::new(vec!["before".to_string(), "thunk".to_string(), "after".to_string()])
+}
+
+fn stdlib_dynamic_wind(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    // The arguments after those given in order are taken by keyword
+    let argument = |index: usize, name: &str, context: &Context| match args.get(index).or_else(|| keyword_args.get(name)) {
+	Some(value) => value.get_function(context),
+	None => Err(Box::new(Exception::new(&vec!["dynamic-wind"], &format!("missing argument {}", name), context))),
+    };
+    let before = argument(0, "before", context)?;
+    let thunk = argument(1, "thunk", context)?;
+    let after = argument(2, "after", context)?;
+    before.call_raw(vec![], Kwargs::new(), context, &vec![])?;
+    // after runs however thunk is left, including by an error or an escaping continuation
+    let value = thunk.call_raw(vec![], Kwargs::new(), context, &vec![]);
+    after.call_raw(vec![], Kwargs::new(), context, &vec![])?;
+    Ok(value?.unwrap_or_else(Value::new_nil))
+}
+
+fn stdlib_raise_shape() -> FunctionShape {
+    FunctionShape::new(vec!["value".to_string()])
+}
+
+fn stdlib_raise(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let value = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("value").ok_or(Box::new(Exception::new(&vec!["raise"], "missing argument value", context)))?
+    };
+    // An exception object from a handler is raised again as it was, backtrace included
+    if let Some(exception) = as_exception(value, context) {
+	return Err(condition::resignal(exception, context));
+    }
+    Err(condition::signal(Box::new(Exception::new_raise(value.clone(), context)), context))
+}
+
+fn stdlib_signal_shape() -> FunctionShape {
+    FunctionShape::new(vec!["condition".to_string()])
+}
+
+/// Offers a condition to the handlers without it being an error, returns nil if none of them takes it
+fn stdlib_signal(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let condition = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("condition").ok_or(Box::new(Exception::new(&vec!["signal"], "missing argument condition", context)))?
+    };
+    let exception = condition::signal(Box::new(Exception::new_raise(condition.clone(), context)), context);
+    if exception.is_escape() || condition::get_catcher(&exception).is_some() {
+	return Err(exception);
+    }
+    Ok(Value::new_nil())
+}
+
+fn stdlib_invoke_restart_shape() -> FunctionShape {
+    FunctionShape::new(vec!["restart".to_string()]).with_rest(Some("args".to_string()))
+}
+
+fn stdlib_invoke_restart(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let (restart, values) = match (args.split_first(), keyword_args.get("restart")) {
+	(Some((restart, values)), _) => (restart, values),
+	(None, Some(restart)) => (restart, &[][..]),
+	(None, None) => return Err(Box::new(Exception::new(&vec!["invoke-restart"], "wrong number of arguments", context))),
+    };
+    let name = restart.get_symbol(context)?.join(".");
+    let Some(id) = context.find_restart(&name) else {
+	return Err(Box::new(Exception::new(&vec!["invoke-restart"], &format!("no restart named {}", name), context)));
+    };
+    let args = Value::new_vector(values.to_vec(), context);
+    Err(Box::new(Exception::new_escape(id, args, context)))
+}
+
+fn stdlib_compute_restarts_shape() -> FunctionShape {
+    FunctionShape::new(vec![])
+}
+
+fn stdlib_compute_restarts(context: &mut Context, _args: Vec<Value>, _keyword_args: Kwargs) -> HelperResult<Value> {
+    let names = context.get_restart_names().into_iter().map(|name| Value::new_symbol(vec![name], context)).collect();
+    Ok(Value::new_list(names, context))
+}
+
+fn as_exception<'a>(value: &'a Value, context: &Context) -> Option<&'a Exception> {
+    if !value.is_rust_value() {
+	return None;
+    }
+    value.get_rust_value(context).ok()?.downcast_ref::<Exception>()
+}
+
+fn get_exception<'a>(who: &str, value: &'a Value, context: &Context) -> HelperResult<&'a Exception> {
+    as_exception(value, context).ok_or(Box::new(Exception::new(&vec![who], "argument must be an exception", context)))
+}
+
+fn stdlib_exception_shape() -> FunctionShape {
+    FunctionShape::new(vec!["exception".to_string()])
+}
+
+fn stdlib_is_exception(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let exception = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("exception").ok_or(Box::new(Exception::new(&vec!["exception?"], "missing argument exception", context)))?
+    };
+    Ok(Value::new_boolean(as_exception(exception, context).is_some()))
+}
+
+fn stdlib_exception_who(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let exception = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("exception").ok_or(Box::new(Exception::new(&vec!["exception-who"], "missing argument exception", context)))?
+    };
+    Ok(get_exception("exception-who", exception, context)?.get_who_value())
+}
+
+fn stdlib_exception_message(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let exception = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("exception").ok_or(Box::new(Exception::new(&vec!["exception-message"], "missing argument exception", context)))?
+    };
+    Ok(get_exception("exception-message", exception, context)?.get_message())
+}
+
+fn stdlib_exception_backtrace(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let exception = if args.len() == 1 {
+	&args[0]
+    } else {
+	keyword_args.get("exception").ok_or(Box::new(Exception::new(&vec!["exception-backtrace"], "missing argument exception", context)))?
+    };
+    Ok(get_exception("exception-backtrace", exception, context)?.get_backtrace_value(context))
+}
+
+fn stdlib_exception_field_shape() -> FunctionShape {
+    FunctionShape::new(vec!["exception".to_string(), "field".to_string()])
+}
+
+fn stdlib_exception_field(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -> HelperResult<Value> {
+    let (exception, field) = if args.len() == 2 {
+	(&args[0], &args[1])
+    } else if args.len() == 1 {
+	let field = keyword_args.get("field").ok_or(Box::new(Exception::new(&vec!["exception-field"], "missing argument field", context)))?;
+	(&args[0], field)
+    } else {
+	let exception = keyword_args.get("exception").ok_or(Box::new(Exception::new(&vec!["exception-field"], "missing argument exception", context)))?;
+	let field = keyword_args.get("field").ok_or(Box::new(Exception::new(&vec!["exception-field"], "missing argument field", context)))?;
+	(exception, field)
+    };
+    let field = field.get_symbol(context)?.join(".");
+    let exception = get_exception("exception-field", exception, context)?;
+    Ok(exception.get_field(&field).cloned().unwrap_or_else(Value::new_nil))
+}
+
 fn stdlib_while_shape() -> FunctionShape {
     FunctionShape::new(vec!["condition".to_string(), "body".to_string()])
 }
@@ -2347,6 +2627,27 @@ pub fn get_stdlib(context: &mut Context) -> ContextFrame {
     bindings.insert("for-range".to_string(), Value::new_function(Function::Native(stdlib_for_range, stdlib_for_range_shape()), context));
     bindings.insert("vector".to_string(), Value::new_function(Function::Native(stdlib_vector, stdlib_vector_shape()), context));
     bindings.insert("debug-display".to_string(), Value::new_function(Function::Native(stdlib_debug_display, stdlib_debug_display_shape()), context));
+    bindings.insert("sexpr?".to_string(), Value::new_function(Function::Native(stdlib_is_sexpr, stdlib_is_sexpr_shape()), context));
+    bindings.insert("sexpr->list".to_string(), Value::new_function(Function::Native(stdlib_sexpr_to_list, stdlib_sexpr_to_list_shape()), context));
+    bindings.insert("list->sexpr".to_string(), Value::new_function(Function::Native(stdlib_list_to_sexpr, stdlib_list_to_sexpr_shape()), context));
+    bindings.insert("disassemble".to_string(), Value::new_function(Function::Native(stdlib_disassemble, stdlib_disassemble_shape()), context));
+    bindings.insert("macroexpand".to_string(), Value::new_function(Function::Native(stdlib_macroexpand, stdlib_macroexpand_shape()), context));
+    bindings.insert("macroexpand-1".to_string(), Value::new_function(Function::Native(stdlib_macroexpand_1, stdlib_macroexpand_1_shape()), context));
+    bindings.insert("gensym".to_string(), Value::new_function(Function::Native(stdlib_gensym, stdlib_gensym_shape()), context));
+    bindings.insert("call-with-current-continuation".to_string(), Value::new_function(Function::Native(stdlib_call_with_escape, stdlib_call_with_escape_shape()), context));
+    bindings.insert("call/cc".to_string(), Value::new_function(Function::Native(stdlib_call_with_escape, stdlib_call_with_escape_shape()), context));
+    bindings.insert("call-with-escape-continuation".to_string(), Value::new_function(Function::Native(stdlib_call_with_escape, stdlib_call_with_escape_shape()), context));
+    bindings.insert("raise".to_string(), Value::new_function(Function::Native(stdlib_raise, stdlib_raise_shape()), context));
+    bindings.insert("signal".to_string(), Value::new_function(Function::Native(stdlib_signal, stdlib_signal_shape()), context));
+    bindings.insert("invoke-restart".to_string(), Value::new_function(Function::Native(stdlib_invoke_restart, stdlib_invoke_restart_shape()), context));
+    bindings.insert("compute-restarts".to_string(), Value::new_function(Function::Native(stdlib_compute_restarts, stdlib_compute_restarts_shape()), context));
+    bindings.insert("exception?".to_string(), Value::new_function(Function::Native(stdlib_is_exception, stdlib_exception_shape()), context));
+    bindings.insert("exception-who".to_string(), Value::new_function(Function::Native(stdlib_exception_who, stdlib_exception_shape()), context));
+    bindings.insert("exception-message".to_string(), Value::new_function(Function::Native(stdlib_exception_message, stdlib_exception_shape()), context));
+    bindings.insert("exception-backtrace".to_string(), Value::new_function(Function::Native(stdlib_exception_backtrace, stdlib_exception_shape()), context));
+    bindings.insert("exception-field".to_string(), Value::new_function(Function::Native(stdlib_exception_field, stdlib_exception_field_shape()), context));
+    bindings.insert("dynamic-wind".to_string(), Value::new_function(Function::Native(stdlib_dynamic_wind, stdlib_dynamic_wind_shape()), context));
+    bindings.insert("call/ec".to_string(), Value::new_function(Function::Native(stdlib_call_with_escape, stdlib_call_with_escape_shape()), context));
     
     
 
@@ -2355,3 +2656,108 @@ pub fn get_stdlib(context: &mut Context) -> ContextFrame {
 
     ContextFrame::new_with_bindings(bindings)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::bytecode::compiler::tests::eval_vm;
+    use crate::interpreter::walkthrough::tests::eval;
+
+    #[test]
+    fn test_call_cc_escapes_nested_calls() {
+	let source = "(define (descend n k) (if (= n 0) (k 'escaped) (+ 1 (descend (- n 1) k))))
+(call/cc (lambda (k) (descend 5 k)))";
+	assert_eq!(eval(source).unwrap(), "'escaped");
+	assert_eq!(eval_vm(source).unwrap(), "'escaped");
+	assert_eq!(eval("(+ 1 (call/cc (lambda (k) (+ 10 (k 1)))))").unwrap(), "2");
+	assert_eq!(eval("(call/cc :procedure (lambda (k) (k 'keyword)))").unwrap(), "'keyword");
+	assert_eq!(eval_vm("(call/cc :procedure (lambda (k) (k 'keyword)))").unwrap(), "'keyword");
+    }
+
+    #[test]
+    fn test_call_cc_after_its_extent() {
+	let source = "(define saved #f)
+(call/cc (lambda (k) (set! saved k) 1))
+(saved 2)";
+	for result in [eval(source), eval_vm(source)] {
+	    let e = result.unwrap_err();
+	    assert!(e.to_string().ends_with("'continuation: continuation is no longer active"), "{}", e);
+	}
+    }
+
+    #[test]
+    fn test_dynamic_wind_after_runs_however_thunk_is_left() {
+	let source = "(define afters 0)
+(define (before) #t)
+(define (after) (set! afters (+ afters 1)))
+(define normal (dynamic-wind before (lambda () 'value) after))
+(define failed (try (dynamic-wind before (lambda () (error 'who \"bad\")) after) ((catch 'who message) message)))
+(define escaped (call/cc (lambda (k) (dynamic-wind before (lambda () (k 'out)) after))))
+`(,normal ,failed ,escaped ,afters)";
+	assert_eq!(eval(source).unwrap(), "'('value bad 'out 3)");
+	assert_eq!(eval_vm(source).unwrap(), "'('value bad 'out 3)");
+	let source = "(dynamic-wind (lambda () #t) :after (lambda () #t) :thunk (lambda () 'value))";
+	assert_eq!(eval(source).unwrap(), "'value");
+	assert_eq!(eval_vm(source).unwrap(), "'value");
+    }
+
+    #[test]
+    fn test_dynamic_wind_checks_the_arguments_of_its_procedures() {
+	let source = "(define (two a b) a)\n(dynamic-wind two two two)";
+	for result in [eval(source), eval_vm(source)] {
+	    let e = result.unwrap_err();
+	    assert!(e.to_string().ends_with("'procedure: wrong number of arguments"), "{}", e);
+	}
+	let e = eval("(dynamic-wind raise raise raise)").unwrap_err();
+	assert_eq!(e.to_string(), "test.lpy:1:1: 'procedure: wrong number of arguments");
+    }
+
+    #[test]
+    fn test_finally_runs_once_when_a_handler_reraises() {
+	let source = "(define finals 0)
+(define message
+  (try (try (error 'who \"bad\")
+         ((catch-all condition) (raise condition))
+         (finally (set! finals (+ finals 1))))
+    ((catch 'who message) message)))
+`(,message ,finals)";
+	assert_eq!(eval(source).unwrap(), "'(bad 1)");
+	assert_eq!(eval_vm(source).unwrap(), "'(bad 1)");
+    }
+
+    #[test]
+    fn test_raise_values_that_are_not_strings() {
+	let source = "`(,(try (raise 42) ((catch-all v) v)) ,(try (raise 'sym) ((catch-all v) v)) ,(try (raise '(1 2)) ((catch-all v) v)))";
+	assert_eq!(eval(source).unwrap(), "'(42 'sym '(1 2))");
+	assert_eq!(eval_vm(source).unwrap(), "'(42 'sym '(1 2))");
+	assert_eq!(eval("(raise 42)").unwrap_err().to_string(), "test.lpy:1:1: 'raise: uncaught value 42");
+    }
+
+    #[test]
+    fn test_conditions_by_keyword() {
+	let source = "(try (raise :value 'sym) ((catch-all v) v))";
+	assert_eq!(eval(source).unwrap(), "'sym");
+	assert_eq!(eval_vm(source).unwrap(), "'sym");
+	assert_eq!(eval("(signal :condition 'sym)").unwrap(), "nil");
+	let source = "(try (error 'who \"bad\") ((catch-all c) `(,(exception? :exception c) ,(exception-who :exception c) ,(exception-message :exception c) ,(exception-field c :field 'missing))))";
+	assert_eq!(eval(source).unwrap(), "'(true 'who bad nil)");
+	assert_eq!(eval_vm(source).unwrap(), "'(true 'who bad nil)");
+    }
+
+    #[test]
+    fn test_catch_all() {
+	let source = "(try (error 'who \"bad\") ((catch-all c) `(,(exception-who c) ,(exception-message c))))";
+	assert_eq!(eval(source).unwrap(), "'('who bad)");
+	assert_eq!(eval_vm(source).unwrap(), "'('who bad)");
+    }
+
+    #[test]
+    fn test_catch_if() {
+	let source = "(try (raise 'sym) ((catch-if integer? v) 'number) ((catch-if symbol? v) v))";
+	assert_eq!(eval(source).unwrap(), "'sym");
+	assert_eq!(eval_vm(source).unwrap(), "'sym");
+	assert_eq!(eval("(try (raise 7) ((catch-if integer? v) (+ v 1)))").unwrap(), "8");
+	// A condition no predicate accepts is not caught
+	let e = eval("(try (error 'who \"bad\") ((catch-if integer? c) 'caught))").unwrap_err();
+	assert_eq!(e.to_string(), "test.lpy:1:6: 'who: bad");
+    }
+}
@@ -102,9 +102,9 @@ fn stdlib_udp_socket(context: &mut Context, args: Vec<Value>, keyword_args: Kwar
 
     let addr = addr.get_rust_value(context)?;
     let socket = if let Some(addr) = addr.downcast_ref::<std::net::SocketAddrV4>() {
-	std::net::UdpSocket::bind(addr).map_err(|_| Exception::new(&vec!["network","udp-socket"], "socket bind error", context))?
+	std::net::UdpSocket::bind(addr).map_err(|err| Exception::new_io(&vec!["network","udp-socket"], &err, context))?
     } else if let Some(addr) = addr.downcast_ref::<std::net::SocketAddrV6>() {
-	std::net::UdpSocket::bind(addr).map_err(|_| Exception::new(&vec!["network","udp-socket"], "socket bind error", context))?
+	std::net::UdpSocket::bind(addr).map_err(|err| Exception::new_io(&vec!["network","udp-socket"], &err, context))?
     } else {
 	return Err(Box::new(Exception::new(&vec!["network","udp-socket"], "addr is not a socket address", context)));
     };
@@ -129,9 +129,9 @@ fn stdlib_tcp_socket(context: &mut Context, args: Vec<Value>, keyword_args: Kwar
 
     let addr = addr.get_rust_value(context)?;
     let socket = if let Some(addr) = addr.downcast_ref::<std::net::SocketAddrV4>() {
-	std::net::TcpStream::connect(addr).map_err(|err| Exception::new(&vec!["network","tcp-socket"], &format!("{}", err), context))?
+	std::net::TcpStream::connect(addr).map_err(|err| Exception::new_io(&vec!["network","tcp-socket"], &err, context))?
     } else if let Some(addr) = addr.downcast_ref::<std::net::SocketAddrV6>() {
-	std::net::TcpStream::connect(addr).map_err(|err| Exception::new(&vec!["network","tcp-socket"], &format!("{}", err), context))?
+	std::net::TcpStream::connect(addr).map_err(|err| Exception::new_io(&vec!["network","tcp-socket"], &err, context))?
     } else {
 	return Err(Box::new(Exception::new(&vec!["network","tcp-socket"], "addr is not a socket address", context)));
     };
@@ -156,9 +156,9 @@ fn stdlib_tcp_listener(context: &mut Context, args: Vec<Value>, keyword_args: Kw
 
     let addr = addr.get_rust_value(context)?;
     let listener = if let Some(addr) = addr.downcast_ref::<std::net::SocketAddrV4>() {
-	std::net::TcpListener::bind(addr).map_err(|err| Exception::new(&vec!["network","tcp-listener"], &format!("{}", err), context))?
+	std::net::TcpListener::bind(addr).map_err(|err| Exception::new_io(&vec!["network","tcp-listener"], &err, context))?
     } else if let Some(addr) = addr.downcast_ref::<std::net::SocketAddrV6>() {
-	std::net::TcpListener::bind(addr).map_err(|err| Exception::new(&vec!["network","tcp-listener"], &format!("{}", err), context))?
+	std::net::TcpListener::bind(addr).map_err(|err| Exception::new_io(&vec!["network","tcp-listener"], &err, context))?
     } else {
 	return Err(Box::new(Exception::new(&vec!["network","tcp-listener"], "addr is not a socket address", context)));
     };
@@ -195,7 +195,7 @@ fn stdlib_connect(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs)
 	let socket = socket.get_rust_value(context)?;
 	if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 	    if let Some(socket) = socket {
-		socket.connect(addr).map_err(|err| Exception::new(&vec!["network","connect"], &format!("{}", err), context))?;
+		socket.connect(addr).map_err(|err| Exception::new_io(&vec!["network","connect"], &err, context))?;
 	    } else {
 		return Err(Box::new(Exception::new(&vec!["network","connect"], "socket is not a udp socket", context)));
 	    }
@@ -206,7 +206,7 @@ fn stdlib_connect(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs)
 	let socket = socket.get_rust_value(context)?;
 	if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 	    if let Some(socket) = socket {
-		socket.connect(addr).map_err(|err| Exception::new(&vec!["network","connect"], &format!("{}", err), context))?;
+		socket.connect(addr).map_err(|err| Exception::new_io(&vec!["network","connect"], &err, context))?;
 	    } else {
 		return Err(Box::new(Exception::new(&vec!["network","connect"], "socket is not a udp socket", context)));
 	    }
@@ -239,7 +239,7 @@ fn stdlib_accept(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs)
 	return Err(Box::new(Exception::new(&vec!["network","accept"], "listener is closed", context)));
     }
     let listener = listener.as_ref().unwrap();
-    let (stream, addr) = listener.accept().map_err(|err| Exception::new(&vec!["network","accept"], &format!("{}", err), context))?;
+    let (stream, addr) = listener.accept().map_err(|err| Exception::new_io(&vec!["network","accept"], &err, context))?;
     let stream = Box::new(stream);
     let addr = Box::new(addr);
 
@@ -272,12 +272,12 @@ fn stdlib_set_nonblocking(context: &mut Context, args: Vec<Value>, keyword_args:
     let socket = socket.get_rust_value(context)?;
     if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 	if let Some(socket) = socket {
-	    socket.set_nonblocking(nonblocking).map_err(|err| Exception::new(&vec!["network","set-nonblocking"], &format!("{}", err), context))?;
+	    socket.set_nonblocking(nonblocking).map_err(|err| Exception::new_io(&vec!["network","set-nonblocking"], &err, context))?;
 	} else {
 	    return Err(Box::new(Exception::new(&vec!["network","set-nonblocking"], "socket is closed", context)));
 	}
     } else if let Some(socket) = socket.downcast_ref::<std::net::TcpStream>() {
-	socket.set_nonblocking(nonblocking).map_err(|err| Exception::new(&vec!["network","set-nonblocking"], &format!("{}", err), context))?;
+	socket.set_nonblocking(nonblocking).map_err(|err| Exception::new_io(&vec!["network","set-nonblocking"], &err, context))?;
     } else {
 	return Err(Box::new(Exception::new(&vec!["network","set-nonblocking"], "socket is not a socket", context)));
     };
@@ -303,12 +303,12 @@ fn stdlib_send(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) ->
 	    let socket = socket.get_rust_value(context)?;
 	    if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 		if let Some(socket) = socket {
-		    socket.send(&data.as_bytes()).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		    socket.send(&data.as_bytes()).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 		} else {
 		    return Err(Box::new(Exception::new(&vec!["network","send"], "socket is closed", context)));
 		}
 	    } else if let Some(mut socket) = socket.downcast_ref::<std::net::TcpStream>() {
-		socket.write_all(&data.as_bytes()).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		socket.write_all(&data.as_bytes()).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 	    } else {
 		return Err(Box::new(Exception::new(&vec!["network","send"], "socket is not a socket", context)));
 	    };
@@ -316,12 +316,12 @@ fn stdlib_send(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) ->
 	    let socket = socket.get_rust_value(context)?;
 	    if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 		if let Some(socket) = socket {
-		    socket.send(&data).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		    socket.send(&data).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 		} else {
 		    return Err(Box::new(Exception::new(&vec!["network","send"], "socket is closed", context)));
 		}
 	    } else if let Some(mut socket) = socket.downcast_ref::<std::net::TcpStream>() {
-		socket.write_all(&data).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		socket.write_all(&data).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 	    } else {
 		return Err(Box::new(Exception::new(&vec!["network","send"], "socket is not a socket", context)));
 	    };
@@ -333,12 +333,12 @@ fn stdlib_send(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) ->
 	    let socket = socket.get_rust_value(context)?;
 	    if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 		if let Some(socket) = socket {
-		    socket.send(&data.as_bytes()).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		    socket.send(&data.as_bytes()).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 		} else {
 		    return Err(Box::new(Exception::new(&vec!["network","send"], "socket is closed", context)));
 		}
 	    } else if let Some(mut socket) = socket.downcast_ref::<std::net::TcpStream>() {
-		socket.write_all(&data.as_bytes()).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		socket.write_all(&data.as_bytes()).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 	    } else {
 		return Err(Box::new(Exception::new(&vec!["network","send"], "socket is not a socket", context)));
 	    };
@@ -346,12 +346,12 @@ fn stdlib_send(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) ->
 	    let socket = socket.get_rust_value(context)?;
 	    if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 		if let Some(socket) = socket {
-		    socket.send(&data).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		    socket.send(&data).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 		} else {
 		    return Err(Box::new(Exception::new(&vec!["network","send"], "socket is closed", context)));
 		}
 	    } else if let Some(mut socket) = socket.downcast_ref::<std::net::TcpStream>() {
-		socket.write_all(&data).map_err(|err| Exception::new(&vec!["network","send"], &format!("{}", err), context))?;
+		socket.write_all(&data).map_err(|err| Exception::new_io(&vec!["network","send"], &err, context))?;
 	    } else {
 		return Err(Box::new(Exception::new(&vec!["network","send"], "socket is not a socket", context)));
 	    };
@@ -381,13 +381,13 @@ fn stdlib_receive(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs)
     let mut buffer = [0; 1024];
     let data = if let Some(socket) = socket.downcast_ref::<Option<std::net::UdpSocket>>() {
 	if let Some(socket) = socket {
-	    let (size, _) = socket.recv_from(&mut buffer).map_err(|err| Exception::new(&vec!["network","receive"], &format!("{}", err), context))?;
+	    let (size, _) = socket.recv_from(&mut buffer).map_err(|err| Exception::new_io(&vec!["network","receive"], &err, context))?;
 	    buffer[..size].to_vec()
 	} else {
 	    return Err(Box::new(Exception::new(&vec!["network","receive"], "socket is closed", context)));
 	}
     } else if let Some(mut socket) = socket.downcast_ref::<std::net::TcpStream>() {
-	let size = socket.read(&mut buffer).map_err(|err| Exception::new(&vec!["network","receive"], &format!("{}", err), context))?;
+	let size = socket.read(&mut buffer).map_err(|err| Exception::new_io(&vec!["network","receive"], &err, context))?;
 	buffer[..size].to_vec()
     } else {
 	return Err(Box::new(Exception::new(&vec!["network","receive"], "socket is not a socket", context)));
@@ -416,7 +416,7 @@ fn stdlib_close(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) -
 	    return Err(Box::new(Exception::new(&vec!["network","close"], "socket is already closed", context)));
 	}
     } else if let Some(socket) = socket.downcast_ref::<std::net::TcpStream>() {
-	socket.shutdown(std::net::Shutdown::Both).map_err(|err| Exception::new(&vec!["network","close"], &format!("{}", err), context))?;
+	socket.shutdown(std::net::Shutdown::Both).map_err(|err| Exception::new_io(&vec!["network","close"], &err, context))?;
     } else if let Some(socket) = socket.downcast_mut::<Option<std::net::TcpListener>>() {
 	let socket = socket.take();
 	if socket.is_none() {
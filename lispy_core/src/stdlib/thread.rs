@@ -50,9 +50,10 @@ fn stdlib_spawn_named(context: &mut Context, args: Vec<Value>, keyword_args: Kwa
     spawn_wrapper(context, function, Some(name))
 }
 
+/// The thread shares the global frame with its parent, so top level definitions and assignments made
+/// by either are seen by both. Use the sync module to guard state that both change.
 fn spawn_wrapper(context: &mut Context, function : Value, name: Option<String>) -> HelperResult<Value> {
-    let mut new_context = context.clone();
-    new_context.push_frame(Some(context.copy_frame_at(0)));
+    let new_context = context.clone();
     let builder = std::thread::Builder::new();
     let builder = if let Some(name) = name {
 	builder.name(name.to_string())
@@ -65,7 +66,7 @@ fn spawn_wrapper(context: &mut Context, function : Value, name: Option<String>)
 	let mut new_context = new_context;
 	let function = function.get_function(&new_context).expect("function is not a function");
         function.call(&vec!["<procedure>".to_string()], &vec![], &mut new_context, &vec![])
-    });
+    }).map_err(|err| Box::new(Exception::new_io(&vec!["thread", "spawn"], &err, context)))?;
 
     let handle = Box::new(Some(handle));
 
@@ -90,7 +91,7 @@ fn stdlib_join(context: &mut Context, args: Vec<Value>, keyword_args: Kwargs) ->
 
     let handle = handle.get_rust_value_mut(context)?;
 
-    let mut handle = handle.downcast_mut::<Box<Option<std::thread::JoinHandle<InterpreterResult>>>>();
+    let mut handle = handle.downcast_mut::<Option<std::thread::JoinHandle<InterpreterResult>>>();
     let handle = handle.as_mut().expect("downcast error");
 
     Ok(handle.take().unwrap().join().expect("join error")?.unwrap())
@@ -141,3 +142,18 @@ pub fn get_thread_library(context: &mut Context) -> Module {
 
     Module::new_loaded(frame)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::walkthrough::tests::eval;
+
+    #[test]
+    fn test_threads_share_globals() {
+	let source = "(define counter 0)
+(define (bump) (set! counter (+ counter 1)) counter)
+(thread.join (thread.spawn bump))
+(bump)
+counter";
+	assert_eq!(eval(source).unwrap(), "2");
+    }
+}